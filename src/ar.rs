@@ -1,20 +1,138 @@
-use std::io::Read;
+use std::ffi::OsStr;
+use std::io::SeekFrom;
 use std::os::unix::prelude::{OsStrExt, OsStringExt};
 
 use smoosh::CompressionType;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
 use tracing::debug;
 
 crate::util::archive_format!(Ar, "a.ar", ar_open, ar_close);
 
+const GLOBAL_HEADER: &[u8; 8] = b"!<arch>\n";
+const THIN_GLOBAL_HEADER: &[u8; 8] = b"!<thin>\n";
+const HEADER_SIZE: usize = 60;
+const BSD_SYMBOL_TABLE: &[u8] = b"__.SYMDEF";
+
+/// Which flavour of ar an archive is. They differ in how names that don't
+/// fit in the header are stored, and in what the symbol table looks like.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ArVariant {
+    /// No long names or symbol table. Names that don't fit are written
+    /// BSD-style.
+    #[default]
+    Common,
+    /// GNU and System V, with a `//` table of long names and a `/` symbol
+    /// table.
+    Gnu,
+    /// BSD and Darwin, with long names before each member's data and a
+    /// `__.SYMDEF` symbol table.
+    Bsd,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct ArState {
+    variant: std::sync::Mutex<ArVariant>,
+    /// Whether the archive is a static library, ie. whether a symbol table
+    /// is written on close.
+    symbol_table: std::sync::Mutex<bool>,
+    /// The symbols that the original symbol table had for members that
+    /// aren't objects we can read, like LLVM bitcode, along with a hash of
+    /// the member so they're dropped if it changes.
+    foreign_symbols: HashMap<PathBuf, (u64, Vec<Vec<u8>>)>,
+    /// The members that haven't changed since the archive was opened.
+    originals: std::sync::Mutex<HashMap<PathBuf, ArOriginal>>,
+    /// The variant, length and member count of the archive, if it's
+    /// uncompressed and has no symbol table, so that new members can be
+    /// written at the end instead of writing it again.
+    appendable: Option<(ArVariant, usize, usize)>,
+    /// Names that more than one member had. Only the last of those can be
+    /// read, and the archive can't be written back without losing the
+    /// others.
+    duplicates: Vec<PathBuf>,
+}
+
+#[derive(Debug)]
+struct ArOriginal {
+    index: usize,
+    mtime: u64,
+    uid: u32,
+    gid: u32,
+    mode: u32,
+}
+
+impl crate::util::LazyEntries for ArState {
+    fn invalidate(&self, path: &Path) {
+        self.originals.lock().unwrap().remove(path);
+    }
+}
+
+impl ArFloppyDisk {
+    pub fn variant(&self) -> ArVariant {
+        *self.state.variant.lock().unwrap()
+    }
+
+    pub fn set_variant(&self, variant: ArVariant) {
+        *self.state.variant.lock().unwrap() = variant;
+    }
+
+    /// Whether the archive has a symbol table, like static libraries do.
+    /// It's rebuilt from the ELF and Mach-O objects in the archive on close.
+    pub fn has_symbol_table(&self) -> bool {
+        *self.state.symbol_table.lock().unwrap()
+    }
+
+    /// Adds or removes the symbol table. Common archives can't have one, so
+    /// they're written as GNU archives instead.
+    pub fn set_symbol_table(&self, symbol_table: bool) {
+        *self.state.symbol_table.lock().unwrap() = symbol_table;
+    }
+
+    /// Maps each global symbol to the member that defines it, as the symbol
+    /// table would on close. This reflects members added, changed or
+    /// removed since the archive was opened. When more than one member
+    /// defines a symbol, the first one wins, like it does for the linker.
+    pub async fn symbols(&self) -> Result<IndexMap<OsString, PathBuf>> {
+        let members = read_members(self, &*self.ordered_paths.lock().await).await?;
+        let mut symbols = IndexMap::new();
+        for member in &members {
+            let path = Path::new("/").join(OsStr::from_bytes(&member.name));
+            for symbol in self.member_symbols(member) {
+                symbols
+                    .entry(OsString::from_vec(symbol))
+                    .or_insert_with(|| path.clone());
+            }
+        }
+        Ok(symbols)
+    }
+
+    /// The member that defines `symbol`, if any.
+    pub async fn find_symbol<S: AsRef<OsStr>>(&self, symbol: S) -> Result<Option<PathBuf>> {
+        Ok(self.symbols().await?.swap_remove(symbol.as_ref()))
+    }
+
+    fn member_symbols(&self, member: &Member) -> Vec<Vec<u8>> {
+        object_symbols(&member.data).unwrap_or_else(|| {
+            let path = Path::new("/").join(OsStr::from_bytes(&member.name));
+            match self.state.foreign_symbols.get(&path) {
+                Some((hash, symbols)) if *hash == content_hash(&member.data) => symbols.clone(),
+                _ => vec![],
+            }
+        })
+    }
+}
+
 async fn ar_open<P: Into<PathBuf>>(path: P) -> Result<ArInternalMetadata> {
     let path = path.into();
     if !crate::util::exists_async(path.clone()).await {
         debug!("creating empty ar!");
-        let _archive = ar::Archive::new(std::fs::File::create(path)?);
+        tokio::fs::write(&path, GLOBAL_HEADER).await?;
         return Ok(ArInternalMetadata {
             delegate: MemFloppyDisk::new(),
             compression: CompressionType::None,
             ordered_paths: IndexSet::new(),
+            times: HashMap::new(),
+            hard_links: IndexMap::new(),
+            state: Default::default(),
         });
     }
 
@@ -24,20 +142,33 @@ async fn ar_open<P: Into<PathBuf>>(path: P) -> Result<ArInternalMetadata> {
     let mut buffer = vec![];
     let c = smoosh::recompress(&mut file, &mut buffer, smoosh::CompressionType::None).await?;
 
-    let mut archive = ar::Archive::new(buffer.as_slice());
+    let archive = RawArchive::parse(&buffer)?;
+    debug!(
+        "found {:?} ar with {} members",
+        archive.variant,
+        archive.members.len()
+    );
     let out = MemFloppyDisk::new();
     let mut ordered_paths = IndexSet::new();
+    let mut times = HashMap::new();
+    let mut originals = HashMap::new();
+    let mut duplicates = vec![];
 
-    while let Some(entry) = archive.next_entry() {
-        let mut entry = entry?;
-        let header = entry.header();
-        let path = PathBuf::from(OsString::from_vec(header.identifier().to_vec()));
+    for (index, member) in archive.members.iter().enumerate() {
+        let path = PathBuf::from(OsString::from_vec(member.name.clone()));
         let path = if !path.starts_with("/") {
             PathBuf::from("/").join(path)
         } else {
             path
         };
-        ordered_paths.insert(path.clone());
+        if !ordered_paths.insert(path.clone()) && !duplicates.contains(&path) {
+            debug!("found duplicate ar member {}", path.display());
+            duplicates.push(path.clone());
+        }
+        times.insert(
+            path.clone(),
+            std::time::UNIX_EPOCH + std::time::Duration::from_secs(member.mtime),
+        );
         debug!("processing archive path {}", path.display());
 
         if let Some(parent) = path.parent() {
@@ -46,43 +177,225 @@ async fn ar_open<P: Into<PathBuf>>(path: P) -> Result<ArInternalMetadata> {
         let mut handle = MemOpenOptions::new()
             .create(true)
             .write(true)
+            .truncate(true)
             .open(&out, &path)
             .await?;
 
-        let mut data = vec![];
-        entry.read_to_end(&mut data)?;
-        tokio::io::copy(&mut data.as_slice(), &mut handle).await?;
+        tokio::io::copy(&mut &member.data[..], &mut handle).await?;
+        out.set_permissions(&path, MemPermissions::from_mode(member.mode & 0o7777))
+            .await?;
+        out.chown(&path, member.uid, member.gid).await?;
+        originals.insert(
+            path,
+            ArOriginal {
+                index,
+                mtime: member.mtime,
+                uid: member.uid,
+                gid: member.gid,
+                mode: member.mode & 0o7777,
+            },
+        );
         debug!("copied path!");
     }
 
+    let mut foreign_symbols: HashMap<PathBuf, (u64, Vec<Vec<u8>>)> = HashMap::new();
+    if let Some(table) = archive.symbol_table {
+        match table.parse() {
+            Ok(symbols) => {
+                for (symbol, offset) in symbols {
+                    let Some(idx) = archive.offsets.iter().position(|o| *o == offset) else {
+                        continue;
+                    };
+                    let member = &archive.members[idx];
+                    if object_symbols(&member.data).is_none() {
+                        let path = Path::new("/").join(OsStr::from_bytes(&member.name));
+                        foreign_symbols
+                            .entry(path)
+                            .or_insert_with(|| (content_hash(&member.data), vec![]))
+                            .1
+                            .push(symbol);
+                    }
+                }
+            }
+            Err(e) => debug!("ignoring unreadable ar symbol table: {e}"),
+        }
+    }
+
+    let appendable = match (c, archive.symbol_table, archive.end == buffer.len()) {
+        (CompressionType::None, None, true) if duplicates.is_empty() => {
+            Some((archive.variant, buffer.len(), originals.len()))
+        }
+        _ => None,
+    };
+
     debug!("finished opening ar!");
 
     Ok(ArInternalMetadata {
         delegate: out,
         compression: c,
         ordered_paths,
+        times,
+        hard_links: IndexMap::new(),
+        state: ArState {
+            variant: std::sync::Mutex::new(archive.variant),
+            symbol_table: std::sync::Mutex::new(archive.symbol_table.is_some()),
+            foreign_symbols,
+            originals: std::sync::Mutex::new(originals),
+            appendable,
+            duplicates,
+        },
     })
 }
 
-async fn ar_close(
-    disk: &MemFloppyDisk,
-    scope: &Path,
-    compression: CompressionType,
-    ordered_paths: &IndexSet<PathBuf>,
-) -> Result<()> {
+async fn ar_close(ar: &ArFloppyDisk) -> Result<()> {
+    let scope = &ar.path;
+    let compression = ar.compression;
+    let ordered_paths = &*ar.ordered_paths.lock().await;
     debug!("closing ar at {}", scope.display());
+    if let Some(path) = ar.state.duplicates.first() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            format!(
+                "can't write {}, it has more than one member named {}",
+                scope.display(),
+                path.display()
+            ),
+        ));
+    }
+    if let Some((end, data)) = ar_append(ar, ordered_paths).await? {
+        debug!("appending {} bytes at {}", data.len(), end);
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .open(scope)
+            .await?;
+        file.seek(SeekFrom::Start(end as u64)).await?;
+        file.write_all(&data).await?;
+        file.set_len((end + data.len()) as u64).await?;
+        file.flush().await?;
+        debug!("finished appending to ar!");
+        return Ok(());
+    }
+
+    let members = read_members(ar, ordered_paths).await?;
+    let variant = match (ar.variant(), ar.has_symbol_table()) {
+        (ArVariant::Common, true) => ArVariant::Gnu,
+        (variant, _) => variant,
+    };
+    let symbols = match ar.has_symbol_table() {
+        true => {
+            let symbols: Vec<(Vec<u8>, usize)> = members
+                .iter()
+                .enumerate()
+                .flat_map(|(idx, member)| {
+                    ar.member_symbols(member)
+                        .into_iter()
+                        .map(move |symbol| (symbol, idx))
+                })
+                .collect();
+            Some(symbols)
+        }
+        false => None,
+    };
+    let buffer = write_archive(variant, &members, symbols.as_deref())?;
+    let mut out = vec![];
+    crate::util::write_compressed(&buffer, &mut out, compression).await?;
+
+    // The archive is only truncated once it's known that it can be written.
     let mut file = tokio::fs::OpenOptions::new()
         .write(true)
-        .truncate(true) // TODO: Validate this is actually desired...
+        .truncate(true)
         .open(scope)
         .await?;
-    let mut buffer = vec![];
-    let mut archive = ar::Builder::new(&mut buffer);
+    file.write_all(&out).await?;
+    file.flush().await?;
+    debug!("finished closing ar!");
 
+    Ok(())
+}
+
+/// If every member that was in the archive is still at the start of it,
+/// unchanged, returns where the archive ends and the new members to write
+/// there.
+async fn ar_append(
+    ar: &ArFloppyDisk,
+    ordered_paths: &IndexSet<PathBuf>,
+) -> Result<Option<(usize, Vec<u8>)>> {
+    let Some((variant, end, count)) = ar.state.appendable else {
+        return Ok(None);
+    };
+    if !matches!(ar.compression, CompressionType::None)
+        || ar.has_symbol_table()
+        || ar.variant() != variant
+    {
+        return Ok(None);
+    }
+    let members = read_members(ar, ordered_paths).await?;
+    if members.len() < count {
+        return Ok(None);
+    }
+    {
+        let originals = ar.state.originals.lock().unwrap();
+        for (index, member) in members[..count].iter().enumerate() {
+            let path = Path::new("/").join(OsStr::from_bytes(&member.name));
+            match originals.get(&path) {
+                Some(original)
+                    if original.index == index
+                        && original.mtime == member.mtime
+                        && original.uid == member.uid
+                        && original.gid == member.gid
+                        && original.mode == member.mode & 0o7777 => {}
+                _ => return Ok(None),
+            }
+        }
+    }
+
+    // BSD names are padded to align the data after them, which depends on
+    // where in the archive they are.
+    let mut out = vec![0; end % 8];
+    for member in &members[count..] {
+        match variant {
+            ArVariant::Common | ArVariant::Bsd => write_bsd_member(
+                &mut out,
+                &member.name,
+                member.mtime,
+                member.uid,
+                member.gid,
+                member.mode,
+                &member.data,
+            )?,
+            ArVariant::Gnu => {
+                // Long names would have to go in the name table, which comes
+                // before every member.
+                if member.name.len() > 15 || member.name.contains(&b'/') {
+                    return Ok(None);
+                }
+                let mut identifier = member.name.clone();
+                identifier.push(b'/');
+                write_header(
+                    &mut out,
+                    &identifier,
+                    member.mtime,
+                    member.uid,
+                    member.gid,
+                    member.mode,
+                    member.data.len(),
+                )?;
+                out.extend_from_slice(&member.data);
+                pad(&mut out);
+            }
+        }
+    }
+    out.drain(..end % 8);
+    Ok(Some((end, out)))
+}
+
+async fn read_members(ar: &ArFloppyDisk, ordered_paths: &IndexSet<PathBuf>) -> Result<Vec<Member>> {
+    let disk = &ar.delegate;
     debug!("walking ar paths...");
     debug!("found {} paths!", ordered_paths.len());
     // We only need to write file paths into the ar.
     // Directories are implied by the file paths.
+    let mut members = vec![];
     for path in ordered_paths {
         debug!("processing archive path {}", path.display());
         let metadata = disk.metadata(path).await?;
@@ -94,34 +407,1089 @@ async fn ar_close(
             tokio::io::AsyncReadExt::read_to_end(&mut handle, &mut data).await?;
             debug!("read full file from memfs!");
 
-            let mut header = ar::Header::new(
-                path.strip_prefix("/")
+            members.push(Member {
+                name: path
+                    .strip_prefix("/")
                     .unwrap()
                     .as_os_str()
                     .as_bytes()
                     .to_vec(),
-                data.len() as u64,
-            );
-            header.set_gid(metadata.gid()?);
-            header.set_uid(metadata.uid()?);
-            header.set_mode(metadata.permissions().mode());
-            header.set_mtime(
-                metadata
-                    .modified()?
+                mtime: ar
+                    .modified(path)
+                    .await?
                     .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs(),
-            );
-            debug!("created header!");
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0),
+                uid: metadata.uid()?,
+                gid: metadata.gid()?,
+                mode: metadata.permissions().mode(),
+                data,
+            });
+        }
+    }
+    Ok(members)
+}
+
+/// A member as it's stored in the archive.
+struct Member {
+    name: Vec<u8>,
+    mtime: u64,
+    uid: u32,
+    gid: u32,
+    mode: u32,
+    data: Vec<u8>,
+}
 
-            // TODO: async safety
-            archive.append(&header, &mut data.as_slice())?;
-            debug!("appended to archive: {}", path.display());
+/// An archive, with the long name and symbol tables taken out.
+struct RawArchive<'a> {
+    variant: ArVariant,
+    symbol_table: Option<SymbolTable<'a>>,
+    members: Vec<Member>,
+    /// Where each member's header starts, which is what symbol tables
+    /// point to.
+    offsets: Vec<usize>,
+    /// Where the last member ends, including its padding, which can be past
+    /// the end of the data if a writer left it out.
+    end: usize,
+}
+
+/// The contents of a symbol table, in one of its layouts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SymbolTable<'a> {
+    Gnu(&'a [u8]),
+    /// `/SYM64/`, for archives over 4GB.
+    Gnu64(&'a [u8]),
+    Bsd(&'a [u8]),
+    /// `__.SYMDEF_64`.
+    Bsd64(&'a [u8]),
+}
+
+impl SymbolTable<'_> {
+    /// Parses the table into symbol names and the offset of the header of
+    /// the member that defines each.
+    fn parse(self) -> Result<Vec<(Vec<u8>, usize)>> {
+        let truncated = || invalid("truncated ar symbol table");
+        let (data, wide, big_endian) = match self {
+            Self::Gnu(data) => (data, false, true),
+            Self::Gnu64(data) => (data, true, true),
+            Self::Bsd(data) => (data, false, false),
+            Self::Bsd64(data) => (data, true, false),
+        };
+        let reader = Reader { data, big_endian };
+        let word = if wide { 8 } else { 4 };
+        let read = |offset: usize| -> Result<usize> {
+            match wide {
+                true => reader.u64(offset).map(|v| v as usize),
+                false => reader.u32(offset).map(|v| v as usize),
+            }
+            .ok_or_else(truncated)
+        };
+
+        let mut out = vec![];
+        match self {
+            Self::Gnu(_) | Self::Gnu64(_) => {
+                let count = read(0)?;
+                let mut names = data
+                    .get(word + count.checked_mul(word).ok_or_else(truncated)?..)
+                    .ok_or_else(truncated)?;
+                for idx in 0..count {
+                    let end = names.iter().position(|b| *b == 0).ok_or_else(truncated)?;
+                    out.push((names[..end].to_vec(), read(word + idx * word)?));
+                    names = &names[end + 1..];
+                }
+            }
+            Self::Bsd(_) | Self::Bsd64(_) => {
+                // The sizes come from the archive, so adding them up can
+                // overflow.
+                let size = read(0)?;
+                let ranlibs_end = size.checked_add(word).ok_or_else(truncated)?;
+                let strings_start = ranlibs_end.checked_add(word).ok_or_else(truncated)?;
+                let strings_end = strings_start
+                    .checked_add(read(ranlibs_end)?)
+                    .ok_or_else(truncated)?;
+                let strings = data.get(strings_start..strings_end).ok_or_else(truncated)?;
+                for entry in (word..ranlibs_end).step_by(word * 2) {
+                    let name = strings.get(read(entry)?..).ok_or_else(truncated)?;
+                    let end = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+                    out.push((name[..end].to_vec(), read(entry + word)?));
+                }
+            }
         }
+        Ok(out)
     }
+}
 
-    smoosh::recompress(&mut buffer.as_slice(), &mut file, compression).await?;
-    debug!("finished closing ar!");
+impl<'a> RawArchive<'a> {
+    fn parse(data: &'a [u8]) -> Result<Self> {
+        if data.starts_with(THIN_GLOBAL_HEADER) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "thin ar archives are not supported",
+            ));
+        }
+        if !data.starts_with(GLOBAL_HEADER) {
+            return Err(invalid("not an ar archive"));
+        }
+
+        let mut variant = ArVariant::Common;
+        let mut symbol_table = None;
+        let mut name_table: &[u8] = &[];
+        let mut members = vec![];
+        let mut offsets = vec![];
+        let mut offset = GLOBAL_HEADER.len();
+        let mut end = offset;
+        while offset < data.len() {
+            // Some writers pad the end of the archive with a newline.
+            if data.len() - offset < HEADER_SIZE && data[offset..].iter().all(|b| *b == b'\n') {
+                break;
+            }
+            let header = data
+                .get(offset..offset + HEADER_SIZE)
+                .ok_or_else(|| invalid("truncated ar header"))?;
+            if &header[58..60] != b"`\n" {
+                return Err(invalid("invalid ar header"));
+            }
+            let size = number(&header[48..58], 10)? as usize;
+            let header_offset = offset;
+            let start = offset + HEADER_SIZE;
+            let mut contents = data
+                .get(start..start + size)
+                .ok_or_else(|| invalid("truncated ar member"))?;
+            offset = start + size + size % 2;
+            end = offset;
+
+            let identifier = trim_end(&header[..16], b' ');
+            let name = match identifier {
+                b"/" => {
+                    variant = ArVariant::Gnu;
+                    symbol_table = Some(SymbolTable::Gnu(contents));
+                    continue;
+                }
+                b"/SYM64/" => {
+                    variant = ArVariant::Gnu;
+                    symbol_table = Some(SymbolTable::Gnu64(contents));
+                    continue;
+                }
+                b"//" => {
+                    variant = ArVariant::Gnu;
+                    name_table = contents;
+                    continue;
+                }
+                _ if identifier.starts_with(b"#1/") => {
+                    variant = ArVariant::Bsd;
+                    let len = number(&identifier[3..], 10)? as usize;
+                    let name = contents
+                        .get(..len)
+                        .ok_or_else(|| invalid("truncated ar name"))?;
+                    contents = &contents[len..];
+                    let name = trim_end(name, 0);
+                    if name.starts_with(BSD_SYMBOL_TABLE) {
+                        symbol_table = Some(bsd_symbol_table_kind(name, contents));
+                        continue;
+                    }
+                    name.to_vec()
+                }
+                _ if identifier.starts_with(BSD_SYMBOL_TABLE) => {
+                    variant = ArVariant::Bsd;
+                    symbol_table = Some(bsd_symbol_table_kind(identifier, contents));
+                    continue;
+                }
+                [b'/', index @ ..] => {
+                    variant = ArVariant::Gnu;
+                    let start = number(index, 10)? as usize;
+                    let rest = name_table
+                        .get(start..)
+                        .ok_or_else(|| invalid("ar long name is out of range"))?;
+                    // Names can have slashes in them, so they end at `/\n`.
+                    let end = rest
+                        .windows(2)
+                        .position(|w| w == b"/\n")
+                        .or_else(|| rest.iter().position(|b| *b == 0 || *b == b'\n'))
+                        .unwrap_or(rest.len());
+                    rest[..end].to_vec()
+                }
+                [name @ .., b'/'] => {
+                    variant = ArVariant::Gnu;
+                    name.to_vec()
+                }
+                name => name.to_vec(),
+            };
+
+            offsets.push(header_offset);
+            members.push(Member {
+                name,
+                mtime: number(&header[16..28], 10)?,
+                uid: number(&header[28..34], 10)? as u32,
+                gid: number(&header[34..40], 10)? as u32,
+                mode: number(&header[40..48], 8)? as u32,
+                data: contents.to_vec(),
+            });
+        }
 
+        Ok(Self {
+            variant,
+            symbol_table,
+            members,
+            offsets,
+            end,
+        })
+    }
+}
+
+fn bsd_symbol_table_kind<'a>(name: &[u8], contents: &'a [u8]) -> SymbolTable<'a> {
+    match name.starts_with(b"__.SYMDEF_64") {
+        true => SymbolTable::Bsd64(contents),
+        false => SymbolTable::Bsd(contents),
+    }
+}
+
+/// Writes an archive. `symbols` are symbol names and the index of the
+/// member that defines them.
+fn write_archive(
+    variant: ArVariant,
+    members: &[Member],
+    symbols: Option<&[(Vec<u8>, usize)]>,
+) -> Result<Vec<u8>> {
+    let mut out = GLOBAL_HEADER.to_vec();
+    // The symbol table has the offsets of the members after it, but its size
+    // doesn't depend on them, so it's written with placeholders first and
+    // filled in once the members are.
+    let placeholders = vec![0; members.len()];
+    let table = match (variant, symbols) {
+        (ArVariant::Common, _) | (_, None) => None,
+        (ArVariant::Gnu, Some(symbols)) => {
+            let table = gnu_symbol_table(symbols, &placeholders);
+            write_header(&mut out, b"/", 0, 0, 0, 0, table.len())?;
+            out.extend_from_slice(&table);
+            Some((symbols, out.len() - table.len()))
+        }
+        (ArVariant::Bsd, Some(symbols)) => {
+            let table = bsd_symbol_table(symbols, &placeholders);
+            write_bsd_member(&mut out, BSD_SYMBOL_TABLE, 0, 0, 0, 0, &table)?;
+            Some((symbols, out.len() - table.len()))
+        }
+    };
+
+    let mut offsets = vec![];
+    match variant {
+        ArVariant::Common | ArVariant::Bsd => {
+            for member in members {
+                offsets.push(out.len());
+                write_bsd_member(
+                    &mut out,
+                    &member.name,
+                    member.mtime,
+                    member.uid,
+                    member.gid,
+                    member.mode,
+                    &member.data,
+                )?;
+            }
+        }
+        ArVariant::Gnu => {
+            // Long names, and names with slashes in them, go in the name
+            // table, each ending with `/\n`.
+            let mut name_table = vec![];
+            let identifiers: Vec<Vec<u8>> = members
+                .iter()
+                .map(|member| {
+                    if member.name.len() > 15 || member.name.contains(&b'/') {
+                        let identifier = format!("/{}", name_table.len()).into_bytes();
+                        name_table.extend_from_slice(&member.name);
+                        name_table.extend_from_slice(b"/\n");
+                        identifier
+                    } else {
+                        let mut identifier = member.name.clone();
+                        identifier.push(b'/');
+                        identifier
+                    }
+                })
+                .collect();
+            if !name_table.is_empty() {
+                write_header(&mut out, b"//", 0, 0, 0, 0, name_table.len())?;
+                out.extend_from_slice(&name_table);
+                pad(&mut out);
+            }
+            for (member, identifier) in members.iter().zip(&identifiers) {
+                offsets.push(out.len());
+                write_header(
+                    &mut out,
+                    identifier,
+                    member.mtime,
+                    member.uid,
+                    member.gid,
+                    member.mode,
+                    member.data.len(),
+                )?;
+                out.extend_from_slice(&member.data);
+                pad(&mut out);
+            }
+        }
+    }
+
+    if let Some((symbols, start)) = table {
+        if out.len() > u32::MAX as usize {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "ar symbol tables for archives over 4GB are not supported",
+            ));
+        }
+        let table = match variant {
+            ArVariant::Gnu => gnu_symbol_table(symbols, &offsets),
+            _ => bsd_symbol_table(symbols, &offsets),
+        };
+        out[start..start + table.len()].copy_from_slice(&table);
+    }
+    Ok(out)
+}
+
+/// Writes a member with its name in the header if it fits, and before its
+/// data otherwise. Those names are padded so that the data is 8-byte
+/// aligned, which is what Darwin's tools do for objects.
+fn write_bsd_member(
+    out: &mut Vec<u8>,
+    name: &[u8],
+    mtime: u64,
+    uid: u32,
+    gid: u32,
+    mode: u32,
+    data: &[u8],
+) -> Result<()> {
+    if name.len() > 16 || name.contains(&b' ') || name.starts_with(BSD_SYMBOL_TABLE) {
+        let unpadded = out.len() + HEADER_SIZE + name.len();
+        let padded = name.len() + (8 - unpadded % 8) % 8;
+        let identifier = format!("#1/{padded}").into_bytes();
+        write_header(out, &identifier, mtime, uid, gid, mode, padded + data.len())?;
+        out.extend_from_slice(name);
+        out.resize(out.len() + padded - name.len(), 0);
+    } else {
+        write_header(out, name, mtime, uid, gid, mode, data.len())?;
+    }
+    out.extend_from_slice(data);
+    pad(out);
+    Ok(())
+}
+
+fn write_header(
+    out: &mut Vec<u8>,
+    identifier: &[u8],
+    mtime: u64,
+    uid: u32,
+    gid: u32,
+    mode: u32,
+    size: usize,
+) -> Result<()> {
+    let fields = format!("{mtime:<12}{uid:<6}{gid:<6}{mode:<8o}{size:<10}`\n");
+    if identifier.len() > 16 || fields.len() != HEADER_SIZE - 16 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "ar header for {} doesn't fit",
+                String::from_utf8_lossy(identifier)
+            ),
+        ));
+    }
+    out.extend_from_slice(identifier);
+    out.resize(out.len() + 16 - identifier.len(), b' ');
+    out.extend_from_slice(fields.as_bytes());
     Ok(())
 }
+
+fn pad(out: &mut Vec<u8>) {
+    if !out.len().is_multiple_of(2) {
+        out.push(b'\n');
+    }
+}
+
+/// A big-endian count, the offset of each symbol's member, and then the
+/// symbol names.
+fn gnu_symbol_table(symbols: &[(Vec<u8>, usize)], offsets: &[usize]) -> Vec<u8> {
+    let mut out = (symbols.len() as u32).to_be_bytes().to_vec();
+    for (_, member) in symbols {
+        out.extend_from_slice(&(offsets[*member] as u32).to_be_bytes());
+    }
+    for (symbol, _) in symbols {
+        out.extend_from_slice(symbol);
+        out.push(0);
+    }
+    // GNU ar pads the table with NULs rather than a newline.
+    if !out.len().is_multiple_of(2) {
+        out.push(0);
+    }
+    out
+}
+
+/// The size of the `ranlib` structs, the structs themselves, which are the
+/// offset of the symbol's name and of its member, and then the names.
+fn bsd_symbol_table(symbols: &[(Vec<u8>, usize)], offsets: &[usize]) -> Vec<u8> {
+    let mut strings = vec![];
+    let mut ranlibs = vec![];
+    for (symbol, member) in symbols {
+        ranlibs.extend_from_slice(&(strings.len() as u32).to_le_bytes());
+        ranlibs.extend_from_slice(&(offsets[*member] as u32).to_le_bytes());
+        strings.extend_from_slice(symbol);
+        strings.push(0);
+    }
+    strings.resize(strings.len().div_ceil(8) * 8, 0);
+
+    let mut out = (ranlibs.len() as u32).to_le_bytes().to_vec();
+    out.extend_from_slice(&ranlibs);
+    out.extend_from_slice(&(strings.len() as u32).to_le_bytes());
+    out.extend_from_slice(&strings);
+    out
+}
+
+/// The global symbols that an ELF or Mach-O object defines, which is what
+/// goes in the symbol table, or `None` for anything else.
+fn object_symbols(data: &[u8]) -> Option<Vec<Vec<u8>>> {
+    if data.starts_with(b"\x7fELF") {
+        elf_symbols(data)
+    } else {
+        macho_symbols(data)
+    }
+}
+
+fn content_hash(data: &[u8]) -> u64 {
+    use std::hash::{Hash, Hasher};
+
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    data.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn elf_symbols(data: &[u8]) -> Option<Vec<Vec<u8>>> {
+    const SHT_SYMTAB: u32 = 2;
+    const SHN_UNDEF: u16 = 0;
+    const STB_GLOBAL: u8 = 1;
+    const STB_WEAK: u8 = 2;
+    const STB_GNU_UNIQUE: u8 = 10;
+
+    let wide = match data.get(4)? {
+        1 => false,
+        2 => true,
+        _ => return None,
+    };
+    let reader = Reader {
+        data,
+        big_endian: *data.get(5)? == 2,
+    };
+    let (shoff, shentsize, shnum) = match wide {
+        true => (
+            reader.u64(0x28)? as usize,
+            reader.u16(0x3a)? as usize,
+            reader.u16(0x3c)? as usize,
+        ),
+        false => (
+            reader.u32(0x20)? as usize,
+            reader.u16(0x2e)? as usize,
+            reader.u16(0x30)? as usize,
+        ),
+    };
+    // Where a section's contents are, and which section it links to. The
+    // offsets come from the object, so adding them up can overflow.
+    let section = |idx: usize| -> Option<(u32, usize, usize, usize)> {
+        let header = shoff.checked_add(idx.checked_mul(shentsize)?)?;
+        Some(match wide {
+            true => (
+                reader.u32(header.checked_add(4)?)?,
+                reader.u64(header.checked_add(0x18)?)? as usize,
+                reader.u64(header.checked_add(0x20)?)? as usize,
+                reader.u32(header.checked_add(0x28)?)? as usize,
+            ),
+            false => (
+                reader.u32(header.checked_add(4)?)?,
+                reader.u32(header.checked_add(0x10)?)? as usize,
+                reader.u32(header.checked_add(0x14)?)? as usize,
+                reader.u32(header.checked_add(0x18)?)? as usize,
+            ),
+        })
+    };
+
+    let mut out = vec![];
+    for idx in 0..shnum {
+        let (kind, offset, size, link) = section(idx)?;
+        if kind != SHT_SYMTAB {
+            continue;
+        }
+        let (_, strings, strings_size, _) = section(link)?;
+        let strings = data.get(strings..strings.checked_add(strings_size)?)?;
+        let entry_size = if wide { 24 } else { 16 };
+        for symbol in (offset..offset.checked_add(size)?)
+            .step_by(entry_size)
+            .skip(1)
+        {
+            let (info, shndx) = match wide {
+                true => (
+                    reader.u8(symbol.checked_add(4)?)?,
+                    reader.u16(symbol.checked_add(6)?)?,
+                ),
+                false => (
+                    reader.u8(symbol.checked_add(12)?)?,
+                    reader.u16(symbol.checked_add(14)?)?,
+                ),
+            };
+            if shndx == SHN_UNDEF || !matches!(info >> 4, STB_GLOBAL | STB_WEAK | STB_GNU_UNIQUE) {
+                continue;
+            }
+            let name = strings.get(reader.u32(symbol)? as usize..)?;
+            let name = &name[..name.iter().position(|b| *b == 0)?];
+            out.push(name.to_vec());
+        }
+    }
+    Some(out)
+}
+
+fn macho_symbols(data: &[u8]) -> Option<Vec<Vec<u8>>> {
+    const LC_SYMTAB: u32 = 0x2;
+    const N_STAB: u8 = 0xe0;
+    const N_TYPE: u8 = 0x0e;
+    const N_EXT: u8 = 0x01;
+    const N_UNDF: u8 = 0x0;
+    const N_ABS: u8 = 0x2;
+    const N_SECT: u8 = 0xe;
+
+    let (wide, big_endian) = match data.get(..4)? {
+        [0xcf, 0xfa, 0xed, 0xfe] => (true, false),
+        [0xce, 0xfa, 0xed, 0xfe] => (false, false),
+        [0xfe, 0xed, 0xfa, 0xcf] => (true, true),
+        [0xfe, 0xed, 0xfa, 0xce] => (false, true),
+        _ => return None,
+    };
+    let reader = Reader { data, big_endian };
+    let commands = reader.u32(16)? as usize;
+    let mut command = if wide { 32 } else { 28 };
+    let mut out = vec![];
+    for _ in 0..commands {
+        let (kind, size) = (
+            reader.u32(command)?,
+            reader.u32(command.checked_add(4)?)? as usize,
+        );
+        if kind == LC_SYMTAB {
+            let symbols = reader.u32(command.checked_add(8)?)? as usize;
+            let count = reader.u32(command.checked_add(12)?)? as usize;
+            let strings = reader.u32(command.checked_add(16)?)? as usize;
+            let strings = data.get(
+                strings..strings.checked_add(reader.u32(command.checked_add(20)?)? as usize)?,
+            )?;
+            let entry_size = if wide { 16 } else { 12 };
+            for idx in 0..count {
+                let symbol = symbols.checked_add(idx.checked_mul(entry_size)?)?;
+                let kind = reader.u8(symbol.checked_add(4)?)?;
+                let value = match wide {
+                    true => reader.u64(symbol.checked_add(8)?)?,
+                    false => reader.u32(symbol.checked_add(8)?)? as u64,
+                };
+                let defined = match kind & N_TYPE {
+                    N_SECT | N_ABS => true,
+                    // Common symbols are undefined, with their size as the
+                    // value.
+                    N_UNDF => value != 0,
+                    _ => false,
+                };
+                if kind & N_STAB != 0 || kind & N_EXT == 0 || !defined {
+                    continue;
+                }
+                let name = strings.get(reader.u32(symbol)? as usize..)?;
+                let name = &name[..name.iter().position(|b| *b == 0)?];
+                out.push(name.to_vec());
+            }
+        }
+        // A command that's empty would be read forever.
+        if size == 0 {
+            return None;
+        }
+        command = command.checked_add(size)?;
+    }
+    Some(out)
+}
+
+/// Reads integers from an object file in its own byte order.
+struct Reader<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl Reader<'_> {
+    fn u8(&self, offset: usize) -> Option<u8> {
+        self.data.get(offset).copied()
+    }
+
+    fn u16(&self, offset: usize) -> Option<u16> {
+        let bytes = self
+            .data
+            .get(offset..offset.checked_add(2)?)?
+            .try_into()
+            .ok()?;
+        Some(match self.big_endian {
+            true => u16::from_be_bytes(bytes),
+            false => u16::from_le_bytes(bytes),
+        })
+    }
+
+    fn u32(&self, offset: usize) -> Option<u32> {
+        let bytes = self
+            .data
+            .get(offset..offset.checked_add(4)?)?
+            .try_into()
+            .ok()?;
+        Some(match self.big_endian {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes),
+        })
+    }
+
+    fn u64(&self, offset: usize) -> Option<u64> {
+        let bytes = self
+            .data
+            .get(offset..offset.checked_add(8)?)?
+            .try_into()
+            .ok()?;
+        Some(match self.big_endian {
+            true => u64::from_be_bytes(bytes),
+            false => u64::from_le_bytes(bytes),
+        })
+    }
+}
+
+fn trim_end(mut data: &[u8], byte: u8) -> &[u8] {
+    while let [rest @ .., last] = data {
+        if *last != byte {
+            break;
+        }
+        data = rest;
+    }
+    data
+}
+
+/// Parses a header field, which is padded with spaces. Some tools leave
+/// owners blank.
+fn number(field: &[u8], radix: u32) -> Result<u64> {
+    let field = std::str::from_utf8(field)
+        .map_err(|_| invalid("invalid ar header field"))?
+        .trim();
+    if field.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(field, radix)
+        .map_err(|_| invalid(&format!("invalid ar header field {field:?}")))
+}
+
+fn invalid(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod ar_tests {
+    use super::*;
+    use crate::util::tests::TempFile;
+
+    async fn names(disk: &ArFloppyDisk) -> Result<Vec<OsString>> {
+        let mut names = vec![];
+        let mut read_dir = disk.read_dir("/").await?;
+        while let Some(entry) = read_dir.next_entry().await? {
+            names.push(entry.file_name());
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Every symbol in the symbol table, with the name of its member.
+    fn symbols(data: &[u8]) -> Result<Vec<(String, String)>> {
+        let archive = RawArchive::parse(data)?;
+        let mut out: Vec<(String, String)> = archive
+            .symbol_table
+            .unwrap()
+            .parse()?
+            .into_iter()
+            .map(|(symbol, offset)| {
+                let idx = archive.offsets.iter().position(|o| *o == offset).unwrap();
+                (
+                    String::from_utf8(symbol).unwrap(),
+                    String::from_utf8(archive.members[idx].name.clone()).unwrap(),
+                )
+            })
+            .collect();
+        out.sort();
+        Ok(out)
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_gnu_static_library_works() -> Result<()> {
+        let archive = TempFile::new("./fixtures/a.gnu.a").await?;
+        {
+            let disk = ArFloppyDisk::open(archive.path_view()).await?;
+            assert_eq!(ArVariant::Gnu, disk.variant());
+            assert!(disk.has_symbol_table());
+            // The symbol and name tables aren't members.
+            assert_eq!(
+                vec![
+                    OsString::from("a.txt"),
+                    OsString::from("a_very_long_object_name.o"),
+                    OsString::from("short.o"),
+                ],
+                names(&disk).await?
+            );
+            assert_eq!("asdf\n", disk.read_to_string("/a.txt").await?);
+            disk.write("/another_long_member_name.txt", "wow!!!")
+                .await?;
+            disk.close().await?;
+        }
+
+        let data = tokio::fs::read(archive.path_view()).await?;
+        let fixture = tokio::fs::read("./fixtures/a.gnu.a").await?;
+        assert_eq!(symbols(&fixture)?, symbols(&data)?);
+        assert!(symbols(&data)?.contains(&(
+            "long_name_function".to_string(),
+            "a_very_long_object_name.o".to_string()
+        )));
+
+        // Other readers should agree on the names.
+        let mut reader = ar::Archive::new(data.as_slice());
+        let mut identifiers = vec![];
+        while let Some(entry) = reader.next_entry() {
+            identifiers.push(String::from_utf8(entry?.header().identifier().to_vec()).unwrap());
+        }
+        assert_eq!(ar::Variant::GNU, reader.variant());
+        assert_eq!(
+            vec![
+                "a_very_long_object_name.o",
+                "short.o",
+                "a.txt",
+                "another_long_member_name.txt"
+            ],
+            identifiers
+        );
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_bsd_static_library_works() -> Result<()> {
+        let archive = TempFile::new("./fixtures/a.bsd.a").await?;
+        {
+            let disk = ArFloppyDisk::open(archive.path_view()).await?;
+            assert_eq!(ArVariant::Bsd, disk.variant());
+            assert!(disk.has_symbol_table());
+            assert_eq!(
+                vec![
+                    OsString::from("a.txt"),
+                    OsString::from("a_very_long_object_name.o"),
+                    OsString::from("short.o"),
+                ],
+                names(&disk).await?
+            );
+            disk.close().await?;
+        }
+
+        let data = tokio::fs::read(archive.path_view()).await?;
+        let fixture = tokio::fs::read("./fixtures/a.bsd.a").await?;
+        assert_eq!(symbols(&fixture)?, symbols(&data)?);
+        let archive = RawArchive::parse(&data)?;
+        assert_eq!(ArVariant::Bsd, archive.variant);
+        assert_eq!(3, archive.members.len());
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_removing_members_updates_symbol_table() -> Result<()> {
+        let archive = TempFile::new("./fixtures/a.gnu.a").await?;
+        {
+            let disk = ArFloppyDisk::open(archive.path_view()).await?;
+            disk.remove_file("/short.o").await?;
+            disk.close().await?;
+        }
+
+        let data = tokio::fs::read(archive.path_view()).await?;
+        assert!(symbols(&data)?
+            .iter()
+            .all(|(_, member)| member == "a_very_long_object_name.o"));
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_symbol_index_works() -> Result<()> {
+        for fixture in ["./fixtures/a.gnu.a", "./fixtures/a.bsd.a"] {
+            let archive = TempFile::new(fixture).await?;
+            let disk = ArFloppyDisk::open(archive.path_view()).await?;
+            let prefix = match disk.variant() {
+                ArVariant::Bsd => "_",
+                _ => "",
+            };
+            let symbol = |name: &str| OsString::from(format!("{prefix}{name}"));
+
+            // The index agrees with the symbol table that was read.
+            let fixture_data = tokio::fs::read(fixture).await?;
+            let mut index: Vec<(String, String)> = disk
+                .symbols()
+                .await?
+                .into_iter()
+                .map(|(symbol, member)| {
+                    (
+                        symbol.into_string().unwrap(),
+                        member.file_name().unwrap().to_string_lossy().into_owned(),
+                    )
+                })
+                .collect();
+            index.sort();
+            assert_eq!(symbols(&fixture_data)?, index);
+
+            // Moving an object around moves its symbols with it.
+            let short = disk.read("/short.o").await?;
+            disk.remove_file("/short.o").await?;
+            assert_eq!(None, disk.find_symbol(symbol("short_function")).await?);
+            disk.write("/renamed.o", &short).await?;
+            assert_eq!(
+                Some(PathBuf::from("/renamed.o")),
+                disk.find_symbol(symbol("short_function")).await?
+            );
+            assert_eq!(
+                Some(PathBuf::from("/a_very_long_object_name.o")),
+                disk.find_symbol(symbol("long_name_function")).await?
+            );
+            disk.close().await?;
+
+            let data = tokio::fs::read(archive.path_view()).await?;
+            assert!(symbols(&data)?.contains(&(
+                symbol("short_function").into_string().unwrap(),
+                "renamed.o".to_string()
+            )));
+        }
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_duplicate_members_are_not_dropped() -> Result<()> {
+        let dir = crate::util::TempDir::new().await?;
+        let path = dir.join("dupes.a");
+        let member = |data: &[u8]| Member {
+            name: b"a.o".to_vec(),
+            mtime: 0,
+            uid: 0,
+            gid: 0,
+            mode: 0o644,
+            data: data.to_vec(),
+        };
+        let data = write_archive(
+            ArVariant::Common,
+            &[member(b"first and longer"), member(b"second")],
+            None,
+        )?;
+        tokio::fs::write(&path, &data).await?;
+
+        let disk = ArFloppyDisk::open(&path).await?;
+        assert_eq!("second", disk.read_to_string("/a.o").await?);
+        disk.write("/b.o", "new").await?;
+        let err = disk.close().await.unwrap_err();
+        assert_eq!(std::io::ErrorKind::Unsupported, err.kind());
+        assert_eq!(data, tokio::fs::read(&path).await?);
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_foreign_symbols_are_kept() -> Result<()> {
+        let dir = crate::util::TempDir::new().await?;
+        let path = dir.join("lto.a");
+        let member = |name: &str, data: &[u8]| Member {
+            name: name.as_bytes().to_vec(),
+            mtime: 0,
+            uid: 0,
+            gid: 0,
+            mode: 0o644,
+            data: data.to_vec(),
+        };
+        let data = write_archive(
+            ArVariant::Gnu,
+            &[
+                member("a.bc", b"BC\xc0\xde"),
+                member("b.bc", b"BC\xc0\xde!"),
+            ],
+            Some(&[(b"from_a".to_vec(), 0), (b"from_b".to_vec(), 1)]),
+        )?;
+        tokio::fs::write(&path, data).await?;
+
+        {
+            let disk = ArFloppyDisk::open(&path).await?;
+            disk.write("/b.bc", "changed").await?;
+            disk.close().await?;
+        }
+        assert_eq!(
+            vec![("from_a".to_string(), "a.bc".to_string())],
+            symbols(&tokio::fs::read(&path).await?)?
+        );
+
+        Ok(())
+    }
+
+    #[test_log::test]
+    fn test_corrupt_symbol_tables_are_errors() {
+        let mut table = u64::MAX.to_le_bytes().to_vec();
+        table.extend_from_slice(&[0; 16]);
+        for table in [SymbolTable::Bsd64(&table), SymbolTable::Gnu64(&table)] {
+            let err = table.parse().unwrap_err();
+            assert_eq!(std::io::ErrorKind::InvalidData, err.kind());
+        }
+
+        // Objects with offsets that don't add up just have no symbols.
+        let mut elf = vec![0; 0x40];
+        elf[..6].copy_from_slice(b"\x7fELF\x02\x01");
+        elf[0x28..0x30].copy_from_slice(&u64::MAX.to_le_bytes());
+        elf[0x3a..0x3c].copy_from_slice(&64u16.to_le_bytes());
+        elf[0x3c..0x3e].copy_from_slice(&2u16.to_le_bytes());
+        assert_eq!(None, object_symbols(&elf));
+        let mut macho = vec![0; 0x40];
+        macho[..4].copy_from_slice(&[0xcf, 0xfa, 0xed, 0xfe]);
+        macho[16..20].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(None, object_symbols(&macho));
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_object_symbols_work() -> Result<()> {
+        let fixture = tokio::fs::read("./fixtures/a.gnu.a").await?;
+        let archive = RawArchive::parse(&fixture)?;
+        let mut symbols = object_symbols(&archive.members[0].data).unwrap();
+        symbols.sort();
+        assert_eq!(
+            vec![
+                b"long_name_function".to_vec(),
+                b"shared_counter".to_vec(),
+                b"uses_hidden".to_vec(),
+                b"weak_symbol".to_vec(),
+            ],
+            symbols
+        );
+        let mut symbols = object_symbols(&archive.members[1].data).unwrap();
+        symbols.sort();
+        assert_eq!(
+            vec![b"common_thing".to_vec(), b"short_function".to_vec()],
+            symbols
+        );
+        assert!(object_symbols(&archive.members[2].data).is_none());
+
+        let fixture = tokio::fs::read("./fixtures/a.bsd.a").await?;
+        let archive = RawArchive::parse(&fixture)?;
+        assert_eq!(
+            vec![
+                b"_long_name_function".to_vec(),
+                b"_shared_counter".to_vec(),
+                b"_common_thing".to_vec(),
+            ],
+            object_symbols(&archive.members[0].data).unwrap()
+        );
+        assert_eq!(
+            vec![b"_short_function".to_vec()],
+            object_symbols(&archive.members[1].data).unwrap()
+        );
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_additions_are_appended() -> Result<()> {
+        let dir = crate::util::TempDir::new().await?;
+        let path = dir.join("a.ar");
+        let member = |name: &str, data: &[u8]| Member {
+            name: name.as_bytes().to_vec(),
+            mtime: 0,
+            uid: 0,
+            gid: 0,
+            mode: 0o644,
+            data: data.to_vec(),
+        };
+        for variant in [ArVariant::Gnu, ArVariant::Bsd] {
+            let data = write_archive(variant, &[member("a_long_member_name.txt", b"odd")], None)?;
+            tokio::fs::write(&path, &data).await?;
+            {
+                let disk = ArFloppyDisk::open(&path).await?;
+                disk.write("/b.txt", "wow!!!").await?;
+                disk.write("/c_long_name.txt", "more!!!").await?;
+                assert!(ar_append(&disk, &*disk.ordered_paths.lock().await)
+                    .await?
+                    .is_some());
+                disk.close().await?;
+            }
+
+            let appended = tokio::fs::read(&path).await?;
+            assert_eq!(data, appended[..data.len()]);
+            let archive = RawArchive::parse(&appended)?;
+            assert_eq!(variant, archive.variant);
+            assert_eq!(3, archive.members.len());
+            assert_eq!(b"more!!!", &archive.members[2].data[..]);
+
+            let disk = ArFloppyDisk::open(&path).await?;
+            disk.set_permissions("/b.txt", ArPermissions::from_mode(0o600))
+                .await?;
+            assert!(ar_append(&disk, &*disk.ordered_paths.lock().await)
+                .await?
+                .is_none());
+        }
+
+        // GNU long names go in the name table, which can't be added to.
+        let data = write_archive(ArVariant::Gnu, &[member("a.txt", b"asdf")], None)?;
+        tokio::fs::write(&path, &data).await?;
+        let disk = ArFloppyDisk::open(&path).await?;
+        disk.write("/d.txt", "gnu!!!").await?;
+        assert!(ar_append(&disk, &*disk.ordered_paths.lock().await)
+            .await?
+            .is_some());
+        disk.write("/a_long_member_name.txt", "gnu!!!").await?;
+        assert!(ar_append(&disk, &*disk.ordered_paths.lock().await)
+            .await?
+            .is_none());
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_failed_close_keeps_the_archive() -> Result<()> {
+        let archive = TempFile::new("./fixtures/a.ar").await?;
+        let before = tokio::fs::read(archive.path_view()).await?;
+        {
+            let disk = ArFloppyDisk::open(archive.path_view()).await?;
+            disk.chown("/a.txt", 10_000_000, 0).await?;
+            let err = disk.close().await.unwrap_err();
+            assert_eq!(std::io::ErrorKind::InvalidInput, err.kind());
+        }
+        assert_eq!(before, tokio::fs::read(archive.path_view()).await?);
+
+        // Times before the epoch are written as the epoch.
+        {
+            let disk = ArFloppyDisk::open(archive.path_view()).await?;
+            disk.set_modified(
+                "/a.txt",
+                std::time::UNIX_EPOCH - std::time::Duration::from_secs(60),
+            )
+            .await?;
+            disk.close().await?;
+        }
+        let disk = ArFloppyDisk::open(archive.path_view()).await?;
+        assert_eq!(std::time::UNIX_EPOCH, disk.modified("/a.txt").await?);
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_long_names_with_slashes_work() -> Result<()> {
+        let archive = TempFile::new("./fixtures/a.ar").await?;
+        {
+            let disk = ArFloppyDisk::open(archive.path_view()).await?;
+            assert_eq!(ArVariant::Gnu, disk.variant());
+            assert!(!disk.has_symbol_table());
+            disk.create_dir_all("/some/nested").await?;
+            disk.write("/some/nested/path.txt", "nested!!!").await?;
+            disk.close().await?;
+        }
+
+        let disk = ArFloppyDisk::open(archive.path_view()).await?;
+        assert!(!disk.has_symbol_table());
+        assert_eq!(
+            "nested!!!",
+            disk.read_to_string("/some/nested/path.txt").await?
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,776 @@
+use std::collections::HashMap;
+use std::io::{Result, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use clap::{Parser, Subcommand};
+use floppy_disk::prelude::*;
+use smoosh::CompressionType;
+use tracing::debug;
+
+use crate::convert::ConvertOptions;
+use crate::diff::{DiffOptions, Difference};
+use crate::format::{with_disk, ArchiveFormat};
+use crate::import::{FloppyDiskHardLinkExt, ImportOptions, ImportedEntry, ImportedKind};
+
+/// floppy-disk facades for common archive formats!
+#[derive(Debug, Parser)]
+#[command(name = "flop", version)]
+pub struct Cli {
+    /// The format of the archive, instead of detecting it. For `convert`,
+    /// this is the format of the new archive.
+    #[arg(short, long, global = true)]
+    format: Option<ArchiveFormat>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// List the entries in an archive.
+    Ls {
+        archive: PathBuf,
+        #[arg(default_value = "/")]
+        path: PathBuf,
+        /// Show modes, owners, sizes, and mtimes.
+        #[arg(short, long)]
+        long: bool,
+    },
+    /// Print the contents of files in an archive.
+    Cat {
+        archive: PathBuf,
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+    },
+    /// Show the metadata of an entry in an archive.
+    Stat { archive: PathBuf, path: PathBuf },
+    /// Extract an archive, or part of one, onto the host.
+    Extract {
+        archive: PathBuf,
+        #[arg(default_value = ".")]
+        dest: PathBuf,
+        /// Only extract this path from the archive.
+        #[arg(short, long, default_value = "/")]
+        path: PathBuf,
+    },
+    /// Add host files and directories to an archive, creating it if needed.
+    Add {
+        archive: PathBuf,
+        #[arg(required = true)]
+        sources: Vec<PathBuf>,
+        /// The directory in the archive to add to.
+        #[arg(short, long, default_value = "/")]
+        to: PathBuf,
+        /// Only add paths matching this glob.
+        #[arg(long)]
+        include: Vec<String>,
+        /// Never add paths matching this glob.
+        #[arg(long)]
+        exclude: Vec<String>,
+    },
+    /// Remove entries from an archive.
+    Rm {
+        archive: PathBuf,
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+        /// Remove directories and everything in them.
+        #[arg(short, long)]
+        recursive: bool,
+    },
+    /// Move an entry in an archive.
+    Mv {
+        archive: PathBuf,
+        from: PathBuf,
+        to: PathBuf,
+    },
+    /// Change the mode of entries in an archive.
+    Chmod {
+        archive: PathBuf,
+        /// An octal mode, ie. `755`.
+        mode: String,
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+    },
+    /// Change the owner of entries in an archive.
+    Chown {
+        archive: PathBuf,
+        /// `uid` or `uid:gid`.
+        owner: String,
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+    },
+    /// Convert an archive into another format.
+    Convert {
+        src: PathBuf,
+        dst: PathBuf,
+        /// One of none, gzip, xz, zstd, bzip2, zlib, or deflate. Defaults to
+        /// whatever the extension of `dst` implies.
+        #[arg(short, long, value_parser = parse_compression)]
+        compression: Option<CompressionType>,
+    },
+    /// Show the differences between two archives.
+    Diff {
+        a: PathBuf,
+        b: PathBuf,
+        /// Print the differences as JSON.
+        #[arg(long)]
+        json: bool,
+        /// Don't report this kind of difference. One of content, type, mode,
+        /// owner, mtime, or target.
+        #[arg(long)]
+        ignore: Vec<Difference>,
+    },
+}
+
+/// Runs the `flop` command described by `cli`, writing its output to `out`.
+pub async fn run<W: Write>(cli: Cli, out: &mut W) -> Result<()> {
+    debug!("running {:?}", cli.command);
+    let format = cli.format;
+    match cli.command {
+        Command::Ls {
+            archive,
+            path,
+            long,
+        } => {
+            let format = existing(format, &archive).await?;
+            with_disk!(format, &archive, |disk| ls(&disk, &path, long, out).await?);
+        }
+        Command::Cat { archive, paths } => {
+            let format = existing(format, &archive).await?;
+            with_disk!(format, &archive, |disk| {
+                for path in &paths {
+                    out.write_all(&disk.read(path).await?)?;
+                }
+            });
+        }
+        Command::Stat { archive, path } => {
+            let format = existing(format, &archive).await?;
+            with_disk!(format, &archive, |disk| stat(&disk, &path, out).await?);
+        }
+        Command::Extract {
+            archive,
+            dest,
+            path,
+        } => {
+            let format = existing(format, &archive).await?;
+            with_disk!(format, &archive, |disk| extract(&disk, &path, &dest)
+                .await?);
+        }
+        Command::Add {
+            archive,
+            sources,
+            to,
+            include,
+            exclude,
+        } => {
+            let mut options = ImportOptions::new();
+            for glob in include {
+                options = options.include(glob)?;
+            }
+            for glob in exclude {
+                options = options.exclude(glob)?;
+            }
+
+            let created = !crate::util::exists_async(&archive).await;
+            let format = detect(format, &archive).await?;
+            let host = TokioFloppyDisk::new(None);
+            with_disk!(format, &archive, |disk| {
+                if created {
+                    disk.set_compression(compression_from_extension(&archive));
+                }
+                for source in &sources {
+                    let dest = match source.file_name() {
+                        Some(name) if tokio::fs::metadata(source).await?.is_dir() => to.join(name),
+                        _ => to.clone(),
+                    };
+                    disk.import_from_with(&host, source, &dest, &options)
+                        .await?;
+                }
+                disk.close().await?;
+            });
+        }
+        Command::Rm {
+            archive,
+            paths,
+            recursive,
+        } => {
+            let format = existing(format, &archive).await?;
+            with_disk!(format, &archive, |disk| {
+                for path in &paths {
+                    let metadata = disk.symlink_metadata(path).await?;
+                    if metadata.is_dir() && !recursive {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            format!("{} is a directory, use -r to remove it", path.display()),
+                        ));
+                    } else if metadata.is_dir() {
+                        disk.remove_dir_all(path).await?;
+                    } else {
+                        disk.remove_file(path).await?;
+                    }
+                }
+                disk.close().await?;
+            });
+        }
+        Command::Mv { archive, from, to } => {
+            let format = existing(format, &archive).await?;
+            with_disk!(format, &archive, |disk| {
+                disk.rename(&from, &to).await?;
+                disk.close().await?;
+            });
+        }
+        Command::Chmod {
+            archive,
+            mode,
+            paths,
+        } => {
+            let mode = u32::from_str_radix(&mode, 8).map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("invalid mode: {mode}"),
+                )
+            })?;
+            let format = existing(format, &archive).await?;
+            with_disk!(format, &archive, |disk| {
+                for path in &paths {
+                    chmod(&disk, path, mode).await?;
+                }
+                disk.close().await?;
+            });
+        }
+        Command::Chown {
+            archive,
+            owner,
+            paths,
+        } => {
+            let (uid, gid) = parse_owner(&owner)?;
+            let format = existing(format, &archive).await?;
+            with_disk!(format, &archive, |disk| {
+                for path in &paths {
+                    let gid = match gid {
+                        Some(gid) => gid,
+                        None => disk.symlink_metadata(path).await?.gid()?,
+                    };
+                    disk.chown(path, uid, gid).await?;
+                }
+                disk.close().await?;
+            });
+        }
+        Command::Convert {
+            src,
+            dst,
+            compression,
+        } => {
+            let dst_format = match format {
+                Some(format) => format,
+                None => ArchiveFormat::from_extension(&dst).ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("could not detect archive format of {}", dst.display()),
+                    )
+                })?,
+            };
+            let compression = compression.unwrap_or_else(|| compression_from_extension(&dst));
+            let report = crate::convert(
+                &src,
+                &dst,
+                dst_format,
+                &ConvertOptions::new().compression(compression),
+            )
+            .await?;
+            for lossy in &report.losses {
+                writeln!(out, "{}: {:?}", lossy.path.display(), lossy.loss)?;
+            }
+        }
+        Command::Diff { a, b, json, ignore } => {
+            let a_format = existing(format, &a).await?;
+            let b_format = existing(format, &b).await?;
+            let opts = ignore
+                .into_iter()
+                .fold(DiffOptions::new(), |opts, difference| {
+                    opts.ignore(difference)
+                });
+            let diff = crate::diff::diff_as(&a, a_format, &b, b_format, &opts).await?;
+            if json {
+                writeln!(out, "{}", diff.to_json()?)?;
+            } else {
+                write!(out, "{diff}")?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn detect(format: Option<ArchiveFormat>, archive: &Path) -> Result<ArchiveFormat> {
+    match format {
+        Some(format) => Ok(format),
+        None => ArchiveFormat::detect(archive).await,
+    }
+}
+
+/// Like [`detect`], but for archives that have to exist already. Opening a
+/// missing archive creates it, which is only what `add` wants.
+async fn existing(format: Option<ArchiveFormat>, archive: &Path) -> Result<ArchiveFormat> {
+    if let Err(e) = tokio::fs::metadata(archive).await {
+        return Err(std::io::Error::new(
+            e.kind(),
+            format!("could not open {}: {e}", archive.display()),
+        ));
+    }
+    detect(format, archive).await
+}
+
+fn parse_compression(s: &str) -> std::result::Result<CompressionType, String> {
+    match s.to_lowercase().as_str() {
+        "none" => Ok(CompressionType::None),
+        "gz" | "gzip" => Ok(CompressionType::Gzip),
+        "xz" => Ok(CompressionType::Xz),
+        "zst" | "zstd" => Ok(CompressionType::Zstd),
+        "bz2" | "bzip" | "bzip2" => Ok(CompressionType::Bzip),
+        "zlib" => Ok(CompressionType::Zlib),
+        "deflate" => Ok(CompressionType::Deflate),
+        _ => Err(format!("unknown compression: {s}")),
+    }
+}
+
+fn compression_from_extension(path: &Path) -> CompressionType {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    let ext = name
+        .rsplit_once('.')
+        .map(|(_, ext)| ext)
+        .unwrap_or_default();
+    match ext {
+        "tgz" => CompressionType::Gzip,
+        "txz" => CompressionType::Xz,
+        "tbz2" => CompressionType::Bzip,
+        "tzst" => CompressionType::Zstd,
+        ext => parse_compression(ext).unwrap_or(CompressionType::None),
+    }
+}
+
+fn parse_owner(owner: &str) -> Result<(u32, Option<u32>)> {
+    let invalid = || {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid owner: {owner}"),
+        )
+    };
+    match owner.split_once(':') {
+        Some((uid, gid)) => Ok((
+            uid.parse().map_err(|_| invalid())?,
+            Some(gid.parse().map_err(|_| invalid())?),
+        )),
+        None => Ok((owner.parse().map_err(|_| invalid())?, None)),
+    }
+}
+
+#[derive(Debug)]
+enum WalkedKind {
+    Directory,
+    File,
+    Symlink(PathBuf),
+    /// A hard link to an earlier entry.
+    HardLink(PathBuf),
+}
+
+/// An entry found by [`walk`]. Unlike an [`ImportedEntry`], it only has the
+/// metadata, so listing an archive doesn't read everything in it.
+#[derive(Debug)]
+struct WalkedEntry {
+    path: PathBuf,
+    kind: WalkedKind,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+/// Finds everything at and below `path` depth-first, in name order, with
+/// the absolute path in the archive of each entry.
+async fn walk<'a, D>(disk: &'a D, path: &Path) -> Result<Vec<WalkedEntry>>
+where
+    D: FloppyDisk<'a> + FloppyDiskHardLinkExt + Sync,
+    D::Metadata: FloppyUnixMetadata,
+    D::Permissions: FloppyUnixPermissions,
+{
+    let mut entries = vec![];
+    let mut links: HashMap<u64, PathBuf> = HashMap::new();
+    let mut stack = vec![crate::util::normalize_path(path)];
+    while let Some(path) = stack.pop() {
+        let metadata = disk.symlink_metadata(&path).await?;
+        let kind = if metadata.is_symlink() {
+            WalkedKind::Symlink(disk.read_link(&path).await?)
+        } else if metadata.is_dir() {
+            let mut children = vec![];
+            let mut read_dir = disk.read_dir(&path).await?;
+            while let Some(entry) = read_dir.next_entry().await? {
+                children.push(entry.file_name());
+            }
+            children.sort();
+            for child in children.into_iter().rev() {
+                stack.push(path.join(child));
+            }
+            WalkedKind::Directory
+        } else {
+            match disk.hard_link_id(&path).await? {
+                Some(id) => match links.get(&id) {
+                    Some(target) => WalkedKind::HardLink(target.clone()),
+                    None => {
+                        links.insert(id, path.clone());
+                        WalkedKind::File
+                    }
+                },
+                None => WalkedKind::File,
+            }
+        };
+
+        entries.push(WalkedEntry {
+            len: match kind {
+                WalkedKind::File => metadata.len(),
+                _ => 0,
+            },
+            path,
+            kind,
+            mode: metadata.permissions().mode(),
+            uid: metadata.uid()?,
+            gid: metadata.gid()?,
+            modified: metadata.modified().ok(),
+        });
+    }
+
+    Ok(entries)
+}
+
+async fn ls<'a, D, W>(disk: &'a D, path: &Path, long: bool, out: &mut W) -> Result<()>
+where
+    D: FloppyDisk<'a> + FloppyDiskHardLinkExt + Sync,
+    D::Metadata: FloppyUnixMetadata,
+    D::Permissions: FloppyUnixPermissions,
+    W: Write,
+{
+    for entry in walk(disk, path).await? {
+        let path = &entry.path;
+        if path.as_os_str() == "/" {
+            continue;
+        }
+        if !long {
+            writeln!(out, "{}", path.display())?;
+            continue;
+        }
+
+        write!(
+            out,
+            "{} {:>5} {:>5} {:>10} {} {}",
+            mode_string(&entry.kind, entry.mode),
+            entry.uid,
+            entry.gid,
+            entry.len,
+            format_time(entry.modified),
+            path.display()
+        )?;
+        match &entry.kind {
+            WalkedKind::Symlink(target) => writeln!(out, " -> {}", target.display())?,
+            WalkedKind::HardLink(target) => writeln!(out, " link to {}", target.display())?,
+            _ => writeln!(out)?,
+        }
+    }
+
+    Ok(())
+}
+
+async fn stat<'a, D, W>(disk: &'a D, path: &Path, out: &mut W) -> Result<()>
+where
+    D: FloppyDisk<'a> + Sync,
+    D::Metadata: FloppyUnixMetadata,
+    D::Permissions: FloppyUnixPermissions,
+    W: Write,
+{
+    let path = crate::util::normalize_path(path);
+    let metadata = disk.symlink_metadata(&path).await?;
+    let mode = metadata.permissions().mode() & 0o7777;
+    let kind = if metadata.is_symlink() {
+        "symlink"
+    } else if metadata.is_dir() {
+        "directory"
+    } else {
+        "file"
+    };
+
+    writeln!(out, "  path: {}", path.display())?;
+    writeln!(out, "  type: {kind}")?;
+    writeln!(out, "  size: {}", metadata.len())?;
+    writeln!(out, "  mode: {mode:04o}")?;
+    writeln!(out, "   uid: {}", metadata.uid()?)?;
+    writeln!(out, "   gid: {}", metadata.gid()?)?;
+    writeln!(out, " mtime: {}", format_time(metadata.modified().ok()))?;
+    if metadata.is_symlink() {
+        writeln!(out, "target: {}", disk.read_link(&path).await?.display())?;
+    }
+
+    Ok(())
+}
+
+/// Writes everything at and below `path` in the archive into the host
+/// directory `dest`. Ownership is left alone, since it usually can't be set
+/// without being root anyways.
+async fn extract<'a, D>(disk: &'a D, path: &Path, dest: &Path) -> Result<()>
+where
+    D: FloppyDisk<'a> + FloppyDiskHardLinkExt + Sync,
+    D::Metadata: FloppyUnixMetadata,
+    D::Permissions: FloppyUnixPermissions,
+{
+    let entries = crate::import::collect(disk, path, &ImportOptions::new()).await?;
+    debug!("extracting {} entries to {}", entries.len(), dest.display());
+    tokio::fs::create_dir_all(dest).await?;
+
+    // Directories are finished last, so that their mtimes aren't bumped and
+    // read-only modes don't stop their children from being written.
+    let mut directories = vec![];
+    for entry in entries {
+        let target = dest.join(&entry.path);
+        if let Some(parent) = target.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        match &entry.kind {
+            ImportedKind::Directory => {
+                tokio::fs::create_dir_all(&target).await?;
+                directories.push((target, entry));
+                continue;
+            }
+            ImportedKind::File(data) => tokio::fs::write(&target, data).await?,
+            ImportedKind::Symlink(to) => {
+                tokio::fs::symlink(to, &target).await?;
+                continue;
+            }
+            ImportedKind::HardLink(to) => {
+                tokio::fs::hard_link(dest.join(to), &target).await?;
+                continue;
+            }
+        }
+        finish_extracted(&target, &entry).await?;
+    }
+
+    for (target, entry) in directories.iter().rev() {
+        finish_extracted(target, entry).await?;
+    }
+
+    Ok(())
+}
+
+async fn finish_extracted(target: &Path, entry: &ImportedEntry) -> Result<()> {
+    if let Some(modified) = entry.modified {
+        std::fs::File::open(target)?.set_modified(modified)?;
+    }
+    tokio::fs::set_permissions(
+        target,
+        std::os::unix::fs::PermissionsExt::from_mode(entry.mode & 0o7777),
+    )
+    .await
+}
+
+async fn chmod<'a, D>(disk: &'a D, path: &Path, mode: u32) -> Result<()>
+where
+    D: FloppyDisk<'a>,
+    D::Permissions: FloppyUnixPermissions,
+{
+    disk.set_permissions(path, D::Permissions::from_mode(mode))
+        .await
+}
+
+fn mode_string(kind: &WalkedKind, mode: u32) -> String {
+    let kind = match kind {
+        WalkedKind::Directory => 'd',
+        WalkedKind::Symlink(_) => 'l',
+        _ => '-',
+    };
+    let mut out = String::from(kind);
+    for shift in [6, 3, 0] {
+        let bits = (mode >> shift) & 0o7;
+        out.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        out.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        out.push(if bits & 0o1 != 0 { 'x' } else { '-' });
+    }
+    out
+}
+
+fn format_time(time: Option<SystemTime>) -> String {
+    match time {
+        Some(time) => chrono::DateTime::<chrono::Utc>::from(time)
+            .format("%Y-%m-%d %H:%M")
+            .to_string(),
+        None => "-".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use clap::Parser;
+    use floppy_disk::prelude::*;
+
+    use super::{run, Cli};
+    use crate::util::TempDir;
+
+    async fn flop<I: IntoIterator<Item = S>, S: AsRef<str>>(args: I) -> std::io::Result<String> {
+        let args = std::iter::once("flop".to_string())
+            .chain(args.into_iter().map(|s| s.as_ref().to_string()));
+        let cli = Cli::try_parse_from(args)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let mut out = vec![];
+        run(cli, &mut out).await?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    fn arg(path: &Path) -> String {
+        path.to_string_lossy().to_string()
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_ls_and_cat_work() -> std::io::Result<()> {
+        for fixture in ["a.ar", "a.cpio", "a.tar", "a.zip"] {
+            let fixture = format!("./fixtures/{fixture}");
+            assert_eq!("/a.txt\n", flop(["ls", &fixture]).await?);
+            assert_eq!("asdf\n", flop(["cat", &fixture, "/a.txt"]).await?);
+        }
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_ls_long_works() -> std::io::Result<()> {
+        let out = TempDir::new().await?;
+        let path = out.join("links.tar");
+        let disk = crate::tar::TarFloppyDisk::open(&path).await?;
+        disk.write("/a.txt", "asdf").await?;
+        disk.hard_link("/a.txt", "/b.txt").await?;
+        disk.symlink("a.txt", "/c.txt").await?;
+        disk.close().await?;
+
+        let ls = flop(["ls", "-l", &arg(&path)]).await?;
+        let lines: Vec<&str> = ls.lines().collect();
+        assert_eq!(3, lines.len());
+        assert!(lines[0].starts_with('-'));
+        assert!(lines[0].contains("    4 "));
+        assert!(lines[0].ends_with(" /a.txt"));
+        assert!(lines[1].ends_with(" /b.txt link to /a.txt"));
+        assert!(lines[2].starts_with('l'));
+        assert!(lines[2].ends_with(" /c.txt -> a.txt"));
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_add_and_edit_work() -> std::io::Result<()> {
+        let src = TempDir::new().await?;
+        tokio::fs::create_dir_all(src.join("dir/nested")).await?;
+        tokio::fs::write(src.join("dir/nested/a.txt"), "asdf").await?;
+        tokio::fs::write(src.join("b.txt"), "hjkl").await?;
+        let out = TempDir::new().await?;
+        let archive = arg(&out.join("out.tar.gz"));
+
+        flop([
+            "add",
+            &archive,
+            &arg(&src.join("dir")),
+            &arg(&src.join("b.txt")),
+        ])
+        .await?;
+        assert_eq!(
+            "/b.txt\n/dir\n/dir/nested\n/dir/nested/a.txt\n",
+            flop(["ls", &archive]).await?
+        );
+
+        flop(["mv", &archive, "/dir", "/moved"]).await?;
+        flop(["chmod", &archive, "600", "/b.txt"]).await?;
+        flop(["chown", &archive, "0:0", "/b.txt"]).await?;
+        assert!(flop(["rm", &archive, "/moved"]).await.is_err());
+        flop(["rm", "-r", &archive, "/moved/nested"]).await?;
+
+        assert_eq!("/b.txt\n/moved\n", flop(["ls", &archive]).await?);
+        let stat = flop(["stat", &archive, "/b.txt"]).await?;
+        assert!(stat.contains("mode: 0600"));
+        assert!(stat.contains("uid: 0"));
+        assert_eq!(
+            crate::ArchiveFormat::Tar,
+            crate::ArchiveFormat::detect(&archive).await?
+        );
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_missing_archives_are_not_created() -> std::io::Result<()> {
+        let out = TempDir::new().await?;
+        let tar = arg(&out.join("missing.tar"));
+        let zip = arg(&out.join("missing.zip"));
+        let dest = arg(&out.join("dest"));
+
+        for args in [
+            vec!["ls", &tar],
+            vec!["cat", &zip, "/x"],
+            vec!["stat", &tar, "/x"],
+            vec!["extract", &zip, &dest],
+            vec!["rm", &tar, "/x"],
+            vec!["diff", &tar, &zip],
+            vec!["diff", "./fixtures/a.tar", &zip],
+        ] {
+            let err = flop(args).await.unwrap_err();
+            assert_eq!(std::io::ErrorKind::NotFound, err.kind());
+        }
+        assert!(!crate::util::exists_async(&tar).await);
+        assert!(!crate::util::exists_async(&zip).await);
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_extract_works() -> std::io::Result<()> {
+        let out = TempDir::new().await?;
+        flop(["extract", "./fixtures/a.zip", &arg(&out)]).await?;
+        assert_eq!(
+            "asdf\n",
+            tokio::fs::read_to_string(out.join("a.txt")).await?
+        );
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_convert_and_diff_work() -> std::io::Result<()> {
+        let out = TempDir::new().await?;
+        let zip = arg(&out.join("out.zip"));
+        let cpio = arg(&out.join("out.cpio"));
+
+        flop(["convert", "./fixtures/a.tar", &zip]).await?;
+        assert_eq!(
+            "",
+            flop([
+                "diff",
+                "--ignore",
+                "owner",
+                "--ignore",
+                "mtime",
+                "./fixtures/a.tar",
+                &zip
+            ])
+            .await?
+        );
+
+        flop(["add", &cpio, "./fixtures/a.txt"]).await?;
+        flop(["add", &cpio, "./Cargo.toml"]).await?;
+        let diff = flop(["diff", &zip, &cpio]).await?;
+        assert!(diff.contains("+ /Cargo.toml"));
+        let json = flop(["diff", "--json", &zip, &cpio]).await?;
+        assert!(json.contains("\"change\": \"added\""));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,266 @@
+use std::ffi::OsString;
+use std::io::{Result, Write};
+use std::ops::Deref;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+
+use floppy_disk::mem::MemFloppyDisk;
+use floppy_disk::prelude::*;
+use smoosh::CompressionType;
+use tracing::debug;
+
+const GZIP_FEXTRA: u8 = 0x04;
+const GZIP_FNAME: u8 = 0x08;
+
+/// A single compressed file, like `config.json.gz`, exposed as a disk with
+/// one file in it. The file is named after the gzip header's original name
+/// if there is one, and after the compressed file with its extension
+/// stripped otherwise. On close, the file is compressed again with
+/// whatever it was read with.
+#[derive(Debug)]
+pub struct CompressedFileFloppyDisk {
+    delegate: MemFloppyDisk,
+    path: PathBuf,
+    name: PathBuf,
+    compression: CompressionType,
+    /// The gzip header's name and mtime, so that they survive a round-trip.
+    gzip_header: Option<(Option<Vec<u8>>, u32)>,
+}
+
+impl CompressedFileFloppyDisk {
+    /// Opens the compressed file at `path`, or creates an empty one if it
+    /// doesn't exist, picking the compression from its extension.
+    pub async fn open<P: AsRef<Path>>(path: P) -> Result<CompressedFileFloppyDisk> {
+        let path = path.as_ref();
+        let delegate = MemFloppyDisk::new();
+        if !crate::util::exists_async(path).await {
+            let compression = compression_for_extension(path);
+            debug!(
+                "creating new {:?} compressed file at {}",
+                compression,
+                path.display()
+            );
+            let name = Path::new("/").join(stripped_name(path, compression)?);
+            delegate.write(&name, b"").await?;
+            return Ok(Self {
+                delegate,
+                path: path.to_path_buf(),
+                name,
+                compression,
+                gzip_header: None,
+            });
+        }
+
+        debug!("opening compressed file {}", path.display());
+        let raw = tokio::fs::read(path).await?;
+        let mut data = vec![];
+        let compression =
+            smoosh::recompress(&mut raw.as_slice(), &mut data, CompressionType::None).await?;
+        let gzip_header = match compression {
+            CompressionType::Gzip => Some(parse_gzip_header(&raw)?),
+            _ => None,
+        };
+        let name = match &gzip_header {
+            // Only the file name, since nothing stops the header from having
+            // a whole path in it.
+            Some((Some(original), _)) => Path::new(&OsString::from_vec(original.clone()))
+                .file_name()
+                .map(PathBuf::from),
+            _ => None,
+        };
+        let name = match name {
+            Some(name) => name,
+            None => stripped_name(path, compression)?,
+        };
+        let name = Path::new("/").join(name);
+        debug!("exposing {:?} data as {}", compression, name.display());
+        delegate.write(&name, &data).await?;
+
+        Ok(Self {
+            delegate,
+            path: path.to_path_buf(),
+            name,
+            compression,
+            gzip_header,
+        })
+    }
+
+    /// The path of the one file on the disk, like `/config.json`.
+    pub fn name(&self) -> &Path {
+        &self.name
+    }
+
+    /// The compression that will be applied to the file when it's closed.
+    pub fn compression(&self) -> CompressionType {
+        self.compression
+    }
+
+    pub fn set_compression(&mut self, compression: CompressionType) {
+        self.compression = compression;
+    }
+
+    /// Compresses the file again and writes it back.
+    pub async fn close(self) -> Result<()> {
+        debug!("closing compressed file at {}", self.path.display());
+        let data = self.delegate.read(&self.name).await?;
+        let mut out = vec![];
+        match (self.compression, &self.gzip_header) {
+            (CompressionType::Gzip, Some((original, mtime))) => {
+                let mut builder = flate2::GzBuilder::new().mtime(*mtime);
+                if let Some(original) = original {
+                    builder = builder.filename(original.as_slice());
+                }
+                let mut encoder = builder.write(&mut out, flate2::Compression::default());
+                encoder.write_all(&data)?;
+                encoder.finish()?;
+            }
+            (compression, _) => {
+                crate::util::write_compressed(&data, &mut out, compression).await?;
+            }
+        }
+        tokio::fs::write(&self.path, out).await
+    }
+}
+
+impl Deref for CompressedFileFloppyDisk {
+    type Target = MemFloppyDisk;
+
+    fn deref(&self) -> &Self::Target {
+        &self.delegate
+    }
+}
+
+fn compression_for_extension(path: &Path) -> CompressionType {
+    let extension = path.extension().map(|ext| ext.as_bytes()).unwrap_or(b"");
+    [
+        CompressionType::Bzip,
+        CompressionType::Gzip,
+        CompressionType::Xz,
+        CompressionType::Zstd,
+    ]
+    .into_iter()
+    .find(|compression| compression.file_extension().as_bytes() == extension)
+    .unwrap_or(CompressionType::None)
+}
+
+/// The name of `path` without the extension for `compression`, if it has
+/// it.
+fn stripped_name(path: &Path, compression: CompressionType) -> Result<PathBuf> {
+    let invalid = || {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{} has no file name", path.display()),
+        )
+    };
+    let name = path.file_name().ok_or_else(invalid)?.as_bytes();
+    let suffix = format!(".{}", compression.file_extension());
+    let name = match name.strip_suffix(suffix.as_bytes()) {
+        Some(stripped) if !stripped.is_empty() && compression != CompressionType::None => stripped,
+        _ => name,
+    };
+    Ok(PathBuf::from(OsString::from_vec(name.to_vec())))
+}
+
+/// Reads the original file name, if any, and mtime from a gzip header.
+fn parse_gzip_header(data: &[u8]) -> Result<(Option<Vec<u8>>, u32)> {
+    let truncated =
+        || std::io::Error::new(std::io::ErrorKind::InvalidData, "truncated gzip header");
+    let header = data.get(..10).ok_or_else(truncated)?;
+    let flags = header[3];
+    let mtime = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    let mut offset = 10;
+    if flags & GZIP_FEXTRA != 0 {
+        let len = data.get(offset..offset + 2).ok_or_else(truncated)?;
+        offset += 2 + u16::from_le_bytes([len[0], len[1]]) as usize;
+    }
+    let name = match flags & GZIP_FNAME {
+        0 => None,
+        _ => {
+            let rest = data.get(offset..).ok_or_else(truncated)?;
+            let end = rest.iter().position(|b| *b == 0).ok_or_else(truncated)?;
+            Some(rest[..end].to_vec())
+        }
+    };
+    Ok((name, mtime))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tests::TempFile;
+
+    #[test_log::test(tokio::test)]
+    async fn test_read_works() -> Result<()> {
+        for (fixture, compression) in [
+            ("a.txt.gz", CompressionType::Gzip),
+            ("a.txt.xz", CompressionType::Xz),
+            ("a.txt.zst", CompressionType::Zstd),
+            ("a.txt.bz2", CompressionType::Bzip),
+        ] {
+            let disk = CompressedFileFloppyDisk::open(format!("./fixtures/{fixture}")).await?;
+            assert_eq!(compression, disk.compression());
+            assert_eq!(Path::new("/a.txt"), disk.name());
+            assert_eq!("asdf\n", disk.read_to_string("/a.txt").await?);
+        }
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_write_works() -> Result<()> {
+        for fixture in ["a.txt.gz", "a.txt.xz", "a.txt.zst", "a.txt.bz2"] {
+            let file = TempFile::new(format!("./fixtures/{fixture}")).await?;
+            {
+                let disk = CompressedFileFloppyDisk::open(file.path_view()).await?;
+                disk.write("/a.txt", "wow!!!").await?;
+                disk.close().await?;
+            }
+            {
+                let disk = CompressedFileFloppyDisk::open(file.path_view()).await?;
+                assert_eq!("wow!!!", disk.read_to_string("/a.txt").await?);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_gzip_name_works() -> Result<()> {
+        let file = TempFile::new("./fixtures/renamed.gz").await?;
+        {
+            let disk = CompressedFileFloppyDisk::open(file.path_view()).await?;
+            assert_eq!(Path::new("/original.txt"), disk.name());
+            disk.write("/original.txt", "changed!!!").await?;
+            disk.close().await?;
+        }
+
+        let raw = tokio::fs::read(file.path_view()).await?;
+        assert_eq!(
+            (Some(b"original.txt".to_vec()), 1687000000),
+            parse_gzip_header(&raw)?
+        );
+        let disk = CompressedFileFloppyDisk::open(file.path_view()).await?;
+        assert_eq!("changed!!!", disk.read_to_string("/original.txt").await?);
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_create_works() -> Result<()> {
+        let dir = crate::util::TempDir::new().await?;
+        let path = dir.join("config.json.zst");
+        {
+            let disk = CompressedFileFloppyDisk::open(&path).await?;
+            assert_eq!(CompressionType::Zstd, disk.compression());
+            assert_eq!(Path::new("/config.json"), disk.name());
+            disk.write("/config.json", "{}").await?;
+            disk.close().await?;
+        }
+
+        let disk = CompressedFileFloppyDisk::open(&path).await?;
+        assert_eq!(CompressionType::Zstd, disk.compression());
+        assert_eq!("{}", disk.read_to_string("/config.json").await?);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,317 @@
+use std::io::Result;
+use std::path::{Path, PathBuf};
+
+use floppy_disk::prelude::*;
+use smoosh::CompressionType;
+use tracing::debug;
+
+use crate::format::{with_disk, ArchiveFormat};
+use crate::import::{FloppyDiskHardLinkExt, ImportOptions, ImportedEntry, ImportedKind};
+
+/// Something about an entry that the target format couldn't represent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Loss {
+    /// The target can't store symlinks, so the entry was skipped.
+    Symlink,
+    /// The target can't store hard links, so the entry was stored as a copy.
+    HardLink,
+    /// The target can't store directories. Non-empty directories are still
+    /// implied by their contents, but their metadata is gone.
+    Directory,
+    /// The target can't store the owning uid/gid.
+    Ownership,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LossyEntry {
+    pub path: PathBuf,
+    pub loss: Loss,
+}
+
+/// What happened during a [`convert`].
+#[derive(Debug, Default, Clone)]
+pub struct ConvertReport {
+    pub source_format: Option<ArchiveFormat>,
+    pub entries: usize,
+    pub losses: Vec<LossyEntry>,
+}
+
+impl ConvertReport {
+    pub fn is_lossless(&self) -> bool {
+        self.losses.is_empty()
+    }
+
+    fn check(&mut self, entries: &[ImportedEntry], target: ArchiveFormat) {
+        for entry in entries {
+            let path = crate::util::normalize_path(&entry.path);
+            let mut lose = |loss| {
+                self.losses.push(LossyEntry {
+                    path: path.clone(),
+                    loss,
+                })
+            };
+
+            match entry.kind {
+                ImportedKind::Symlink(_) if !target.supports_symlinks() => lose(Loss::Symlink),
+                ImportedKind::HardLink(_) if !target.supports_hard_links() => lose(Loss::HardLink),
+                ImportedKind::Directory
+                    if !target.supports_directories() && path.as_os_str() != "/" =>
+                {
+                    lose(Loss::Directory)
+                }
+                _ => {}
+            }
+
+            let owned = matches!(entry.kind, ImportedKind::File(_) | ImportedKind::Directory);
+            if owned && !target.supports_ownership() && path.as_os_str() != "/" {
+                lose(Loss::Ownership);
+            }
+        }
+        self.entries += entries.len();
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct ConvertOptions {
+    compression: Option<CompressionType>,
+    filter: ImportOptions,
+}
+
+impl ConvertOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Compress the output with `compression`. Defaults to the compression
+    /// of the source archive.
+    pub fn compression(mut self, compression: CompressionType) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Only convert the entries allowed by `filter`.
+    pub fn filter(mut self, filter: ImportOptions) -> Self {
+        self.filter = filter;
+        self
+    }
+}
+
+/// Converts the archive at `src_path` (of any supported format) into a new
+/// `dst_format` archive at `dst_path`, overwriting whatever is there. Returns
+/// a report of everything that couldn't be represented in the new format.
+pub async fn convert<P: AsRef<Path>, Q: AsRef<Path>>(
+    src_path: P,
+    dst_path: Q,
+    dst_format: ArchiveFormat,
+    opts: &ConvertOptions,
+) -> Result<ConvertReport> {
+    let src_path = src_path.as_ref();
+    let dst_path = dst_path.as_ref();
+    crate::util::require_exists(src_path).await?;
+    let src_format = ArchiveFormat::detect(src_path).await?;
+    debug!(
+        "converting {} ({}) to {} ({})",
+        src_path.display(),
+        src_format,
+        dst_path.display(),
+        dst_format
+    );
+
+    // The source is only read from, so it's never closed.
+    let mut report = with_disk!(src_format, src_path, |src| {
+        let compression = opts.compression.unwrap_or(src.compression());
+        convert_from(&src, dst_path, dst_format, compression, opts).await?
+    });
+    report.source_format = Some(src_format);
+
+    Ok(report)
+}
+
+async fn convert_from<'a, S>(
+    src: &'a S,
+    dst_path: &Path,
+    dst_format: ArchiveFormat,
+    compression: CompressionType,
+    opts: &ConvertOptions,
+) -> Result<ConvertReport>
+where
+    S: FloppyDisk<'a> + FloppyDiskHardLinkExt + Sync,
+    S::Metadata: FloppyUnixMetadata,
+    S::Permissions: FloppyUnixPermissions,
+{
+    let entries = crate::import::collect(src, Path::new("/"), &opts.filter).await?;
+    let mut report = ConvertReport::default();
+    report.check(&entries, dst_format);
+    // Symlinks would otherwise be silently dropped on close.
+    let entries = entries
+        .into_iter()
+        .filter(|entry| {
+            dst_format.supports_symlinks() || !matches!(entry.kind, ImportedKind::Symlink(_))
+        })
+        .collect();
+
+    if crate::util::exists_async(dst_path).await {
+        tokio::fs::remove_file(dst_path).await?;
+    }
+
+    with_disk!(dst_format, dst_path, |dst| {
+        dst.set_compression(compression);
+        dst.import_entries(Path::new("/"), entries).await?;
+        dst.close().await?;
+    });
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use floppy_disk::prelude::*;
+    use smoosh::CompressionType;
+
+    use super::{convert, ConvertOptions, Loss, LossyEntry};
+    use crate::ar::ArFloppyDisk;
+    use crate::cpio::CpioFloppyDisk;
+    use crate::format::ArchiveFormat;
+    use crate::tar::{TarFloppyDisk, TarPermissions};
+    use crate::util::TempDir;
+    use crate::zip::ZipFloppyDisk;
+
+    async fn make_tar(dir: &TempDir) -> std::io::Result<PathBuf> {
+        let path = dir.join("src.tar");
+        let disk = TarFloppyDisk::open(&path).await?;
+        disk.create_dir_all("/bin").await?;
+        disk.create_dir_all("/empty").await?;
+        disk.write("/bin/tool", "#!/bin/sh\necho hi\n").await?;
+        disk.set_permissions("/bin/tool", TarPermissions::from_mode(0o755))
+            .await?;
+        disk.hard_link("/bin/tool", "/bin/tool2").await?;
+        disk.symlink("tool", "/bin/alias").await?;
+        disk.close().await?;
+        Ok(path)
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_missing_source_is_not_created() -> std::io::Result<()> {
+        let dir = TempDir::new().await?;
+        let src = dir.join("missing.tar");
+        let dst = dir.join("out.zip");
+
+        let err = convert(&src, &dst, ArchiveFormat::Zip, &ConvertOptions::new())
+            .await
+            .unwrap_err();
+        assert_eq!(std::io::ErrorKind::NotFound, err.kind());
+        assert!(!crate::util::exists_async(&src).await);
+        assert!(!crate::util::exists_async(&dst).await);
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_tar_to_zip_works() -> std::io::Result<()> {
+        let dir = TempDir::new().await?;
+        let src = make_tar(&dir).await?;
+        let dst = dir.join("out.zip");
+
+        let report = convert(&src, &dst, ArchiveFormat::Zip, &ConvertOptions::new()).await?;
+        assert_eq!(Some(ArchiveFormat::Tar), report.source_format);
+        assert!(report.losses.contains(&LossyEntry {
+            path: PathBuf::from("/bin/tool2"),
+            loss: Loss::HardLink,
+        }));
+        assert!(report.losses.contains(&LossyEntry {
+            path: PathBuf::from("/bin/tool"),
+            loss: Loss::Ownership,
+        }));
+        assert!(!report.losses.iter().any(|l| l.loss == Loss::Symlink));
+
+        let disk = ZipFloppyDisk::open(&dst).await?;
+        assert_eq!(
+            "#!/bin/sh\necho hi\n",
+            disk.read_to_string("/bin/tool").await?
+        );
+        assert_eq!(
+            "#!/bin/sh\necho hi\n",
+            disk.read_to_string("/bin/tool2").await?
+        );
+        assert_eq!(PathBuf::from("tool"), disk.read_link("/bin/alias").await?);
+        assert!(disk.metadata("/empty").await?.is_dir());
+        assert_eq!(
+            0o755,
+            disk.metadata("/bin/tool").await?.permissions().mode() & 0o777
+        );
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_tar_to_ar_reports_losses() -> std::io::Result<()> {
+        let dir = TempDir::new().await?;
+        let src = make_tar(&dir).await?;
+        let dst = dir.join("out.a");
+
+        let report = convert(&src, &dst, ArchiveFormat::Ar, &ConvertOptions::new()).await?;
+        assert!(!report.is_lossless());
+        assert!(report.losses.contains(&LossyEntry {
+            path: PathBuf::from("/bin/alias"),
+            loss: Loss::Symlink,
+        }));
+        assert!(report.losses.contains(&LossyEntry {
+            path: PathBuf::from("/empty"),
+            loss: Loss::Directory,
+        }));
+
+        let disk = ArFloppyDisk::open(&dst).await?;
+        assert_eq!(
+            "#!/bin/sh\necho hi\n",
+            disk.read_to_string("/bin/tool").await?
+        );
+        assert!(!disk.try_exists("/bin/alias").await?);
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_zip_to_compressed_cpio_works() -> std::io::Result<()> {
+        let dir = TempDir::new().await?;
+        let dst = dir.join("out.cpio.gz");
+
+        let report = convert(
+            "./fixtures/a.zip",
+            &dst,
+            ArchiveFormat::Cpio,
+            &ConvertOptions::new().compression(CompressionType::Gzip),
+        )
+        .await?;
+        assert!(report.is_lossless());
+
+        let disk = CpioFloppyDisk::open(&dst).await?;
+        assert_eq!(CompressionType::Gzip, disk.compression());
+        assert_eq!("asdf\n", disk.read_to_string("/a.txt").await?);
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_tar_to_cpio_and_back_keeps_symlinks() -> std::io::Result<()> {
+        let dir = TempDir::new().await?;
+        let src = make_tar(&dir).await?;
+        let cpio = dir.join("out.cpio");
+        let tar = dir.join("back.tar");
+
+        convert(&src, &cpio, ArchiveFormat::Cpio, &ConvertOptions::new()).await?;
+        let report = convert(&cpio, &tar, ArchiveFormat::Tar, &ConvertOptions::new()).await?;
+        assert!(report.is_lossless());
+
+        let disk = TarFloppyDisk::open(&tar).await?;
+        assert_eq!(PathBuf::from("tool"), disk.read_link("/bin/alias").await?);
+        assert!(disk.metadata("/empty").await?.is_dir());
+        assert_eq!(
+            "#!/bin/sh\necho hi\n",
+            disk.read_to_string("/bin/tool").await?
+        );
+
+        Ok(())
+    }
+}
@@ -1,4 +1,4 @@
-use std::sync::Arc;
+use std::os::unix::prelude::{OsStrExt, OsStringExt};
 
 use smoosh::CompressionType;
 use tokio::io::AsyncReadExt;
@@ -6,6 +6,51 @@ use tracing::debug;
 
 crate::util::archive_format!(Cpio, "a.cpio", cpio_open, cpio_close);
 
+/// Which of the cpio header formats an archive uses.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum CpioVariant {
+    /// The SVR4 "new ASCII" format (`070701`), with hex fields.
+    #[default]
+    Newc,
+    /// Like [`CpioVariant::Newc`], but with a checksum of each file
+    /// (`070702`).
+    Crc,
+    /// The POSIX.1 portable ASCII format (`070707`), with octal fields.
+    Odc,
+    /// The old binary format. Both byte orders are read, but it's always
+    /// written little-endian.
+    Bin,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct CpioState {
+    variant: std::sync::Mutex<CpioVariant>,
+}
+
+impl crate::util::LazyEntries for CpioState {}
+
+impl CpioFloppyDisk {
+    /// The format the archive was read with, and will be written with.
+    pub fn variant(&self) -> CpioVariant {
+        *self.state.variant.lock().unwrap()
+    }
+
+    pub fn set_variant(&self, variant: CpioVariant) {
+        *self.state.variant.lock().unwrap() = variant;
+    }
+}
+
+const S_IFMT: u32 = 0o170000;
+const S_IFLNK: u32 = 0o120000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+const NEWC_MAGIC: &[u8] = b"070701";
+const CRC_MAGIC: &[u8] = b"070702";
+const ODC_MAGIC: &[u8] = b"070707";
+const BIN_MAGIC: u16 = 0o070707;
+const TRAILER: &[u8] = b"TRAILER!!!";
+
 async fn cpio_open<P: Into<PathBuf>>(path: P) -> Result<CpioInternalMetadata> {
     let path = path.into();
     if !crate::util::exists_async(path.clone()).await {
@@ -13,61 +58,108 @@ async fn cpio_open<P: Into<PathBuf>>(path: P) -> Result<CpioInternalMetadata> {
             delegate: MemFloppyDisk::new(),
             compression: CompressionType::None,
             ordered_paths: IndexSet::new(),
+            times: HashMap::new(),
+            hard_links: IndexMap::new(),
+            state: Default::default(),
         });
     }
 
     debug!("loading cpio archive from {}...", path.display());
-    let out = MemFloppyDisk::new();
-    let mut ordered_paths = IndexSet::new();
     let mut file = crate::util::async_file(path).await?;
     let mut buffer = vec![];
-    let c = smoosh::recompress(&mut file, &mut buffer, smoosh::CompressionType::None).await?;
+    file.read_to_end(&mut buffer).await?;
     debug!("loaded cpio archive!");
+    cpio_read(&buffer).await
+}
+
+/// Loads a (possibly compressed) cpio archive from memory, ie. the payload of
+/// an rpm.
+pub(crate) async fn cpio_read(data: &[u8]) -> Result<CpioInternalMetadata> {
+    let out = MemFloppyDisk::new();
+    let mut ordered_paths = IndexSet::new();
+    let mut times = HashMap::new();
+    let mut buffer = vec![];
+    let c = smoosh::recompress(&mut &data[..], &mut buffer, smoosh::CompressionType::None).await?;
 
     debug!("reading cpio entries...");
-    for file in cpio_reader::iter_files(&buffer) {
+    let (variant, entries) = parse_entries(&buffer)?;
+    debug!("found {} {:?} cpio entries", entries.len(), variant);
+
+    // Hard links share a dev and ino. newc only stores the data with the
+    // last of them, while the other variants store it with each one.
+    let is_link = |file: &CpioEntry| file.mode & S_IFMT == S_IFREG && file.nlink > 1;
+    let mut link_data = HashMap::new();
+    for file in entries.iter().filter(|file| is_link(file)) {
+        if !file.data.is_empty() {
+            link_data.insert((file.dev, file.ino), file.data);
+        }
+    }
+    let mut link_targets: HashMap<(u64, u32), PathBuf> = HashMap::new();
+    let mut hard_links = IndexMap::new();
+
+    for file in entries {
         debug!("reading next entry...");
-        let file_path = if file.name().starts_with('/') {
-            PathBuf::from(file.name())
-        } else {
-            PathBuf::from("/").join(file.name())
-        };
+        let file_path = crate::util::normalize_path(std::ffi::OsStr::from_bytes(file.name));
         ordered_paths.insert(file_path.clone());
+        times.insert(
+            file_path.clone(),
+            std::time::UNIX_EPOCH + std::time::Duration::from_secs(file.mtime),
+        );
+
+        let mode = file.mode;
+        if mode & S_IFMT == S_IFDIR {
+            debug!("found cpio dir: {}", file_path.display());
+            out.create_dir_all(&file_path).await?;
+            out.set_permissions(&file_path, MemPermissions::from_mode(mode & 0o7777))
+                .await?;
+            out.chown(&file_path, file.uid, file.gid).await?;
+            continue;
+        }
 
         if let Some(parent) = file_path.parent() {
             out.create_dir_all(parent).await?;
         }
+
+        if mode & S_IFMT == S_IFLNK {
+            let to = PathBuf::from(OsString::from_vec(file.data.to_vec()));
+            debug!(
+                "found cpio symlink: {} -> {}",
+                file_path.display(),
+                to.display()
+            );
+            out.symlink(to, file_path).await?;
+            continue;
+        }
+
+        let mut data = file.data;
+        if is_link(&file) {
+            let key = (file.dev, file.ino);
+            data = link_data.get(&key).copied().unwrap_or_default();
+            match link_targets.get(&key) {
+                Some(target) => {
+                    debug!("found cpio hard link: {}", file_path.display());
+                    hard_links.insert(file_path.clone(), target.clone());
+                }
+                None => {
+                    link_targets.insert(key, file_path.clone());
+                }
+            }
+        }
+
         let mut mem_file = MemOpenOptions::new()
             .create(true)
             .write(true)
             .open(&out, &file_path)
             .await?;
         debug!("found cpio file: {}", file_path.display());
-        let cpio_file_content = file.file().to_vec();
-        let mut buf = vec![];
-        smoosh::recompress(
-            &mut cpio_file_content.as_slice(),
-            &mut buf,
-            CompressionType::None,
-        )
-        .await?;
-
-        // TODO: random trailing null bytes show up and idk WHY ;-;
-        let mut buf_slice = if buf[buf.len() - 1] == 0 {
-            &buf[0..buf.len() - 1]
-        } else {
-            buf.as_slice()
-        };
-        tokio::io::copy(&mut buf_slice, &mut mem_file).await?;
+        tokio::io::copy(&mut data, &mut mem_file).await?;
         debug!("copied bytes!");
         mem_file
-            .set_permissions(MemPermissions::from_mode(file.mode().bits()))
+            .set_permissions(MemPermissions::from_mode(mode & 0o7777))
             .await?;
         debug!("set perms!");
 
-        let uid = file.uid();
-        let gid = file.gid();
-        out.chown(&file_path, uid, gid).await?;
+        out.chown(&file_path, file.uid, file.gid).await?;
         debug!("loaded file!");
     }
 
@@ -75,60 +167,570 @@ async fn cpio_open<P: Into<PathBuf>>(path: P) -> Result<CpioInternalMetadata> {
         delegate: out,
         compression: c,
         ordered_paths,
+        times,
+        hard_links,
+        state: CpioState {
+            variant: std::sync::Mutex::new(variant),
+        },
     })
 }
 
-async fn cpio_close(
-    disk: &MemFloppyDisk,
-    scope: &Path,
-    compression: CompressionType,
-    ordered_paths: &IndexSet<PathBuf>,
-) -> Result<()> {
-    let scope_clone = scope.to_path_buf();
-    debug!("closing cpio archive at {}...", scope.display());
-    let buffer = Arc::new(std::sync::Mutex::new(vec![]));
+async fn cpio_close(cpio: &CpioFloppyDisk) -> Result<()> {
+    let disk = &cpio.delegate;
+    let scope = &cpio.path;
+    let compression = cpio.compression;
+    let variant = cpio.variant();
+    let ordered_paths = &*cpio.ordered_paths.lock().await;
+    debug!(
+        "closing {:?} cpio archive at {}...",
+        variant,
+        scope.display()
+    );
+    let mut buffer = vec![];
 
     debug!("found {} paths!", ordered_paths.len());
-    for path in ordered_paths {
-        let metadata = disk.metadata(path).await?;
-        if metadata.is_file() {
-            let writer = cpio::newc::Builder::new(&path.to_string_lossy());
-            let mut handle = MemOpenOptions::new().read(true).open(disk, path).await?;
-
-            let mut data = vec![];
-            handle.read_to_end(&mut data).await?;
-
-            let archive = buffer.clone();
-            tokio::task::spawn_blocking(move || {
-                let mut archive = archive.lock().unwrap();
-                let mut writer = writer
-                    .gid(metadata.gid().unwrap())
-                    .uid(metadata.uid().unwrap())
-                    .mode(metadata.permissions().mode())
-                    .write(&mut *archive, metadata.len() as u32);
+    for (idx, path) in ordered_paths.iter().enumerate() {
+        if path.as_os_str() == "/" {
+            continue;
+        }
+        let mtime = cpio
+            .modified(path)
+            .await?
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let name = path.as_os_str().as_bytes();
 
-                std::io::copy(&mut data.as_slice(), &mut writer).unwrap();
+        let (metadata, mode, data) = if let Ok(link) = disk.read_link(path).await {
+            let metadata = disk.symlink_metadata(path).await?;
+            (
+                metadata,
+                S_IFLNK | 0o777,
+                link.as_os_str().as_bytes().to_vec(),
+            )
+        } else {
+            let metadata = disk.metadata(path).await?;
+            let mode = metadata.permissions().mode() & 0o7777;
+            if metadata.is_dir() {
+                (metadata, S_IFDIR | mode, vec![])
+            } else if metadata.is_file() {
+                let mut handle = MemOpenOptions::new().read(true).open(disk, path).await?;
+                let mut data = vec![];
+                handle.read_to_end(&mut data).await?;
+                (metadata, S_IFREG | mode, data)
+            } else {
+                continue;
+            }
+        };
 
-                writer.finish().unwrap();
-            })
-            .await?;
-            debug!("wrote file: {}", path.display());
-        }
+        write_entry(
+            &mut buffer,
+            variant,
+            &CpioEntry {
+                name,
+                dev: 0,
+                ino: idx as u32 + 1,
+                mode,
+                uid: metadata.uid()?,
+                gid: metadata.gid()?,
+                nlink: if mode & S_IFMT == S_IFDIR { 2 } else { 1 },
+                mtime,
+                data: &data,
+            },
+        )?;
+        debug!("wrote path: {}", path.display());
     }
+    write_entry(
+        &mut buffer,
+        variant,
+        &CpioEntry {
+            name: TRAILER,
+            dev: 0,
+            ino: 0,
+            mode: 0,
+            uid: 0,
+            gid: 0,
+            nlink: 1,
+            mtime: 0,
+            data: &[],
+        },
+    )?;
 
     let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
         .write(true)
         .truncate(true)
-        .open(scope_clone)
+        .open(scope)
         .await?;
-    // TODO: Is there a better way to pull this data out?
-    let buffer = tokio::task::spawn_blocking(move || {
-        let data = buffer.lock().unwrap();
-        data.clone()
-    })
-    .await?;
-    smoosh::recompress(&mut buffer.as_slice(), &mut file, compression).await?;
+    crate::util::write_compressed(&buffer, &mut file, compression).await?;
     debug!("wrote cpio archive!");
 
     Ok(())
 }
+
+/// An entry as it's stored in the archive.
+struct CpioEntry<'a> {
+    name: &'a [u8],
+    dev: u64,
+    ino: u32,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    nlink: u32,
+    mtime: u64,
+    data: &'a [u8],
+}
+
+/// Reads every entry up to the trailer, along with the variant of the first
+/// header. The trailer is optional, since older versions of this crate
+/// didn't write one.
+fn parse_entries(data: &[u8]) -> Result<(CpioVariant, Vec<CpioEntry<'_>>)> {
+    let mut variant = None;
+    let mut entries = vec![];
+    let mut offset = 0;
+    while data[offset..].iter().any(|b| *b != 0) {
+        let (entry_variant, entry, next) = parse_entry(data, offset)?;
+        variant.get_or_insert(entry_variant);
+        offset = next;
+        if entry.name == TRAILER {
+            break;
+        }
+        entries.push(entry);
+    }
+    Ok((variant.unwrap_or_default(), entries))
+}
+
+/// Parses the entry at `offset`, returning it with the offset of the next
+/// one.
+fn parse_entry(data: &[u8], offset: usize) -> Result<(CpioVariant, CpioEntry<'_>, usize)> {
+    let rest = &data[offset..];
+    let magic = rest
+        .get(..6)
+        .ok_or_else(|| invalid("truncated cpio header"))?;
+    let (variant, header_size, fields, align) = if magic == NEWC_MAGIC || magic == CRC_MAGIC {
+        let header = rest
+            .get(..110)
+            .ok_or_else(|| invalid("truncated cpio header"))?;
+        let fields = header[6..]
+            .chunks(8)
+            .map(|field| number(field, 16))
+            .collect::<Result<Vec<_>>>()?;
+        let variant = match magic == CRC_MAGIC {
+            true => CpioVariant::Crc,
+            false => CpioVariant::Newc,
+        };
+        // dev, ino, mode, uid, gid, nlink, mtime, filesize, namesize, check
+        let fields = [
+            (fields[7] << 32) | fields[8],
+            fields[0],
+            fields[1],
+            fields[2],
+            fields[3],
+            fields[4],
+            fields[5],
+            fields[6],
+            fields[11],
+            fields[12],
+        ];
+        (variant, 110, fields, 4)
+    } else if magic == ODC_MAGIC {
+        let header = rest
+            .get(..76)
+            .ok_or_else(|| invalid("truncated cpio header"))?;
+        let field = |start: usize, len: usize| number(&header[start..start + len], 8);
+        let fields = [
+            field(6, 6)?,
+            field(12, 6)?,
+            field(18, 6)?,
+            field(24, 6)?,
+            field(30, 6)?,
+            field(36, 6)?,
+            field(48, 11)?,
+            field(65, 11)?,
+            field(59, 6)?,
+            0,
+        ];
+        (CpioVariant::Odc, 76, fields, 1)
+    } else {
+        let header = rest
+            .get(..26)
+            .ok_or_else(|| invalid("truncated cpio header"))?;
+        let big_endian = match [header[0], header[1]] {
+            magic if u16::from_le_bytes(magic) == BIN_MAGIC => false,
+            magic if u16::from_be_bytes(magic) == BIN_MAGIC => true,
+            _ => return Err(invalid("not a cpio archive")),
+        };
+        let field = |idx: usize| {
+            let bytes = [header[idx * 2], header[idx * 2 + 1]];
+            match big_endian {
+                true => u16::from_be_bytes(bytes) as u64,
+                false => u16::from_le_bytes(bytes) as u64,
+            }
+        };
+        let fields = [
+            field(1),
+            field(2),
+            field(3),
+            field(4),
+            field(5),
+            field(6),
+            (field(8) << 16) | field(9),
+            (field(11) << 16) | field(12),
+            field(10),
+            0,
+        ];
+        (CpioVariant::Bin, 26, fields, 2)
+    };
+    let [dev, ino, mode, uid, gid, nlink, mtime, filesize, namesize, check] = fields;
+
+    let name_start = offset + header_size;
+    let name = usize::try_from(namesize)
+        .ok()
+        .filter(|namesize| *namesize > 0)
+        .and_then(|namesize| data.get(name_start..name_start + namesize))
+        .ok_or_else(|| invalid("bad cpio name size"))?;
+    let data_start = align_to(name_start + name.len(), align);
+    let file = usize::try_from(filesize)
+        .ok()
+        .and_then(|filesize| data.get(data_start..data_start + filesize))
+        .ok_or_else(|| invalid("truncated cpio entry"))?;
+    let name = trim_end(name, 0);
+
+    // Like GNU cpio, only regular files are checksummed.
+    if variant == CpioVariant::Crc
+        && mode as u32 & S_IFMT == S_IFREG
+        && checksum(file) != check as u32
+    {
+        return Err(invalid(&format!(
+            "cpio checksum mismatch for {}",
+            String::from_utf8_lossy(name)
+        )));
+    }
+
+    let entry = CpioEntry {
+        name,
+        dev,
+        ino: ino as u32,
+        mode: mode as u32,
+        uid: uid as u32,
+        gid: gid as u32,
+        nlink: nlink as u32,
+        mtime,
+        data: file,
+    };
+    let next = align_to(data_start + file.len(), align).min(data.len());
+    Ok((variant, entry, next))
+}
+
+fn write_entry(out: &mut Vec<u8>, variant: CpioVariant, entry: &CpioEntry) -> Result<()> {
+    let too_big = || {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "{} doesn't fit in a {:?} cpio header",
+                String::from_utf8_lossy(entry.name),
+                variant
+            ),
+        )
+    };
+    let namesize = entry.name.len() as u64 + 1;
+    let filesize = entry.data.len() as u64;
+    match variant {
+        CpioVariant::Newc | CpioVariant::Crc => {
+            let (magic, check) = match variant {
+                CpioVariant::Crc if entry.mode & S_IFMT == S_IFREG => {
+                    (CRC_MAGIC, checksum(entry.data) as u64)
+                }
+                CpioVariant::Crc => (CRC_MAGIC, 0),
+                _ => (NEWC_MAGIC, 0),
+            };
+            out.extend_from_slice(magic);
+            for field in [
+                entry.ino as u64,
+                entry.mode as u64,
+                entry.uid as u64,
+                entry.gid as u64,
+                entry.nlink as u64,
+                entry.mtime,
+                filesize,
+                entry.dev >> 32,
+                entry.dev & 0xffffffff,
+                0,
+                0,
+                namesize,
+                check,
+            ] {
+                if field > u32::MAX as u64 {
+                    return Err(too_big());
+                }
+                out.extend_from_slice(format!("{field:08X}").as_bytes());
+            }
+            out.extend_from_slice(entry.name);
+            out.push(0);
+            out.resize(align_to(out.len(), 4), 0);
+            out.extend_from_slice(entry.data);
+            out.resize(align_to(out.len(), 4), 0);
+        }
+        CpioVariant::Odc => {
+            out.extend_from_slice(ODC_MAGIC);
+            for (field, width) in [
+                (entry.dev & 0o777777, 6),
+                (entry.ino as u64 & 0o777777, 6),
+                (entry.mode as u64, 6),
+                (entry.uid as u64, 6),
+                (entry.gid as u64, 6),
+                (entry.nlink as u64, 6),
+                (0, 6),
+                (entry.mtime, 11),
+                (namesize, 6),
+                (filesize, 11),
+            ] {
+                let field = format!("{field:0width$o}");
+                if field.len() != width {
+                    return Err(too_big());
+                }
+                out.extend_from_slice(field.as_bytes());
+            }
+            out.extend_from_slice(entry.name);
+            out.push(0);
+            out.extend_from_slice(entry.data);
+        }
+        CpioVariant::Bin => {
+            let fields = [
+                BIN_MAGIC as u64,
+                entry.dev & 0xffff,
+                entry.ino as u64 & 0xffff,
+                entry.mode as u64,
+                entry.uid as u64,
+                entry.gid as u64,
+                entry.nlink as u64,
+                0,
+                entry.mtime >> 16,
+                entry.mtime & 0xffff,
+                namesize,
+                filesize >> 16,
+                filesize & 0xffff,
+            ];
+            if fields.iter().any(|field| *field > u16::MAX as u64) {
+                return Err(too_big());
+            }
+            for field in fields {
+                out.extend_from_slice(&(field as u16).to_le_bytes());
+            }
+            out.extend_from_slice(entry.name);
+            out.push(0);
+            out.resize(align_to(out.len(), 2), 0);
+            out.extend_from_slice(entry.data);
+            out.resize(align_to(out.len(), 2), 0);
+        }
+    }
+    Ok(())
+}
+
+/// The sum of every byte, which is what the crc format calls a checksum.
+fn checksum(data: &[u8]) -> u32 {
+    data.iter()
+        .fold(0u32, |sum, byte| sum.wrapping_add(*byte as u32))
+}
+
+fn align_to(offset: usize, align: usize) -> usize {
+    offset.next_multiple_of(align)
+}
+
+fn trim_end(mut data: &[u8], byte: u8) -> &[u8] {
+    while let [rest @ .., last] = data {
+        if *last != byte {
+            break;
+        }
+        data = rest;
+    }
+    data
+}
+
+fn number(field: &[u8], radix: u32) -> Result<u64> {
+    std::str::from_utf8(field)
+        .ok()
+        .and_then(|field| u64::from_str_radix(field, radix).ok())
+        .ok_or_else(|| invalid("bad number in cpio header"))
+}
+
+fn invalid(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod cpio_tests {
+    use super::*;
+    use crate::util::tests::TempFile;
+
+    #[test_log::test(tokio::test)]
+    async fn test_variants_work() -> Result<()> {
+        for (fixture, variant) in [
+            ("a.cpio", CpioVariant::Odc),
+            ("a.newc.cpio", CpioVariant::Newc),
+            ("a.crc.cpio", CpioVariant::Crc),
+            ("a.odc.cpio", CpioVariant::Odc),
+            ("a.bin.cpio", CpioVariant::Bin),
+        ] {
+            let archive = TempFile::new(format!("./fixtures/{fixture}")).await?;
+            {
+                let disk = CpioFloppyDisk::open(archive.path_view()).await?;
+                assert_eq!(variant, disk.variant());
+                assert_eq!("asdf\n", disk.read_to_string("/a.txt").await?);
+                disk.write("/b.txt", "wow!!!").await?;
+                disk.close().await?;
+            }
+
+            let disk = CpioFloppyDisk::open(archive.path_view()).await?;
+            assert_eq!(variant, disk.variant());
+            assert_eq!("asdf\n", disk.read_to_string("/a.txt").await?);
+            assert_eq!("wow!!!", disk.read_to_string("/b.txt").await?);
+        }
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_data_is_exact() -> Result<()> {
+        let expected: [(&str, &[u8]); 6] = [
+            ("/ab", b"x"),
+            ("/abc", b"xy\0"),
+            ("/abcd", b"\0"),
+            ("/empty", b""),
+            ("/nul.bin", b"abc\0"),
+            ("/zeros.bin", b"\0\0\0"),
+        ];
+        let disk = CpioFloppyDisk::open("./fixtures/nul.cpio").await?;
+        for (path, data) in expected {
+            assert_eq!(data, disk.read(path).await?);
+        }
+
+        for variant in [
+            CpioVariant::Newc,
+            CpioVariant::Crc,
+            CpioVariant::Odc,
+            CpioVariant::Bin,
+        ] {
+            let archive = TempFile::new("./fixtures/nul.cpio").await?;
+            {
+                let disk = CpioFloppyDisk::open(archive.path_view()).await?;
+                disk.set_variant(variant);
+                disk.close().await?;
+            }
+
+            let disk = CpioFloppyDisk::open(archive.path_view()).await?;
+            for (path, data) in expected {
+                assert_eq!(data, disk.read(path).await?, "{path} in {variant:?}");
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_choosing_variant_works() -> Result<()> {
+        let dir = crate::util::TempDir::new().await?;
+        let path = dir.join("new.cpio");
+        {
+            let disk = CpioFloppyDisk::open(&path).await?;
+            assert_eq!(CpioVariant::Newc, disk.variant());
+            disk.set_variant(CpioVariant::Odc);
+            disk.write("/a.txt", "asdf\n").await?;
+            disk.close().await?;
+        }
+
+        let data = tokio::fs::read(&path).await?;
+        assert!(data.starts_with(ODC_MAGIC));
+        let disk = CpioFloppyDisk::open(&path).await?;
+        assert_eq!(CpioVariant::Odc, disk.variant());
+        assert_eq!("asdf\n", disk.read_to_string("/a.txt").await?);
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_crc_checksum_is_verified() -> Result<()> {
+        let err = CpioFloppyDisk::open("./fixtures/bad.crc.cpio")
+            .await
+            .unwrap_err();
+        assert_eq!(std::io::ErrorKind::InvalidData, err.kind());
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_hard_links_work() -> Result<()> {
+        for variant in [CpioVariant::Newc, CpioVariant::Odc] {
+            let mut data = vec![];
+            for (name, dev, ino, nlink, contents) in [
+                (&b"a.txt"[..], 1, 7, 2, &b"linked\n"[..]),
+                (b"b.txt", 1, 7, 2, b"linked\n"),
+                (b"c.txt", 2, 7, 1, b"not linked\n"),
+            ] {
+                // newc only has the data on the last link.
+                let contents = match (variant, name) {
+                    (CpioVariant::Newc, b"a.txt") => b"",
+                    _ => contents,
+                };
+                write_entry(
+                    &mut data,
+                    variant,
+                    &CpioEntry {
+                        name,
+                        dev,
+                        ino,
+                        mode: S_IFREG | 0o644,
+                        uid: 0,
+                        gid: 0,
+                        nlink,
+                        mtime: 0,
+                        data: contents,
+                    },
+                )?;
+            }
+
+            let disk =
+                CpioFloppyDisk::from_metadata(PathBuf::from("a.cpio"), cpio_read(&data).await?);
+            assert_eq!("linked\n", disk.read_to_string("/a.txt").await?);
+            assert_eq!("linked\n", disk.read_to_string("/b.txt").await?);
+            assert_eq!("not linked\n", disk.read_to_string("/c.txt").await?);
+            assert_eq!(
+                Some(PathBuf::from("/a.txt")),
+                disk.hard_link_target("/b.txt").await
+            );
+            assert_eq!(None, disk.hard_link_target("/c.txt").await);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_checksums_are_written() -> Result<()> {
+        let mut data = vec![];
+        write_entry(
+            &mut data,
+            CpioVariant::Crc,
+            &CpioEntry {
+                name: b"a.txt",
+                dev: 0,
+                ino: 1,
+                mode: S_IFREG | 0o644,
+                uid: 0,
+                gid: 0,
+                nlink: 1,
+                mtime: 0,
+                data: b"asdf\n",
+            },
+        )?;
+        // The last header field, before the name.
+        assert_eq!(
+            format!("{:08X}", checksum(b"asdf\n")).as_bytes(),
+            &data[102..110]
+        );
+        let (variant, entries) = parse_entries(&data)?;
+        assert_eq!(CpioVariant::Crc, variant);
+        assert_eq!(b"asdf\n", entries[0].data);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,374 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::io::Result;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+use floppy_disk::mem::MemFloppyDisk;
+use floppy_disk::prelude::*;
+use indexmap::{IndexMap, IndexSet};
+use smoosh::CompressionType;
+use tracing::debug;
+
+use crate::ar::{ArFloppyDisk, ArPermissions};
+use crate::tar::{TarFloppyDisk, TarInternalMetadata, TarState};
+
+const DEBIAN_BINARY: &str = "/debian-binary";
+const TAR_EXTENSIONS: [&str; 5] = ["", ".gz", ".xz", ".zst", ".bz2"];
+
+/// A `.deb` package. The payload (`data.tar.*`) is exposed by derefing to a
+/// [`TarFloppyDisk`], and the maintainer scripts and `control` file
+/// (`control.tar.*`) by [`DebFloppyDisk::control`]. Both are written back,
+/// with the compression they were read with, on close.
+#[derive(Debug)]
+pub struct DebFloppyDisk {
+    ar: ArFloppyDisk,
+    control: TarFloppyDisk,
+    data: TarFloppyDisk,
+    control_member: PathBuf,
+    data_member: PathBuf,
+}
+
+impl DebFloppyDisk {
+    /// Opens the package at `path`, or creates an empty one with
+    /// xz-compressed members if it doesn't exist.
+    pub async fn open<P: AsRef<Path>>(path: P) -> Result<DebFloppyDisk> {
+        let path = path.as_ref();
+        let created = !crate::util::exists_async(path).await;
+        let ar = ArFloppyDisk::open(path).await?;
+        if created {
+            debug!("creating new deb at {}", path.display());
+            write_member(&ar, Path::new(DEBIAN_BINARY), b"2.0\n").await?;
+            return Ok(Self {
+                ar,
+                control: empty_tar("/control.tar.xz"),
+                data: empty_tar("/data.tar.xz"),
+                control_member: PathBuf::from("/control.tar.xz"),
+                data_member: PathBuf::from("/data.tar.xz"),
+            });
+        }
+
+        debug!("opening deb at {}", path.display());
+        let version = ar.read_to_string(DEBIAN_BINARY).await.map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{} is missing debian-binary", path.display()),
+            )
+        })?;
+        if !version.starts_with("2.") {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("unsupported deb version: {}", version.trim()),
+            ));
+        }
+
+        let control_member = find_member(&ar, "control.tar").await?;
+        let data_member = find_member(&ar, "data.tar").await?;
+        let control = open_tar(&ar, &control_member).await?;
+        let data = open_tar(&ar, &data_member).await?;
+
+        Ok(Self {
+            ar,
+            control,
+            data,
+            control_member,
+            data_member,
+        })
+    }
+
+    /// The contents of `control.tar.*`, ie. `/control`, `/postinst`, etc.
+    pub fn control(&self) -> &TarFloppyDisk {
+        &self.control
+    }
+
+    /// The contents of `data.tar.*`. This is also what the disk derefs to.
+    pub fn data(&self) -> &TarFloppyDisk {
+        &self.data
+    }
+
+    /// Parses the current contents of `/control` in the control view.
+    pub async fn control_fields(&self) -> Result<ControlFields> {
+        ControlFields::parse(&self.control.read_to_string("/control").await?)
+    }
+
+    /// Replaces `/control` in the control view with `fields`.
+    pub async fn set_control_fields(&self, fields: &ControlFields) -> Result<()> {
+        self.control.write("/control", fields.to_string()).await
+    }
+
+    /// Rebuilds the package. `debian-binary` always comes first, followed by
+    /// the control and data members, as dpkg requires.
+    pub async fn close(self) -> Result<()> {
+        debug!("closing deb");
+        let control = crate::tar::tar_write(&self.control).await?;
+        let data = crate::tar::tar_write(&self.data).await?;
+        write_member(&self.ar, &self.control_member, &control).await?;
+        write_member(&self.ar, &self.data_member, &data).await?;
+        self.ar.close().await
+    }
+}
+
+impl Deref for DebFloppyDisk {
+    type Target = TarFloppyDisk;
+
+    fn deref(&self) -> &Self::Target {
+        &self.data
+    }
+}
+
+async fn find_member(ar: &ArFloppyDisk, prefix: &str) -> Result<PathBuf> {
+    for ext in TAR_EXTENSIONS {
+        let member = PathBuf::from(format!("/{prefix}{ext}"));
+        if ar.try_exists(&member).await? {
+            return Ok(member);
+        }
+    }
+    Err(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("deb is missing {prefix}"),
+    ))
+}
+
+async fn open_tar(ar: &ArFloppyDisk, member: &Path) -> Result<TarFloppyDisk> {
+    debug!("loading deb member {}", member.display());
+    let data = ar.read(member).await?;
+    let metadata = crate::tar::tar_read(&data).await?;
+    Ok(TarFloppyDisk::from_metadata(member.to_path_buf(), metadata))
+}
+
+fn empty_tar(member: &str) -> TarFloppyDisk {
+    let mut state = TarState::default();
+    state.dot_prefix = true;
+    TarFloppyDisk::from_metadata(
+        PathBuf::from(member),
+        TarInternalMetadata {
+            delegate: MemFloppyDisk::new(),
+            compression: CompressionType::Xz,
+            ordered_paths: IndexSet::from([PathBuf::from("/")]),
+            times: HashMap::new(),
+            hard_links: IndexMap::new(),
+            state,
+        },
+    )
+}
+
+async fn write_member(ar: &ArFloppyDisk, member: &Path, data: &[u8]) -> Result<()> {
+    let created = !ar.try_exists(member).await?;
+    ar.write(member, data).await?;
+    if created {
+        // Match what dpkg-deb writes.
+        ar.set_permissions(member, ArPermissions::from_mode(0o644))
+            .await?;
+        ar.chown(member, 0, 0).await?;
+    }
+    Ok(())
+}
+
+/// The fields of a `control` file, in order. Multi-line values are stored
+/// without the leading space of each continuation line.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ControlFields {
+    fields: IndexMap<String, String>,
+}
+
+impl ControlFields {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn parse(input: &str) -> Result<ControlFields> {
+        let mut fields: IndexMap<String, String> = IndexMap::new();
+        let mut last: Option<String> = None;
+        for line in input.lines() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            if line.starts_with(' ') || line.starts_with('\t') {
+                let value = last.as_ref().and_then(|name| fields.get_mut(name));
+                let Some(value) = value else {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("continuation line without a field: {line}"),
+                    ));
+                };
+                value.push('\n');
+                value.push_str(&line[1..]);
+                continue;
+            }
+
+            let Some((name, value)) = line.split_once(':') else {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("invalid control line: {line}"),
+                ));
+            };
+            let name = name.trim().to_string();
+            fields.insert(name.clone(), value.trim().to_string());
+            last = Some(name);
+        }
+
+        Ok(Self { fields })
+    }
+
+    /// Field names are case-insensitive.
+    pub fn get<S: AsRef<str>>(&self, name: S) -> Option<&str> {
+        self.key(name.as_ref())
+            .and_then(|key| self.fields.get(key))
+            .map(|value| value.as_str())
+    }
+
+    /// Sets `name` to `value`, keeping its position if it already exists.
+    pub fn set<S: Into<String>, T: Into<String>>(&mut self, name: S, value: T) {
+        let name = name.into();
+        let name = self.key(&name).map(|key| key.to_string()).unwrap_or(name);
+        self.fields.insert(name, value.into());
+    }
+
+    pub fn remove<S: AsRef<str>>(&mut self, name: S) -> Option<String> {
+        let key = self.key(name.as_ref())?.to_string();
+        self.fields.shift_remove(&key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn package(&self) -> Option<&str> {
+        self.get("Package")
+    }
+
+    pub fn version(&self) -> Option<&str> {
+        self.get("Version")
+    }
+
+    pub fn architecture(&self) -> Option<&str> {
+        self.get("Architecture")
+    }
+
+    fn key(&self, name: &str) -> Option<&str> {
+        self.fields
+            .keys()
+            .find(|key| key.eq_ignore_ascii_case(name))
+            .map(|key| key.as_str())
+    }
+}
+
+impl Display for ControlFields {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (name, value) in &self.fields {
+            let mut lines = value.split('\n');
+            writeln!(f, "{}: {}", name, lines.next().unwrap_or_default())?;
+            for line in lines {
+                writeln!(f, " {line}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use floppy_disk::prelude::*;
+
+    use super::{ControlFields, DebFloppyDisk};
+    use crate::util::TempDir;
+
+    #[test_log::test(tokio::test)]
+    async fn test_read_works() -> std::io::Result<()> {
+        let disk = DebFloppyDisk::open("./fixtures/a.deb").await?;
+        assert_eq!("asdf\n", disk.read_to_string("/usr/share/a/a.txt").await?);
+        assert_eq!(
+            0o755,
+            disk.control()
+                .metadata("/postinst")
+                .await?
+                .permissions()
+                .mode()
+                & 0o777
+        );
+
+        let fields = disk.control_fields().await?;
+        assert_eq!(Some("a"), fields.package());
+        assert_eq!(Some("1.0.0"), fields.get("version"));
+        assert_eq!(
+            Some("a test package\nIt has a description\n.\nthat spans lines."),
+            fields.get("Description")
+        );
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_write_works() -> std::io::Result<()> {
+        let archive = crate::util::tests::TempFile::new("./fixtures/a.deb").await?;
+        {
+            let disk = DebFloppyDisk::open(archive.path_view()).await?;
+            disk.write("/usr/share/a/b.txt", "wow!!!").await?;
+            let mut fields = disk.control_fields().await?;
+            fields.set("Version", "1.0.1");
+            disk.set_control_fields(&fields).await?;
+            disk.close().await?;
+        }
+        {
+            let disk = DebFloppyDisk::open(archive.path_view()).await?;
+            assert_eq!("wow!!!", disk.read_to_string("/usr/share/a/b.txt").await?);
+            assert_eq!("asdf\n", disk.read_to_string("/usr/share/a/a.txt").await?);
+            let fields = disk.control_fields().await?;
+            assert_eq!(Some("1.0.1"), fields.version());
+            assert_eq!(Some("all"), fields.architecture());
+        }
+
+        let data = std::fs::read(archive.path_view())?;
+        let mut ar = ar::Archive::new(data.as_slice());
+        let mut members = vec![];
+        while let Some(entry) = ar.next_entry() {
+            let mut entry = entry?;
+            members.push(String::from_utf8_lossy(entry.header().identifier()).to_string());
+            let mut data = vec![];
+            entry.read_to_end(&mut data)?;
+            // Members are still xz, like dpkg-deb wrote them.
+            if members.len() > 1 {
+                assert!(data.starts_with(&[0xfd, b'7', b'z', b'X', b'Z']));
+            }
+        }
+        assert_eq!(
+            vec!["debian-binary", "control.tar.xz", "data.tar.xz"],
+            members
+        );
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_create_works() -> std::io::Result<()> {
+        let dir = TempDir::new().await?;
+        let path = dir.join("new.deb");
+        {
+            let disk = DebFloppyDisk::open(&path).await?;
+            disk.create_dir_all("/usr/bin").await?;
+            disk.write("/usr/bin/hello", "#!/bin/sh\necho hello\n")
+                .await?;
+            let mut fields = ControlFields::new();
+            fields.set("Package", "hello");
+            fields.set("Version", "0.1.0");
+            fields.set("Description", "says hello\nand nothing else");
+            disk.set_control_fields(&fields).await?;
+            disk.close().await?;
+        }
+        {
+            let disk = DebFloppyDisk::open(&path).await?;
+            assert_eq!(
+                "#!/bin/sh\necho hello\n",
+                disk.read_to_string("/usr/bin/hello").await?
+            );
+            assert_eq!(
+                "Package: hello\nVersion: 0.1.0\nDescription: says hello\n and nothing else\n",
+                disk.control().read_to_string("/control").await?
+            );
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,425 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+use std::io::Result;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use floppy_disk::prelude::*;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tracing::debug;
+
+use crate::format::{with_disk, ArchiveFormat};
+use crate::import::{FloppyDiskHardLinkExt, ImportOptions, ImportedKind};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryType {
+    File,
+    Directory,
+    Symlink,
+    HardLink,
+}
+
+/// Everything about a single archive entry that [`diff`] compares.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct EntryInfo {
+    #[serde(rename = "type")]
+    pub entry_type: EntryType,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// Seconds since the epoch.
+    pub mtime: Option<i64>,
+    pub size: u64,
+    /// The hex-encoded SHA-256 of the contents of files and hard links.
+    pub sha256: Option<String>,
+    /// Where a symlink points, or the path that a hard link links to.
+    pub target: Option<PathBuf>,
+}
+
+/// One way that an entry can differ between two archives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Difference {
+    /// The contents of the file changed.
+    Content,
+    /// The entry changed type, ie. from a file to a symlink.
+    Type,
+    Mode,
+    /// The uid or gid changed.
+    Owner,
+    Mtime,
+    /// A symlink or hard link points somewhere else.
+    Target,
+}
+
+impl Difference {
+    pub const ALL: [Difference; 6] = [
+        Difference::Content,
+        Difference::Type,
+        Difference::Mode,
+        Difference::Owner,
+        Difference::Mtime,
+        Difference::Target,
+    ];
+
+    pub fn is_metadata(&self) -> bool {
+        !matches!(self, Difference::Content)
+    }
+}
+
+impl Display for Difference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Difference::Content => "content",
+            Difference::Type => "type",
+            Difference::Mode => "mode",
+            Difference::Owner => "owner",
+            Difference::Mtime => "mtime",
+            Difference::Target => "target",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for Difference {
+    type Err = std::io::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|difference| difference.to_string().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("unknown difference: {s}"),
+                )
+            })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum Change {
+    Added {
+        path: PathBuf,
+        entry: EntryInfo,
+    },
+    Removed {
+        path: PathBuf,
+        entry: EntryInfo,
+    },
+    Modified {
+        path: PathBuf,
+        before: EntryInfo,
+        after: EntryInfo,
+        differences: Vec<Difference>,
+    },
+}
+
+impl Change {
+    pub fn path(&self) -> &Path {
+        match self {
+            Change::Added { path, .. } | Change::Removed { path, .. } => path,
+            Change::Modified { path, .. } => path,
+        }
+    }
+}
+
+/// Everything that changed between two archives, in path order.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct ArchiveDiff {
+    pub changes: Vec<Change>,
+}
+
+impl ArchiveDiff {
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).map_err(std::io::Error::other)
+    }
+}
+
+/// One line per change, ie. `+ /added`, `- /removed`, and
+/// `~ /modified (content, mode)`.
+impl Display for ArchiveDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for change in &self.changes {
+            match change {
+                Change::Added { path, .. } => writeln!(f, "+ {}", path.display())?,
+                Change::Removed { path, .. } => writeln!(f, "- {}", path.display())?,
+                Change::Modified {
+                    path, differences, ..
+                } => {
+                    let differences: Vec<String> =
+                        differences.iter().map(|d| d.to_string()).collect();
+                    writeln!(f, "~ {} ({})", path.display(), differences.join(", "))?
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct DiffOptions {
+    ignore: Vec<Difference>,
+}
+
+impl DiffOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Don't report `difference`. Useful for ownership when comparing
+    /// against formats that can't store it, or for mtimes when comparing
+    /// against zips, which only store them to the nearest 2 seconds.
+    pub fn ignore(mut self, difference: Difference) -> Self {
+        self.ignore.push(difference);
+        self
+    }
+}
+
+/// Compares the archives at `a` and `b`, which don't need to be the same
+/// format.
+pub async fn diff<P: AsRef<Path>, Q: AsRef<Path>>(a: P, b: Q) -> Result<ArchiveDiff> {
+    diff_with(a, b, &DiffOptions::new()).await
+}
+
+pub async fn diff_with<P: AsRef<Path>, Q: AsRef<Path>>(
+    a: P,
+    b: Q,
+    opts: &DiffOptions,
+) -> Result<ArchiveDiff> {
+    let a_format = ArchiveFormat::detect(&a).await?;
+    let b_format = ArchiveFormat::detect(&b).await?;
+    diff_as(a, a_format, b, b_format, opts).await
+}
+
+/// Like [`diff_with`], but with the formats already known.
+pub(crate) async fn diff_as<P: AsRef<Path>, Q: AsRef<Path>>(
+    a: P,
+    a_format: ArchiveFormat,
+    b: Q,
+    b_format: ArchiveFormat,
+    opts: &DiffOptions,
+) -> Result<ArchiveDiff> {
+    let a = a.as_ref();
+    let b = b.as_ref();
+    debug!(
+        "diffing {} ({}) against {} ({})",
+        a.display(),
+        a_format,
+        b.display(),
+        b_format
+    );
+
+    crate::util::require_exists(a).await?;
+    crate::util::require_exists(b).await?;
+
+    // Neither archive is closed, since they're only read from.
+    let before = with_disk!(a_format, a, |disk| entries(&disk).await?);
+    let after = with_disk!(b_format, b, |disk| entries(&disk).await?);
+
+    Ok(compare(before, after, opts))
+}
+
+pub(crate) async fn entries<'a, D>(disk: &'a D) -> Result<BTreeMap<PathBuf, EntryInfo>>
+where
+    D: FloppyDisk<'a> + FloppyDiskHardLinkExt + Sync,
+    D::Metadata: FloppyUnixMetadata,
+    D::Permissions: FloppyUnixPermissions,
+{
+    let mut out = BTreeMap::new();
+    let mut hashes: HashMap<PathBuf, (u64, String)> = HashMap::new();
+    for entry in crate::import::collect(disk, Path::new("/"), &ImportOptions::new()).await? {
+        if entry.path.as_os_str().is_empty() {
+            continue;
+        }
+        let path = crate::util::normalize_path(&entry.path);
+        let (entry_type, size, sha256, target) = match entry.kind {
+            ImportedKind::Directory => (EntryType::Directory, 0, None, None),
+            ImportedKind::File(data) => {
+                let hash = format!("{:x}", Sha256::digest(&data));
+                hashes.insert(path.clone(), (data.len() as u64, hash.clone()));
+                (EntryType::File, data.len() as u64, Some(hash), None)
+            }
+            ImportedKind::Symlink(target) => (EntryType::Symlink, 0, None, Some(target)),
+            ImportedKind::HardLink(target) => {
+                let target = crate::util::normalize_path(target);
+                let (size, hash) = hashes.get(&target).cloned().unzip();
+                (EntryType::HardLink, size.unwrap_or(0), hash, Some(target))
+            }
+        };
+
+        out.insert(
+            path,
+            EntryInfo {
+                entry_type,
+                mode: entry.mode & 0o7777,
+                uid: entry.uid,
+                gid: entry.gid,
+                mtime: entry
+                    .modified
+                    .map(|modified| chrono::DateTime::<chrono::Utc>::from(modified).timestamp()),
+                size,
+                sha256,
+                target,
+            },
+        );
+    }
+
+    Ok(out)
+}
+
+pub(crate) fn compare(
+    mut before: BTreeMap<PathBuf, EntryInfo>,
+    after: BTreeMap<PathBuf, EntryInfo>,
+    opts: &DiffOptions,
+) -> ArchiveDiff {
+    let mut changes = vec![];
+    for (path, after) in after {
+        let Some(before) = before.remove(&path) else {
+            changes.push(Change::Added { path, entry: after });
+            continue;
+        };
+
+        let differences: Vec<Difference> = Difference::ALL
+            .into_iter()
+            .filter(|difference| !opts.ignore.contains(difference))
+            .filter(|difference| match difference {
+                Difference::Content => before.sha256 != after.sha256,
+                Difference::Type => before.entry_type != after.entry_type,
+                Difference::Mode => before.mode != after.mode,
+                Difference::Owner => before.uid != after.uid || before.gid != after.gid,
+                Difference::Mtime => before.mtime != after.mtime,
+                Difference::Target => before.target != after.target,
+            })
+            .collect();
+        if !differences.is_empty() {
+            changes.push(Change::Modified {
+                path,
+                before,
+                after,
+                differences,
+            });
+        }
+    }
+    changes.extend(
+        before
+            .into_iter()
+            .map(|(path, entry)| Change::Removed { path, entry }),
+    );
+    changes.sort_by(|a, b| a.path().cmp(b.path()));
+
+    ArchiveDiff { changes }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use floppy_disk::prelude::*;
+
+    use super::{diff, diff_with, Change, DiffOptions, Difference};
+    use crate::tar::{TarFloppyDisk, TarPermissions};
+    use crate::util::TempDir;
+
+    #[test_log::test(tokio::test)]
+    async fn test_missing_archives_are_not_created() -> std::io::Result<()> {
+        let dir = TempDir::new().await?;
+        let missing = dir.join("missing.tar");
+
+        for (a, b) in [
+            (missing.as_path(), "./fixtures/a.tar".as_ref()),
+            ("./fixtures/a.tar".as_ref(), missing.as_path()),
+        ] {
+            let err = diff(a, b).await.unwrap_err();
+            assert_eq!(std::io::ErrorKind::NotFound, err.kind());
+        }
+        assert!(!crate::util::exists_async(&missing).await);
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_diff_works() -> std::io::Result<()> {
+        let dir = TempDir::new().await?;
+        let a = dir.join("a.tar");
+        let b = dir.join("b.tar");
+        for (path, contents) in [(&a, "one"), (&b, "two")] {
+            let disk = TarFloppyDisk::open(path).await?;
+            disk.write("/same.txt", "same").await?;
+            disk.write("/changed.txt", contents).await?;
+            disk.write("/chmod.txt", "chmod").await?;
+            disk.close().await?;
+        }
+        let disk = TarFloppyDisk::open(&a).await?;
+        disk.write("/removed.txt", "bye").await?;
+        disk.symlink("same.txt", "/link").await?;
+        disk.close().await?;
+        let disk = TarFloppyDisk::open(&b).await?;
+        disk.write("/added.txt", "hi").await?;
+        disk.symlink("changed.txt", "/link").await?;
+        disk.set_permissions("/chmod.txt", TarPermissions::from_mode(0o600))
+            .await?;
+        disk.close().await?;
+
+        let opts = DiffOptions::new().ignore(Difference::Mtime);
+        let result = diff_with(&a, &b, &opts).await?;
+        let summary: Vec<(PathBuf, Vec<Difference>)> = result
+            .changes
+            .iter()
+            .map(|change| match change {
+                Change::Modified {
+                    path, differences, ..
+                } => (path.clone(), differences.clone()),
+                change => (change.path().to_path_buf(), vec![]),
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                (PathBuf::from("/added.txt"), vec![]),
+                (PathBuf::from("/changed.txt"), vec![Difference::Content]),
+                (PathBuf::from("/chmod.txt"), vec![Difference::Mode]),
+                (PathBuf::from("/link"), vec![Difference::Target]),
+                (PathBuf::from("/removed.txt"), vec![]),
+            ],
+            summary
+        );
+        assert!(matches!(result.changes[0], Change::Added { .. }));
+        assert!(matches!(result.changes[4], Change::Removed { .. }));
+        assert_eq!(
+            "+ /added.txt\n~ /changed.txt (content)\n~ /chmod.txt (mode)\n~ /link (target)\n- /removed.txt\n",
+            result.to_string()
+        );
+
+        let json: serde_json::Value = serde_json::from_str(&result.to_json()?)?;
+        assert_eq!("modified", json["changes"][1]["change"]);
+        assert_eq!("content", json["changes"][1]["differences"][0]);
+        assert_eq!("file", json["changes"][1]["after"]["type"]);
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_diff_across_formats_works() -> std::io::Result<()> {
+        let result = diff("./fixtures/a.tar", "./fixtures/a.zip").await?;
+        assert!(result
+            .changes
+            .iter()
+            .all(|change| matches!(change, Change::Modified { .. })));
+
+        let opts = DiffOptions::new()
+            .ignore(Difference::Mtime)
+            .ignore(Difference::Owner);
+        assert!(diff_with("./fixtures/a.tar", "./fixtures/a.zip", &opts)
+            .await?
+            .is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,780 @@
+use std::collections::HashSet;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::FileExt;
+
+use tracing::{debug, trace, warn};
+
+crate::image::image_format!(Ext4, "a.ext4", Ext4Reader, ext4_open);
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const MAGIC: u16 = 0xef53;
+const ROOT_INODE: u32 = 2;
+const GOOD_OLD_INODE_SIZE: usize = 128;
+
+const INCOMPAT_COMPRESSION: u32 = 0x0001;
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+const INCOMPAT_RECOVER: u32 = 0x0004;
+const INCOMPAT_JOURNAL_DEV: u32 = 0x0008;
+const INCOMPAT_META_BG: u32 = 0x0010;
+const INCOMPAT_64BIT: u32 = 0x0080;
+const INCOMPAT_ENCRYPT: u32 = 0x10000;
+
+const FLAG_EXTENTS: u32 = 0x80000;
+const FLAG_INLINE_DATA: u32 = 0x10000000;
+
+const EXTENT_MAGIC: u16 = 0xf30a;
+/// Extents longer than this are uninitialized, and read as zeroes.
+const EXTENT_MAX_INITIALIZED: u16 = 32768;
+const XATTR_MAGIC: u32 = 0xea020000;
+const XATTR_BLOCK_HEADER_SIZE: usize = 32;
+
+const S_IFMT: u16 = 0o170000;
+const S_IFREG: u16 = 0o100000;
+const S_IFDIR: u16 = 0o040000;
+const S_IFLNK: u16 = 0o120000;
+
+#[derive(Debug)]
+pub(crate) struct Ext4Reader {
+    file: std::fs::File,
+    superblock: Superblock,
+    /// Where each block group's inode table starts.
+    inode_tables: Vec<u64>,
+    /// The most that any one file can hold, so that a corrupt size isn't
+    /// allocated before anything is read.
+    max_file_size: u64,
+}
+
+#[async_trait::async_trait]
+impl ImageReader for Ext4Reader {
+    /// The inode number. Inodes are read again when the file is, so that
+    /// nothing but the tree is kept in memory.
+    type Data = u32;
+
+    async fn read_file(&self, ino: &Self::Data, size: u64) -> Result<Vec<u8>> {
+        let inode = self.inode(*ino)?;
+        let data = self.inode_data(&inode)?;
+        if data.len() as u64 != size {
+            return Err(invalid(&format!("inode {ino} changed size")));
+        }
+        Ok(data)
+    }
+}
+
+impl Ext4FloppyDisk {
+    pub fn volume_name(&self) -> &str {
+        &self.reader.superblock.volume_name
+    }
+
+    pub fn uuid(&self) -> [u8; 16] {
+        self.reader.superblock.uuid
+    }
+
+    pub fn block_size(&self) -> u64 {
+        self.reader.superblock.block_size
+    }
+
+    /// The extended attributes of `path`, with their namespace prefixes,
+    /// like `user.` or `security.`. Symlinks are not followed.
+    pub fn xattrs<P: AsRef<Path>>(&self, path: P) -> Result<Vec<(String, Vec<u8>)>> {
+        let (_, entry) = self.entry(path, false)?;
+        let inode = self.reader.inode(entry.ino as u32)?;
+        Ok(self
+            .reader
+            .xattrs(&inode)?
+            .into_iter()
+            // Where inline data that doesn't fit in the inode goes.
+            .filter(|(name, _)| name != "system.data")
+            .collect())
+    }
+}
+
+async fn ext4_open(path: &Path) -> Result<(Ext4Reader, ImageTree<u32>)> {
+    debug!("opening ext4 image {}", path.display());
+    let file = std::fs::File::open(path)?;
+    let mut superblock = [0; SUPERBLOCK_SIZE];
+    file.read_exact_at(&mut superblock, SUPERBLOCK_OFFSET)?;
+    let superblock = Superblock::parse(&superblock)?;
+    debug!(
+        "found ext4 image with {} byte blocks and {} groups",
+        superblock.block_size, superblock.groups
+    );
+
+    // Files can't be bigger than the filesystem, or the image that it's in.
+    let image_size = file.metadata()?.len();
+    let max_file_size = superblock
+        .blocks
+        .saturating_mul(superblock.block_size)
+        .min(image_size);
+
+    // The group descriptors start in the block after the superblock.
+    let descriptors_size = superblock.groups as u64 * superblock.descriptor_size as u64;
+    if descriptors_size > image_size {
+        return Err(invalid("ext4 image is too small for its group descriptors"));
+    }
+    let mut descriptors = vec![0; descriptors_size as usize];
+    file.read_exact_at(
+        &mut descriptors,
+        superblock.block_offset(superblock.first_data_block + 1)?,
+    )?;
+    let inode_tables = descriptors
+        .chunks_exact(superblock.descriptor_size)
+        .map(|descriptor| {
+            let low = le_u32(descriptor, 8) as u64;
+            let high = match superblock.descriptor_size >= 64 {
+                true => le_u32(descriptor, 0x28) as u64,
+                false => 0,
+            };
+            superblock.block_offset(high << 32 | low)
+        })
+        .collect::<Result<_>>()?;
+
+    let reader = Ext4Reader {
+        file,
+        superblock,
+        inode_tables,
+        max_file_size,
+    };
+    let tree = reader.walk()?;
+    Ok((reader, tree))
+}
+
+#[derive(Debug)]
+struct Superblock {
+    inodes_per_group: u32,
+    groups: u32,
+    blocks: u64,
+    block_size: u64,
+    first_data_block: u64,
+    inode_size: usize,
+    descriptor_size: usize,
+    incompat: u32,
+    uuid: [u8; 16],
+    volume_name: String,
+}
+
+impl Superblock {
+    fn parse(data: &[u8]) -> Result<Self> {
+        if le_u16(data, 0x38) != MAGIC {
+            return Err(invalid("not an ext2/3/4 image"));
+        }
+        let incompat = le_u32(data, 0x60);
+        for (flag, name) in [
+            (INCOMPAT_COMPRESSION, "compression"),
+            (INCOMPAT_JOURNAL_DEV, "external journal devices"),
+            (INCOMPAT_META_BG, "meta block groups"),
+            (INCOMPAT_ENCRYPT, "encryption"),
+        ] {
+            if incompat & flag != 0 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    format!("ext4 images with {name} are not supported"),
+                ));
+            }
+        }
+        if incompat & INCOMPAT_RECOVER != 0 {
+            warn!("ext4 journal needs recovery, recent changes may be missing");
+        }
+
+        let log_block_size = le_u32(data, 0x18);
+        if log_block_size > 6 {
+            return Err(invalid("invalid ext4 block size"));
+        }
+        let (inode_size, descriptor_size) = match le_u32(data, 0x4c) {
+            0 => (GOOD_OLD_INODE_SIZE, 32),
+            _ => (
+                le_u16(data, 0x58) as usize,
+                match incompat & INCOMPAT_64BIT {
+                    0 => 32,
+                    _ => le_u16(data, 0xfe) as usize,
+                },
+            ),
+        };
+        let inodes_per_group = le_u32(data, 0x28);
+        if inodes_per_group == 0
+            || inode_size < GOOD_OLD_INODE_SIZE
+            || !inode_size.is_power_of_two()
+            || descriptor_size < 32
+        {
+            return Err(invalid("invalid ext4 superblock"));
+        }
+        let mut uuid = [0; 16];
+        uuid.copy_from_slice(&data[0x68..0x78]);
+        let volume_name = &data[0x78..0x88];
+        let volume_name = &volume_name[..volume_name.iter().position(|b| *b == 0).unwrap_or(16)];
+        let blocks_high = match incompat & INCOMPAT_64BIT {
+            0 => 0,
+            _ => le_u32(data, 0x150) as u64,
+        };
+        Ok(Self {
+            inodes_per_group,
+            groups: le_u32(data, 0).div_ceil(inodes_per_group),
+            blocks: blocks_high << 32 | le_u32(data, 0x4) as u64,
+            block_size: 1024 << log_block_size,
+            first_data_block: le_u32(data, 0x14) as u64,
+            inode_size,
+            descriptor_size,
+            incompat,
+            uuid,
+            volume_name: String::from_utf8_lossy(volume_name).to_string(),
+        })
+    }
+
+    /// Where `block` starts in the image.
+    fn block_offset(&self, block: u64) -> Result<u64> {
+        block
+            .checked_mul(self.block_size)
+            .ok_or_else(|| invalid(&format!("ext4 block {block} is out of range")))
+    }
+}
+
+#[derive(Debug)]
+struct Inode {
+    ino: u32,
+    mode: u16,
+    uid: u32,
+    gid: u32,
+    size: u64,
+    mtime: SystemTime,
+    links: u16,
+    flags: u32,
+    /// The block map, extent tree root, inline data, or symlink target.
+    block: [u8; 60],
+    /// The block that holds extended attributes that don't fit in the
+    /// inode.
+    xattr_block: u64,
+    /// How many 512 byte sectors are allocated to the inode.
+    sectors: u64,
+    /// The space after the inode's fixed fields, where extended attributes
+    /// can go.
+    extra: Vec<u8>,
+}
+
+impl Ext4Reader {
+    fn inode(&self, ino: u32) -> Result<Inode> {
+        let superblock = &self.superblock;
+        let index = ino
+            .checked_sub(1)
+            .ok_or_else(|| invalid("invalid ext4 inode number"))?;
+        let table = self
+            .inode_tables
+            .get((index / superblock.inodes_per_group) as usize)
+            .ok_or_else(|| invalid(&format!("ext4 inode {ino} is out of range")))?;
+        let mut data = vec![0; superblock.inode_size];
+        let offset = table
+            .checked_add(
+                (index % superblock.inodes_per_group) as u64 * superblock.inode_size as u64,
+            )
+            .ok_or_else(|| invalid(&format!("ext4 inode {ino} is out of range")))?;
+        self.file.read_exact_at(&mut data, offset)?;
+
+        let extra_size = match data.len() > GOOD_OLD_INODE_SIZE {
+            true => le_u16(&data, 0x80) as usize,
+            false => 0,
+        };
+        if GOOD_OLD_INODE_SIZE + extra_size > data.len() {
+            return Err(invalid(&format!("ext4 inode {ino} is corrupt")));
+        }
+        // Times after 2038 borrow the low bits of the `_extra` fields,
+        // which also have the nanoseconds.
+        let seconds = le_u32(&data, 0x10) as i32 as i64;
+        let (seconds, nanos) = match extra_size >= 12 {
+            true => {
+                let extra = le_u32(&data, 0x88);
+                (seconds + (((extra & 3) as i64) << 32), extra >> 2)
+            }
+            false => (seconds, 0),
+        };
+        let mtime = match u64::try_from(seconds) {
+            Ok(seconds) => {
+                std::time::UNIX_EPOCH + std::time::Duration::new(seconds, nanos.min(999_999_999))
+            }
+            Err(_) => std::time::UNIX_EPOCH,
+        };
+
+        let mut block = [0; 60];
+        block.copy_from_slice(&data[0x28..0x64]);
+        Ok(Inode {
+            ino,
+            mode: le_u16(&data, 0),
+            uid: le_u16(&data, 0x2) as u32 | (le_u16(&data, 0x78) as u32) << 16,
+            gid: le_u16(&data, 0x18) as u32 | (le_u16(&data, 0x7a) as u32) << 16,
+            size: le_u32(&data, 0x4) as u64 | (le_u32(&data, 0x6c) as u64) << 32,
+            mtime,
+            links: le_u16(&data, 0x1a),
+            flags: le_u32(&data, 0x20),
+            block,
+            xattr_block: le_u32(&data, 0x68) as u64 | (le_u16(&data, 0x76) as u64) << 32,
+            sectors: le_u32(&data, 0x1c) as u64 | (le_u16(&data, 0x74) as u64) << 32,
+            extra: data[GOOD_OLD_INODE_SIZE + extra_size..].to_vec(),
+        })
+    }
+
+    /// Reads the contents of a file, directory, or symlink.
+    fn inode_data(&self, inode: &Inode) -> Result<Vec<u8>> {
+        let size = usize::try_from(inode.size)
+            .ok()
+            .filter(|_| inode.size <= self.max_file_size)
+            .ok_or_else(|| invalid(&format!("ext4 inode {} is too big", inode.ino)))?;
+        if inode.flags & FLAG_INLINE_DATA != 0 {
+            let mut out = inode.block[..size.min(60)].to_vec();
+            if size > 60 {
+                let xattrs = self.xattrs(inode)?;
+                if let Some((_, rest)) = xattrs.iter().find(|(name, _)| name == "system.data") {
+                    out.extend_from_slice(rest);
+                }
+            }
+            if out.len() != size {
+                return Err(invalid(&format!("ext4 inode {} is truncated", inode.ino)));
+            }
+            return Ok(out);
+        }
+        if inode.mode & S_IFMT == S_IFLNK && self.is_fast_symlink(inode) {
+            return Ok(inode.block[..size].to_vec());
+        }
+
+        let block_size = self.superblock.block_size;
+        let mut out = vec![0; size];
+        let runs = match inode.flags & FLAG_EXTENTS {
+            0 => self.block_map(inode)?,
+            _ => {
+                let mut runs = vec![];
+                self.extents(&inode.block, &mut runs, 0)?;
+                runs
+            }
+        };
+        trace!("reading {} runs for inode {}", runs.len(), inode.ino);
+        for (logical, physical, len) in runs {
+            let start = logical * block_size;
+            if start >= size as u64 {
+                continue;
+            }
+            let end = (start + len * block_size).min(size as u64);
+            self.file.read_exact_at(
+                &mut out[start as usize..end as usize],
+                self.superblock.block_offset(physical)?,
+            )?;
+        }
+        Ok(out)
+    }
+
+    /// Short symlinks keep their target where the block map would be.
+    fn is_fast_symlink(&self, inode: &Inode) -> bool {
+        let xattr_sectors = match inode.xattr_block {
+            0 => 0,
+            _ => self.superblock.block_size / 512,
+        };
+        inode.size < 60
+            && inode.flags & FLAG_EXTENTS == 0
+            && inode.sectors.saturating_sub(xattr_sectors) == 0
+    }
+
+    /// Collects the `(logical, physical, length)` runs of blocks under an
+    /// extent tree node. Uninitialized extents are skipped, since they read
+    /// as zeroes.
+    fn extents(&self, node: &[u8], out: &mut Vec<(u64, u64, u64)>, level: usize) -> Result<()> {
+        if le_u16(node, 0) != EXTENT_MAGIC || level > 5 {
+            return Err(invalid("invalid ext4 extent tree"));
+        }
+        let entries = le_u16(node, 2) as usize;
+        let depth = le_u16(node, 6);
+        if 12 + entries * 12 > node.len() {
+            return Err(invalid("invalid ext4 extent tree"));
+        }
+        for entry in node[12..12 + entries * 12].chunks_exact(12) {
+            let logical = le_u32(entry, 0) as u64;
+            if depth == 0 {
+                let len = le_u16(entry, 4);
+                if len > EXTENT_MAX_INITIALIZED {
+                    continue;
+                }
+                let physical = (le_u16(entry, 6) as u64) << 32 | le_u32(entry, 8) as u64;
+                out.push((logical, physical, len as u64));
+            } else {
+                let child = (le_u16(entry, 8) as u64) << 32 | le_u32(entry, 4) as u64;
+                let block = self.read_block(child)?;
+                self.extents(&block, out, level + 1)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Collects the runs of blocks in an old-style block map, with direct,
+    /// indirect, double indirect, and triple indirect blocks.
+    fn block_map(&self, inode: &Inode) -> Result<Vec<(u64, u64, u64)>> {
+        let count = inode.size.div_ceil(self.superblock.block_size) as usize;
+        let mut blocks: Vec<u64> = (0..12)
+            .map(|idx| le_u32(&inode.block, idx * 4) as u64)
+            .collect();
+        for (idx, level) in [(12, 1), (13, 2), (14, 3)] {
+            self.indirect(
+                le_u32(&inode.block, idx * 4) as u64,
+                level,
+                count,
+                &mut blocks,
+            )?;
+        }
+        blocks.truncate(count);
+
+        // Join contiguous blocks into runs, skipping holes.
+        let mut runs: Vec<(u64, u64, u64)> = vec![];
+        for (logical, physical) in blocks.into_iter().enumerate() {
+            if physical == 0 {
+                continue;
+            }
+            match runs.last_mut() {
+                Some((start, first, len))
+                    if *start + *len == logical as u64 && *first + *len == physical =>
+                {
+                    *len += 1
+                }
+                _ => runs.push((logical as u64, physical, 1)),
+            }
+        }
+        Ok(runs)
+    }
+
+    fn indirect(&self, block: u64, level: u32, count: usize, out: &mut Vec<u64>) -> Result<()> {
+        if out.len() >= count {
+            return Ok(());
+        }
+        let per_block = self.superblock.block_size as usize / 4;
+        if block == 0 {
+            let hole = per_block.saturating_pow(level).min(count - out.len());
+            out.resize(out.len() + hole, 0);
+            return Ok(());
+        }
+        let data = self.read_block(block)?;
+        for entry in data.chunks_exact(4) {
+            let entry = le_u32(entry, 0) as u64;
+            match level {
+                1 => out.push(entry),
+                _ => self.indirect(entry, level - 1, count, out)?,
+            }
+            if out.len() >= count {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_block(&self, block: u64) -> Result<Vec<u8>> {
+        let mut data = vec![0; self.superblock.block_size as usize];
+        self.file
+            .read_exact_at(&mut data, self.superblock.block_offset(block)?)?;
+        Ok(data)
+    }
+
+    /// Every extended attribute, from both the inode and its xattr block.
+    fn xattrs(&self, inode: &Inode) -> Result<Vec<(String, Vec<u8>)>> {
+        let mut out = vec![];
+        if inode.extra.len() >= 4 && le_u32(&inode.extra, 0) == XATTR_MAGIC {
+            // Values in the inode are relative to the first entry.
+            let entries = &inode.extra[4..];
+            self.parse_xattrs(entries, 0, entries, &mut out)?;
+        }
+        if inode.xattr_block != 0 {
+            let block = self.read_block(inode.xattr_block)?;
+            if le_u32(&block, 0) != XATTR_MAGIC {
+                return Err(invalid("invalid ext4 xattr block"));
+            }
+            self.parse_xattrs(&block, XATTR_BLOCK_HEADER_SIZE, &block, &mut out)?;
+        }
+        Ok(out)
+    }
+
+    fn parse_xattrs(
+        &self,
+        data: &[u8],
+        mut offset: usize,
+        values: &[u8],
+        out: &mut Vec<(String, Vec<u8>)>,
+    ) -> Result<()> {
+        while offset + 16 <= data.len() && le_u32(data, offset) != 0 {
+            let entry = &data[offset..];
+            let name_len = entry[0] as usize;
+            let prefix = match entry[1] {
+                1 => "user.",
+                2 => "system.posix_acl_access",
+                3 => "system.posix_acl_default",
+                4 => "trusted.",
+                6 => "security.",
+                7 => "system.",
+                8 => "system.richacl",
+                _ => "",
+            };
+            let name = entry
+                .get(16..16 + name_len)
+                .ok_or_else(|| invalid("ext4 xattr is truncated"))?;
+            let value_offset = le_u16(entry, 2) as usize;
+            let value_inode = le_u32(entry, 4);
+            let value_size = le_u32(entry, 8) as usize;
+            let value = match value_inode {
+                // Big values can live in their own inode.
+                0 => values
+                    .get(value_offset..value_offset + value_size)
+                    .ok_or_else(|| invalid("ext4 xattr value is out of range"))?
+                    .to_vec(),
+                ino => self.inode_data(&self.inode(ino)?)?,
+            };
+            out.push((format!("{prefix}{}", String::from_utf8_lossy(name)), value));
+            offset += (16 + name_len).div_ceil(4) * 4;
+        }
+        Ok(())
+    }
+
+    /// Reads a directory's entries, returning each name and inode number.
+    fn read_dir(&self, inode: &Inode) -> Result<Vec<(OsString, u32)>> {
+        let mut out = vec![];
+        if inode.flags & FLAG_INLINE_DATA != 0 {
+            // Inline directories start with the parent's inode number
+            // instead of `.` and `..`, and can continue in an xattr.
+            self.parse_dir(&inode.block[4..], &mut out);
+            let xattrs = self.xattrs(inode)?;
+            if let Some((_, rest)) = xattrs.iter().find(|(name, _)| name == "system.data") {
+                self.parse_dir(rest, &mut out);
+            }
+        } else {
+            let data = self.inode_data(inode)?;
+            for block in data.chunks(self.superblock.block_size as usize) {
+                self.parse_dir(block, &mut out);
+            }
+        }
+        Ok(out)
+    }
+
+    fn parse_dir(&self, data: &[u8], out: &mut Vec<(OsString, u32)>) {
+        let filetype = self.superblock.incompat & INCOMPAT_FILETYPE != 0;
+        let mut offset = 0;
+        while offset + 8 <= data.len() {
+            let ino = le_u32(data, offset);
+            let rec_len = le_u16(data, offset + 4) as usize;
+            let name_len = match filetype {
+                true => data[offset + 6] as usize,
+                false => le_u16(data, offset + 6) as usize,
+            };
+            if rec_len < 8 || offset + 8 + name_len > data.len() {
+                break;
+            }
+            let name = &data[offset + 8..offset + 8 + name_len];
+            if ino != 0 && name != b"." && name != b".." {
+                out.push((OsString::from_vec(name.to_vec()), ino));
+            }
+            offset += rec_len;
+        }
+    }
+
+    fn walk(&self) -> Result<ImageTree<u32>> {
+        let root = self.inode(ROOT_INODE)?;
+        let mut tree = ImageTree::new(self.entry(&root)?);
+        let mut visited = HashSet::from([ROOT_INODE]);
+        let mut pending = vec![(PathBuf::from("/"), root)];
+        while let Some((dir, inode)) = pending.pop() {
+            for (name, ino) in self.read_dir(&inode)? {
+                if name.as_encoded_bytes().contains(&b'/') {
+                    return Err(invalid(&format!("invalid ext4 name {name:?}")));
+                }
+                let path = dir.join(&name);
+                trace!("found {}", path.display());
+                let inode = self.inode(ino)?;
+                match inode.mode & S_IFMT {
+                    S_IFREG | S_IFLNK => tree.insert(&path, self.entry(&inode)?),
+                    S_IFDIR => {
+                        if !visited.insert(ino) {
+                            return Err(invalid("ext4 directories loop"));
+                        }
+                        tree.insert(&path, self.entry(&inode)?);
+                        pending.push((path, inode));
+                    }
+                    mode => trace!("skipping {} with mode {:o}", path.display(), mode),
+                }
+            }
+        }
+        Ok(tree)
+    }
+
+    fn entry(&self, inode: &Inode) -> Result<ImageEntry<u32>> {
+        let base = ImageEntry {
+            mode: (inode.mode & 0o7777) as u32,
+            uid: inode.uid,
+            gid: inode.gid,
+            mtime: inode.mtime,
+            ino: inode.ino as u64,
+            ..ImageEntry::directory(0)
+        };
+        Ok(match inode.mode & S_IFMT {
+            S_IFREG => ImageEntry {
+                kind: ImageKind::File(inode.ino),
+                size: inode.size,
+                link_id: (inode.links > 1).then_some(inode.ino as u64),
+                ..base
+            },
+            S_IFLNK => {
+                let target = self.inode_data(inode)?;
+                ImageEntry {
+                    kind: ImageKind::Symlink(PathBuf::from(OsString::from_vec(target))),
+                    size: inode.size,
+                    ..base
+                }
+            }
+            _ => base,
+        })
+    }
+}
+
+fn le_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn le_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+fn invalid(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::import::FloppyDiskHardLinkExt;
+
+    async fn count_entries(disk: &Ext4FloppyDisk, path: &str) -> Result<usize> {
+        let mut count = 0;
+        let mut read_dir = disk.read_dir(path).await?;
+        while read_dir.next_entry().await?.is_some() {
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_ext4_works() -> Result<()> {
+        let disk = Ext4FloppyDisk::open("./fixtures/a.ext4").await?;
+        assert_eq!("flop", disk.volume_name());
+        assert_eq!(1024, disk.block_size());
+
+        assert_eq!("tiny\n", disk.read_to_string("/inline.txt").await?);
+        let big = disk.read("/big.bin").await?;
+        assert_eq!(40000, big.len());
+        assert!(big
+            .iter()
+            .enumerate()
+            .all(|(i, b)| *b == (i * 7 % 256) as u8));
+        let metadata = disk.metadata("/big.bin").await?;
+        assert_eq!(0o600, metadata.permissions().mode());
+        assert_eq!(1000, metadata.uid()?);
+        assert_eq!(100, metadata.gid()?);
+        assert_eq!(
+            std::time::UNIX_EPOCH + std::time::Duration::from_secs(1687000000),
+            metadata.modified()?
+        );
+
+        // Eight islands of data, so the extent tree is more than one level.
+        // mke2fs doesn't keep the trailing hole.
+        let sparse = disk.read("/sparse.bin").await?;
+        assert_eq!(7 * 8192 + 1024, sparse.len());
+        for (idx, chunk) in sparse.chunks(8192).enumerate() {
+            assert!(chunk[..1024].iter().all(|b| *b == idx as u8 + 1));
+            assert!(chunk[1024..].iter().all(|b| *b == 0));
+        }
+
+        assert_eq!(
+            "hello from a subdirectory\n",
+            disk.read_to_string("/dir/nested.txt").await?
+        );
+        assert!(disk.metadata("/empty_dir").await?.is_dir());
+        assert_eq!(60, count_entries(&disk, "/many").await?);
+        assert_eq!("41\n", disk.read_to_string("/many/file41.txt").await?);
+
+        assert_eq!(PathBuf::from("a.txt"), disk.read_link("/link").await?);
+        assert_eq!("asdf\n", disk.read_to_string("/link").await?);
+        // Long enough to not fit in the inode, so it's stored as inline
+        // data instead.
+        assert_eq!(
+            PathBuf::from(format!("dir/{}/../nested.txt", "x".repeat(80))),
+            disk.read_link("/long_link").await?
+        );
+
+        assert!(disk.hard_link_id("/a.txt").await?.is_some());
+        assert_eq!(
+            disk.hard_link_id("/a.txt").await?,
+            disk.hard_link_id("/hard.txt").await?
+        );
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_ext2_works() -> Result<()> {
+        let disk = Ext4FloppyDisk::open("./fixtures/a.ext2").await?;
+        assert_eq!("asdf\n", disk.read_to_string("/a.txt").await?);
+        // More than 12 blocks, so some are behind an indirect block.
+        let big = disk.read("/big.bin").await?;
+        assert!(big
+            .iter()
+            .enumerate()
+            .all(|(i, b)| *b == (i * 7 % 256) as u8));
+        let sparse = disk.read("/sparse.bin").await?;
+        assert_eq!(8 * 8192, sparse.len());
+        assert!(sparse[8192 * 7..8192 * 7 + 1024].iter().all(|b| *b == 8));
+        assert_eq!(
+            PathBuf::from(format!("dir/{}/../nested.txt", "x".repeat(80))),
+            disk.read_link("/long_link").await?
+        );
+        assert_eq!(60, count_entries(&disk, "/many").await?);
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_corrupt_sizes_are_errors() -> Result<()> {
+        let image = crate::util::tests::TempFile::new("./fixtures/a.ext4").await?;
+        let disk = Ext4FloppyDisk::open(image.path_view()).await?;
+        let (_, entry) = disk.entry("/big.bin", false)?;
+        let index = entry.ino as u32 - 1;
+        let superblock = &disk.reader.superblock;
+        let offset = disk.reader.inode_tables[(index / superblock.inodes_per_group) as usize]
+            + (index % superblock.inodes_per_group) as u64 * superblock.inode_size as u64;
+
+        // Files are read again when they're read, so this is what's seen.
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(image.path_view())?;
+        file.write_all_at(&u32::MAX.to_le_bytes(), offset + 0x4)?;
+        file.write_all_at(&u32::MAX.to_le_bytes(), offset + 0x6c)?;
+        let err = disk.read("/big.bin").await.unwrap_err();
+        assert_eq!(std::io::ErrorKind::InvalidData, err.kind());
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_xattrs_work() -> Result<()> {
+        let disk = Ext4FloppyDisk::open("./fixtures/a.ext4").await?;
+        assert_eq!(
+            vec![("user.comment".to_string(), b"hello".to_vec())],
+            disk.xattrs("/a.txt")?
+        );
+        assert_eq!(
+            vec![(
+                "security.selinux".to_string(),
+                b"system_u:object_r:etc_t:s0".to_vec()
+            )],
+            disk.xattrs("/dir")?
+        );
+        // Too big for the inode, so it's in an xattr block.
+        assert_eq!(
+            vec![("trusted.big".to_string(), vec![b'v'; 300])],
+            disk.xattrs("/big.bin")?
+        );
+        assert!(disk.xattrs("/inline.txt")?.is_empty());
+
+        Ok(())
+    }
+}
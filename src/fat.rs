@@ -0,0 +1,1369 @@
+use std::collections::HashSet;
+use std::os::unix::fs::FileExt;
+
+use smoosh::CompressionType;
+use tracing::debug;
+
+crate::util::archive_format!(Fat, "a.img", fat_open, fat_close);
+
+/// The size of a 3.5" high density floppy, which is what new images are
+/// formatted as unless told otherwise.
+const DEFAULT_SIZE: u64 = 1_474_560;
+const DIR_ENTRY_SIZE: usize = 32;
+
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_HIDDEN: u8 = 0x02;
+const ATTR_SYSTEM: u8 = 0x04;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0f;
+
+/// Set in the `NTRes` byte when the base name or extension of a short name
+/// is all lowercase.
+const LOWERCASE_BASE: u8 = 0x08;
+const LOWERCASE_EXT: u8 = 0x10;
+
+#[derive(Debug)]
+pub(crate) struct FatState {
+    geometry: Geometry,
+    /// The boot sector of the image that was opened, so that boot code
+    /// survives a round trip.
+    boot_sector: Option<Vec<u8>>,
+    label: std::sync::Mutex<Option<String>>,
+    /// Hidden, system and archive attributes. The read-only attribute is
+    /// stored in the permissions instead.
+    attributes: std::sync::Mutex<HashMap<PathBuf, u8>>,
+}
+
+impl crate::util::LazyEntries for FatState {}
+
+impl Default for FatState {
+    fn default() -> Self {
+        Self {
+            geometry: Geometry::for_size(DEFAULT_SIZE).unwrap(),
+            boot_sector: None,
+            label: Default::default(),
+            attributes: Default::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatType {
+    fn from_clusters(clusters: u32) -> FatType {
+        match clusters {
+            0..=4084 => FatType::Fat12,
+            4085..=65524 => FatType::Fat16,
+            _ => FatType::Fat32,
+        }
+    }
+
+    fn end_of_chain(&self) -> u32 {
+        match self {
+            FatType::Fat12 => 0xfff,
+            FatType::Fat16 => 0xffff,
+            FatType::Fat32 => 0x0fffffff,
+        }
+    }
+
+    fn is_end_of_chain(&self, entry: u32) -> bool {
+        entry >= self.end_of_chain() - 7
+    }
+
+    /// How many bits each entry in the FAT takes.
+    fn bits(&self) -> u64 {
+        match self {
+            FatType::Fat12 => 12,
+            FatType::Fat16 => 16,
+            FatType::Fat32 => 32,
+        }
+    }
+}
+
+/// The DOS attributes that don't have a unix equivalent. Read-only files
+/// are the ones without any write permissions.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FatAttributes {
+    pub hidden: bool,
+    pub system: bool,
+    pub archive: bool,
+}
+
+impl FatAttributes {
+    fn from_byte(attributes: u8) -> Self {
+        Self {
+            hidden: attributes & ATTR_HIDDEN != 0,
+            system: attributes & ATTR_SYSTEM != 0,
+            archive: attributes & ATTR_ARCHIVE != 0,
+        }
+    }
+
+    fn to_byte(self) -> u8 {
+        let mut out = 0;
+        if self.hidden {
+            out |= ATTR_HIDDEN;
+        }
+        if self.system {
+            out |= ATTR_SYSTEM;
+        }
+        if self.archive {
+            out |= ATTR_ARCHIVE;
+        }
+        out
+    }
+}
+
+impl FatFloppyDisk {
+    /// Creates an empty disk that is formatted as a fresh image of `size`
+    /// bytes on close, replacing anything already at `path`. The FAT type
+    /// and cluster size are picked based on the size.
+    pub async fn create<P: AsRef<Path>>(path: P, size: u64) -> Result<FatFloppyDisk> {
+        let path = path.as_ref();
+        debug!("creating {} byte fat image at {}", size, path.display());
+        let state = FatState {
+            geometry: Geometry::for_size(size)?,
+            ..Default::default()
+        };
+        Ok(Self::from_metadata(
+            path.to_path_buf(),
+            empty_metadata(state),
+        ))
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.state.geometry.fat_type()
+    }
+
+    /// The size of the image in bytes.
+    pub fn size(&self) -> u64 {
+        self.state.geometry.total_sectors as u64 * self.state.geometry.bytes_per_sector as u64
+    }
+
+    pub fn volume_label(&self) -> Option<String> {
+        self.state.label.lock().unwrap().clone()
+    }
+
+    /// Labels are uppercased and cut down to 11 characters on close.
+    pub fn set_volume_label<S: Into<String>>(&self, label: Option<S>) {
+        *self.state.label.lock().unwrap() = label.map(|label| label.into());
+    }
+
+    pub fn attributes<P: AsRef<Path>>(&self, path: P) -> FatAttributes {
+        let path = crate::util::normalize_path(path);
+        let attributes = self.state.attributes.lock().unwrap();
+        FatAttributes::from_byte(attributes.get(&path).copied().unwrap_or(0))
+    }
+
+    pub fn set_attributes<P: AsRef<Path>>(&self, path: P, attributes: FatAttributes) {
+        let path = crate::util::normalize_path(path);
+        self.state
+            .attributes
+            .lock()
+            .unwrap()
+            .insert(path, attributes.to_byte());
+    }
+}
+
+fn empty_metadata(state: FatState) -> FatInternalMetadata {
+    FatInternalMetadata {
+        delegate: MemFloppyDisk::new(),
+        compression: CompressionType::None,
+        ordered_paths: IndexSet::new(),
+        times: HashMap::new(),
+        hard_links: IndexMap::new(),
+        state,
+    }
+}
+
+async fn fat_open<P: Into<PathBuf>>(path: P) -> Result<FatInternalMetadata> {
+    let path = path.into();
+    if !crate::util::exists_async(&path).await {
+        debug!("creating empty fat image!");
+        return Ok(empty_metadata(FatState::default()));
+    }
+
+    debug!("opening fat image {}", path.display());
+    let file = std::fs::File::open(&path)?;
+    let mut boot_sector = vec![0; 512];
+    file.read_exact_at(&mut boot_sector, 0)?;
+    let geometry = Geometry::parse(&boot_sector)?;
+    boot_sector.resize(geometry.bytes_per_sector as usize, 0);
+    file.read_exact_at(&mut boot_sector, 0)?;
+    debug!("found {:?} image", geometry.fat_type());
+
+    if geometry.fat_offset(1) > file.metadata()?.len() {
+        return Err(invalid("fat image is truncated"));
+    }
+    let mut fat = vec![0; geometry.fat_size() as usize];
+    file.read_exact_at(&mut fat, geometry.fat_offset(0))?;
+    let reader = Reader {
+        file,
+        geometry,
+        fat,
+    };
+
+    let out = MemFloppyDisk::new();
+    let mut ordered_paths = IndexSet::new();
+    let mut times = HashMap::new();
+    let mut attributes = HashMap::new();
+    let mut label = None;
+
+    let root = match geometry.fat_type() {
+        FatType::Fat32 => reader.read_chain(geometry.root_cluster)?,
+        _ => {
+            let mut root =
+                vec![0; geometry.root_dir_sectors() as usize * geometry.bytes_per_sector as usize];
+            reader
+                .file
+                .read_exact_at(&mut root, geometry.root_dir_offset())?;
+            root
+        }
+    };
+    let mut pending = vec![(PathBuf::from("/"), root)];
+    while let Some((dir, data)) = pending.pop() {
+        for entry in parse_dir(&data) {
+            if entry.attributes & ATTR_VOLUME_ID != 0 {
+                if dir == Path::new("/") {
+                    label = Some(entry.name.clone());
+                }
+                continue;
+            }
+            let path = dir.join(&entry.name);
+            trace!("found {}", path.display());
+            ordered_paths.insert(path.clone());
+            times.insert(path.clone(), entry.modified);
+            let extra = entry.attributes & (ATTR_HIDDEN | ATTR_SYSTEM | ATTR_ARCHIVE);
+            if extra != 0 {
+                attributes.insert(path.clone(), extra);
+            }
+
+            let mode = if entry.attributes & ATTR_DIRECTORY != 0 {
+                out.create_dir(&path).await?;
+                let data = reader.read_chain(entry.cluster)?;
+                pending.push((path.clone(), data));
+                0o755
+            } else {
+                let mut data = match entry.cluster {
+                    0 => vec![],
+                    cluster => reader.read_chain(cluster)?,
+                };
+                if data.len() < entry.size as usize {
+                    return Err(invalid(&format!("{} is truncated", path.display())));
+                }
+                data.truncate(entry.size as usize);
+                out.write(&path, data).await?;
+                0o644
+            };
+            let mode = match entry.attributes & ATTR_READ_ONLY {
+                0 => mode,
+                _ => mode & !0o222,
+            };
+            out.set_permissions(&path, MemPermissions::from_mode(mode))
+                .await?;
+            out.chown(&path, 0, 0).await?;
+        }
+    }
+
+    if label.is_none() {
+        label = geometry.boot_label(&boot_sector);
+    }
+    debug!("finished opening fat image!");
+
+    Ok(FatInternalMetadata {
+        delegate: out,
+        compression: CompressionType::None,
+        ordered_paths,
+        times,
+        hard_links: IndexMap::new(),
+        state: FatState {
+            geometry,
+            boot_sector: Some(boot_sector),
+            label: std::sync::Mutex::new(label),
+            attributes: std::sync::Mutex::new(attributes),
+        },
+    })
+}
+
+async fn fat_close(fat: &FatFloppyDisk) -> Result<()> {
+    let disk = &fat.delegate;
+    let geometry = &fat.state.geometry;
+    let fat_type = geometry.fat_type();
+    // Renames can leave children before their parents, so put every
+    // directory before its contents.
+    let mut ordered_paths: Vec<PathBuf> = fat.ordered_paths.lock().await.iter().cloned().collect();
+    ordered_paths.sort_by_key(|path| path.components().count());
+    debug!("closing {:?} image at {}", fat_type, fat.path.display());
+
+    // Work out what goes in each directory.
+    let mut nodes = vec![Node::directory(PathBuf::from("/"))];
+    let mut index = HashMap::from([(PathBuf::from("/"), 0)]);
+    for path in ordered_paths
+        .iter()
+        .filter(|path| path.as_path() != Path::new("/"))
+    {
+        let metadata = disk.symlink_metadata(path).await?;
+        if metadata.is_symlink() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!("fat images can't store symlinks: {}", path.display()),
+            ));
+        }
+        let parent = path.parent().unwrap_or(Path::new("/"));
+        let Some(&parent) = index.get(parent) else {
+            return Err(invalid(&format!("{} has no parent", path.display())));
+        };
+        let node = match metadata.is_dir() {
+            true => Node::directory(path.clone()),
+            false => Node {
+                path: path.clone(),
+                is_dir: false,
+                size: metadata.len(),
+                children: vec![],
+                entries: vec![],
+                cluster: 0,
+            },
+        };
+        let idx = nodes.len();
+        index.insert(path.clone(), idx);
+        nodes[parent].children.push(idx);
+        nodes.push(node);
+    }
+
+    // Directory entries, which also decides how big each directory is.
+    let label = fat.volume_label().map(|label| short_label(&label));
+    for idx in 0..nodes.len() {
+        if !nodes[idx].is_dir {
+            continue;
+        }
+        let mut used = HashSet::new();
+        let mut names = vec![];
+        for &child in &nodes[idx].children {
+            let name = nodes[child]
+                .path
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .to_string();
+            names.push((child, ShortName::for_name(&name, &mut used)?, name));
+        }
+        let mut entries = 0;
+        if idx == 0 && label.is_some() {
+            entries += 1;
+        }
+        if idx != 0 {
+            entries += 2;
+        }
+        for (_, short, name) in &names {
+            entries += 1 + if short.needs_long_name {
+                lfn_count(name)
+            } else {
+                0
+            };
+        }
+        let node = &mut nodes[idx];
+        node.size = (entries * DIR_ENTRY_SIZE) as u64;
+        node.entries = names
+            .into_iter()
+            .map(|(child, short, _)| (child, short))
+            .collect();
+    }
+
+    // Hand out clusters in order: the root directory (on FAT32), then every
+    // other directory, then file contents.
+    let cluster_size = geometry.cluster_size();
+    let mut table = vec![0u32; geometry.clusters() as usize + 2];
+    table[0] = (fat_type.end_of_chain() & !0xff) | geometry.media as u32;
+    table[1] = fat_type.end_of_chain();
+    let mut next = 2u32;
+    let mut allocate = |bytes: u64, min: u64| -> Result<u32> {
+        let count = bytes.div_ceil(cluster_size).max(min) as u32;
+        if count == 0 {
+            return Ok(0);
+        }
+        if (next + count) as usize > table.len() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::StorageFull,
+                "fat image is full",
+            ));
+        }
+        let first = next;
+        for cluster in first..first + count - 1 {
+            table[cluster as usize] = cluster + 1;
+        }
+        table[(first + count - 1) as usize] = fat_type.end_of_chain();
+        next += count;
+        Ok(first)
+    };
+
+    if fat_type == FatType::Fat32 {
+        nodes[0].cluster = allocate(nodes[0].size, 1)?;
+    } else if nodes[0].size > geometry.root_entries as u64 * DIR_ENTRY_SIZE as u64 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::StorageFull,
+            format!(
+                "fat root directory only has room for {} entries",
+                geometry.root_entries
+            ),
+        ));
+    }
+    for node in nodes.iter_mut().skip(1).filter(|node| node.is_dir) {
+        node.cluster = allocate(node.size, 1)?;
+    }
+    for node in nodes.iter_mut().filter(|node| !node.is_dir) {
+        node.cluster = allocate(node.size, 0)?;
+    }
+    let used = next - 2;
+    debug!("allocated {} of {} clusters", used, geometry.clusters());
+
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&fat.path)?;
+    file.set_len(fat.size())?;
+
+    // Boot sector, and on FAT32 the FSInfo sector and their backups.
+    let root_cluster = nodes[0].cluster;
+    let boot_sector = geometry.boot_sector(fat.state.boot_sector.as_deref(), label, root_cluster);
+    file.write_all_at(&boot_sector, 0)?;
+    if fat_type == FatType::Fat32 {
+        let fs_info = fs_info(geometry, geometry.clusters() - used, next);
+        let bps = geometry.bytes_per_sector as u64;
+        file.write_all_at(&fs_info, geometry.fs_info_sector as u64 * bps)?;
+        if geometry.backup_boot_sector != 0 {
+            let backup = geometry.backup_boot_sector as u64 * bps;
+            file.write_all_at(&boot_sector, backup)?;
+            file.write_all_at(&fs_info, backup + geometry.fs_info_sector as u64 * bps)?;
+        }
+    }
+
+    let table = encode_fat(fat_type, &table, geometry.fat_size());
+    for idx in 0..geometry.fats {
+        file.write_all_at(&table, geometry.fat_offset(idx))?;
+    }
+
+    for idx in 0..nodes.len() {
+        let node = &nodes[idx];
+        let offset = match (idx, fat_type) {
+            (0, FatType::Fat12 | FatType::Fat16) => geometry.root_dir_offset(),
+            _ if node.cluster == 0 => continue,
+            _ => geometry.cluster_offset(node.cluster),
+        };
+
+        if !node.is_dir {
+            trace!("writing {}", node.path.display());
+            file.write_all_at(&disk.read(&node.path).await?, offset)?;
+            continue;
+        }
+
+        let mut data = vec![];
+        let modified = fat.modified(&node.path).await?;
+        if idx == 0 {
+            if let Some(label) = label {
+                data.extend(raw_entry(&label, ATTR_VOLUME_ID, 0, 0, 0, modified));
+            }
+        } else {
+            let parent = node.path.parent().and_then(|parent| index.get(parent));
+            let parent_cluster = match parent {
+                Some(0) | None => 0,
+                Some(&parent) => nodes[parent].cluster,
+            };
+            data.extend(raw_entry(
+                b".          ",
+                ATTR_DIRECTORY,
+                0,
+                node.cluster,
+                0,
+                modified,
+            ));
+            data.extend(raw_entry(
+                b"..         ",
+                ATTR_DIRECTORY,
+                0,
+                parent_cluster,
+                0,
+                modified,
+            ));
+        }
+        for (child, short) in &node.entries {
+            let child = &nodes[*child];
+            let name = child.path.file_name().unwrap().to_string_lossy();
+            if short.needs_long_name {
+                data.extend(lfn_entries(&name, &short.name));
+            }
+            let metadata = disk.metadata(&child.path).await?;
+            let mut attributes = fat.attributes(&child.path).to_byte();
+            if child.is_dir {
+                attributes |= ATTR_DIRECTORY;
+            }
+            if metadata.permissions().mode() & 0o222 == 0 {
+                attributes |= ATTR_READ_ONLY;
+            }
+            data.extend(raw_entry(
+                &short.name,
+                attributes,
+                short.case,
+                child.cluster,
+                if child.is_dir { 0 } else { child.size as u32 },
+                fat.modified(&child.path).await?,
+            ));
+        }
+        file.write_all_at(&data, offset)?;
+    }
+
+    file.sync_all()?;
+    debug!("finished closing fat image!");
+    Ok(())
+}
+
+#[derive(Debug)]
+struct Node {
+    path: PathBuf,
+    is_dir: bool,
+    size: u64,
+    children: Vec<usize>,
+    entries: Vec<(usize, ShortName)>,
+    cluster: u32,
+}
+
+impl Node {
+    fn directory(path: PathBuf) -> Self {
+        Self {
+            path,
+            is_dir: true,
+            size: 0,
+            children: vec![],
+            entries: vec![],
+            cluster: 0,
+        }
+    }
+}
+
+/// The BIOS parameter block, ie. how the image is laid out.
+#[derive(Debug, Clone, Copy)]
+struct Geometry {
+    bytes_per_sector: u32,
+    sectors_per_cluster: u32,
+    reserved_sectors: u32,
+    fats: u32,
+    root_entries: u32,
+    total_sectors: u32,
+    fat_sectors: u32,
+    media: u8,
+    sectors_per_track: u16,
+    heads: u16,
+    root_cluster: u32,
+    fs_info_sector: u32,
+    backup_boot_sector: u32,
+}
+
+impl Geometry {
+    fn parse(boot: &[u8]) -> Result<Geometry> {
+        let u16_at = |offset: usize| u16::from_le_bytes([boot[offset], boot[offset + 1]]) as u32;
+        let u32_at = |offset: usize| {
+            u32::from_le_bytes([
+                boot[offset],
+                boot[offset + 1],
+                boot[offset + 2],
+                boot[offset + 3],
+            ])
+        };
+        if boot[510..512] != [0x55, 0xaa] {
+            return Err(invalid("fat boot sector has no signature"));
+        }
+        let fat_sectors = match u16_at(22) {
+            0 => u32_at(36),
+            sectors => sectors,
+        };
+        let total_sectors = match u16_at(19) {
+            0 => u32_at(32),
+            sectors => sectors,
+        };
+        let geometry = Self {
+            bytes_per_sector: u16_at(11),
+            sectors_per_cluster: boot[13] as u32,
+            reserved_sectors: u16_at(14),
+            fats: boot[16] as u32,
+            root_entries: u16_at(17),
+            total_sectors,
+            fat_sectors,
+            media: boot[21],
+            sectors_per_track: u16_at(24) as u16,
+            heads: u16_at(26) as u16,
+            root_cluster: u32_at(44),
+            fs_info_sector: u16_at(48),
+            backup_boot_sector: u16_at(50),
+        };
+        // The sizes come straight from the image, so check that they add up
+        // before anything else does any arithmetic with them.
+        let fat_size = geometry.fat_sectors.checked_mul(geometry.bytes_per_sector);
+        let first_data_sector = geometry
+            .fats
+            .checked_mul(geometry.fat_sectors)
+            .and_then(|fats| fats.checked_add(geometry.reserved_sectors))
+            .and_then(|sectors| sectors.checked_add(geometry.root_dir_sectors()));
+        let valid = [512, 1024, 2048, 4096].contains(&geometry.bytes_per_sector)
+            && geometry.sectors_per_cluster.is_power_of_two()
+            && geometry.fats > 0
+            && geometry.fat_sectors > 0
+            && fat_size.is_some()
+            && first_data_sector.is_some_and(|sector| sector < geometry.total_sectors);
+        if !valid {
+            return Err(invalid("invalid fat boot sector"));
+        }
+        if geometry.fat_bytes_needed(geometry.fat_type()) > geometry.fat_size() as u64 {
+            return Err(invalid("fat is too small for the clusters in the image"));
+        }
+        Ok(geometry)
+    }
+
+    /// Lays out a fresh image of `size` bytes, the same way that most
+    /// formatting tools do.
+    fn for_size(size: u64) -> Result<Geometry> {
+        if !size.is_multiple_of(512) || size / 512 > u32::MAX as u64 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("can't format a {size} byte fat image"),
+            ));
+        }
+        let total_sectors = (size / 512) as u32;
+
+        // (sectors, sectors per cluster, root entries, media, sectors per
+        // track, heads) for the standard floppy formats.
+        let floppies = [
+            (320, 1, 64, 0xfe, 8, 1),
+            (360, 1, 64, 0xfc, 9, 1),
+            (640, 2, 112, 0xff, 8, 2),
+            (720, 2, 112, 0xfd, 9, 2),
+            (1440, 2, 112, 0xf9, 9, 2),
+            (2400, 1, 224, 0xf9, 15, 2),
+            (2880, 1, 224, 0xf0, 18, 2),
+            (5760, 2, 240, 0xf0, 36, 2),
+        ];
+        let (fat_type, sectors_per_cluster, root_entries, media, sectors_per_track, heads) =
+            match floppies.iter().find(|floppy| floppy.0 == total_sectors) {
+                Some(&(_, spc, root, media, spt, heads)) => {
+                    (FatType::Fat12, spc, root, media, spt, heads)
+                }
+                None if total_sectors < 8400 => {
+                    let mut spc = 1;
+                    while total_sectors / spc > 4084 {
+                        spc *= 2;
+                    }
+                    (FatType::Fat12, spc, 512, 0xf8, 32, 64)
+                }
+                None if total_sectors < 1_048_576 => {
+                    let spc = match total_sectors {
+                        ..=32_680 => 2,
+                        32_681..=262_144 => 4,
+                        262_145..=524_288 => 8,
+                        _ => 16,
+                    };
+                    (FatType::Fat16, spc, 512, 0xf8, 32, 64)
+                }
+                None => {
+                    let spc = match total_sectors {
+                        ..=16_777_216 => 8,
+                        16_777_217..=33_554_432 => 16,
+                        33_554_433..=67_108_864 => 32,
+                        _ => 64,
+                    };
+                    (FatType::Fat32, spc, 0, 0xf8, 32, 64)
+                }
+            };
+
+        let mut geometry = Self {
+            bytes_per_sector: 512,
+            sectors_per_cluster,
+            reserved_sectors: if fat_type == FatType::Fat32 { 32 } else { 1 },
+            fats: 2,
+            root_entries,
+            total_sectors,
+            fat_sectors: 1,
+            media,
+            sectors_per_track,
+            heads,
+            root_cluster: if fat_type == FatType::Fat32 { 2 } else { 0 },
+            fs_info_sector: if fat_type == FatType::Fat32 { 1 } else { 0 },
+            backup_boot_sector: if fat_type == FatType::Fat32 { 6 } else { 0 },
+        };
+        // The FATs take space away from the clusters that they describe, so
+        // grow them until they're big enough.
+        loop {
+            if geometry.first_data_sector() >= total_sectors {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("{size} bytes is too small for a fat image"),
+                ));
+            }
+            let needed = geometry.fat_bytes_needed(fat_type).div_ceil(512) as u32;
+            if needed <= geometry.fat_sectors {
+                break;
+            }
+            geometry.fat_sectors = needed;
+        }
+        if geometry.fat_type() != fat_type {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("can't format a {size} byte fat image"),
+            ));
+        }
+        Ok(geometry)
+    }
+
+    fn fat_type(&self) -> FatType {
+        if self.root_entries == 0 && self.root_cluster != 0 {
+            return FatType::Fat32;
+        }
+        FatType::from_clusters(self.clusters())
+    }
+
+    fn root_dir_sectors(&self) -> u32 {
+        (self.root_entries * DIR_ENTRY_SIZE as u32).div_ceil(self.bytes_per_sector)
+    }
+
+    fn first_data_sector(&self) -> u32 {
+        self.reserved_sectors + self.fats * self.fat_sectors + self.root_dir_sectors()
+    }
+
+    fn clusters(&self) -> u32 {
+        (self.total_sectors - self.first_data_sector()) / self.sectors_per_cluster
+    }
+
+    /// The size of one copy of the FAT in bytes.
+    fn fat_size(&self) -> u32 {
+        self.fat_sectors * self.bytes_per_sector
+    }
+
+    /// How many bytes the FAT needs to describe every cluster, plus the two
+    /// reserved entries at the start.
+    fn fat_bytes_needed(&self, fat_type: FatType) -> u64 {
+        ((self.clusters() as u64 + 2) * fat_type.bits()).div_ceil(8)
+    }
+
+    fn cluster_size(&self) -> u64 {
+        (self.bytes_per_sector * self.sectors_per_cluster) as u64
+    }
+
+    fn fat_offset(&self, idx: u32) -> u64 {
+        (self.reserved_sectors + idx * self.fat_sectors) as u64 * self.bytes_per_sector as u64
+    }
+
+    fn root_dir_offset(&self) -> u64 {
+        self.fat_offset(self.fats)
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.first_data_sector() as u64 * self.bytes_per_sector as u64
+            + (cluster as u64 - 2) * self.cluster_size()
+    }
+
+    /// Where the extended boot record is. It's after the FAT32-only fields.
+    fn ebr_offset(&self) -> usize {
+        match self.fat_type() {
+            FatType::Fat32 => 64,
+            _ => 36,
+        }
+    }
+
+    fn boot_label(&self, boot: &[u8]) -> Option<String> {
+        let ebr = self.ebr_offset();
+        if boot[ebr + 2] != 0x29 {
+            return None;
+        }
+        let label = String::from_utf8_lossy(&boot[ebr + 7..ebr + 18])
+            .trim_end()
+            .to_string();
+        match label.as_str() {
+            "" | "NO NAME" => None,
+            _ => Some(label),
+        }
+    }
+
+    fn boot_sector(
+        &self,
+        template: Option<&[u8]>,
+        label: Option<[u8; 11]>,
+        root_cluster: u32,
+    ) -> Vec<u8> {
+        let fat_type = self.fat_type();
+        let ebr = self.ebr_offset();
+        let mut boot = match template {
+            Some(template) => template.to_vec(),
+            None => {
+                let mut boot = vec![0; self.bytes_per_sector as usize];
+                // A jump over the BPB, then an infinite loop where the boot
+                // code would go.
+                let jump = match fat_type {
+                    FatType::Fat32 => 0x58,
+                    _ => 0x3c,
+                };
+                boot[..3].copy_from_slice(&[0xeb, jump, 0x90]);
+                boot[3..11].copy_from_slice(b"FLOP    ");
+                boot[jump as usize + 2..jump as usize + 4].copy_from_slice(&[0xeb, 0xfe]);
+                boot[11..13].copy_from_slice(&(self.bytes_per_sector as u16).to_le_bytes());
+                boot[13] = self.sectors_per_cluster as u8;
+                boot[14..16].copy_from_slice(&(self.reserved_sectors as u16).to_le_bytes());
+                boot[16] = self.fats as u8;
+                boot[17..19].copy_from_slice(&(self.root_entries as u16).to_le_bytes());
+                if self.total_sectors < 0x10000 {
+                    boot[19..21].copy_from_slice(&(self.total_sectors as u16).to_le_bytes());
+                } else {
+                    boot[32..36].copy_from_slice(&self.total_sectors.to_le_bytes());
+                }
+                boot[21] = self.media;
+                if fat_type == FatType::Fat32 {
+                    boot[36..40].copy_from_slice(&self.fat_sectors.to_le_bytes());
+                    boot[48..50].copy_from_slice(&(self.fs_info_sector as u16).to_le_bytes());
+                    boot[50..52].copy_from_slice(&(self.backup_boot_sector as u16).to_le_bytes());
+                } else {
+                    boot[22..24].copy_from_slice(&(self.fat_sectors as u16).to_le_bytes());
+                }
+                boot[24..26].copy_from_slice(&self.sectors_per_track.to_le_bytes());
+                boot[26..28].copy_from_slice(&self.heads.to_le_bytes());
+                boot[ebr] = if self.media == 0xf8 { 0x80 } else { 0 };
+                boot[ebr + 2] = 0x29;
+                let id = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map(|duration| duration.as_secs() as u32)
+                    .unwrap_or_default();
+                boot[ebr + 3..ebr + 7].copy_from_slice(&id.to_le_bytes());
+                let name: &[u8; 8] = match fat_type {
+                    FatType::Fat12 => b"FAT12   ",
+                    FatType::Fat16 => b"FAT16   ",
+                    FatType::Fat32 => b"FAT32   ",
+                };
+                boot[ebr + 18..ebr + 26].copy_from_slice(name);
+                boot[510..512].copy_from_slice(&[0x55, 0xaa]);
+                boot
+            }
+        };
+        if fat_type == FatType::Fat32 {
+            boot[44..48].copy_from_slice(&root_cluster.to_le_bytes());
+        }
+        if boot[ebr + 2] == 0x29 {
+            boot[ebr + 7..ebr + 18].copy_from_slice(&label.unwrap_or(*b"NO NAME    "));
+        }
+        boot
+    }
+}
+
+fn fs_info(geometry: &Geometry, free: u32, next: u32) -> Vec<u8> {
+    let mut sector = vec![0; geometry.bytes_per_sector as usize];
+    sector[0..4].copy_from_slice(&0x41615252u32.to_le_bytes());
+    sector[484..488].copy_from_slice(&0x61417272u32.to_le_bytes());
+    sector[488..492].copy_from_slice(&free.to_le_bytes());
+    sector[492..496].copy_from_slice(&next.to_le_bytes());
+    sector[508..512].copy_from_slice(&0xaa550000u32.to_le_bytes());
+    sector
+}
+
+fn encode_fat(fat_type: FatType, table: &[u32], size: u32) -> Vec<u8> {
+    let mut out = vec![0u8; size as usize];
+    for (cluster, &entry) in table.iter().enumerate() {
+        match fat_type {
+            FatType::Fat12 => {
+                let offset = cluster + cluster / 2;
+                let current = u16::from_le_bytes([out[offset], out[offset + 1]]);
+                let value = if cluster % 2 == 1 {
+                    (current & 0x000f) | ((entry as u16) << 4)
+                } else {
+                    (current & 0xf000) | (entry as u16 & 0x0fff)
+                };
+                out[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+            }
+            FatType::Fat16 => {
+                out[cluster * 2..cluster * 2 + 2].copy_from_slice(&(entry as u16).to_le_bytes())
+            }
+            FatType::Fat32 => {
+                out[cluster * 4..cluster * 4 + 4].copy_from_slice(&entry.to_le_bytes())
+            }
+        }
+    }
+    out
+}
+
+struct Reader {
+    file: std::fs::File,
+    geometry: Geometry,
+    fat: Vec<u8>,
+}
+
+impl Reader {
+    fn next_cluster(&self, cluster: u32) -> Result<u32> {
+        let cluster = cluster as usize;
+        let entry = |offset: usize, len: usize| {
+            self.fat
+                .get(offset..offset + len)
+                .ok_or_else(|| invalid("fat cluster is past the end of the fat"))
+        };
+        Ok(match self.geometry.fat_type() {
+            FatType::Fat12 => {
+                let value = entry(cluster + cluster / 2, 2)?;
+                let value = u16::from_le_bytes([value[0], value[1]]);
+                if cluster % 2 == 1 {
+                    (value >> 4) as u32
+                } else {
+                    (value & 0x0fff) as u32
+                }
+            }
+            FatType::Fat16 => {
+                let value = entry(cluster * 2, 2)?;
+                u16::from_le_bytes([value[0], value[1]]) as u32
+            }
+            FatType::Fat32 => {
+                let value = entry(cluster * 4, 4)?;
+                u32::from_le_bytes([value[0], value[1], value[2], value[3]]) & 0x0fffffff
+            }
+        })
+    }
+
+    /// Reads every cluster in the chain starting at `cluster`.
+    fn read_chain(&self, mut cluster: u32) -> Result<Vec<u8>> {
+        let fat_type = self.geometry.fat_type();
+        let max = self.geometry.clusters() + 2;
+        let mut out = vec![];
+        let mut seen = 0;
+        while cluster >= 2 && cluster < max {
+            seen += 1;
+            if seen > max {
+                return Err(invalid("fat cluster chain loops"));
+            }
+            let start = out.len();
+            out.resize(start + self.geometry.cluster_size() as usize, 0);
+            self.file
+                .read_exact_at(&mut out[start..], self.geometry.cluster_offset(cluster))?;
+            let next = self.next_cluster(cluster)?;
+            if fat_type.is_end_of_chain(next) {
+                break;
+            }
+            cluster = next;
+        }
+        Ok(out)
+    }
+}
+
+#[derive(Debug)]
+struct DirEntry {
+    name: String,
+    attributes: u8,
+    cluster: u32,
+    size: u32,
+    modified: SystemTime,
+}
+
+/// Parses the entries in a directory, skipping `.`, `..` and deleted
+/// entries, and joining long names with their short name entries.
+fn parse_dir(data: &[u8]) -> Vec<DirEntry> {
+    let mut out = vec![];
+    let mut long_name: Vec<(u8, [u16; 13])> = vec![];
+    let mut long_checksum = 0;
+    for raw in data.chunks_exact(DIR_ENTRY_SIZE) {
+        match raw[0] {
+            0x00 => break,
+            0xe5 => {
+                long_name.clear();
+                continue;
+            }
+            _ => {}
+        }
+
+        let attributes = raw[11];
+        if attributes & 0x3f == ATTR_LONG_NAME {
+            if raw[0] & 0x40 != 0 {
+                long_name.clear();
+            }
+            let mut chars = [0u16; 13];
+            let units = raw[1..11]
+                .chunks(2)
+                .chain(raw[14..26].chunks(2))
+                .chain(raw[28..32].chunks(2));
+            for (idx, unit) in units.enumerate() {
+                chars[idx] = u16::from_le_bytes([unit[0], unit[1]]);
+            }
+            long_name.push((raw[0] & 0x1f, chars));
+            long_checksum = raw[13];
+            continue;
+        }
+
+        let mut short = [0u8; 11];
+        short.copy_from_slice(&raw[..11]);
+        if short[0] == 0x05 {
+            short[0] = 0xe5;
+        }
+        let long = if !long_name.is_empty() && checksum(&short) == long_checksum {
+            long_name.sort_by_key(|(ord, _)| *ord);
+            let units: Vec<u16> = long_name
+                .iter()
+                .flat_map(|(_, chars)| chars.iter().copied())
+                .take_while(|&unit| unit != 0)
+                .collect();
+            Some(String::from_utf16_lossy(&units))
+        } else {
+            None
+        };
+        long_name.clear();
+
+        if &short == b".          " || &short == b"..         " {
+            continue;
+        }
+        let name = match long {
+            Some(long) => long,
+            None if attributes & ATTR_VOLUME_ID != 0 => {
+                String::from_utf8_lossy(&short).trim_end().to_string()
+            }
+            None => short_to_string(&short, raw[12]),
+        };
+        let u16_at = |offset: usize| u16::from_le_bytes([raw[offset], raw[offset + 1]]);
+        out.push(DirEntry {
+            name,
+            attributes,
+            cluster: ((u16_at(20) as u32) << 16) | u16_at(26) as u32,
+            size: u32::from_le_bytes([raw[28], raw[29], raw[30], raw[31]]),
+            modified: dos_to_system_time(u16_at(24), u16_at(22)),
+        });
+    }
+    out
+}
+
+fn short_to_string(short: &[u8; 11], case: u8) -> String {
+    let part = |bytes: &[u8], lower: bool| {
+        let part = String::from_utf8_lossy(bytes).trim_end().to_string();
+        if lower {
+            part.to_lowercase()
+        } else {
+            part
+        }
+    };
+    let base = part(&short[..8], case & LOWERCASE_BASE != 0);
+    let ext = part(&short[8..], case & LOWERCASE_EXT != 0);
+    match ext.is_empty() {
+        true => base,
+        false => format!("{base}.{ext}"),
+    }
+}
+
+/// An 8.3 name, and whether it needs a long name alongside it.
+#[derive(Debug)]
+struct ShortName {
+    name: [u8; 11],
+    case: u8,
+    needs_long_name: bool,
+}
+
+impl ShortName {
+    /// Picks a short name for `name` that isn't in `used` yet.
+    fn for_name(name: &str, used: &mut HashSet<[u8; 11]>) -> Result<ShortName> {
+        if name.encode_utf16().count() > 255 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("fat names can't be longer than 255 characters: {name}"),
+            ));
+        }
+
+        let (base, ext) = match name.rsplit_once('.') {
+            Some((base, ext)) if !base.is_empty() => (base, ext),
+            _ => (name, ""),
+        };
+        let valid = |part: &str, max: usize| {
+            part.len() <= max
+                && part
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'()-@^_`{}~".contains(&b))
+        };
+        let uniform = |part: &str| {
+            let upper = part.to_ascii_uppercase() == part;
+            let lower = part.to_ascii_lowercase() == part;
+            (upper || lower, !upper && lower)
+        };
+        let (base_uniform, base_lower) = uniform(base);
+        let (ext_uniform, ext_lower) = uniform(ext);
+        if !base.is_empty() && valid(base, 8) && valid(ext, 3) && base_uniform && ext_uniform {
+            let short = pad(&base.to_ascii_uppercase(), &ext.to_ascii_uppercase());
+            if used.insert(short) {
+                let mut case = 0;
+                if base_lower {
+                    case |= LOWERCASE_BASE;
+                }
+                if ext_lower {
+                    case |= LOWERCASE_EXT;
+                }
+                return Ok(Self {
+                    name: short,
+                    case,
+                    needs_long_name: false,
+                });
+            }
+        }
+
+        // Otherwise, it's a mangled name like `ALONGF~1.TXT`.
+        let clean = |part: &str| -> String {
+            part.chars()
+                .filter(|c| *c != ' ' && *c != '.')
+                .map(|c| {
+                    let c = c.to_ascii_uppercase();
+                    if c.is_ascii() && valid(&c.to_string(), 1) {
+                        c
+                    } else {
+                        '_'
+                    }
+                })
+                .collect()
+        };
+        let base = clean(base.trim_start_matches('.'));
+        let ext: String = clean(ext).chars().take(3).collect();
+        for n in 1..1_000_000 {
+            let tail = format!("~{n}");
+            let keep = 8 - tail.len();
+            let short = pad(&format!("{}{tail}", &base[..base.len().min(keep)]), &ext);
+            if used.insert(short) {
+                return Ok(Self {
+                    name: short,
+                    case: 0,
+                    needs_long_name: true,
+                });
+            }
+        }
+        Err(invalid("ran out of short names"))
+    }
+}
+
+fn pad(base: &str, ext: &str) -> [u8; 11] {
+    let mut out = [b' '; 11];
+    out[..base.len()].copy_from_slice(base.as_bytes());
+    out[8..8 + ext.len()].copy_from_slice(ext.as_bytes());
+    out
+}
+
+fn short_label(label: &str) -> [u8; 11] {
+    let mut out = [b' '; 11];
+    for (idx, byte) in label.to_ascii_uppercase().bytes().take(11).enumerate() {
+        out[idx] = if byte.is_ascii() { byte } else { b'_' };
+    }
+    out
+}
+
+fn checksum(short: &[u8; 11]) -> u8 {
+    short
+        .iter()
+        .fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
+
+fn lfn_count(name: &str) -> usize {
+    (name.encode_utf16().count() + 1).div_ceil(13)
+}
+
+/// The long name entries for `name`, in the order they go on disk.
+fn lfn_entries(name: &str, short: &[u8; 11]) -> Vec<u8> {
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    units.push(0);
+    while !units.len().is_multiple_of(13) {
+        units.push(0xffff);
+    }
+    let checksum = checksum(short);
+    let count = units.len() / 13;
+    let mut out = vec![];
+    for (idx, chunk) in units.chunks(13).enumerate().rev() {
+        let mut raw = [0u8; DIR_ENTRY_SIZE];
+        raw[0] = (idx + 1) as u8 | if idx + 1 == count { 0x40 } else { 0 };
+        raw[11] = ATTR_LONG_NAME;
+        raw[13] = checksum;
+        let offsets = (1..11)
+            .step_by(2)
+            .chain((14..26).step_by(2))
+            .chain((28..32).step_by(2));
+        for (unit, offset) in chunk.iter().zip(offsets) {
+            raw[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+        }
+        out.extend(raw);
+    }
+    out
+}
+
+fn raw_entry(
+    name: &[u8; 11],
+    attributes: u8,
+    case: u8,
+    cluster: u32,
+    size: u32,
+    modified: SystemTime,
+) -> [u8; DIR_ENTRY_SIZE] {
+    let (date, time) = system_time_to_dos(modified);
+    let mut raw = [0u8; DIR_ENTRY_SIZE];
+    raw[..11].copy_from_slice(name);
+    if raw[0] == 0xe5 {
+        raw[0] = 0x05;
+    }
+    raw[11] = attributes;
+    raw[12] = case;
+    raw[14..16].copy_from_slice(&time.to_le_bytes());
+    raw[16..18].copy_from_slice(&date.to_le_bytes());
+    raw[18..20].copy_from_slice(&date.to_le_bytes());
+    raw[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    raw[22..24].copy_from_slice(&time.to_le_bytes());
+    raw[24..26].copy_from_slice(&date.to_le_bytes());
+    raw[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+    raw[28..32].copy_from_slice(&size.to_le_bytes());
+    raw
+}
+
+/// DOS timestamps have no time zone, so they're treated as UTC.
+fn dos_to_system_time(date: u16, time: u16) -> SystemTime {
+    crate::util::civil_to_system_time(
+        1980 + (date >> 9) as i64,
+        ((date >> 5) & 0x0f).max(1) as u32,
+        (date & 0x1f).max(1) as u32,
+        (time >> 11) as u32,
+        ((time >> 5) & 0x3f) as u32,
+        ((time & 0x1f) * 2) as u32,
+    )
+}
+
+/// DOS timestamps only go from 1980 to 2107, with two second precision.
+fn system_time_to_dos(time: SystemTime) -> (u16, u16) {
+    let (year, month, day, hour, minute, second) = crate::util::system_time_to_civil(time);
+    if year < 1980 {
+        return ((1 << 5) | 1, 0);
+    }
+    if year > 2107 {
+        return ((127 << 9) | (12 << 5) | 31, (23 << 11) | (59 << 5) | 29);
+    }
+    let date = (((year - 1980) as u16) << 9) | ((month as u16) << 5) | day as u16;
+    let time = ((hour as u16) << 11) | ((minute as u16) << 5) | (second as u16 / 2);
+    (date, time)
+}
+
+fn invalid(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod fat_tests {
+    use super::*;
+
+    #[test_log::test(tokio::test)]
+    async fn test_fixture_works() -> Result<()> {
+        let disk = FatFloppyDisk::open("./fixtures/a.img").await?;
+        assert_eq!(FatType::Fat12, disk.fat_type());
+        assert_eq!(163_840, disk.size());
+        assert_eq!(Some("FLOPPY".to_string()), disk.volume_label());
+
+        assert_eq!(
+            "long\n",
+            disk.read_to_string("/A Long File Name.txt").await?
+        );
+        assert_eq!(
+            "hello from a subdirectory\n",
+            disk.read_to_string("/DIR/readme.txt").await?
+        );
+        let frag = disk.read("/FRAG.BIN").await?;
+        assert_eq!(1500, frag.len());
+        assert!(frag.iter().enumerate().all(|(i, b)| *b == (i % 251) as u8));
+        assert_eq!("", disk.read_to_string("/EMPTY.TXT").await?);
+        assert!(!disk.try_exists("/DELETED.TXT").await?);
+
+        assert_eq!(0o444, disk.metadata("/RO.TXT").await?.permissions().mode());
+        assert!(disk.attributes("/HIDDEN.TXT").hidden);
+        assert_eq!(
+            std::time::UNIX_EPOCH + std::time::Duration::from_secs(1687000000),
+            disk.metadata("/a.txt").await?.modified()?
+        );
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_round_trip_works() -> Result<()> {
+        let image = crate::util::tests::TempFile::new("./fixtures/a.img").await?;
+        {
+            let disk = FatFloppyDisk::open(image.path_view()).await?;
+            disk.write("/Another long name.md", "# hi").await?;
+            disk.write("/lower.txt", "lower").await?;
+            disk.write("/MiXeD.TxT", "mixed").await?;
+            disk.write("/big.bin", vec![7u8; 10_000]).await?;
+            disk.set_volume_label(Some("renamed"));
+            disk.close().await?;
+        }
+
+        let disk = FatFloppyDisk::open(image.path_view()).await?;
+        assert_eq!(Some("RENAMED".to_string()), disk.volume_label());
+        assert_eq!("# hi", disk.read_to_string("/Another long name.md").await?);
+        assert_eq!("lower", disk.read_to_string("/lower.txt").await?);
+        assert_eq!("mixed", disk.read_to_string("/MiXeD.TxT").await?);
+        assert_eq!(vec![7u8; 10_000], disk.read("/big.bin").await?);
+        assert_eq!(
+            "long\n",
+            disk.read_to_string("/A Long File Name.txt").await?
+        );
+        assert_eq!(0o444, disk.metadata("/RO.TXT").await?.permissions().mode());
+        assert!(disk.attributes("/HIDDEN.TXT").hidden);
+        assert_eq!(
+            std::time::UNIX_EPOCH + std::time::Duration::from_secs(1687000000),
+            disk.metadata("/a.txt").await?.modified()?
+        );
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_create_works() -> Result<()> {
+        let dir = crate::util::TempDir::new().await?;
+        for (size, fat_type) in [
+            (2 * 1024 * 1024, FatType::Fat12),
+            (32 * 1024 * 1024, FatType::Fat16),
+            (600 * 1024 * 1024, FatType::Fat32),
+        ] {
+            let path = dir.join(format!("{size}.img"));
+            let disk = FatFloppyDisk::create(&path, size).await?;
+            disk.create_dir_all("/EFI/BOOT").await?;
+            disk.write("/EFI/BOOT/BOOTX64.EFI", "not really").await?;
+            disk.close().await?;
+            assert_eq!(size, std::fs::metadata(&path)?.len());
+
+            let disk = FatFloppyDisk::open(&path).await?;
+            assert_eq!(fat_type, disk.fat_type());
+            assert_eq!(
+                "not really",
+                disk.read_to_string("/EFI/BOOT/BOOTX64.EFI").await?
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_corrupt_geometry_fails() -> Result<()> {
+        let fixture = tokio::fs::read("./fixtures/a.img").await?;
+        let dir = crate::util::TempDir::new().await?;
+        // More sectors than the FAT has room for, and a FAT that's bigger
+        // than the image.
+        for (offset, value) in [(19, 8000u16), (22, u16::MAX)] {
+            let mut image = fixture.clone();
+            image[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+            let path = dir.join("corrupt.img");
+            tokio::fs::write(&path, image).await?;
+            let err = FatFloppyDisk::open(&path).await.unwrap_err();
+            assert_eq!(std::io::ErrorKind::InvalidData, err.kind());
+        }
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_full_image_fails() -> Result<()> {
+        let dir = crate::util::TempDir::new().await?;
+        let disk = FatFloppyDisk::create(dir.join("small.img"), 163_840).await?;
+        disk.write("/big.bin", vec![0u8; 200_000]).await?;
+        let err = disk.close().await.unwrap_err();
+        assert_eq!(std::io::ErrorKind::StorageFull, err.kind());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,187 @@
+use std::fmt::Display;
+use std::io::Result;
+use std::path::Path;
+use std::str::FromStr;
+
+use tracing::debug;
+
+/// The archive formats that flop can open.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ArchiveFormat {
+    Ar,
+    Cpio,
+    Tar,
+    Zip,
+}
+
+impl ArchiveFormat {
+    pub const ALL: [ArchiveFormat; 4] = [
+        ArchiveFormat::Ar,
+        ArchiveFormat::Cpio,
+        ArchiveFormat::Tar,
+        ArchiveFormat::Zip,
+    ];
+
+    /// Figures out the format of the archive at `path`. Existing archives are
+    /// sniffed by their (decompressed) contents, and paths that don't exist
+    /// yet are guessed from their extension.
+    pub async fn detect<P: AsRef<Path>>(path: P) -> Result<ArchiveFormat> {
+        let path = path.as_ref();
+        if crate::util::exists_async(path).await {
+            let mut file = tokio::fs::File::open(path).await?;
+            let mut buffer = vec![];
+            smoosh::recompress(&mut file, &mut buffer, smoosh::CompressionType::None).await?;
+            if let Some(format) = Self::from_magic(&buffer) {
+                debug!("detected {} from contents of {}", format, path.display());
+                return Ok(format);
+            }
+        }
+
+        Self::from_extension(path).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("could not detect archive format of {}", path.display()),
+            )
+        })
+    }
+
+    /// Guesses the format from the magic bytes at the start of an
+    /// uncompressed archive.
+    pub fn from_magic(data: &[u8]) -> Option<ArchiveFormat> {
+        if data.starts_with(b"!<arch>\n") {
+            Some(ArchiveFormat::Ar)
+        } else if data.starts_with(b"070701")
+            || data.starts_with(b"070702")
+            || data.starts_with(b"070707")
+            || data.starts_with(&[0xc7, 0x71])
+            || data.starts_with(&[0x71, 0xc7])
+        {
+            Some(ArchiveFormat::Cpio)
+        } else if data.starts_with(b"PK\x03\x04") || data.starts_with(b"PK\x05\x06") {
+            Some(ArchiveFormat::Zip)
+        } else if data.len() >= 262 && &data[257..262] == b"ustar" {
+            Some(ArchiveFormat::Tar)
+        } else {
+            None
+        }
+    }
+
+    /// Guesses the format from a file name, ie. `foo.tar.gz` is a tar.
+    pub fn from_extension<P: AsRef<Path>>(path: P) -> Option<ArchiveFormat> {
+        let name = path.as_ref().file_name()?.to_string_lossy().to_lowercase();
+        let name = [".gz", ".xz", ".zst", ".bz2", ".lz4"]
+            .iter()
+            .find_map(|ext| name.strip_suffix(ext))
+            .unwrap_or(&name);
+
+        let (_, ext) = name.rsplit_once('.')?;
+        match ext {
+            "a" | "ar" | "lib" => Some(ArchiveFormat::Ar),
+            "cpio" => Some(ArchiveFormat::Cpio),
+            "tar" | "tgz" | "txz" | "tbz2" | "tzst" => Some(ArchiveFormat::Tar),
+            "zip" | "jar" | "war" | "apk" | "whl" => Some(ArchiveFormat::Zip),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn supports_symlinks(&self) -> bool {
+        !matches!(self, ArchiveFormat::Ar)
+    }
+
+    pub(crate) fn supports_hard_links(&self) -> bool {
+        matches!(self, ArchiveFormat::Tar)
+    }
+
+    pub(crate) fn supports_directories(&self) -> bool {
+        !matches!(self, ArchiveFormat::Ar)
+    }
+
+    pub(crate) fn supports_ownership(&self) -> bool {
+        !matches!(self, ArchiveFormat::Zip)
+    }
+}
+
+impl Display for ArchiveFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ArchiveFormat::Ar => "ar",
+            ArchiveFormat::Cpio => "cpio",
+            ArchiveFormat::Tar => "tar",
+            ArchiveFormat::Zip => "zip",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for ArchiveFormat {
+    type Err = std::io::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|format| format.to_string().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("unknown archive format: {s}"),
+                )
+            })
+    }
+}
+
+/// Opens `$path` as the disk type for `$format` and evaluates `$body` with it
+/// bound to `$disk`. Every arm is monomorphised separately, so `$body` can
+/// use the disk generically.
+macro_rules! with_disk {
+    ( $format:expr, $path:expr, | $disk:ident | $body:expr ) => {
+        match $format {
+            $crate::format::ArchiveFormat::Ar => {
+                #[allow(unused_mut)]
+                let mut $disk = $crate::ar::ArFloppyDisk::open($path).await?;
+                $body
+            }
+            $crate::format::ArchiveFormat::Cpio => {
+                #[allow(unused_mut)]
+                let mut $disk = $crate::cpio::CpioFloppyDisk::open($path).await?;
+                $body
+            }
+            $crate::format::ArchiveFormat::Tar => {
+                #[allow(unused_mut)]
+                let mut $disk = $crate::tar::TarFloppyDisk::open($path).await?;
+                $body
+            }
+            $crate::format::ArchiveFormat::Zip => {
+                #[allow(unused_mut)]
+                let mut $disk = $crate::zip::ZipFloppyDisk::open($path).await?;
+                $body
+            }
+        }
+    };
+}
+
+pub(crate) use with_disk;
+
+#[cfg(test)]
+mod tests {
+    use super::ArchiveFormat;
+
+    #[test_log::test(tokio::test)]
+    async fn test_detect_works() -> std::io::Result<()> {
+        for (fixture, format) in [
+            ("./fixtures/a.ar", ArchiveFormat::Ar),
+            ("./fixtures/a.cpio", ArchiveFormat::Cpio),
+            ("./fixtures/a.tar", ArchiveFormat::Tar),
+            ("./fixtures/a.zip", ArchiveFormat::Zip),
+        ] {
+            assert_eq!(format, ArchiveFormat::detect(fixture).await?);
+        }
+
+        assert_eq!(
+            ArchiveFormat::Tar,
+            ArchiveFormat::detect("./does/not/exist.tar.gz").await?
+        );
+        assert!(ArchiveFormat::detect("./fixtures/a.txt").await.is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,763 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::ffi::OsString;
+use std::io::Result;
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+
+/// Generates a read-only `FloppyDisk` for a filesystem image. Unlike
+/// [`crate::util::archive_format`], nothing is copied into a memfs: the
+/// directory tree is read when the image is opened, and file contents are
+/// only read from the image when a file is opened.
+///
+/// `$open` reads the tree and returns it alongside `$reader`, an
+/// [`ImageReader`] that knows how to read file contents later.
+macro_rules! image_format {
+    ( $format:ident, $fixture:expr, $reader:ty, $open:expr ) => {
+        paste::paste! {
+            use std::ffi::OsString;
+            use std::io::Result;
+            use std::path::{Path, PathBuf};
+            use std::time::SystemTime;
+
+            use floppy_disk::prelude::*;
+            use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite};
+            use tokio::pin;
+
+            use crate::image::{ImageEntry, ImageKind, ImageReader, ImageTree};
+
+            type [< $format Data >] = <$reader as ImageReader>::Data;
+
+            #[derive(Debug)]
+            pub struct [< $format FloppyDisk >] {
+                #[allow(dead_code)]
+                path: PathBuf,
+                reader: $reader,
+                tree: ImageTree<[< $format Data >]>,
+            }
+
+            impl [< $format FloppyDisk >] {
+                pub async fn open<P: AsRef<Path>>(path: P) -> Result<[< $format FloppyDisk >]> {
+                    let path = path.as_ref();
+                    let (reader, tree) = $open(path).await?;
+                    Ok(Self {
+                        path: path.to_path_buf(),
+                        reader,
+                        tree,
+                    })
+                }
+
+                fn entry<P: AsRef<Path>>(
+                    &self,
+                    path: P,
+                    follow: bool,
+                ) -> Result<(PathBuf, &ImageEntry<[< $format Data >]>)> {
+                    let path = self.tree.resolve(path.as_ref(), follow)?;
+                    let entry = self.tree.get(&path).ok_or_else(|| crate::image::not_found(&path))?;
+                    Ok((path, entry))
+                }
+
+                async fn read_entry(
+                    &self,
+                    path: &Path,
+                    entry: &ImageEntry<[< $format Data >]>,
+                ) -> Result<Vec<u8>> {
+                    match &entry.kind {
+                        ImageKind::File(data) => self.reader.read_file(data, entry.size).await,
+                        ImageKind::Directory => Err(std::io::Error::new(
+                            std::io::ErrorKind::IsADirectory,
+                            format!("{} is a directory", path.display()),
+                        )),
+                        ImageKind::Symlink(_) => unreachable!("symlinks are always resolved"),
+                    }
+                }
+            }
+
+            #[async_trait::async_trait]
+            impl<'a> FloppyDisk<'a> for [< $format FloppyDisk >] {
+                type DirBuilder = [< $format DirBuilder >];
+                type DirEntry = [< $format DirEntry >];
+                type File = [< $format File >];
+                type FileType = [< $format FileType >];
+                type Metadata = [< $format Metadata >];
+                type OpenOptions = [< $format OpenOptions >];
+                type Permissions = [< $format Permissions >];
+                type ReadDir = [< $format ReadDir >];
+
+                async fn canonicalize<P: AsRef<Path> + Send>(&self, path: P) -> Result<PathBuf> {
+                    self.entry(path, true).map(|(path, _)| path)
+                }
+
+                async fn copy<P: AsRef<Path> + Send>(&self, _from: P, _to: P) -> Result<u64> {
+                    Err(crate::image::read_only())
+                }
+
+                async fn create_dir<P: AsRef<Path> + Send>(&self, _path: P) -> Result<()> {
+                    Err(crate::image::read_only())
+                }
+
+                async fn create_dir_all<P: AsRef<Path> + Send>(&self, _path: P) -> Result<()> {
+                    Err(crate::image::read_only())
+                }
+
+                async fn hard_link<P: AsRef<Path> + Send>(&self, _src: P, _dst: P) -> Result<()> {
+                    Err(crate::image::read_only())
+                }
+
+                async fn metadata<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::Metadata> {
+                    self.entry(path, true).map(|(_, entry)| [< $format Metadata >]::new(entry))
+                }
+
+                async fn read<P: AsRef<Path> + Send>(&self, path: P) -> Result<Vec<u8>> {
+                    let (path, entry) = self.entry(path, true)?;
+                    self.read_entry(&path, entry).await
+                }
+
+                async fn read_dir<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::ReadDir> {
+                    let (path, entry) = self.entry(path, true)?;
+                    if !matches!(entry.kind, ImageKind::Directory) {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::NotADirectory,
+                            format!("{} is not a directory", path.display()),
+                        ));
+                    }
+                    let entries: Vec<_> = self
+                        .tree
+                        .children(&path)
+                        .map(|(path, entry)| [< $format DirEntry >] {
+                            path: path.clone(),
+                            metadata: [< $format Metadata >]::new(entry),
+                        })
+                        .collect();
+                    Ok([< $format ReadDir >](entries.into_iter()))
+                }
+
+                async fn read_link<P: AsRef<Path> + Send>(&self, path: P) -> Result<PathBuf> {
+                    let (path, entry) = self.entry(path, false)?;
+                    match &entry.kind {
+                        ImageKind::Symlink(target) => Ok(target.clone()),
+                        _ => Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            format!("{} is not a symlink", path.display()),
+                        )),
+                    }
+                }
+
+                async fn read_to_string<P: AsRef<Path> + Send>(&self, path: P) -> Result<String> {
+                    String::from_utf8(self.read(path).await?)
+                        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+                }
+
+                async fn remove_dir<P: AsRef<Path> + Send>(&self, _path: P) -> Result<()> {
+                    Err(crate::image::read_only())
+                }
+
+                async fn remove_dir_all<P: AsRef<Path> + Send>(&self, _path: P) -> Result<()> {
+                    Err(crate::image::read_only())
+                }
+
+                async fn remove_file<P: AsRef<Path> + Send>(&self, _path: P) -> Result<()> {
+                    Err(crate::image::read_only())
+                }
+
+                async fn rename<P: AsRef<Path> + Send>(&self, _from: P, _to: P) -> Result<()> {
+                    Err(crate::image::read_only())
+                }
+
+                async fn set_permissions<P: AsRef<Path> + Send>(
+                    &self,
+                    _path: P,
+                    _perm: Self::Permissions,
+                ) -> Result<()> {
+                    Err(crate::image::read_only())
+                }
+
+                async fn symlink<P: AsRef<Path> + Send>(&self, _src: P, _dst: P) -> Result<()> {
+                    Err(crate::image::read_only())
+                }
+
+                async fn symlink_metadata<P: AsRef<Path> + Send>(
+                    &self,
+                    path: P,
+                ) -> Result<Self::Metadata> {
+                    self.entry(path, false).map(|(_, entry)| [< $format Metadata >]::new(entry))
+                }
+
+                async fn try_exists<P: AsRef<Path> + Send>(&self, path: P) -> Result<bool> {
+                    match self.entry(path, true) {
+                        Ok(_) => Ok(true),
+                        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+                        Err(e) => Err(e),
+                    }
+                }
+
+                async fn write<P: AsRef<Path> + Send>(
+                    &self,
+                    _path: P,
+                    _contents: impl AsRef<[u8]> + Send,
+                ) -> Result<()> {
+                    Err(crate::image::read_only())
+                }
+
+                fn new_dir_builder(&'a self) -> Self::DirBuilder {
+                    [< $format DirBuilder >]
+                }
+            }
+
+            #[async_trait::async_trait]
+            impl crate::import::FloppyDiskHardLinkExt for [< $format FloppyDisk >] {
+                async fn hard_link_id<P: AsRef<Path> + Send>(&self, path: P) -> Result<Option<u64>> {
+                    self.entry(path, false).map(|(_, entry)| entry.link_id)
+                }
+            }
+
+            #[async_trait::async_trait]
+            impl FloppyDiskUnixExt for [< $format FloppyDisk >] {
+                async fn chown<P: Into<PathBuf> + Send>(
+                    &self,
+                    _path: P,
+                    _uid: u32,
+                    _gid: u32,
+                ) -> Result<()> {
+                    Err(crate::image::read_only())
+                }
+            }
+
+            #[derive(Debug)]
+            pub struct [< $format DirBuilder >];
+
+            #[async_trait::async_trait]
+            impl FloppyDirBuilder for [< $format DirBuilder >] {
+                fn recursive(&mut self, _recursive: bool) -> &mut Self {
+                    self
+                }
+
+                async fn create<P: AsRef<Path> + Send>(&self, _path: P) -> Result<()> {
+                    Err(crate::image::read_only())
+                }
+
+                #[cfg(unix)]
+                fn mode(&mut self, _mode: u32) -> &mut Self {
+                    self
+                }
+            }
+
+            #[derive(Debug)]
+            pub struct [< $format DirEntry >] {
+                #[doc(hidden)] path: PathBuf,
+                #[doc(hidden)] metadata: [< $format Metadata >],
+            }
+
+            #[async_trait::async_trait]
+            impl<'a> FloppyDirEntry<'a, [< $format FloppyDisk >]> for [< $format DirEntry >] {
+                fn path(&self) -> PathBuf {
+                    self.path.clone()
+                }
+
+                fn file_name(&self) -> OsString {
+                    self.path.file_name().unwrap_or_default().to_os_string()
+                }
+
+                async fn metadata(&self) -> Result<<[< $format FloppyDisk >] as FloppyDisk<'a>>::Metadata> {
+                    Ok(self.metadata.clone())
+                }
+
+                async fn file_type(&self) -> Result<<[< $format FloppyDisk >] as FloppyDisk<'a>>::FileType> {
+                    Ok(self.metadata.file_type)
+                }
+
+                #[cfg(unix)]
+                fn ino(&self) -> u64 {
+                    self.metadata.ino
+                }
+            }
+
+            #[derive(Debug)]
+            pub struct [< $format File >] {
+                #[doc(hidden)] data: std::io::Cursor<Vec<u8>>,
+                #[doc(hidden)] metadata: [< $format Metadata >],
+            }
+
+            #[async_trait::async_trait]
+            impl<'a> FloppyFile<'a, [< $format FloppyDisk >]> for [< $format File >] {
+                async fn sync_all(&mut self) -> Result<()> {
+                    Ok(())
+                }
+
+                async fn sync_data(&mut self) -> Result<()> {
+                    Ok(())
+                }
+
+                async fn set_len(&mut self, _size: u64) -> Result<()> {
+                    Err(crate::image::read_only())
+                }
+
+                async fn metadata(&self) -> Result<<[< $format FloppyDisk >] as FloppyDisk>::Metadata> {
+                    Ok(self.metadata.clone())
+                }
+
+                async fn try_clone(&'a self) -> Result<Box<<[< $format FloppyDisk >] as FloppyDisk>::File>> {
+                    Ok(Box::new([< $format File >] {
+                        data: self.data.clone(),
+                        metadata: self.metadata.clone(),
+                    }))
+                }
+
+                async fn set_permissions(
+                    &self,
+                    _perm: <[< $format FloppyDisk >] as FloppyDisk>::Permissions,
+                ) -> Result<()> {
+                    Err(crate::image::read_only())
+                }
+
+                async fn permissions(&self) -> Result<<[< $format FloppyDisk >] as FloppyDisk>::Permissions> {
+                    Ok(self.metadata.permissions())
+                }
+            }
+
+            impl AsyncRead for [< $format File >] {
+                fn poll_read(
+                    self: std::pin::Pin<&mut Self>,
+                    cx: &mut std::task::Context<'_>,
+                    buf: &mut tokio::io::ReadBuf<'_>,
+                ) -> std::task::Poll<std::io::Result<()>> {
+                    let this = self.get_mut();
+                    let data = &mut this.data;
+                    pin!(data);
+                    AsyncRead::poll_read(data, cx, buf)
+                }
+            }
+
+            impl AsyncWrite for [< $format File >] {
+                fn poll_write(
+                    self: std::pin::Pin<&mut Self>,
+                    _cx: &mut std::task::Context<'_>,
+                    _buf: &[u8],
+                ) -> std::task::Poll<std::result::Result<usize, std::io::Error>> {
+                    std::task::Poll::Ready(Err(crate::image::read_only()))
+                }
+
+                fn poll_flush(
+                    self: std::pin::Pin<&mut Self>,
+                    _cx: &mut std::task::Context<'_>,
+                ) -> std::task::Poll<std::result::Result<(), std::io::Error>> {
+                    std::task::Poll::Ready(Ok(()))
+                }
+
+                fn poll_shutdown(
+                    self: std::pin::Pin<&mut Self>,
+                    _cx: &mut std::task::Context<'_>,
+                ) -> std::task::Poll<std::result::Result<(), std::io::Error>> {
+                    std::task::Poll::Ready(Ok(()))
+                }
+            }
+
+            impl AsyncSeek for [< $format File >] {
+                fn start_seek(
+                    self: std::pin::Pin<&mut Self>,
+                    position: std::io::SeekFrom,
+                ) -> std::io::Result<()> {
+                    let this = self.get_mut();
+                    let data = &mut this.data;
+                    pin!(data);
+                    AsyncSeek::start_seek(data, position)
+                }
+
+                fn poll_complete(
+                    self: std::pin::Pin<&mut Self>,
+                    cx: &mut std::task::Context<'_>,
+                ) -> std::task::Poll<std::io::Result<u64>> {
+                    let this = self.get_mut();
+                    let data = &mut this.data;
+                    pin!(data);
+                    AsyncSeek::poll_complete(data, cx)
+                }
+            }
+
+            #[derive(Debug, Clone, Copy)]
+            #[repr(transparent)]
+            pub struct [< $format FileType >](#[doc(hidden)] crate::image::ImageFileType);
+
+            impl FloppyFileType for [< $format FileType >] {
+                fn is_dir(&self) -> bool {
+                    matches!(self.0, crate::image::ImageFileType::Directory)
+                }
+
+                fn is_file(&self) -> bool {
+                    matches!(self.0, crate::image::ImageFileType::File)
+                }
+
+                fn is_symlink(&self) -> bool {
+                    matches!(self.0, crate::image::ImageFileType::Symlink)
+                }
+            }
+
+            #[derive(Debug, Clone)]
+            pub struct [< $format Metadata >] {
+                #[doc(hidden)] file_type: [< $format FileType >],
+                #[doc(hidden)] mode: u32,
+                #[doc(hidden)] uid: u32,
+                #[doc(hidden)] gid: u32,
+                #[doc(hidden)] mtime: SystemTime,
+                #[doc(hidden)] size: u64,
+                #[doc(hidden)] ino: u64,
+            }
+
+            impl [< $format Metadata >] {
+                fn new(entry: &ImageEntry<[< $format Data >]>) -> Self {
+                    Self {
+                        file_type: [< $format FileType >](entry.kind.file_type()),
+                        mode: entry.mode,
+                        uid: entry.uid,
+                        gid: entry.gid,
+                        mtime: entry.mtime,
+                        size: entry.size,
+                        ino: entry.ino,
+                    }
+                }
+            }
+
+            impl<'a> FloppyMetadata<'a, [< $format FloppyDisk >]> for [< $format Metadata >] {
+                fn file_type(&self) -> <[< $format FloppyDisk >] as FloppyDisk<'a>>::FileType {
+                    self.file_type
+                }
+
+                fn is_dir(&self) -> bool {
+                    self.file_type.is_dir()
+                }
+
+                fn is_file(&self) -> bool {
+                    self.file_type.is_file()
+                }
+
+                fn is_symlink(&self) -> bool {
+                    self.file_type.is_symlink()
+                }
+
+                fn len(&self) -> u64 {
+                    self.size
+                }
+
+                fn permissions(&self) -> <[< $format FloppyDisk >] as FloppyDisk<'a>>::Permissions {
+                    [< $format Permissions >](self.mode)
+                }
+
+                fn modified(&self) -> Result<SystemTime> {
+                    Ok(self.mtime)
+                }
+
+                fn accessed(&self) -> Result<SystemTime> {
+                    Ok(self.mtime)
+                }
+
+                fn created(&self) -> Result<SystemTime> {
+                    Ok(self.mtime)
+                }
+            }
+
+            impl FloppyUnixMetadata for [< $format Metadata >] {
+                fn uid(&self) -> Result<u32> {
+                    Ok(self.uid)
+                }
+
+                fn gid(&self) -> Result<u32> {
+                    Ok(self.gid)
+                }
+            }
+
+            #[derive(Debug)]
+            pub struct [< $format OpenOptions >] {
+                #[doc(hidden)] write: bool,
+            }
+
+            #[async_trait::async_trait]
+            impl<'a> FloppyOpenOptions<'a, [< $format FloppyDisk >]> for [< $format OpenOptions >] {
+                fn new() -> Self {
+                    Self { write: false }
+                }
+
+                fn read(self, _read: bool) -> Self {
+                    self
+                }
+
+                fn write(self, write: bool) -> Self {
+                    Self { write: self.write || write }
+                }
+
+                fn append(self, append: bool) -> Self {
+                    Self { write: self.write || append }
+                }
+
+                fn truncate(self, truncate: bool) -> Self {
+                    Self { write: self.write || truncate }
+                }
+
+                fn create(self, create: bool) -> Self {
+                    Self { write: self.write || create }
+                }
+
+                fn create_new(self, create_new: bool) -> Self {
+                    Self { write: self.write || create_new }
+                }
+
+                async fn open<P: AsRef<Path> + Send>(
+                    &self,
+                    disk: &'a [< $format FloppyDisk >],
+                    path: P,
+                ) -> Result<<[< $format FloppyDisk >] as FloppyDisk<'a>>::File> {
+                    if self.write {
+                        return Err(crate::image::read_only());
+                    }
+                    let (path, entry) = disk.entry(path, true)?;
+                    let data = disk.read_entry(&path, entry).await?;
+                    Ok([< $format File >] {
+                        data: std::io::Cursor::new(data),
+                        metadata: [< $format Metadata >]::new(entry),
+                    })
+                }
+            }
+
+            #[derive(Debug, Clone, Copy)]
+            #[repr(transparent)]
+            pub struct [< $format Permissions >](#[doc(hidden)] u32);
+
+            impl FloppyPermissions for [< $format Permissions >] {
+                fn readonly(&self) -> bool {
+                    self.0 & 0o222 == 0
+                }
+
+                fn set_readonly(&mut self, readonly: bool) {
+                    if readonly {
+                        self.0 &= !0o222;
+                    } else {
+                        self.0 |= 0o200;
+                    }
+                }
+            }
+
+            impl FloppyUnixPermissions for [< $format Permissions >] {
+                fn mode(&self) -> u32 {
+                    self.0
+                }
+
+                fn set_mode(&mut self, mode: u32) {
+                    self.0 = mode;
+                }
+
+                fn from_mode(mode: u32) -> Self {
+                    Self(mode)
+                }
+            }
+
+            #[derive(Debug)]
+            pub struct [< $format ReadDir >](
+                #[doc(hidden)] std::vec::IntoIter<[< $format DirEntry >]>,
+            );
+
+            #[async_trait::async_trait]
+            impl<'a> FloppyReadDir<'a, [< $format FloppyDisk >]> for [< $format ReadDir >] {
+                async fn next_entry(
+                    &mut self,
+                ) -> Result<Option<<[< $format FloppyDisk >] as FloppyDisk<'a>>::DirEntry>> {
+                    Ok(self.0.next())
+                }
+            }
+
+            #[cfg(test)]
+            mod image_tests {
+                use super::*;
+                use std::io::Result;
+
+                #[test_log::test(tokio::test)]
+                async fn test_read_works() -> Result<()> {
+                    let disk = [< $format FloppyDisk >]::open(concat!("./fixtures/", $fixture)).await?;
+
+                    let input = disk.read_to_string("/a.txt").await?;
+                    assert_eq!("asdf\n", input);
+
+                    Ok(())
+                }
+
+                #[test_log::test(tokio::test)]
+                async fn test_read_only_works() -> Result<()> {
+                    let disk = [< $format FloppyDisk >]::open(concat!("./fixtures/", $fixture)).await?;
+
+                    let err = disk.write("/b.txt", "wow!!!").await.unwrap_err();
+                    assert_eq!(std::io::ErrorKind::ReadOnlyFilesystem, err.kind());
+                    assert!(disk.remove_file("/a.txt").await.is_err());
+                    assert!(disk.try_exists("/a.txt").await?);
+
+                    // Every file can be read, and is as long as it says it is.
+                    let entries = crate::diff::entries(&disk).await?;
+                    assert!(!entries.is_empty());
+                    for (path, entry) in entries {
+                        if entry.entry_type == crate::diff::EntryType::File {
+                            assert_eq!(entry.size, disk.read(&path).await?.len() as u64);
+                        }
+                    }
+
+                    Ok(())
+                }
+            }
+        }
+    };
+}
+
+pub(crate) use image_format;
+
+/// Reads file contents out of an image, given whatever the format recorded
+/// about where they live when the tree was read.
+#[async_trait::async_trait]
+pub(crate) trait ImageReader: std::fmt::Debug + Send + Sync {
+    type Data: std::fmt::Debug + Clone + Send + Sync;
+
+    async fn read_file(&self, data: &Self::Data, size: u64) -> Result<Vec<u8>>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ImageFileType {
+    File,
+    Directory,
+    Symlink,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum ImageKind<D> {
+    File(D),
+    Directory,
+    Symlink(PathBuf),
+}
+
+impl<D> ImageKind<D> {
+    pub fn file_type(&self) -> ImageFileType {
+        match self {
+            ImageKind::File(_) => ImageFileType::File,
+            ImageKind::Directory => ImageFileType::Directory,
+            ImageKind::Symlink(_) => ImageFileType::Symlink,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ImageEntry<D> {
+    pub kind: ImageKind<D>,
+    /// Permission bits only, without the file type.
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: SystemTime,
+    pub size: u64,
+    pub ino: u64,
+    /// Shared by every path that is a hard link to the same file.
+    pub link_id: Option<u64>,
+}
+
+impl<D> ImageEntry<D> {
+    pub fn directory(mode: u32) -> Self {
+        Self {
+            kind: ImageKind::Directory,
+            mode,
+            uid: 0,
+            gid: 0,
+            mtime: std::time::UNIX_EPOCH,
+            size: 0,
+            ino: 0,
+            link_id: None,
+        }
+    }
+}
+
+/// Every path in an image, keyed by its normalized absolute path.
+#[derive(Debug)]
+pub(crate) struct ImageTree<D> {
+    entries: BTreeMap<PathBuf, ImageEntry<D>>,
+}
+
+impl<D> ImageTree<D> {
+    pub fn new(root: ImageEntry<D>) -> Self {
+        Self {
+            entries: BTreeMap::from([(PathBuf::from("/"), root)]),
+        }
+    }
+
+    pub fn insert<P: AsRef<Path>>(&mut self, path: P, entry: ImageEntry<D>) {
+        self.entries
+            .insert(crate::util::normalize_path(path), entry);
+    }
+
+    pub fn get<P: AsRef<Path>>(&self, path: P) -> Option<&ImageEntry<D>> {
+        self.entries.get(path.as_ref())
+    }
+
+    pub fn children<'a>(
+        &'a self,
+        dir: &'a Path,
+    ) -> impl Iterator<Item = (&'a PathBuf, &'a ImageEntry<D>)> + 'a {
+        self.entries
+            .range(dir.to_path_buf()..)
+            .skip(1)
+            .take_while(move |(path, _)| path.starts_with(dir))
+            .filter(move |(path, _)| path.parent() == Some(dir))
+    }
+
+    /// Finds the entry that `path` refers to, following symlinks along the
+    /// way. The last component is only followed if `follow` is set.
+    pub fn resolve(&self, path: &Path, follow: bool) -> Result<PathBuf> {
+        let mut pending: VecDeque<OsString> = components(path).into();
+        let mut out = PathBuf::from("/");
+        let mut hops = 0;
+        while let Some(name) = pending.pop_front() {
+            if name == ".." {
+                out.pop();
+                continue;
+            }
+            let candidate = out.join(&name);
+            let entry = self
+                .entries
+                .get(&candidate)
+                .ok_or_else(|| not_found(path))?;
+            match &entry.kind {
+                ImageKind::Symlink(target) if follow || !pending.is_empty() => {
+                    hops += 1;
+                    if hops > 40 {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            format!("too many symlinks in {}", path.display()),
+                        ));
+                    }
+                    if target.is_absolute() {
+                        out = PathBuf::from("/");
+                    }
+                    for component in components(target).into_iter().rev() {
+                        pending.push_front(component);
+                    }
+                }
+                _ => out = candidate,
+            }
+        }
+        Ok(out)
+    }
+}
+
+fn components(path: &Path) -> Vec<OsString> {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_os_string()),
+            Component::ParentDir => Some(OsString::from("..")),
+            _ => None,
+        })
+        .collect()
+}
+
+pub(crate) fn read_only() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::ReadOnlyFilesystem,
+        "this image is read-only",
+    )
+}
+
+pub(crate) fn not_found(path: &Path) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        format!("{} not found", path.display()),
+    )
+}
@@ -0,0 +1,318 @@
+use std::collections::HashMap;
+use std::io::Result;
+use std::os::unix::prelude::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use floppy_disk::mem::MemFloppyDisk;
+use floppy_disk::prelude::*;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use tracing::{debug, trace};
+
+/// Lets [`collect`] figure out which paths on a disk are hard links to the
+/// same file.
+#[async_trait::async_trait]
+pub trait FloppyDiskHardLinkExt {
+    /// Returns an id shared by every path that is a hard link to the same
+    /// file, or `None` if `path` isn't hard-linked to anything.
+    async fn hard_link_id<P: AsRef<Path> + Send>(&self, path: P) -> Result<Option<u64>>;
+}
+
+#[async_trait::async_trait]
+impl FloppyDiskHardLinkExt for TokioFloppyDisk {
+    async fn hard_link_id<P: AsRef<Path> + Send>(&self, path: P) -> Result<Option<u64>> {
+        let path = self.canonicalize(path).await?;
+        let metadata = tokio::fs::symlink_metadata(path).await?;
+        if metadata.is_file() && metadata.nlink() > 1 {
+            Ok(Some(metadata.dev().rotate_left(32) ^ metadata.ino()))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl FloppyDiskHardLinkExt for MemFloppyDisk {
+    async fn hard_link_id<P: AsRef<Path> + Send>(&self, _path: P) -> Result<Option<u64>> {
+        Ok(None)
+    }
+}
+
+/// Include/exclude globs for importing a tree into an archive. Globs are
+/// matched against paths relative to the directory being imported, ie.
+/// importing `/src` with an include glob of `**/*.rs` will import
+/// `/src/lib.rs` as well as `/src/a/b.rs`.
+#[derive(Debug, Default, Clone)]
+pub struct ImportOptions {
+    include: Vec<Glob>,
+    exclude: Vec<Glob>,
+}
+
+impl ImportOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only import paths matching `glob`. Can be given more than once. If no
+    /// includes are given, everything is imported.
+    pub fn include<S: AsRef<str>>(mut self, glob: S) -> Result<Self> {
+        self.include.push(parse_glob(glob.as_ref())?);
+        Ok(self)
+    }
+
+    /// Never import paths matching `glob`. Excluding a directory skips
+    /// everything inside of it. Excludes win over includes.
+    pub fn exclude<S: AsRef<str>>(mut self, glob: S) -> Result<Self> {
+        self.exclude.push(parse_glob(glob.as_ref())?);
+        Ok(self)
+    }
+
+    fn build(&self) -> Result<(Option<GlobSet>, GlobSet)> {
+        let include = if self.include.is_empty() {
+            None
+        } else {
+            Some(build_glob_set(&self.include)?)
+        };
+        Ok((include, build_glob_set(&self.exclude)?))
+    }
+}
+
+fn parse_glob(glob: &str) -> Result<Glob> {
+    Glob::new(glob).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))
+}
+
+fn build_glob_set(globs: &[Glob]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        builder.add(glob.clone());
+    }
+    builder
+        .build()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))
+}
+
+#[derive(Debug)]
+pub(crate) enum ImportedKind {
+    Directory,
+    File(Vec<u8>),
+    Symlink(PathBuf),
+    /// A hard link to an earlier entry, relative to the import root.
+    HardLink(PathBuf),
+}
+
+#[derive(Debug)]
+pub(crate) struct ImportedEntry {
+    /// Relative to the import root. Empty for the root itself.
+    pub path: PathBuf,
+    pub kind: ImportedKind,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub modified: Option<SystemTime>,
+}
+
+/// Walks `root` on `disk` depth-first, in name order, and reads everything
+/// that `options` allows. Parents always come before their children.
+pub(crate) async fn collect<'b, D>(
+    disk: &'b D,
+    root: &Path,
+    options: &ImportOptions,
+) -> Result<Vec<ImportedEntry>>
+where
+    D: FloppyDisk<'b> + FloppyDiskHardLinkExt + Sync,
+    D::Metadata: FloppyUnixMetadata,
+    D::Permissions: FloppyUnixPermissions,
+{
+    debug!("collecting entries from {}", root.display());
+    let (include, exclude) = options.build()?;
+    let mut entries = vec![];
+    let mut links: HashMap<u64, PathBuf> = HashMap::new();
+
+    let root_metadata = disk.symlink_metadata(root).await?;
+    let mut stack = if root_metadata.is_dir() {
+        vec![(root.to_path_buf(), PathBuf::new())]
+    } else {
+        // Importing a single file puts it directly in the destination.
+        let name = root.file_name().map(PathBuf::from).unwrap_or_default();
+        vec![(root.to_path_buf(), name)]
+    };
+
+    while let Some((src, relative)) = stack.pop() {
+        trace!("collecting {}", src.display());
+        if !relative.as_os_str().is_empty() && exclude.is_match(&relative) {
+            trace!("excluded: {}", relative.display());
+            continue;
+        }
+
+        let metadata = disk.symlink_metadata(&src).await?;
+        let included = relative.as_os_str().is_empty()
+            || include
+                .as_ref()
+                .map(|include| include.is_match(&relative))
+                .unwrap_or(true);
+        let mode = metadata.permissions().mode();
+        let uid = metadata.uid()?;
+        let gid = metadata.gid()?;
+        let modified = metadata.modified().ok();
+
+        let kind = if metadata.is_symlink() {
+            ImportedKind::Symlink(disk.read_link(&src).await?)
+        } else if metadata.is_dir() {
+            let mut children = vec![];
+            let mut read_dir = disk.read_dir(&src).await?;
+            while let Some(entry) = read_dir.next_entry().await? {
+                children.push(entry.file_name());
+            }
+            children.sort();
+            for child in children.into_iter().rev() {
+                stack.push((src.join(&child), relative.join(&child)));
+            }
+            ImportedKind::Directory
+        } else {
+            match disk.hard_link_id(&src).await? {
+                Some(id) if links.contains_key(&id) && included => {
+                    ImportedKind::HardLink(links[&id].clone())
+                }
+                Some(id) if included => {
+                    links.insert(id, relative.clone());
+                    ImportedKind::File(disk.read(&src).await?)
+                }
+                _ if included => ImportedKind::File(disk.read(&src).await?),
+                _ => continue,
+            }
+        };
+
+        // Directories are always walked so that their children can be
+        // matched, but only kept if they match themselves. Parents of
+        // anything that's kept get created anyways.
+        if !included {
+            continue;
+        }
+
+        entries.push(ImportedEntry {
+            path: relative,
+            kind,
+            mode,
+            uid,
+            gid,
+            modified,
+        });
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::prelude::MetadataExt;
+
+    use floppy_disk::prelude::*;
+
+    use super::ImportOptions;
+    use crate::tar::TarFloppyDisk;
+    use crate::util::TempDir;
+
+    async fn make_tree(root: &TempDir) -> std::io::Result<()> {
+        let root = root.path_view();
+        tokio::fs::create_dir_all(root.join("src/nested")).await?;
+        tokio::fs::create_dir_all(root.join("empty")).await?;
+        tokio::fs::write(root.join("src/lib.rs"), "fn main() {}").await?;
+        tokio::fs::write(root.join("src/nested/a.txt"), "asdf").await?;
+        tokio::fs::write(root.join("README.md"), "# hi").await?;
+        tokio::fs::symlink("src/lib.rs", root.join("link.rs")).await?;
+        tokio::fs::hard_link(root.join("README.md"), root.join("README.copy")).await?;
+        tokio::fs::set_permissions(
+            root.join("src/lib.rs"),
+            std::os::unix::fs::PermissionsExt::from_mode(0o755),
+        )
+        .await?;
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_from_dir_works() -> std::io::Result<()> {
+        let src = TempDir::new().await?;
+        make_tree(&src).await?;
+        let out = TempDir::new().await?;
+        let archive = out.path_view().join("out.tar");
+
+        let disk = TarFloppyDisk::from_dir(&src, &archive).await?;
+        disk.close().await?;
+
+        let disk = TarFloppyDisk::open(&archive).await?;
+        assert_eq!("fn main() {}", disk.read_to_string("/src/lib.rs").await?);
+        assert_eq!("asdf", disk.read_to_string("/src/nested/a.txt").await?);
+        assert_eq!(
+            0o755,
+            disk.metadata("/src/lib.rs").await?.permissions().mode() & 0o777
+        );
+        assert!(disk.metadata("/empty").await?.is_dir());
+        assert_eq!(
+            std::path::PathBuf::from("src/lib.rs"),
+            disk.read_link("/link.rs").await?
+        );
+        assert_eq!("# hi", disk.read_to_string("/README.copy").await?);
+        assert_eq!(
+            Some(std::path::PathBuf::from("/README.copy")),
+            disk.hard_link_target("/README.md").await
+        );
+
+        let host_mtime = std::fs::metadata(src.join("src/lib.rs"))?.mtime();
+        let archive_mtime = disk
+            .metadata("/src/lib.rs")
+            .await?
+            .modified()?
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        assert_eq!(host_mtime, archive_mtime);
+        disk.close().await?;
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_import_from_globs_work() -> std::io::Result<()> {
+        let src = TempDir::new().await?;
+        make_tree(&src).await?;
+        let out = TempDir::new().await?;
+        let archive = out.path_view().join("out.tar");
+
+        let host = TokioFloppyDisk::new(None);
+        let options = ImportOptions::new()
+            .include("**/*.rs")?
+            .include("**/*.txt")?
+            .exclude("src/nested")?;
+        let disk = TarFloppyDisk::open(&archive).await?;
+        disk.import_from_with(&host, src.path_view(), "/imported", &options)
+            .await?;
+        disk.close().await?;
+
+        let disk = TarFloppyDisk::open(&archive).await?;
+        assert!(disk.try_exists("/imported/src/lib.rs").await?);
+        assert!(!disk.try_exists("/imported/src/nested/a.txt").await?);
+        assert!(!disk.try_exists("/imported/README.md").await?);
+        assert!(!disk.try_exists("/imported/empty").await?);
+        disk.close().await?;
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_import_from_another_archive_works() -> std::io::Result<()> {
+        let src = crate::util::tests::TempFile::new("./fixtures/a.tar").await?;
+        let out = TempDir::new().await?;
+        let archive = out.path_view().join("out.tar");
+
+        let source = TarFloppyDisk::open(src.path_view()).await?;
+        let disk = TarFloppyDisk::open(&archive).await?;
+        disk.import_from(&source, "/a.txt", "/copied").await?;
+        disk.close().await?;
+
+        let disk = TarFloppyDisk::open(&archive).await?;
+        assert_eq!("asdf\n", disk.read_to_string("/copied/a.txt").await?);
+        disk.close().await?;
+
+        Ok(())
+    }
+}
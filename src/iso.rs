@@ -0,0 +1,583 @@
+use std::collections::HashSet;
+use std::os::unix::fs::FileExt;
+
+use tracing::{debug, trace};
+
+crate::image::image_format!(Iso, "a.iso", IsoReader, iso_open);
+
+const SECTOR: u64 = 2048;
+const FIRST_DESCRIPTOR: u64 = 16;
+const JOLIET_ESCAPES: [&[u8]; 3] = [b"%/@", b"%/C", b"%/E"];
+
+const FLAG_DIRECTORY: u8 = 0x02;
+const FLAG_MULTI_EXTENT: u8 = 0x80;
+const S_IFMT: u32 = 0o170000;
+const S_IFLNK: u32 = 0o120000;
+
+#[derive(Debug)]
+pub(crate) struct IsoReader {
+    file: std::fs::File,
+    volume_id: String,
+    rock_ridge: bool,
+    joliet: bool,
+}
+
+#[async_trait::async_trait]
+impl ImageReader for IsoReader {
+    /// The sector and length of each extent of a file. Only files bigger
+    /// than 4GiB have more than one.
+    type Data = Vec<(u32, u32)>;
+
+    async fn read_file(&self, extents: &Self::Data, size: u64) -> Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(size as usize);
+        for &(sector, len) in extents {
+            trace!("reading extent at sector {} ({} bytes)", sector, len);
+            let start = out.len();
+            out.resize(start + len as usize, 0);
+            self.file
+                .read_exact_at(&mut out[start..], sector as u64 * SECTOR)?;
+        }
+        Ok(out)
+    }
+}
+
+impl IsoFloppyDisk {
+    /// The volume id from the primary volume descriptor.
+    pub fn volume_id(&self) -> &str {
+        &self.reader.volume_id
+    }
+
+    /// Whether names and attributes came from Rock Ridge extensions.
+    pub fn has_rock_ridge(&self) -> bool {
+        self.reader.rock_ridge
+    }
+
+    /// Whether the image has a Joliet volume descriptor. Joliet names are
+    /// only used when there are no Rock Ridge extensions.
+    pub fn has_joliet(&self) -> bool {
+        self.reader.joliet
+    }
+}
+
+async fn iso_open(path: &Path) -> Result<(IsoReader, ImageTree<IsoData>)> {
+    debug!("opening iso {}", path.display());
+    let file = std::fs::File::open(path)?;
+
+    let mut primary = None;
+    let mut joliet = None;
+    for idx in FIRST_DESCRIPTOR.. {
+        let descriptor = read_sectors(&file, idx, 1)?;
+        if &descriptor[1..6] != b"CD001" {
+            return Err(invalid("invalid iso volume descriptor"));
+        }
+        match descriptor[0] {
+            1 if primary.is_none() => primary = Some(descriptor),
+            2 if JOLIET_ESCAPES.contains(&&descriptor[88..91]) => joliet = Some(descriptor),
+            255 => break,
+            kind => trace!("skipping volume descriptor type {}", kind),
+        }
+    }
+    let primary = primary.ok_or_else(|| invalid("iso has no primary volume descriptor"))?;
+    let volume_id = String::from_utf8_lossy(&primary[40..72]).trim().to_string();
+
+    let root = Record::parse(&primary[156..190], 0)?;
+    let root_dir = read_sectors(&file, root.extents[0].0 as u64, sectors(root.size))?;
+    let dot = Record::parse(&root_dir, 0)?;
+    let susp = match dot.system_use.get(..7) {
+        // The SP entry says how many bytes to skip at the start of every
+        // other system use area.
+        Some([b'S', b'P', 7, 1, 0xbe, 0xef, skip]) => Some(*skip as usize),
+        _ => None,
+    };
+
+    let (mode, root_record) = match (susp, &joliet) {
+        (Some(_), _) => (Mode::RockRidge, root),
+        (None, Some(joliet)) => (Mode::Joliet, Record::parse(&joliet[156..190], 0)?),
+        (None, None) => (Mode::Plain, root),
+    };
+    debug!("reading iso tree as {:?}", mode);
+
+    let reader = IsoReader {
+        file,
+        volume_id,
+        rock_ridge: mode == Mode::RockRidge,
+        joliet: joliet.is_some(),
+    };
+    let walker = Walker {
+        file: &reader.file,
+        mode,
+        skip: susp.unwrap_or(0),
+    };
+    let tree = walker.walk(&root_record)?;
+
+    Ok((reader, tree))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Plain,
+    Joliet,
+    RockRidge,
+}
+
+struct Walker<'a> {
+    file: &'a std::fs::File,
+    mode: Mode,
+    skip: usize,
+}
+
+impl Walker<'_> {
+    fn walk(&self, root: &Record) -> Result<ImageTree<IsoData>> {
+        let root_sector = root.extents[0].0;
+        let records = self.read_dir(root_sector, root.size)?;
+        let mut root_entry = ImageEntry::directory(0o555);
+        if let Some(dot) = records.first() {
+            let rr = self.rock_ridge(dot)?;
+            self.apply(&mut root_entry, dot, &rr);
+        }
+        let mut tree = ImageTree::new(root_entry);
+
+        let mut visited = HashSet::from([root_sector]);
+        let mut pending = vec![(PathBuf::from("/"), records)];
+        while let Some((dir, records)) = pending.pop() {
+            let mut records = records.into_iter().skip(2);
+            while let Some(mut record) = records.next() {
+                // Multi-extent files are several records with the same name,
+                // all but the last flagged.
+                while record.flags & FLAG_MULTI_EXTENT != 0 {
+                    let Some(next) = records.next() else {
+                        return Err(invalid("unterminated multi-extent file"));
+                    };
+                    record.extents.extend(next.extents);
+                    record.size += next.size;
+                    record.flags = next.flags;
+                }
+
+                let rr = self.rock_ridge(&record)?;
+                if rr.relocated {
+                    trace!("skipping relocated directory");
+                    continue;
+                }
+                let name = match &rr.name {
+                    Some(name) => name.clone(),
+                    None => self.name(&record.name),
+                };
+                let path = dir.join(&name);
+                trace!("found {}", path.display());
+
+                let mut entry = if let Some(target) = &rr.symlink {
+                    ImageEntry {
+                        kind: ImageKind::Symlink(PathBuf::from(target)),
+                        size: target.len() as u64,
+                        ..ImageEntry::directory(0o777)
+                    }
+                } else if record.flags & FLAG_DIRECTORY != 0 || rr.child.is_some() {
+                    // Rock Ridge moves directories nested too deeply into
+                    // `rr_moved`, and leaves a link to them behind.
+                    let (sector, size) = match rr.child {
+                        Some(sector) => {
+                            let data = read_sectors(self.file, sector as u64, 1)?;
+                            let dot = Record::parse(&data, 0)?;
+                            (dot.extents[0].0, dot.size)
+                        }
+                        None => (record.extents[0].0, record.size),
+                    };
+                    if visited.insert(sector) {
+                        pending.push((path.clone(), self.read_dir(sector, size)?));
+                    }
+                    ImageEntry {
+                        ino: sector as u64,
+                        ..ImageEntry::directory(0o555)
+                    }
+                } else {
+                    ImageEntry {
+                        kind: ImageKind::File(record.extents.clone()),
+                        size: record.size,
+                        ino: record.extents[0].0 as u64,
+                        ..ImageEntry::directory(0o444)
+                    }
+                };
+                self.apply(&mut entry, &record, &rr);
+                tree.insert(path, entry);
+            }
+        }
+
+        Ok(tree)
+    }
+
+    fn read_dir(&self, sector: u32, size: u64) -> Result<Vec<Record>> {
+        let data = read_sectors(self.file, sector as u64, sectors(size))?;
+        let data = &data[..size as usize];
+        let mut records = vec![];
+        let mut offset = 0;
+        while offset < data.len() {
+            if data[offset] == 0 {
+                // Records never cross sectors, so the rest of this one is
+                // padding.
+                offset = (offset / SECTOR as usize + 1) * SECTOR as usize;
+                continue;
+            }
+            let record = Record::parse(data, offset)?;
+            offset += record.len;
+            records.push(record);
+        }
+        Ok(records)
+    }
+
+    fn name(&self, raw: &[u8]) -> String {
+        let name = match self.mode {
+            Mode::Joliet => {
+                let units: Vec<u16> = raw
+                    .chunks_exact(2)
+                    .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                    .collect();
+                String::from_utf16_lossy(&units)
+            }
+            _ => String::from_utf8_lossy(raw).to_string(),
+        };
+        let name = match name.rsplit_once(';') {
+            Some((name, _version)) => name,
+            None => &name,
+        };
+        // `FOO.` is how ISO 9660 spells a file without an extension.
+        match name.strip_suffix('.') {
+            Some(stripped) if !stripped.is_empty() => stripped.to_string(),
+            _ => name.to_string(),
+        }
+    }
+
+    fn rock_ridge(&self, record: &Record) -> Result<RockRidge> {
+        let mut rr = RockRidge::default();
+        if self.mode != Mode::RockRidge {
+            return Ok(rr);
+        }
+        let mut areas = vec![record
+            .system_use
+            .get(self.skip..)
+            .unwrap_or_default()
+            .to_vec()];
+        let mut symlink = SymlinkBuilder::default();
+        let mut name_done = false;
+        while let Some(area) = areas.pop() {
+            let mut offset = 0;
+            while offset + 4 <= area.len() {
+                let len = area[offset + 2] as usize;
+                if len < 4 || offset + len > area.len() {
+                    break;
+                }
+                let entry = &area[offset..offset + len];
+                match &entry[..2] {
+                    b"CE" if len >= 28 => {
+                        let sector = le_u32(entry, 4);
+                        let at = le_u32(entry, 12) as usize;
+                        let size = le_u32(entry, 20) as usize;
+                        let data =
+                            read_sectors(self.file, sector as u64, sectors((at + size) as u64))?;
+                        areas.push(data[at..at + size].to_vec());
+                    }
+                    b"PX" if len >= 36 => {
+                        rr.mode = Some(le_u32(entry, 4));
+                        rr.links = le_u32(entry, 12);
+                        rr.uid = Some(le_u32(entry, 20));
+                        rr.gid = Some(le_u32(entry, 28));
+                        if len >= 44 {
+                            rr.ino = Some(le_u32(entry, 36) as u64);
+                        }
+                    }
+                    b"NM" if len >= 5 => {
+                        let flags = entry[4];
+                        // Flags 2 and 4 mean `.` and `..`.
+                        if flags & 0x06 == 0 && !name_done {
+                            rr.name
+                                .get_or_insert_with(String::new)
+                                .push_str(&String::from_utf8_lossy(&entry[5..]));
+                            name_done = flags & 0x01 == 0;
+                        }
+                    }
+                    b"SL" if len >= 5 => symlink.push(&entry[5..]),
+                    b"TF" if len >= 5 => rr.mtime = parse_tf(entry),
+                    b"CL" if len >= 12 => rr.child = Some(le_u32(entry, 4)),
+                    b"RE" => rr.relocated = true,
+                    b"ST" => break,
+                    _ => {}
+                }
+                offset += len;
+            }
+        }
+        rr.symlink = symlink.finish();
+        if rr.mode.map(|mode| mode & S_IFMT) == Some(S_IFLNK) && rr.symlink.is_none() {
+            rr.symlink = Some(String::new());
+        }
+        Ok(rr)
+    }
+
+    fn apply(&self, entry: &mut ImageEntry<IsoData>, record: &Record, rr: &RockRidge) {
+        entry.mtime = rr.mtime.unwrap_or(record.mtime);
+        if let Some(mode) = rr.mode {
+            entry.mode = mode & 0o7777;
+        }
+        entry.uid = rr.uid.unwrap_or(0);
+        entry.gid = rr.gid.unwrap_or(0);
+        if let Some(ino) = rr.ino {
+            entry.ino = ino;
+            if rr.links > 1 && matches!(entry.kind, ImageKind::File(_)) {
+                entry.link_id = Some(ino);
+            }
+        }
+    }
+}
+
+/// A directory record, with multi-extent files not yet joined.
+#[derive(Debug)]
+struct Record {
+    len: usize,
+    extents: Vec<(u32, u32)>,
+    size: u64,
+    mtime: SystemTime,
+    flags: u8,
+    name: Vec<u8>,
+    system_use: Vec<u8>,
+}
+
+impl Record {
+    fn parse(data: &[u8], offset: usize) -> Result<Record> {
+        let len = *data
+            .get(offset)
+            .ok_or_else(|| invalid("truncated iso record"))? as usize;
+        let record = data
+            .get(offset..offset + len)
+            .filter(|record| record.len() >= 34)
+            .ok_or_else(|| invalid("truncated iso record"))?;
+        let name_len = record[32] as usize;
+        let name = record
+            .get(33..33 + name_len)
+            .ok_or_else(|| invalid("truncated iso record name"))?;
+        // The name is padded to an even length.
+        let system_use_start = 33 + name_len + (1 - name_len % 2);
+        let size = le_u32(record, 10);
+        Ok(Self {
+            len,
+            extents: vec![(le_u32(record, 2), size)],
+            size: size as u64,
+            mtime: parse_date7(&record[18..25]),
+            flags: record[25],
+            name: name.to_vec(),
+            system_use: record.get(system_use_start..).unwrap_or_default().to_vec(),
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+struct RockRidge {
+    name: Option<String>,
+    symlink: Option<String>,
+    mode: Option<u32>,
+    links: u32,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    ino: Option<u64>,
+    mtime: Option<SystemTime>,
+    child: Option<u32>,
+    relocated: bool,
+}
+
+/// Builds a symlink target out of the component records of `SL` entries,
+/// which can be split across several entries.
+#[derive(Debug, Default)]
+struct SymlinkBuilder {
+    absolute: bool,
+    components: Vec<String>,
+    continuing: bool,
+    seen: bool,
+}
+
+impl SymlinkBuilder {
+    fn push(&mut self, mut records: &[u8]) {
+        self.seen = true;
+        while records.len() >= 2 {
+            let (flags, len) = (records[0], records[1] as usize);
+            let content = records.get(2..2 + len).unwrap_or_default();
+            records = records.get(2 + len..).unwrap_or_default();
+
+            let text = if flags & 0x08 != 0 {
+                self.absolute = true;
+                continue;
+            } else if flags & 0x02 != 0 {
+                ".".to_string()
+            } else if flags & 0x04 != 0 {
+                "..".to_string()
+            } else {
+                String::from_utf8_lossy(content).to_string()
+            };
+            match self.components.last_mut() {
+                Some(last) if self.continuing => last.push_str(&text),
+                _ => self.components.push(text),
+            }
+            self.continuing = flags & 0x01 != 0;
+        }
+    }
+
+    fn finish(self) -> Option<String> {
+        if !self.seen {
+            return None;
+        }
+        let target = self.components.join("/");
+        Some(match self.absolute {
+            true => format!("/{target}"),
+            false => target,
+        })
+    }
+}
+
+/// Parses the modification time out of a `TF` entry.
+fn parse_tf(entry: &[u8]) -> Option<SystemTime> {
+    let flags = entry[4];
+    if flags & 0x02 == 0 {
+        return None;
+    }
+    let size = if flags & 0x80 != 0 { 17 } else { 7 };
+    // Creation time comes first, if it's there.
+    let start = 5 + if flags & 0x01 != 0 { size } else { 0 };
+    let stamp = entry.get(start..start + size)?;
+    match size {
+        7 => Some(parse_date7(stamp)),
+        _ => parse_date17(stamp),
+    }
+}
+
+fn parse_date7(date: &[u8]) -> SystemTime {
+    let time = crate::util::civil_to_system_time(
+        1900 + date[0] as i64,
+        date[1] as u32,
+        date[2] as u32,
+        date[3] as u32,
+        date[4] as u32,
+        date[5] as u32,
+    );
+    apply_offset(time, date[6] as i8)
+}
+
+fn parse_date17(date: &[u8]) -> Option<SystemTime> {
+    let digits = std::str::from_utf8(&date[..16]).ok()?;
+    let field = |range: std::ops::Range<usize>| digits.get(range)?.parse::<u32>().ok();
+    let year = field(0..4)?;
+    if year == 0 {
+        return None;
+    }
+    let time = crate::util::civil_to_system_time(
+        year as i64,
+        field(4..6)?,
+        field(6..8)?,
+        field(8..10)?,
+        field(10..12)?,
+        field(12..14)?,
+    );
+    Some(apply_offset(time, date[16] as i8))
+}
+
+/// Dates are local time, with an offset from UTC in 15 minute intervals.
+fn apply_offset(time: SystemTime, offset: i8) -> SystemTime {
+    let delta = std::time::Duration::from_secs(offset.unsigned_abs() as u64 * 15 * 60);
+    if offset >= 0 {
+        time - delta
+    } else {
+        time + delta
+    }
+}
+
+fn read_sectors(file: &std::fs::File, sector: u64, count: u64) -> Result<Vec<u8>> {
+    let mut data = vec![0; (count * SECTOR) as usize];
+    file.read_exact_at(&mut data, sector * SECTOR)?;
+    Ok(data)
+}
+
+fn sectors(size: u64) -> u64 {
+    size.div_ceil(SECTOR).max(1)
+}
+
+fn le_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+fn invalid(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_log::test(tokio::test)]
+    async fn test_rock_ridge_works() -> Result<()> {
+        let disk = IsoFloppyDisk::open("./fixtures/a.iso").await?;
+        assert_eq!("FLOP", disk.volume_id());
+        assert!(disk.has_rock_ridge());
+        assert!(disk.has_joliet());
+
+        assert_eq!(
+            "long\n",
+            disk.read_to_string("/dir/A Long File Name With Spaces.txt")
+                .await?
+        );
+        let script = disk.metadata("/dir/script.sh").await?;
+        assert_eq!(0o755, script.permissions().mode());
+        assert_eq!(1000, script.uid()?);
+        assert_eq!(100, script.gid()?);
+        assert_eq!(
+            std::time::UNIX_EPOCH + std::time::Duration::from_secs(1687000000),
+            script.modified()?
+        );
+
+        assert!(disk.symlink_metadata("/link").await?.is_symlink());
+        assert_eq!(
+            PathBuf::from("dir/script.sh"),
+            disk.read_link("/link").await?
+        );
+        assert_eq!("#!/bin/sh\necho hi\n", disk.read_to_string("/link").await?);
+
+        let multi = disk.read("/multi.bin").await?;
+        assert_eq!(3000, multi.len());
+        assert!(multi.iter().enumerate().all(|(i, b)| *b == (i % 251) as u8));
+
+        let mut names = vec![];
+        let mut read_dir = disk.read_dir("/dir").await?;
+        while let Some(entry) = read_dir.next_entry().await? {
+            names.push(entry.file_name());
+        }
+        assert_eq!(
+            vec![
+                OsString::from("A Long File Name With Spaces.txt"),
+                OsString::from("script.sh")
+            ],
+            names
+        );
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_joliet_works() -> Result<()> {
+        let disk = IsoFloppyDisk::open("./fixtures/a.joliet.iso").await?;
+        assert!(!disk.has_rock_ridge());
+        assert!(disk.has_joliet());
+
+        assert_eq!("asdf\n", disk.read_to_string("/a.txt").await?);
+        assert_eq!(
+            "long\n",
+            disk.read_to_string("/dir/A Long File Name With Spaces.txt")
+                .await?
+        );
+        // Without Rock Ridge, everything is read-only and owned by root.
+        let script = disk.metadata("/dir/script.sh").await?;
+        assert_eq!(0o444, script.permissions().mode());
+        assert_eq!(0, script.uid()?);
+        assert!(!disk.try_exists("/link").await?);
+
+        Ok(())
+    }
+}
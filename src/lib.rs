@@ -2,20 +2,87 @@ pub mod prelude {
     pub mod ar {
         pub use crate::ar::*;
     }
+    pub mod compressed {
+        pub use crate::compressed::*;
+    }
     pub mod cpio {
         pub use crate::cpio::*;
     }
+    pub mod convert {
+        pub use crate::convert::*;
+    }
+    pub mod deb {
+        pub use crate::deb::*;
+    }
+    pub mod diff {
+        pub use crate::diff::*;
+    }
+    pub mod ext4 {
+        pub use crate::ext4::*;
+    }
+    pub mod fat {
+        pub use crate::fat::*;
+    }
+    pub mod format {
+        pub use crate::format::*;
+    }
+    pub mod import {
+        pub use crate::import::*;
+    }
+    pub mod iso {
+        pub use crate::iso::*;
+    }
+    pub mod oci {
+        pub use crate::oci::*;
+    }
+    pub mod overlay {
+        pub use crate::overlay::*;
+    }
+    pub mod rpm {
+        pub use crate::rpm::*;
+    }
+    pub mod sevenz {
+        pub use crate::sevenz::*;
+    }
+    pub mod squashfs {
+        pub use crate::squashfs::*;
+    }
     pub mod tar {
         pub use crate::tar::*;
     }
+    pub mod xar {
+        pub use crate::xar::*;
+    }
     pub mod zip {
         pub use crate::zip::*;
     }
 }
 
 pub mod ar;
+#[cfg(feature = "cli")]
+pub mod cli;
+pub mod compressed;
+pub mod convert;
 pub mod cpio;
+pub mod deb;
+pub mod diff;
+pub mod ext4;
+pub mod fat;
+pub mod format;
+pub(crate) mod image;
+pub mod import;
+pub mod iso;
+pub mod oci;
+pub mod overlay;
+pub mod rpm;
+pub mod sevenz;
+pub mod squashfs;
 pub mod tar;
+pub mod xar;
 pub mod zip;
 
 pub(crate) mod util;
+
+pub use convert::convert;
+pub use diff::diff;
+pub use format::ArchiveFormat;
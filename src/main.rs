@@ -0,0 +1,16 @@
+use clap::Parser;
+use tracing_subscriber::EnvFilter;
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .with_writer(std::io::stderr)
+        .init();
+
+    let cli = flop::cli::Cli::parse();
+    if let Err(e) = flop::cli::run(cli, &mut std::io::stdout().lock()).await {
+        eprintln!("flop: {e}");
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,767 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Result;
+use std::path::{Component, Path, PathBuf};
+
+use floppy_disk::mem::MemFloppyDisk;
+use floppy_disk::prelude::*;
+use indexmap::{IndexMap, IndexSet};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use smoosh::CompressionType;
+use tracing::debug;
+
+use crate::diff::EntryInfo;
+use crate::import::ImportOptions;
+use crate::overlay::OverlayFloppyDisk;
+use crate::tar::{TarFloppyDisk, TarInternalMetadata, TarPermissions};
+
+const INDEX: &str = "index.json";
+const DOCKER_MANIFEST: &str = "manifest.json";
+const DOCKER_MANIFEST_TYPE: &str = "application/vnd.docker.distribution.manifest.v2+json";
+
+/// A container image, either as an OCI image layout directory or as a tar
+/// of one, like `docker save` writes. Older `docker save` tars that only
+/// have a `manifest.json` work too.
+///
+/// Each layer is a [`TarFloppyDisk`] that can be changed in place, and
+/// [`OciImage::rootfs`] merges them all. Only the first image in the index
+/// is used.
+///
+/// On close, changed and new layers are written out as new blobs, and the
+/// config, manifest and index are rewritten to point at them. Blobs that
+/// nothing points at anymore are removed, unless the index has other images
+/// in it that might still need them.
+#[derive(Debug)]
+pub struct OciImage {
+    store: Store,
+    index: Option<Value>,
+    docker: Option<Value>,
+    manifest: Option<Value>,
+    manifest_path: Option<String>,
+    config: Value,
+    /// The config as it was read, to tell if it needs writing.
+    original_config: Value,
+    config_path: String,
+    layers: Vec<TarFloppyDisk>,
+    sources: Vec<Option<LayerSource>>,
+    /// Whether layers are stored as `<id>/layer.tar`, like docker did before
+    /// it switched to the OCI layout.
+    legacy: bool,
+}
+
+/// Where a layer was read from, and what it looked like at the time.
+#[derive(Debug)]
+struct LayerSource {
+    path: String,
+    descriptor: Option<Value>,
+    entries: BTreeMap<PathBuf, EntryInfo>,
+}
+
+impl OciImage {
+    /// Opens the image layout directory or image tar at `path`.
+    pub async fn open<P: AsRef<Path>>(path: P) -> Result<OciImage> {
+        let path = path.as_ref();
+        let store = if tokio::fs::metadata(path).await?.is_dir() {
+            debug!("opening image layout directory {}", path.display());
+            Store::Dir(path.to_path_buf())
+        } else {
+            debug!("opening image tar {}", path.display());
+            Store::Tar(Box::new(TarFloppyDisk::open(path).await?))
+        };
+
+        let index = match store.exists(INDEX).await {
+            true => Some(parse_json(&store.read(INDEX).await?)?),
+            false => None,
+        };
+        let docker = match store.exists(DOCKER_MANIFEST).await {
+            true => Some(parse_json(&store.read(DOCKER_MANIFEST).await?)?),
+            false => None,
+        };
+
+        let (manifest, manifest_path, config_path, layer_paths) = if let Some(index) = &index {
+            let descriptor = index["manifests"]
+                .get(0)
+                .ok_or_else(|| invalid("image index has no manifests"))?;
+            if descriptor["mediaType"]
+                .as_str()
+                .unwrap_or_default()
+                .ends_with("index.v1+json")
+            {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    "nested image indexes are not supported",
+                ));
+            }
+            let manifest_path = blob_path(descriptor)?;
+            let manifest = parse_json(&read_blob(&store, descriptor).await?)?;
+            let config_path = blob_path(&manifest["config"])?;
+            let layer_paths = descriptors(&manifest["layers"])
+                .iter()
+                .map(blob_path)
+                .collect::<Result<Vec<_>>>()?;
+            (
+                Some(manifest),
+                Some(manifest_path),
+                config_path,
+                layer_paths,
+            )
+        } else if let Some(docker) = &docker {
+            let image = docker
+                .get(0)
+                .ok_or_else(|| invalid("manifest.json has no images"))?;
+            let config_path = image["Config"]
+                .as_str()
+                .ok_or_else(|| invalid("manifest.json has no Config"))
+                .and_then(layout_path)?;
+            let layer_paths = descriptors(&image["Layers"])
+                .iter()
+                .map(|path| {
+                    path.as_str()
+                        .ok_or_else(|| invalid("manifest.json has an invalid layer"))
+                        .and_then(layout_path)
+                })
+                .collect::<Result<Vec<_>>>()?;
+            (None, None, config_path, layer_paths)
+        } else {
+            return Err(invalid("neither index.json nor manifest.json exist"));
+        };
+
+        let config = match &manifest {
+            Some(manifest) => read_blob(&store, &manifest["config"]).await?,
+            None => store.read(&config_path).await?,
+        };
+        let config = parse_json(&config)?;
+
+        let mut layers = vec![];
+        let mut sources = vec![];
+        for (idx, path) in layer_paths.iter().enumerate() {
+            debug!("loading layer {}", path);
+            let descriptor = manifest
+                .as_ref()
+                .and_then(|manifest| manifest["layers"].get(idx))
+                .cloned();
+            let data = match &descriptor {
+                Some(descriptor) => read_blob(&store, descriptor).await?,
+                None => store.read(path).await?,
+            };
+            let metadata = crate::tar::tar_read(&data).await?;
+            let layer = TarFloppyDisk::from_metadata(PathBuf::from(path), metadata);
+            sources.push(Some(LayerSource {
+                path: path.clone(),
+                descriptor,
+                entries: crate::diff::entries(&layer).await?,
+            }));
+            layers.push(layer);
+        }
+
+        let legacy = !layer_paths.iter().all(|path| path.starts_with("blobs/"));
+
+        Ok(Self {
+            store,
+            index,
+            docker,
+            manifest,
+            manifest_path,
+            original_config: config.clone(),
+            config,
+            config_path,
+            layers,
+            sources,
+            legacy,
+        })
+    }
+
+    /// The layers, bottom first.
+    pub fn layers(&self) -> &[TarFloppyDisk] {
+        &self.layers
+    }
+
+    /// The image config, ie. the entrypoint, environment, history, etc.
+    pub fn config(&self) -> &Value {
+        &self.config
+    }
+
+    /// Changes to the config are written on close. `rootfs.diff_ids` is
+    /// always rewritten to match the layers.
+    pub fn config_mut(&mut self) -> &mut Value {
+        &mut self.config
+    }
+
+    /// The tags the image was saved with, if any.
+    pub fn repo_tags(&self) -> Vec<String> {
+        if let Some(docker) = &self.docker {
+            return descriptors(&docker[0]["RepoTags"])
+                .iter()
+                .filter_map(|tag| tag.as_str().map(|tag| tag.to_string()))
+                .collect();
+        }
+        self.index
+            .as_ref()
+            .and_then(|index| {
+                index["manifests"][0]["annotations"]["io.containerd.image.name"]
+                    .as_str()
+                    .or(
+                        index["manifests"][0]["annotations"]["org.opencontainers.image.ref.name"]
+                            .as_str(),
+                    )
+            })
+            .map(|tag| vec![tag.to_string()])
+            .unwrap_or_default()
+    }
+
+    /// Merges a copy of every layer into one filesystem. Changes to it don't
+    /// affect the image; write them to a new layer with
+    /// [`OverlayFloppyDisk::write_diff_layer`] and [`OciImage::append_layer`]
+    /// to keep them.
+    pub async fn rootfs(&self) -> Result<OverlayFloppyDisk> {
+        let mut layers = vec![];
+        for layer in &self.layers {
+            let copy = empty_layer(PathBuf::new(), CompressionType::None);
+            let entries =
+                crate::import::collect(layer, Path::new("/"), &ImportOptions::new()).await?;
+            copy.import_entries(Path::new("/"), entries).await?;
+            layers.push(copy);
+        }
+        OverlayFloppyDisk::new(layers).await
+    }
+
+    /// Adds an empty layer on top of the others and returns it.
+    pub fn new_layer(&mut self) -> &TarFloppyDisk {
+        let compression = match self.legacy {
+            true => CompressionType::None,
+            false => CompressionType::Gzip,
+        };
+        self.append_layer(empty_layer(PathBuf::new(), compression))
+    }
+
+    /// Adds `layer` on top of the others. It's written with whatever
+    /// compression it was opened with.
+    pub fn append_layer(&mut self, layer: TarFloppyDisk) -> &TarFloppyDisk {
+        self.layers.push(layer);
+        self.sources.push(None);
+        self.layers.last().unwrap()
+    }
+
+    /// Writes changed and new layers, then the config, manifest and index
+    /// that point at them. Nothing is rewritten if neither the layers nor
+    /// the config changed.
+    pub async fn close(mut self) -> Result<()> {
+        let mut changed = self.config != self.original_config;
+        // Layers can share a blob, so it's only removed once.
+        let mut obsolete = IndexSet::new();
+        let mut paths = vec![];
+        let mut layer_descriptors = vec![];
+        let mut diff_ids = vec![];
+        let old_diff_ids = descriptors(&self.config["rootfs"]["diff_ids"]);
+
+        for (idx, layer) in self.layers.iter().enumerate() {
+            if let Some(source) = &self.sources[idx] {
+                if crate::diff::entries(layer).await? == source.entries {
+                    paths.push(source.path.clone());
+                    layer_descriptors.push(source.descriptor.clone());
+                    diff_ids.push(
+                        old_diff_ids
+                            .get(idx)
+                            .cloned()
+                            .ok_or_else(|| invalid("config is missing a diff_id"))?,
+                    );
+                    continue;
+                }
+                obsolete.insert(source.path.clone());
+            }
+            changed = true;
+
+            debug!("writing layer {}", idx);
+            let data = crate::tar::tar_write(layer).await?;
+            let uncompressed = if matches!(layer.compression(), CompressionType::None) {
+                data.clone()
+            } else {
+                let mut out = vec![];
+                smoosh::recompress(&mut data.as_slice(), &mut out, CompressionType::None).await?;
+                out
+            };
+            let diff_id = digest(&uncompressed);
+
+            let path = if self.legacy {
+                let path = format!("{}/layer.tar", &diff_id["sha256:".len()..]);
+                self.store.write(&path, &uncompressed).await?;
+                path
+            } else {
+                let path = format!("blobs/sha256/{}", &digest(&data)["sha256:".len()..]);
+                self.store.write(&path, &data).await?;
+                path
+            };
+
+            let media_type = match self.sources[idx]
+                .as_ref()
+                .and_then(|source| source.descriptor.as_ref())
+            {
+                Some(descriptor) => descriptor["mediaType"].clone(),
+                None => Value::from(self.layer_media_type(layer)?),
+            };
+            layer_descriptors.push(Some(json!({
+                "mediaType": media_type,
+                "digest": digest(&data),
+                "size": data.len(),
+            })));
+            paths.push(path);
+            diff_ids.push(Value::from(diff_id));
+
+            if self.sources[idx].is_none() {
+                if let Some(history) = self.config["history"].as_array_mut() {
+                    history.push(json!({ "created_by": "flop" }));
+                }
+            }
+        }
+
+        if !changed {
+            debug!("image is unchanged");
+            return self.store.close().await;
+        }
+
+        self.config["rootfs"]["diff_ids"] = Value::Array(diff_ids);
+        let config = serde_json::to_vec(&self.config)?;
+        let config_path = match self.legacy {
+            true => format!("{}.json", &digest(&config)["sha256:".len()..]),
+            false => format!("blobs/sha256/{}", &digest(&config)["sha256:".len()..]),
+        };
+        self.store.write(&config_path, &config).await?;
+        obsolete.insert(self.config_path.clone());
+
+        if let (Some(manifest), Some(index)) = (&mut self.manifest, &mut self.index) {
+            manifest["config"]["digest"] = Value::from(digest(&config));
+            manifest["config"]["size"] = Value::from(config.len());
+            manifest["layers"] = Value::Array(layer_descriptors.into_iter().flatten().collect());
+            let data = serde_json::to_vec(&*manifest)?;
+            let path = format!("blobs/sha256/{}", &digest(&data)["sha256:".len()..]);
+            self.store.write(&path, &data).await?;
+            obsolete.extend(self.manifest_path.clone());
+
+            index["manifests"][0]["digest"] = Value::from(digest(&data));
+            index["manifests"][0]["size"] = Value::from(data.len());
+            self.store
+                .write(INDEX, &serde_json::to_vec(&*index)?)
+                .await?;
+        }
+
+        if let Some(docker) = &mut self.docker {
+            docker[0]["Config"] = Value::from(config_path.clone());
+            docker[0]["Layers"] = Value::from(paths.clone());
+            self.store
+                .write(DOCKER_MANIFEST, &serde_json::to_vec(&*docker)?)
+                .await?;
+        }
+
+        let shared = match &self.index {
+            Some(index) => descriptors(&index["manifests"]).len() > 1,
+            None => false,
+        } || descriptors(self.docker.as_ref().unwrap_or(&Value::Null)).len() > 1;
+        if !shared {
+            for path in obsolete {
+                if path != config_path && !paths.contains(&path) {
+                    debug!("removing unused blob {}", path);
+                    self.store.remove(&path).await?;
+                }
+            }
+        }
+
+        self.store.close().await
+    }
+
+    fn layer_media_type(&self, layer: &TarFloppyDisk) -> Result<String> {
+        let docker = self
+            .manifest
+            .as_ref()
+            .map(|manifest| manifest["mediaType"] == DOCKER_MANIFEST_TYPE)
+            .unwrap_or(false);
+        let compression = layer.compression();
+        let media_type = match (docker, compression) {
+            (true, CompressionType::None) => "application/vnd.docker.image.rootfs.diff.tar",
+            (true, CompressionType::Gzip) => "application/vnd.docker.image.rootfs.diff.tar.gzip",
+            (false, CompressionType::None) => "application/vnd.oci.image.layer.v1.tar",
+            (false, CompressionType::Gzip) => "application/vnd.oci.image.layer.v1.tar+gzip",
+            (false, CompressionType::Zstd) => "application/vnd.oci.image.layer.v1.tar+zstd",
+            (_, compression) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    format!("{compression:?} layers are not supported"),
+                ))
+            }
+        };
+        Ok(media_type.to_string())
+    }
+}
+
+/// Where the files of an image live.
+#[derive(Debug)]
+enum Store {
+    Dir(PathBuf),
+    Tar(Box<TarFloppyDisk>),
+}
+
+impl Store {
+    async fn exists(&self, path: &str) -> bool {
+        match self {
+            Store::Dir(root) => crate::util::exists_async(root.join(path)).await,
+            Store::Tar(tar) => tar
+                .try_exists(Path::new("/").join(path))
+                .await
+                .unwrap_or(false),
+        }
+    }
+
+    async fn read(&self, path: &str) -> Result<Vec<u8>> {
+        match self {
+            Store::Dir(root) => tokio::fs::read(root.join(path)).await,
+            Store::Tar(tar) => tar.read(Path::new("/").join(path)).await,
+        }
+    }
+
+    async fn write(&self, path: &str, data: &[u8]) -> Result<()> {
+        match self {
+            Store::Dir(root) => {
+                let path = root.join(path);
+                if let Some(parent) = path.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                tokio::fs::write(path, data).await
+            }
+            Store::Tar(tar) => {
+                let path = Path::new("/").join(path);
+                if let Some(parent) = path.parent() {
+                    tar.create_dir_all(parent).await?;
+                }
+                tar.write(&path, data).await?;
+                tar.set_permissions(&path, TarPermissions::from_mode(0o644))
+                    .await?;
+                tar.chown(&path, 0, 0).await
+            }
+        }
+    }
+
+    async fn remove(&self, path: &str) -> Result<()> {
+        match self {
+            Store::Dir(root) => tokio::fs::remove_file(root.join(path)).await,
+            Store::Tar(tar) => tar.remove_file(Path::new("/").join(path)).await,
+        }
+    }
+
+    async fn close(self) -> Result<()> {
+        match self {
+            Store::Dir(_) => Ok(()),
+            Store::Tar(tar) => tar.close().await,
+        }
+    }
+}
+
+fn empty_layer(path: PathBuf, compression: CompressionType) -> TarFloppyDisk {
+    TarFloppyDisk::from_metadata(
+        path,
+        TarInternalMetadata {
+            delegate: MemFloppyDisk::new(),
+            compression,
+            ordered_paths: IndexSet::new(),
+            times: HashMap::new(),
+            hard_links: IndexMap::new(),
+            state: Default::default(),
+        },
+    )
+}
+
+/// Reads the blob that `descriptor` points at, checking its digest.
+async fn read_blob(store: &Store, descriptor: &Value) -> Result<Vec<u8>> {
+    let path = blob_path(descriptor)?;
+    let data = store.read(&path).await?;
+    let expected = descriptor["digest"].as_str().unwrap_or_default();
+    if expected.starts_with("sha256:") && digest(&data) != expected {
+        return Err(invalid(&format!("digest mismatch for {path}")));
+    }
+    Ok(data)
+}
+
+fn blob_path(descriptor: &Value) -> Result<String> {
+    let digest = descriptor["digest"]
+        .as_str()
+        .ok_or_else(|| invalid("descriptor has no digest"))?;
+    // Digests become paths in the layout, so anything that isn't a plain
+    // name could point outside of it.
+    match digest.split_once(':') {
+        Some((algorithm, hex))
+            if !algorithm.is_empty()
+                && !hex.is_empty()
+                && algorithm
+                    .bytes()
+                    .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit())
+                && hex.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) =>
+        {
+            Ok(format!("blobs/{algorithm}/{hex}"))
+        }
+        _ => Err(invalid(&format!("invalid digest: {digest}"))),
+    }
+}
+
+/// Checks that a path from `manifest.json` stays inside the image, since
+/// it's read, written and removed relative to it.
+fn layout_path(path: &str) -> Result<String> {
+    let valid = !path.is_empty()
+        && Path::new(path)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+    match valid {
+        true => Ok(path.to_string()),
+        false => Err(invalid(&format!("invalid path in manifest.json: {path}"))),
+    }
+}
+
+fn descriptors(value: &Value) -> Vec<Value> {
+    value.as_array().cloned().unwrap_or_default()
+}
+
+fn digest(data: &[u8]) -> String {
+    let hash = Sha256::digest(data);
+    let hex: String = hash.iter().map(|b| format!("{b:02x}")).collect();
+    format!("sha256:{hex}")
+}
+
+fn parse_json(data: &[u8]) -> Result<Value> {
+    serde_json::from_slice(data).map_err(|e| invalid(&e.to_string()))
+}
+
+fn invalid(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use floppy_disk::prelude::*;
+    use serde_json::Value;
+
+    use super::OciImage;
+    use crate::tar::TarFloppyDisk;
+    use crate::util::tests::TempFile;
+    use crate::util::TempDir;
+
+    #[test_log::test(tokio::test)]
+    async fn test_read_works() -> std::io::Result<()> {
+        let image = OciImage::open("./fixtures/a.oci.tar").await?;
+        assert_eq!(2, image.layers().len());
+        assert_eq!(vec!["a:latest".to_string()], image.repo_tags());
+        assert_eq!("/bin/sh", image.config()["config"]["Cmd"][0]);
+        assert_eq!(
+            "localhost\n",
+            image.layers()[0].read_to_string("/etc/hosts").await?
+        );
+
+        let rootfs = image.rootfs().await?;
+        assert_eq!(
+            "127.0.0.1 localhost\n",
+            rootfs.read_to_string("/etc/hosts").await?
+        );
+        assert_eq!("welcome\n", rootfs.read_to_string("/etc/motd").await?);
+        assert!(rootfs.try_exists("/bin/busybox").await?);
+        assert!(!rootfs.try_exists("/bin/sh").await?);
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_append_layer_works() -> std::io::Result<()> {
+        let archive = TempFile::new("./fixtures/a.oci.tar").await?;
+        let mut image = OciImage::open(archive.path_view()).await?;
+        let layer = image.new_layer();
+        layer.create_dir_all("/etc").await?;
+        layer.write("/etc/motd", "changed\n").await?;
+        image.close().await?;
+
+        let image = OciImage::open(archive.path_view()).await?;
+        assert_eq!(3, image.layers().len());
+        assert_eq!(
+            3,
+            image.config()["rootfs"]["diff_ids"]
+                .as_array()
+                .unwrap()
+                .len()
+        );
+        assert_eq!(3, image.config()["history"].as_array().unwrap().len());
+        let rootfs = image.rootfs().await?;
+        assert_eq!("changed\n", rootfs.read_to_string("/etc/motd").await?);
+
+        // Both manifests point at the same, still valid, blobs.
+        let tar = TarFloppyDisk::open(archive.path_view()).await?;
+        let docker: Value = serde_json::from_str(&tar.read_to_string("/manifest.json").await?)?;
+        let layers = docker[0]["Layers"].as_array().unwrap();
+        assert_eq!(3, layers.len());
+        for layer in layers {
+            assert!(
+                tar.try_exists(format!("/{}", layer.as_str().unwrap()))
+                    .await?
+            );
+        }
+        let mut blobs = tar.read_dir("/blobs/sha256").await?;
+        let mut count = 0;
+        while blobs.next_entry().await?.is_some() {
+            count += 1;
+        }
+        // The new layer, config and manifest replace the old config and
+        // manifest.
+        assert_eq!(5, count);
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_layout_directory_works() -> std::io::Result<()> {
+        let dir = TempDir::new().await?;
+        let tar = TarFloppyDisk::open("./fixtures/a.oci.tar").await?;
+        for path in ["oci-layout", "index.json", "manifest.json"] {
+            tokio::fs::write(dir.join(path), tar.read(format!("/{path}")).await?).await?;
+        }
+        tokio::fs::create_dir_all(dir.join("blobs/sha256")).await?;
+        let mut blobs = tar.read_dir("/blobs/sha256").await?;
+        while let Some(blob) = blobs.next_entry().await? {
+            let name = blob.file_name();
+            let data = tar.read(blob.path()).await?;
+            tokio::fs::write(dir.join("blobs/sha256").join(name), data).await?;
+        }
+
+        let image = OciImage::open(dir.path_view()).await?;
+        image.layers()[1].remove_file("/etc/motd").await?;
+        image.close().await?;
+
+        let image = OciImage::open(dir.path_view()).await?;
+        assert_eq!(2, image.layers().len());
+        let rootfs = image.rootfs().await?;
+        assert!(!rootfs.try_exists("/etc/motd").await?);
+        assert!(rootfs.try_exists("/etc/hosts").await?);
+        // The old layer and config were replaced, not added to.
+        assert_eq!(4, std::fs::read_dir(dir.join("blobs/sha256"))?.count());
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_paths_outside_the_layout_are_rejected() -> std::io::Result<()> {
+        let dir = TempDir::new().await?;
+        let layout = dir.join("layout");
+        tokio::fs::create_dir_all(&layout).await?;
+        tokio::fs::write(dir.join("victim"), "keep me").await?;
+
+        for digest in ["sha256:../../victim", "../..:abc", "sha256:ABC", "sha256:"] {
+            let index = serde_json::json!({ "manifests": [{ "digest": digest }] });
+            tokio::fs::write(layout.join("index.json"), index.to_string()).await?;
+            let err = OciImage::open(&layout).await.unwrap_err();
+            assert_eq!(std::io::ErrorKind::InvalidData, err.kind());
+        }
+        tokio::fs::remove_file(layout.join("index.json")).await?;
+
+        for (config, layer) in [
+            ("../victim", "abc/layer.tar"),
+            ("abc.json", "../victim"),
+            ("abc.json", "/victim"),
+            ("abc.json", "abc/../../victim"),
+        ] {
+            let manifest = serde_json::json!([{ "Config": config, "Layers": [layer] }]);
+            tokio::fs::write(layout.join("manifest.json"), manifest.to_string()).await?;
+            let err = OciImage::open(&layout).await.unwrap_err();
+            assert_eq!(std::io::ErrorKind::InvalidData, err.kind());
+        }
+        assert_eq!(
+            "keep me",
+            tokio::fs::read_to_string(dir.join("victim")).await?
+        );
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_legacy_docker_save_works() -> std::io::Result<()> {
+        let dir = TempDir::new().await?;
+        let path = dir.join("legacy.tar");
+        let layer_path = dir.join("layer.tar");
+        let layer = TarFloppyDisk::open(&layer_path).await?;
+        layer.write("/hello", "world").await?;
+        layer.close().await?;
+
+        let image = TarFloppyDisk::open(&path).await?;
+        image.create_dir_all("/abc").await?;
+        image
+            .write("/abc/layer.tar", tokio::fs::read(&layer_path).await?)
+            .await?;
+        image
+            .write(
+                "/abc.json",
+                r#"{"rootfs":{"type":"layers","diff_ids":["sha256:abc"]},"history":[]}"#,
+            )
+            .await?;
+        image
+            .write(
+                "/manifest.json",
+                r#"[{"Config":"abc.json","RepoTags":["legacy:1"],"Layers":["abc/layer.tar"]}]"#,
+            )
+            .await?;
+        image.close().await?;
+
+        let mut image = OciImage::open(&path).await?;
+        assert_eq!(vec!["legacy:1".to_string()], image.repo_tags());
+        assert_eq!(
+            "world",
+            image.rootfs().await?.read_to_string("/hello").await?
+        );
+        image.new_layer().write("/bye", "now").await?;
+        image.close().await?;
+
+        let image = OciImage::open(&path).await?;
+        let rootfs = image.rootfs().await?;
+        assert_eq!("now", rootfs.read_to_string("/bye").await?);
+        let tar = TarFloppyDisk::open(&path).await?;
+        let docker: Value = serde_json::from_str(&tar.read_to_string("/manifest.json").await?)?;
+        let layer = docker[0]["Layers"][1].as_str().unwrap();
+        assert!(layer.ends_with("/layer.tar"));
+        assert!(docker[0]["Config"].as_str().unwrap().ends_with(".json"));
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_shared_layer_blobs_work() -> std::io::Result<()> {
+        let dir = TempDir::new().await?;
+        let path = dir.join("shared.tar");
+        let layer_path = dir.join("layer.tar");
+        let layer = TarFloppyDisk::open(&layer_path).await?;
+        layer.write("/hello", "world").await?;
+        layer.close().await?;
+
+        let image = TarFloppyDisk::open(&path).await?;
+        image.create_dir_all("/abc").await?;
+        image
+            .write("/abc/layer.tar", tokio::fs::read(&layer_path).await?)
+            .await?;
+        image
+            .write(
+                "/abc.json",
+                r#"{"rootfs":{"type":"layers","diff_ids":["sha256:abc","sha256:abc"]},"history":[]}"#,
+            )
+            .await?;
+        image
+            .write(
+                "/manifest.json",
+                r#"[{"Config":"abc.json","Layers":["abc/layer.tar","abc/layer.tar"]}]"#,
+            )
+            .await?;
+        image.close().await?;
+
+        let image = OciImage::open(&path).await?;
+        image.layers()[0].write("/first", "1").await?;
+        image.layers()[1].write("/second", "2").await?;
+        image.close().await?;
+
+        let image = OciImage::open(&path).await?;
+        let rootfs = image.rootfs().await?;
+        assert_eq!("1", rootfs.read_to_string("/first").await?);
+        assert_eq!("2", rootfs.read_to_string("/second").await?);
+        let tar = TarFloppyDisk::open(&path).await?;
+        assert!(!tar.try_exists("/abc/layer.tar").await?);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,427 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsStr;
+use std::io::Result;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+use floppy_disk::mem::MemFloppyDisk;
+use floppy_disk::prelude::*;
+use indexmap::{IndexMap, IndexSet};
+use smoosh::CompressionType;
+use tracing::{debug, trace};
+
+use crate::diff::{Change, EntryInfo, EntryType};
+use crate::import::{ImportOptions, ImportedEntry, ImportedKind};
+use crate::tar::{TarFloppyDisk, TarInternalMetadata};
+
+const WHITEOUT_PREFIX: &str = ".wh.";
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+/// A stack of tar layers, merged the way that container runtimes do it.
+/// Layers are applied in order, so the first layer is the bottom of the
+/// stack. `.wh.<name>` files in a layer hide `<name>` in the layers below,
+/// and a `.wh..wh..opq` file hides everything below in its directory.
+///
+/// The merged view derefs to a [`TarFloppyDisk`] that can be changed freely.
+/// Those changes never touch the layers, but can be written out with
+/// [`OverlayFloppyDisk::flatten`] or [`OverlayFloppyDisk::write_diff_layer`].
+#[derive(Debug)]
+pub struct OverlayFloppyDisk {
+    layers: Vec<TarFloppyDisk>,
+    merged: TarFloppyDisk,
+    /// What the merged view looked like before anything changed it.
+    base: BTreeMap<PathBuf, EntryInfo>,
+}
+
+impl OverlayFloppyDisk {
+    pub async fn new(layers: Vec<TarFloppyDisk>) -> Result<OverlayFloppyDisk> {
+        let merged = TarFloppyDisk::from_metadata(
+            PathBuf::new(),
+            TarInternalMetadata {
+                delegate: MemFloppyDisk::new(),
+                compression: CompressionType::None,
+                ordered_paths: IndexSet::new(),
+                times: HashMap::new(),
+                hard_links: IndexMap::new(),
+                state: Default::default(),
+            },
+        );
+
+        for (idx, layer) in layers.iter().enumerate() {
+            debug!("applying layer {}", idx);
+            let entries =
+                crate::import::collect(layer, Path::new("/"), &ImportOptions::new()).await?;
+            apply_layer(&merged, entries).await?;
+        }
+        let base = crate::diff::entries(&merged).await?;
+
+        Ok(Self {
+            layers,
+            merged,
+            base,
+        })
+    }
+
+    /// Opens every tar in `paths` as a layer, bottom first.
+    pub async fn open<P: AsRef<Path>>(paths: &[P]) -> Result<OverlayFloppyDisk> {
+        let mut layers = vec![];
+        for path in paths {
+            // Opening a tar that doesn't exist makes a new one, but a missing
+            // layer is a mistake.
+            crate::util::require_exists(path).await?;
+            layers.push(TarFloppyDisk::open(path).await?);
+        }
+        Self::new(layers).await
+    }
+
+    pub fn layers(&self) -> &[TarFloppyDisk] {
+        &self.layers
+    }
+
+    /// Writes the merged view, including any changes made to it, to a new
+    /// tar at `path`.
+    pub async fn flatten<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let entries =
+            crate::import::collect(&self.merged, Path::new("/"), &ImportOptions::new()).await?;
+        write_layer(path.as_ref(), entries, vec![]).await
+    }
+
+    /// Writes a new layer to `path` that, stacked on top of the existing
+    /// layers, gives the merged view as it is now. Removed paths become
+    /// whiteouts.
+    pub async fn write_diff_layer<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let current = crate::diff::entries(&self.merged).await?;
+        let diff = crate::diff::compare(self.base.clone(), current, &Default::default());
+
+        let mut changed = vec![];
+        let mut removed: Vec<PathBuf> = vec![];
+        for change in diff.changes {
+            match change {
+                Change::Added { path, .. } => changed.push(path),
+                // A directory that became something else is whited out as a
+                // whole, instead of everything that was in it.
+                Change::Modified {
+                    path,
+                    before,
+                    after,
+                    ..
+                } => {
+                    if before.entry_type == EntryType::Directory
+                        && after.entry_type != EntryType::Directory
+                    {
+                        removed.push(path.clone());
+                    }
+                    changed.push(path);
+                }
+                // Changes are in path order, so a removed directory always
+                // comes before anything that was inside of it.
+                Change::Removed { path, .. } => {
+                    if !removed.iter().any(|parent| path.starts_with(parent)) {
+                        removed.push(path);
+                    }
+                }
+            }
+        }
+        debug!(
+            "diff layer has {} changed and {} removed paths",
+            changed.len(),
+            removed.len()
+        );
+
+        let entries =
+            crate::import::collect(&self.merged, Path::new("/"), &ImportOptions::new()).await?;
+        // Parents of changed paths are kept so that they have the right
+        // metadata in the new layer. New hard links can point at files that
+        // haven't changed, which aren't in the layer, so the first link to
+        // one of those gets its data and the rest link to it instead.
+        let mut unchanged = HashMap::new();
+        let mut moved: HashMap<PathBuf, PathBuf> = HashMap::new();
+        let mut kept = vec![];
+        for mut entry in entries {
+            let path = crate::util::normalize_path(&entry.path);
+            if !changed.iter().any(|changed| changed.starts_with(&path)) {
+                if let ImportedKind::File(data) = entry.kind {
+                    unchanged.insert(entry.path, data);
+                }
+                continue;
+            }
+            if let ImportedKind::HardLink(target) = &entry.kind {
+                if let Some(data) = unchanged.remove(target) {
+                    moved.insert(target.clone(), entry.path.clone());
+                    entry.kind = ImportedKind::File(data);
+                } else if let Some(first) = moved.get(target) {
+                    entry.kind = ImportedKind::HardLink(first.clone());
+                }
+            }
+            kept.push(entry);
+        }
+
+        let whiteouts = removed
+            .into_iter()
+            .filter_map(|path| {
+                let name = path.file_name()?.to_string_lossy().to_string();
+                Some(path.with_file_name(format!("{WHITEOUT_PREFIX}{name}")))
+            })
+            .collect();
+
+        write_layer(path.as_ref(), kept, whiteouts).await
+    }
+}
+
+impl Deref for OverlayFloppyDisk {
+    type Target = TarFloppyDisk;
+
+    fn deref(&self) -> &Self::Target {
+        &self.merged
+    }
+}
+
+async fn apply_layer(merged: &TarFloppyDisk, entries: Vec<ImportedEntry>) -> Result<()> {
+    // Whiteouts only hide what's in the layers below, so they all have to be
+    // handled before anything in this layer is added.
+    let mut kept = vec![];
+    for entry in entries {
+        let path = crate::util::normalize_path(&entry.path);
+        let name = path.file_name().unwrap_or_default();
+        let parent = path.parent().unwrap_or(Path::new("/"));
+
+        if name == OsStr::new(OPAQUE_WHITEOUT) {
+            trace!("opaque whiteout: {}", parent.display());
+            if merged.try_exists(parent).await? {
+                let mut children = vec![];
+                let mut read_dir = merged.read_dir(parent).await?;
+                while let Some(child) = read_dir.next_entry().await? {
+                    children.push(child.path());
+                }
+                for child in children {
+                    remove(merged, &child).await?;
+                }
+            }
+        } else if let Some(hidden) = name.to_string_lossy().strip_prefix(WHITEOUT_PREFIX) {
+            let hidden = parent.join(hidden);
+            trace!("whiteout: {}", hidden.display());
+            remove(merged, &hidden).await?;
+        } else {
+            kept.push(entry);
+        }
+    }
+
+    // Anything replacing an entry of a different kind needs the old one gone
+    // first. Directories are merged instead.
+    for entry in &kept {
+        let path = crate::util::normalize_path(&entry.path);
+        if let Ok(metadata) = merged.symlink_metadata(&path).await {
+            let both_dirs =
+                metadata.is_dir() && matches!(entry.kind, crate::import::ImportedKind::Directory);
+            if !both_dirs {
+                remove(merged, &path).await?;
+            }
+        }
+    }
+
+    merged.import_entries(Path::new("/"), kept).await
+}
+
+async fn remove(merged: &TarFloppyDisk, path: &Path) -> Result<()> {
+    match merged.symlink_metadata(path).await {
+        Ok(metadata) if metadata.is_dir() => merged.remove_dir_all(path).await,
+        Ok(_) => merged.remove_file(path).await,
+        Err(_) => Ok(()),
+    }
+}
+
+async fn write_layer(
+    path: &Path,
+    entries: Vec<ImportedEntry>,
+    whiteouts: Vec<PathBuf>,
+) -> Result<()> {
+    if crate::util::exists_async(path).await {
+        tokio::fs::remove_file(path).await?;
+    }
+
+    // Runtimes apply whiteouts as they come across them, so they go first in
+    // case something in this layer replaces what they hide.
+    let layer = TarFloppyDisk::open(path).await?;
+    for whiteout in whiteouts {
+        trace!("writing whiteout: {}", whiteout.display());
+        if let Some(parent) = whiteout.parent() {
+            layer.create_dir_all(parent).await?;
+        }
+        layer.write(whiteout, []).await?;
+    }
+    layer.import_entries(Path::new("/"), entries).await?;
+    layer.close().await
+}
+
+#[cfg(test)]
+mod tests {
+    use floppy_disk::prelude::*;
+
+    use super::OverlayFloppyDisk;
+    use crate::tar::{TarFloppyDisk, TarPermissions};
+    use crate::util::TempDir;
+
+    async fn make_layers(dir: &TempDir) -> std::io::Result<Vec<std::path::PathBuf>> {
+        let base = dir.join("base.tar");
+        let disk = TarFloppyDisk::open(&base).await?;
+        disk.create_dir_all("/etc/conf.d").await?;
+        disk.write("/etc/conf.d/a.conf", "a").await?;
+        disk.write("/etc/conf.d/b.conf", "b").await?;
+        disk.write("/etc/hosts", "localhost").await?;
+        disk.write("/etc/passwd", "root").await?;
+        disk.create_dir_all("/var/cache").await?;
+        disk.write("/var/cache/junk", "junk").await?;
+        disk.close().await?;
+
+        let upper = dir.join("upper.tar");
+        let disk = TarFloppyDisk::open(&upper).await?;
+        disk.create_dir_all("/etc/conf.d").await?;
+        disk.write("/etc/conf.d/.wh..wh..opq", "").await?;
+        disk.write("/etc/conf.d/c.conf", "c").await?;
+        disk.write("/etc/.wh.passwd", "").await?;
+        disk.write("/etc/hosts", "overridden").await?;
+        disk.create_dir_all("/var").await?;
+        disk.write("/var/cache", "not a dir anymore").await?;
+        disk.close().await?;
+
+        Ok(vec![base, upper])
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_merged_view_works() -> std::io::Result<()> {
+        let dir = TempDir::new().await?;
+        let layers = make_layers(&dir).await?;
+        let overlay = OverlayFloppyDisk::open(&layers).await?;
+
+        assert_eq!(2, overlay.layers().len());
+        assert_eq!("overridden", overlay.read_to_string("/etc/hosts").await?);
+        assert_eq!("c", overlay.read_to_string("/etc/conf.d/c.conf").await?);
+        assert!(!overlay.try_exists("/etc/conf.d/a.conf").await?);
+        assert!(!overlay.try_exists("/etc/conf.d/.wh..wh..opq").await?);
+        assert!(!overlay.try_exists("/etc/passwd").await?);
+        assert!(!overlay.try_exists("/etc/.wh.passwd").await?);
+        assert_eq!(
+            "not a dir anymore",
+            overlay.read_to_string("/var/cache").await?
+        );
+
+        let flat = dir.join("flat.tar");
+        overlay.flatten(&flat).await?;
+        let disk = TarFloppyDisk::open(&flat).await?;
+        assert_eq!("overridden", disk.read_to_string("/etc/hosts").await?);
+        assert!(!disk.try_exists("/etc/passwd").await?);
+        assert!(!disk.try_exists("/etc/conf.d/b.conf").await?);
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_missing_layers_are_not_created() -> std::io::Result<()> {
+        let dir = TempDir::new().await?;
+        let mut layers = make_layers(&dir).await?;
+        let missing = dir.join("missing.tar");
+        layers.push(missing.clone());
+
+        let err = OverlayFloppyDisk::open(&layers).await.unwrap_err();
+        assert_eq!(std::io::ErrorKind::NotFound, err.kind());
+        assert!(!crate::util::exists_async(&missing).await);
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_diff_layer_works() -> std::io::Result<()> {
+        let dir = TempDir::new().await?;
+        let mut layers = make_layers(&dir).await?;
+        let overlay = OverlayFloppyDisk::open(&layers).await?;
+        overlay.write("/etc/motd", "hello").await?;
+        overlay.remove_dir_all("/etc/conf.d").await?;
+        overlay
+            .set_permissions("/etc/hosts", TarPermissions::from_mode(0o600))
+            .await?;
+
+        let diff = dir.join("diff.tar");
+        overlay.write_diff_layer(&diff).await?;
+        let disk = TarFloppyDisk::open(&diff).await?;
+        assert_eq!("hello", disk.read_to_string("/etc/motd").await?);
+        assert!(disk.try_exists("/etc/.wh.conf.d").await?);
+        assert!(!disk.try_exists("/etc/conf.d/.wh.c.conf").await?);
+        assert!(!disk.try_exists("/var/cache").await?);
+
+        layers.push(diff);
+        let restacked = OverlayFloppyDisk::open(&layers).await?;
+        assert_eq!("hello", restacked.read_to_string("/etc/motd").await?);
+        assert!(!restacked.try_exists("/etc/conf.d").await?);
+        assert_eq!(
+            0o600,
+            restacked.metadata("/etc/hosts").await?.permissions().mode() & 0o777
+        );
+        assert!(crate::diff::entries(&*overlay).await? == crate::diff::entries(&*restacked).await?);
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_hard_link_to_lower_layer_works() -> std::io::Result<()> {
+        let dir = TempDir::new().await?;
+        let mut layers = make_layers(&dir).await?;
+        let overlay = OverlayFloppyDisk::open(&layers).await?;
+        overlay.hard_link("/etc/hosts", "/etc/hosts.link").await?;
+        overlay
+            .hard_link("/etc/conf.d/c.conf", "/etc/c.conf.link")
+            .await?;
+        overlay
+            .hard_link("/etc/conf.d/c.conf", "/etc/c.conf.other")
+            .await?;
+
+        let diff = dir.join("diff.tar");
+        overlay.write_diff_layer(&diff).await?;
+        let disk = TarFloppyDisk::open(&diff).await?;
+        assert_eq!("overridden", disk.read_to_string("/etc/hosts.link").await?);
+        assert_eq!("c", disk.read_to_string("/etc/c.conf.link").await?);
+        assert_eq!("c", disk.read_to_string("/etc/c.conf.other").await?);
+        assert!(!disk.try_exists("/etc/hosts").await?);
+
+        layers.push(diff);
+        let restacked = OverlayFloppyDisk::open(&layers).await?;
+        assert_eq!(
+            "overridden",
+            restacked.read_to_string("/etc/hosts.link").await?
+        );
+        assert_eq!("c", restacked.read_to_string("/etc/c.conf.other").await?);
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_directory_replaced_by_file_works() -> std::io::Result<()> {
+        let dir = TempDir::new().await?;
+        let mut layers = make_layers(&dir).await?;
+        let overlay = OverlayFloppyDisk::open(&layers).await?;
+        overlay.remove_dir_all("/etc/conf.d").await?;
+        overlay.write("/etc/conf.d", "a file now").await?;
+
+        let diff = dir.join("diff.tar");
+        overlay.write_diff_layer(&diff).await?;
+        let disk = TarFloppyDisk::open(&diff).await?;
+        assert!(disk.try_exists("/etc/.wh.conf.d").await?);
+        assert!(disk.metadata("/etc/conf.d").await?.is_file());
+        // The whiteout comes before the file that replaces what it hides.
+        let data = tokio::fs::read(&diff).await?;
+        let mut archive = tokio_tar_up2date::Archive::new(data.as_slice());
+        let mut paths = vec![];
+        let mut entries = archive.entries()?;
+        while let Some(entry) = futures::TryStreamExt::try_next(&mut entries).await? {
+            paths.push(entry.path()?.to_string_lossy().to_string());
+        }
+        let position = |path: &str| paths.iter().position(|p| p.ends_with(path));
+        assert!(position("etc/.wh.conf.d") < position("etc/conf.d"));
+
+        layers.push(diff);
+        let restacked = OverlayFloppyDisk::open(&layers).await?;
+        assert_eq!("a file now", restacked.read_to_string("/etc/conf.d").await?);
+        assert!(crate::diff::entries(&*overlay).await? == crate::diff::entries(&*restacked).await?);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,585 @@
+use std::collections::BTreeMap;
+use std::io::Result;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use floppy_disk::FloppyDisk;
+use md5::Md5;
+use sha2::{Digest, Sha256, Sha384, Sha512};
+use tracing::{debug, trace};
+
+use crate::cpio::CpioFloppyDisk;
+
+const LEAD_MAGIC: [u8; 4] = [0xed, 0xab, 0xee, 0xdb];
+const LEAD_SIZE: usize = 96;
+const HEADER_MAGIC: [u8; 4] = [0x8e, 0xad, 0xe8, 0x01];
+/// Files with this flag are owned by the package, but not in the payload.
+const RPMFILE_GHOST: u32 = 64;
+
+/// Well-known header and signature tags.
+pub mod tag {
+    pub const NAME: u32 = 1000;
+    pub const VERSION: u32 = 1001;
+    pub const RELEASE: u32 = 1002;
+    pub const EPOCH: u32 = 1003;
+    pub const SUMMARY: u32 = 1004;
+    pub const DESCRIPTION: u32 = 1005;
+    pub const LICENSE: u32 = 1014;
+    pub const ARCH: u32 = 1022;
+    pub const OLDFILENAMES: u32 = 1027;
+    pub const FILESIZES: u32 = 1028;
+    pub const FILEMODES: u32 = 1030;
+    pub const FILEMTIMES: u32 = 1034;
+    pub const FILEDIGESTS: u32 = 1035;
+    pub const FILELINKTOS: u32 = 1036;
+    pub const FILEFLAGS: u32 = 1037;
+    pub const FILEUSERNAME: u32 = 1039;
+    pub const FILEGROUPNAME: u32 = 1040;
+    pub const DIRINDEXES: u32 = 1116;
+    pub const BASENAMES: u32 = 1117;
+    pub const DIRNAMES: u32 = 1118;
+    pub const PAYLOADFORMAT: u32 = 1124;
+    pub const PAYLOADCOMPRESSOR: u32 = 1125;
+    pub const LONGFILESIZES: u32 = 5008;
+    pub const FILEDIGESTALGO: u32 = 5011;
+
+    /// Signature header: size of the header and payload.
+    pub const SIG_SIZE: u32 = 1000;
+    /// Signature header: MD5 of the header and payload.
+    pub const SIG_MD5: u32 = 1004;
+    /// Signature header: hex SHA-256 of the header.
+    pub const SIG_SHA256: u32 = 273;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RpmValue {
+    Null,
+    Char(Vec<u8>),
+    Int8(Vec<u8>),
+    Int16(Vec<u16>),
+    Int32(Vec<u32>),
+    Int64(Vec<u64>),
+    String(String),
+    Binary(Vec<u8>),
+    StringArray(Vec<String>),
+    I18nString(Vec<String>),
+}
+
+impl RpmValue {
+    /// Strings, and the first translation of i18n strings.
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            RpmValue::String(s) => Some(s),
+            RpmValue::I18nString(s) | RpmValue::StringArray(s) => s.first().map(|s| s.as_str()),
+            _ => None,
+        }
+    }
+
+    pub fn as_strings(&self) -> Option<&[String]> {
+        match self {
+            RpmValue::StringArray(s) | RpmValue::I18nString(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Any integer array, widened.
+    pub fn as_integers(&self) -> Option<Vec<u64>> {
+        match self {
+            RpmValue::Char(v) | RpmValue::Int8(v) => Some(v.iter().map(|&v| v as u64).collect()),
+            RpmValue::Int16(v) => Some(v.iter().map(|&v| v as u64).collect()),
+            RpmValue::Int32(v) => Some(v.iter().map(|&v| v as u64).collect()),
+            RpmValue::Int64(v) => Some(v.clone()),
+            _ => None,
+        }
+    }
+}
+
+/// A parsed rpm header (or signature header), keyed by tag.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RpmHeader {
+    tags: BTreeMap<u32, RpmValue>,
+}
+
+impl RpmHeader {
+    /// Parses the header at the start of `data`, returning it and how many
+    /// bytes it takes up.
+    pub fn parse(data: &[u8]) -> Result<(RpmHeader, usize)> {
+        if data.len() < 16 || data[..4] != HEADER_MAGIC {
+            return Err(invalid("bad rpm header magic"));
+        }
+        let entries = be_u32(data, 8)? as usize;
+        let store_size = be_u32(data, 12)? as usize;
+        let store_start = 16 + entries * 16;
+        let size = store_start + store_size;
+        let store = data
+            .get(store_start..size)
+            .ok_or_else(|| invalid("truncated rpm header"))?;
+
+        let mut tags = BTreeMap::new();
+        for idx in 0..entries {
+            let entry = 16 + idx * 16;
+            let tag = be_u32(data, entry)?;
+            let kind = be_u32(data, entry + 4)?;
+            let offset = be_u32(data, entry + 8)? as usize;
+            let count = be_u32(data, entry + 12)? as usize;
+            trace!("rpm tag {} (type {}, count {})", tag, kind, count);
+            tags.insert(tag, read_value(store, kind, offset, count)?);
+        }
+
+        Ok((Self { tags }, size))
+    }
+
+    pub fn get(&self, tag: u32) -> Option<&RpmValue> {
+        self.tags.get(&tag)
+    }
+
+    pub fn tags(&self) -> impl Iterator<Item = (u32, &RpmValue)> {
+        self.tags.iter().map(|(tag, value)| (*tag, value))
+    }
+
+    pub fn string(&self, tag: u32) -> Option<&str> {
+        self.get(tag).and_then(|v| v.as_str())
+    }
+
+    pub fn strings(&self, tag: u32) -> Option<&[String]> {
+        self.get(tag).and_then(|v| v.as_strings())
+    }
+
+    pub fn integers(&self, tag: u32) -> Option<Vec<u64>> {
+        self.get(tag).and_then(|v| v.as_integers())
+    }
+}
+
+fn read_value(store: &[u8], kind: u32, offset: usize, count: usize) -> Result<RpmValue> {
+    let data = store
+        .get(offset..)
+        .ok_or_else(|| invalid("rpm tag offset out of bounds"))?;
+    let fixed = |size: usize| {
+        data.get(..size * count)
+            .ok_or_else(|| invalid("rpm tag data out of bounds"))
+    };
+    let strings = || -> Result<Vec<String>> {
+        let mut out = vec![];
+        let mut rest = data;
+        for _ in 0..count {
+            let end = rest
+                .iter()
+                .position(|&b| b == 0)
+                .ok_or_else(|| invalid("unterminated rpm string"))?;
+            out.push(String::from_utf8_lossy(&rest[..end]).to_string());
+            rest = &rest[end + 1..];
+        }
+        Ok(out)
+    };
+
+    Ok(match kind {
+        0 => RpmValue::Null,
+        1 => RpmValue::Char(fixed(1)?.to_vec()),
+        2 => RpmValue::Int8(fixed(1)?.to_vec()),
+        3 => RpmValue::Int16(
+            fixed(2)?
+                .chunks(2)
+                .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                .collect(),
+        ),
+        4 => RpmValue::Int32(
+            fixed(4)?
+                .chunks(4)
+                .map(|c| u32::from_be_bytes([c[0], c[1], c[2], c[3]]))
+                .collect(),
+        ),
+        5 => RpmValue::Int64(
+            fixed(8)?
+                .chunks(8)
+                .map(|c| u64::from_be_bytes(c.try_into().unwrap()))
+                .collect(),
+        ),
+        6 => RpmValue::String(
+            strings()?
+                .into_iter()
+                .next()
+                .ok_or_else(|| invalid("rpm string tag has no value"))?,
+        ),
+        7 => RpmValue::Binary(fixed(1)?.to_vec()),
+        8 => RpmValue::StringArray(strings()?),
+        9 => RpmValue::I18nString(strings()?),
+        kind => return Err(invalid(&format!("unknown rpm tag type {kind}"))),
+    })
+}
+
+/// A file, as described by the rpm header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RpmFile {
+    pub path: PathBuf,
+    pub size: u64,
+    pub mode: u32,
+    pub user: String,
+    pub group: String,
+    pub mtime: SystemTime,
+    /// Hex digest of the contents, for regular files. The algorithm is
+    /// whatever `FILEDIGESTALGO` says.
+    pub digest: Option<String>,
+    pub link_to: Option<PathBuf>,
+    pub flags: u32,
+}
+
+/// A read-only view of an rpm. The header is exposed by
+/// [`RpmFloppyDisk::header`], and the payload by derefing to a
+/// [`CpioFloppyDisk`]. Changes to the payload are never written anywhere.
+#[derive(Debug)]
+pub struct RpmFloppyDisk {
+    path: PathBuf,
+    lead_name: String,
+    signature: RpmHeader,
+    header: RpmHeader,
+    payload: CpioFloppyDisk,
+}
+
+impl RpmFloppyDisk {
+    pub async fn open<P: AsRef<Path>>(path: P) -> Result<RpmFloppyDisk> {
+        let path = path.as_ref();
+        debug!("opening rpm {}", path.display());
+        let data = tokio::fs::read(path).await?;
+        let layout = Layout::parse(&data)?;
+        let lead_name = {
+            let name = &data[10..76];
+            let end = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+            String::from_utf8_lossy(&name[..end]).to_string()
+        };
+        let (signature, _) = RpmHeader::parse(&data[LEAD_SIZE..])?;
+        let (header, _) = RpmHeader::parse(&data[layout.header..])?;
+
+        if let Some(format) = header.string(tag::PAYLOADFORMAT) {
+            if format != "cpio" {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    format!("unsupported rpm payload format: {format}"),
+                ));
+            }
+        }
+        debug!(
+            "loading {} payload",
+            header
+                .string(tag::PAYLOADCOMPRESSOR)
+                .unwrap_or("uncompressed")
+        );
+        let metadata = crate::cpio::cpio_read(&data[layout.payload..]).await?;
+        let payload = CpioFloppyDisk::from_metadata(path.to_path_buf(), metadata);
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            lead_name,
+            signature,
+            header,
+            payload,
+        })
+    }
+
+    /// The `name-version-release` in the lead.
+    pub fn lead_name(&self) -> &str {
+        &self.lead_name
+    }
+
+    pub fn signature(&self) -> &RpmHeader {
+        &self.signature
+    }
+
+    pub fn header(&self) -> &RpmHeader {
+        &self.header
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.header.string(tag::NAME)
+    }
+
+    pub fn version(&self) -> Option<&str> {
+        self.header.string(tag::VERSION)
+    }
+
+    pub fn release(&self) -> Option<&str> {
+        self.header.string(tag::RELEASE)
+    }
+
+    pub fn epoch(&self) -> Option<u32> {
+        self.header
+            .integers(tag::EPOCH)
+            .and_then(|v| v.first().map(|&v| v as u32))
+    }
+
+    pub fn arch(&self) -> Option<&str> {
+        self.header.string(tag::ARCH)
+    }
+
+    /// Every file listed in the header, in header order.
+    pub fn files(&self) -> Result<Vec<RpmFile>> {
+        let header = &self.header;
+        let paths: Vec<PathBuf> = match (
+            header.strings(tag::BASENAMES),
+            header.strings(tag::DIRNAMES),
+            header.integers(tag::DIRINDEXES),
+        ) {
+            (Some(basenames), Some(dirnames), Some(indexes)) => basenames
+                .iter()
+                .zip(indexes)
+                .map(|(name, idx)| {
+                    let dir = dirnames
+                        .get(idx as usize)
+                        .ok_or_else(|| invalid("rpm dir index out of bounds"))?;
+                    Ok(PathBuf::from(format!("{dir}{name}")))
+                })
+                .collect::<Result<_>>()?,
+            _ => header
+                .strings(tag::OLDFILENAMES)
+                .unwrap_or_default()
+                .iter()
+                .map(PathBuf::from)
+                .collect(),
+        };
+
+        let integers = |tag| header.integers(tag).unwrap_or_default();
+        let strings = |tag| header.strings(tag).unwrap_or_default();
+        let sizes = header
+            .integers(tag::LONGFILESIZES)
+            .unwrap_or_else(|| integers(tag::FILESIZES));
+        let modes = integers(tag::FILEMODES);
+        let mtimes = integers(tag::FILEMTIMES);
+        let flags = integers(tag::FILEFLAGS);
+        let digests = strings(tag::FILEDIGESTS);
+        let links = strings(tag::FILELINKTOS);
+        let users = strings(tag::FILEUSERNAME);
+        let groups = strings(tag::FILEGROUPNAME);
+
+        Ok(paths
+            .into_iter()
+            .enumerate()
+            .map(|(idx, path)| {
+                let non_empty = |s: Option<&String>| s.filter(|s| !s.is_empty()).cloned();
+                RpmFile {
+                    path,
+                    size: sizes.get(idx).copied().unwrap_or(0),
+                    mode: modes.get(idx).copied().unwrap_or(0) as u32,
+                    user: users.get(idx).cloned().unwrap_or_default(),
+                    group: groups.get(idx).cloned().unwrap_or_default(),
+                    mtime: std::time::UNIX_EPOCH
+                        + std::time::Duration::from_secs(mtimes.get(idx).copied().unwrap_or(0)),
+                    digest: non_empty(digests.get(idx)),
+                    link_to: non_empty(links.get(idx)).map(PathBuf::from),
+                    flags: flags.get(idx).copied().unwrap_or(0) as u32,
+                }
+            })
+            .collect())
+    }
+
+    /// Checks the header and payload digests in the signature, and the
+    /// digest of every file in the payload against the header.
+    pub async fn verify(&self) -> Result<()> {
+        let data = tokio::fs::read(&self.path).await?;
+        let layout = Layout::parse(&data)?;
+
+        if let Some(expected) = self.signature.string(tag::SIG_SHA256) {
+            let actual = hex(&Sha256::digest(&data[layout.header..layout.payload]));
+            if actual != expected {
+                return Err(invalid("rpm header sha256 mismatch"));
+            }
+        }
+        if let Some(RpmValue::Binary(expected)) = self.signature.get(tag::SIG_MD5) {
+            if Md5::digest(&data[layout.header..]).as_slice() != expected.as_slice() {
+                return Err(invalid("rpm header and payload md5 mismatch"));
+            }
+        }
+
+        // Defaults to md5 for rpms old enough not to say.
+        let algo = self
+            .header
+            .integers(tag::FILEDIGESTALGO)
+            .and_then(|v| v.first().copied())
+            .unwrap_or(1);
+        for file in self.files()? {
+            let Some(expected) = &file.digest else {
+                continue;
+            };
+            if file.flags & RPMFILE_GHOST != 0 {
+                continue;
+            }
+            let contents = self.payload.read(&file.path).await?;
+            let actual = match algo {
+                1 => hex(&Md5::digest(&contents)),
+                8 => hex(&Sha256::digest(&contents)),
+                9 => hex(&Sha384::digest(&contents)),
+                10 => hex(&Sha512::digest(&contents)),
+                algo => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::Unsupported,
+                        format!("unsupported rpm file digest algorithm: {algo}"),
+                    ))
+                }
+            };
+            if &actual != expected {
+                return Err(invalid(&format!(
+                    "digest mismatch for {}",
+                    file.path.display()
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Deref for RpmFloppyDisk {
+    type Target = CpioFloppyDisk;
+
+    fn deref(&self) -> &Self::Target {
+        &self.payload
+    }
+}
+
+/// Where each section of an rpm starts.
+struct Layout {
+    header: usize,
+    payload: usize,
+}
+
+impl Layout {
+    fn parse(data: &[u8]) -> Result<Layout> {
+        if data.len() < LEAD_SIZE || data[..4] != LEAD_MAGIC {
+            return Err(invalid("not an rpm"));
+        }
+        let (_, signature_size) = RpmHeader::parse(&data[LEAD_SIZE..])?;
+        // The signature is padded out to 8 bytes.
+        let header = LEAD_SIZE + signature_size.div_ceil(8) * 8;
+        let (_, header_size) = RpmHeader::parse(data.get(header..).unwrap_or_default())?;
+        Ok(Layout {
+            header,
+            payload: header + header_size,
+        })
+    }
+}
+
+fn be_u32(data: &[u8], offset: usize) -> Result<u32> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| invalid("truncated rpm header"))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn invalid(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use floppy_disk::prelude::*;
+
+    use super::RpmFloppyDisk;
+
+    #[test_log::test]
+    fn test_empty_string_tags_are_errors() {
+        let err = super::read_value(b"", 6, 0, 0).unwrap_err();
+        assert_eq!(std::io::ErrorKind::InvalidData, err.kind());
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_verify_does_not_create_the_package() -> std::io::Result<()> {
+        let dir = crate::util::TempDir::new().await?;
+        let path = dir.join("a.rpm");
+        tokio::fs::copy("./fixtures/a.rpm", &path).await?;
+        let rpm = RpmFloppyDisk::open(&path).await?;
+        rpm.verify().await?;
+
+        tokio::fs::remove_file(&path).await?;
+        let err = rpm.verify().await.unwrap_err();
+        assert_eq!(std::io::ErrorKind::NotFound, err.kind());
+        assert!(!crate::util::exists_async(&path).await);
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_read_works() -> std::io::Result<()> {
+        let rpm = RpmFloppyDisk::open("./fixtures/a.rpm").await?;
+        assert_eq!("hello-1.2.3-1", rpm.lead_name());
+        assert_eq!(Some("hello"), rpm.name());
+        assert_eq!(Some("1.2.3"), rpm.version());
+        assert_eq!(Some("1"), rpm.release());
+        assert_eq!(Some(2), rpm.epoch());
+        assert_eq!(Some("noarch"), rpm.arch());
+        assert_eq!(Some("says hello"), rpm.header().string(super::tag::SUMMARY));
+
+        assert_eq!(
+            "#!/bin/sh\necho hello\n",
+            rpm.read_to_string("/usr/bin/hello").await?
+        );
+        assert_eq!(PathBuf::from("hello"), rpm.read_link("/usr/bin/hi").await?);
+        assert!(rpm.metadata("/usr/share/hello").await?.is_dir());
+
+        let files = rpm.files()?;
+        assert_eq!(4, files.len());
+        let hello = files
+            .iter()
+            .find(|f| f.path == std::path::Path::new("/usr/bin/hello"))
+            .unwrap();
+        assert_eq!(0o100755, hello.mode);
+        assert_eq!(21, hello.size);
+        assert_eq!("root", hello.user);
+        assert_eq!(64, hello.digest.as_ref().unwrap().len());
+        assert_eq!("wheel", files[3].group);
+        assert_eq!(Some(PathBuf::from("hello")), files[2].link_to);
+
+        rpm.verify().await?;
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_verify_skips_ghost_files() -> std::io::Result<()> {
+        use super::{tag, RpmValue};
+
+        let mut rpm = RpmFloppyDisk::open("./fixtures/a.rpm").await?;
+        let files = rpm.files()?;
+        let idx = files
+            .iter()
+            .position(|f| f.path == std::path::Path::new("/usr/bin/hello"))
+            .unwrap();
+        // Ghosts aren't in the payload, so their digests can't match.
+        let mut digests = rpm.header.strings(tag::FILEDIGESTS).unwrap().to_vec();
+        digests[idx] = "0".repeat(64);
+        rpm.header
+            .tags
+            .insert(tag::FILEDIGESTS, RpmValue::StringArray(digests));
+        assert!(rpm.verify().await.is_err());
+
+        let mut flags = vec![0; files.len()];
+        flags[idx] = super::RPMFILE_GHOST;
+        rpm.header
+            .tags
+            .insert(tag::FILEFLAGS, RpmValue::Int32(flags));
+        rpm.verify().await?;
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_verify_catches_tampering() -> std::io::Result<()> {
+        let rpm = crate::util::tests::TempFile::new("./fixtures/a.rpm").await?;
+        let mut data = std::fs::read(rpm.path_view())?;
+        let at = data
+            .windows(6)
+            .rposition(|w| w == b"1.2.3\0")
+            .expect("version is in the header");
+        data[at + 4] = b'4';
+        std::fs::write(rpm.path_view(), data)?;
+
+        let disk = RpmFloppyDisk::open(rpm.path_view()).await?;
+        assert_eq!(Some("1.2.4"), disk.version());
+        assert!(disk.verify().await.is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,1253 @@
+use std::os::unix::prelude::{OsStrExt, OsStringExt};
+
+use liblzma::stream::{Action, Filters, LzmaOptions, Status, Stream};
+use smoosh::CompressionType;
+use tokio::io::AsyncWriteExt;
+use tracing::debug;
+
+crate::util::archive_format!(SevenZ, "a.7z", sevenz_open, sevenz_close);
+
+const SIGNATURE: &[u8; 6] = b"7z\xbc\xaf\x27\x1c";
+const SIGNATURE_HEADER_SIZE: usize = 32;
+
+/// The ids that mark each part of a header.
+mod property {
+    pub const END: u8 = 0x00;
+    pub const HEADER: u8 = 0x01;
+    pub const ARCHIVE_PROPERTIES: u8 = 0x02;
+    pub const ADDITIONAL_STREAMS_INFO: u8 = 0x03;
+    pub const MAIN_STREAMS_INFO: u8 = 0x04;
+    pub const FILES_INFO: u8 = 0x05;
+    pub const PACK_INFO: u8 = 0x06;
+    pub const UNPACK_INFO: u8 = 0x07;
+    pub const SUBSTREAMS_INFO: u8 = 0x08;
+    pub const SIZE: u8 = 0x09;
+    pub const CRC: u8 = 0x0a;
+    pub const FOLDER: u8 = 0x0b;
+    pub const CODERS_UNPACK_SIZE: u8 = 0x0c;
+    pub const NUM_UNPACK_STREAM: u8 = 0x0d;
+    pub const EMPTY_STREAM: u8 = 0x0e;
+    pub const EMPTY_FILE: u8 = 0x0f;
+    pub const ANTI: u8 = 0x10;
+    pub const NAME: u8 = 0x11;
+    pub const MTIME: u8 = 0x14;
+    pub const WIN_ATTRIBUTES: u8 = 0x15;
+    pub const ENCODED_HEADER: u8 = 0x17;
+}
+
+const CODER_COPY: &[u8] = &[0x00];
+const CODER_LZMA: &[u8] = &[0x03, 0x01, 0x01];
+const CODER_LZMA2: &[u8] = &[0x21];
+const CODER_AES: &[u8] = &[0x06, 0xf1, 0x07, 0x01];
+
+const ATTRIBUTE_READ_ONLY: u32 = 0x01;
+const ATTRIBUTE_DIRECTORY: u32 = 0x10;
+/// Set when the high 16 bits of the attributes are a unix mode.
+const ATTRIBUTE_UNIX_EXTENSION: u32 = 0x8000;
+
+const S_IFMT: u32 = 0o170000;
+const S_IFLNK: u32 = 0o120000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+/// 7z times are 100ns intervals since 1601, like Windows `FILETIME`s.
+const FILETIME_UNIX_EPOCH: u64 = 116_444_736_000_000_000;
+
+#[derive(Debug, Default)]
+pub(crate) struct SevenZState {
+    method: std::sync::Mutex<SevenZMethod>,
+    /// DOS attributes other than read-only and directory, which come from
+    /// the permissions and file type instead.
+    attributes: std::sync::Mutex<HashMap<PathBuf, u32>>,
+}
+
+impl crate::util::LazyEntries for SevenZState {}
+
+/// How file contents are compressed when the archive is written. All
+/// files go in a single solid block.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SevenZMethod {
+    Copy,
+    Lzma,
+    #[default]
+    Lzma2,
+}
+
+impl SevenZFloppyDisk {
+    /// The method that the archive was read with, or will be written with.
+    /// Archives with more than one method report the first one.
+    pub fn method(&self) -> SevenZMethod {
+        *self.state.method.lock().unwrap()
+    }
+
+    pub fn set_method(&self, method: SevenZMethod) {
+        *self.state.method.lock().unwrap() = method;
+    }
+
+    /// The DOS attributes of `path`, like hidden (`0x02`), system (`0x04`)
+    /// and archive (`0x20`). Read-only and directory are left out, since
+    /// they come from the permissions and file type.
+    pub fn attributes<P: AsRef<Path>>(&self, path: P) -> u32 {
+        let path = crate::util::normalize_path(path);
+        let attributes = self.state.attributes.lock().unwrap();
+        attributes.get(&path).copied().unwrap_or(0)
+    }
+
+    pub fn set_attributes<P: AsRef<Path>>(&self, path: P, attributes: u32) {
+        let path = crate::util::normalize_path(path);
+        self.state.attributes.lock().unwrap().insert(
+            path,
+            attributes & 0x7fff & !(ATTRIBUTE_READ_ONLY | ATTRIBUTE_DIRECTORY),
+        );
+    }
+}
+
+async fn sevenz_open<P: Into<PathBuf>>(path: P) -> Result<SevenZInternalMetadata> {
+    let path = path.into();
+    if !crate::util::exists_async(&path).await {
+        debug!("creating empty 7z archive!");
+        return Ok(SevenZInternalMetadata {
+            delegate: MemFloppyDisk::new(),
+            compression: CompressionType::None,
+            ordered_paths: IndexSet::new(),
+            times: HashMap::new(),
+            hard_links: IndexMap::new(),
+            state: Default::default(),
+        });
+    }
+
+    debug!("opening 7z archive {}", path.display());
+    let mut file = crate::util::async_file(path).await?;
+    let mut buffer = vec![];
+    let c = smoosh::recompress(&mut file, &mut buffer, smoosh::CompressionType::None).await?;
+    let archive = Archive::parse(&buffer)?;
+    debug!("found {} entries", archive.entries.len());
+
+    let out = MemFloppyDisk::new();
+    let mut ordered_paths = IndexSet::new();
+    let mut times = HashMap::new();
+    let mut attributes = HashMap::new();
+    let mut contents = archive.contents.into_iter();
+    for entry in archive.entries {
+        let data = match entry.has_stream {
+            true => contents
+                .next()
+                .ok_or_else(|| invalid("7z archive has too few streams"))?,
+            false => vec![],
+        };
+        if entry.is_anti {
+            debug!("skipping anti-item {}", entry.name);
+            continue;
+        }
+        let path = crate::util::normalize_path(&entry.name);
+        debug!("processing archive path {}", path.display());
+        ordered_paths.insert(path.clone());
+        if let Some(mtime) = entry.mtime {
+            times.insert(path.clone(), mtime);
+        }
+
+        let raw = entry.attributes.unwrap_or(0);
+        let unix = match raw & ATTRIBUTE_UNIX_EXTENSION {
+            0 => None,
+            _ => Some(raw >> 16),
+        };
+        let extra = raw & 0x7fff & !(ATTRIBUTE_READ_ONLY | ATTRIBUTE_DIRECTORY);
+        if extra != 0 {
+            attributes.insert(path.clone(), extra);
+        }
+        let is_dir = entry.is_dir
+            || raw & ATTRIBUTE_DIRECTORY != 0
+            || unix.map(|mode| mode & S_IFMT) == Some(S_IFDIR);
+
+        if let Some(parent) = path.parent() {
+            out.create_dir_all(parent).await?;
+        }
+        if unix.map(|mode| mode & S_IFMT) == Some(S_IFLNK) {
+            // Symlinks are stored with the link target as their contents.
+            let to = PathBuf::from(OsString::from_vec(data));
+            debug!("read symlink: {} -> {}", path.display(), to.display());
+            out.symlink(to, path).await?;
+            continue;
+        }
+        if is_dir {
+            out.create_dir_all(&path).await?;
+        } else {
+            out.write(&path, data).await?;
+        }
+
+        let mode = match unix.map(|mode| mode & 0o7777) {
+            Some(mode) if mode != 0 => mode,
+            _ if is_dir => 0o755,
+            _ => 0o644,
+        };
+        let mode = match raw & ATTRIBUTE_READ_ONLY {
+            0 => mode,
+            _ => mode & !0o222,
+        };
+        out.set_permissions(&path, MemPermissions::from_mode(mode))
+            .await?;
+    }
+
+    Ok(SevenZInternalMetadata {
+        delegate: out,
+        compression: c,
+        ordered_paths,
+        times,
+        hard_links: IndexMap::new(),
+        state: SevenZState {
+            method: std::sync::Mutex::new(archive.method.unwrap_or_default()),
+            attributes: std::sync::Mutex::new(attributes),
+        },
+    })
+}
+
+async fn sevenz_close(sevenz: &SevenZFloppyDisk) -> Result<()> {
+    let disk = &sevenz.delegate;
+    let ordered_paths = &*sevenz.ordered_paths.lock().await;
+    let method = sevenz.method();
+    debug!("closing 7z at {} with {:?}", sevenz.path.display(), method);
+
+    let mut entries = vec![];
+    let mut contents = vec![];
+    for path in ordered_paths {
+        if path.as_os_str() == "/" {
+            continue;
+        }
+        let name = path.strip_prefix("/").unwrap_or(path);
+        let mut attributes = sevenz.attributes(path);
+        let (mode, data) = if let Ok(link) = disk.read_link(path).await {
+            debug!("writing symlink: {} -> {}", path.display(), link.display());
+            (S_IFLNK | 0o777, Some(link.as_os_str().as_bytes().to_vec()))
+        } else {
+            let metadata = disk.metadata(path).await?;
+            let mode = metadata.permissions().mode() & 0o7777;
+            if mode & 0o222 == 0 {
+                attributes |= ATTRIBUTE_READ_ONLY;
+            }
+            if metadata.is_dir() {
+                attributes |= ATTRIBUTE_DIRECTORY;
+                (S_IFDIR | mode, None)
+            } else {
+                (S_IFREG | mode, Some(disk.read(path).await?))
+            }
+        };
+        let is_dir = data.is_none();
+        let has_stream = data.as_ref().is_some_and(|data| !data.is_empty());
+        if let Some(data) = data.filter(|data| !data.is_empty()) {
+            contents.push(data);
+        }
+        entries.push(Entry {
+            name: String::from_utf8_lossy(name.as_os_str().as_bytes()).to_string(),
+            has_stream,
+            is_dir,
+            is_anti: false,
+            mtime: Some(sevenz.modified(path).await?),
+            attributes: Some(attributes | ATTRIBUTE_UNIX_EXTENSION | (mode << 16)),
+        });
+    }
+
+    let data = write_archive(&entries, &contents, method)?;
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&sevenz.path)
+        .await?;
+    let mut out = vec![];
+    crate::util::write_compressed(&data, &mut out, sevenz.compression).await?;
+    file.write_all(&out).await?;
+    file.flush().await?;
+    debug!("done writing 7z!");
+
+    Ok(())
+}
+
+/// A file, directory, or symlink in the archive.
+#[derive(Debug)]
+struct Entry {
+    name: String,
+    has_stream: bool,
+    is_dir: bool,
+    /// Anti-items mark deletions in update archives.
+    is_anti: bool,
+    mtime: Option<SystemTime>,
+    attributes: Option<u32>,
+}
+
+#[derive(Debug)]
+struct Archive {
+    entries: Vec<Entry>,
+    /// The contents of every entry that has a stream, in order.
+    contents: Vec<Vec<u8>>,
+    method: Option<SevenZMethod>,
+}
+
+impl Archive {
+    fn parse(data: &[u8]) -> Result<Archive> {
+        if data.len() < SIGNATURE_HEADER_SIZE || &data[..6] != SIGNATURE {
+            return Err(invalid("not a 7z archive"));
+        }
+        if data[6] != 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!("7z version {}.{} is not supported", data[6], data[7]),
+            ));
+        }
+        let start_header = &data[12..SIGNATURE_HEADER_SIZE];
+        if crc32fast::hash(start_header) != u32_at(data, 8) {
+            return Err(invalid("7z start header crc mismatch"));
+        }
+        let offset = SIGNATURE_HEADER_SIZE as u64 + u64_at(start_header, 0);
+        let size = u64_at(start_header, 8);
+        let header = usize::try_from(offset)
+            .ok()
+            .zip(usize::try_from(offset + size).ok())
+            .and_then(|(start, end)| data.get(start..end))
+            .ok_or_else(|| invalid("7z header is out of range"))?;
+        if crc32fast::hash(header) != u32_at(start_header, 16) {
+            return Err(invalid("7z header crc mismatch"));
+        }
+        if header.is_empty() {
+            // Nothing was ever added.
+            return Ok(Archive {
+                entries: vec![],
+                contents: vec![],
+                method: None,
+            });
+        }
+
+        let packed = &data[SIGNATURE_HEADER_SIZE..];
+        let mut header = header.to_vec();
+        loop {
+            let mut reader = Reader::new(&header);
+            match reader.byte()? {
+                property::HEADER => return Self::parse_header(&mut reader, packed),
+                property::ENCODED_HEADER => {
+                    debug!("decoding encoded 7z header");
+                    let streams = StreamsInfo::parse(&mut reader)?;
+                    header = streams.unpack(packed)?.0.concat();
+                }
+                id => return Err(invalid(&format!("unexpected 7z header property {id}"))),
+            }
+        }
+    }
+
+    fn parse_header(reader: &mut Reader, packed: &[u8]) -> Result<Archive> {
+        let mut id = reader.byte()?;
+        if id == property::ARCHIVE_PROPERTIES {
+            while reader.byte()? != property::END {
+                let size = reader.size()?;
+                reader.bytes(size)?;
+            }
+            id = reader.byte()?;
+        }
+        if id == property::ADDITIONAL_STREAMS_INFO {
+            StreamsInfo::parse(reader)?;
+            id = reader.byte()?;
+        }
+        let (contents, method) = if id == property::MAIN_STREAMS_INFO {
+            let streams = StreamsInfo::parse(reader)?;
+            id = reader.byte()?;
+            streams.unpack(packed)?
+        } else {
+            (vec![], None)
+        };
+        let entries = if id == property::FILES_INFO {
+            let entries = Self::parse_files(reader)?;
+            id = reader.byte()?;
+            entries
+        } else {
+            vec![]
+        };
+        if id != property::END {
+            return Err(invalid(&format!("unexpected 7z header property {id}")));
+        }
+
+        let streams = entries.iter().filter(|entry| entry.has_stream).count();
+        if streams != contents.len() {
+            return Err(invalid("7z archive has the wrong number of streams"));
+        }
+        Ok(Archive {
+            entries,
+            contents,
+            method,
+        })
+    }
+
+    fn parse_files(reader: &mut Reader) -> Result<Vec<Entry>> {
+        let count = reader.size()?;
+        let mut entries: Vec<Entry> = (0..count)
+            .map(|_| Entry {
+                name: String::new(),
+                has_stream: true,
+                is_dir: false,
+                is_anti: false,
+                mtime: None,
+                attributes: None,
+            })
+            .collect();
+        let mut empty_streams = vec![];
+        let mut empty_files = vec![];
+        let mut anti = vec![];
+
+        loop {
+            let id = reader.byte()?;
+            if id == property::END {
+                break;
+            }
+            let size = reader.size()?;
+            let mut reader = Reader::new(reader.bytes(size)?);
+            match id {
+                property::EMPTY_STREAM => empty_streams = reader.bits(count)?,
+                property::EMPTY_FILE => empty_files = reader.bits(count_set(&empty_streams))?,
+                property::ANTI => anti = reader.bits(count_set(&empty_streams))?,
+                property::NAME => {
+                    reader.external()?;
+                    let units: Vec<u16> = reader
+                        .rest()
+                        .chunks_exact(2)
+                        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+                        .collect();
+                    let mut names = units.split(|unit| *unit == 0);
+                    for entry in &mut entries {
+                        let name = names
+                            .next()
+                            .ok_or_else(|| invalid("7z archive has too few names"))?;
+                        entry.name = String::from_utf16_lossy(name);
+                    }
+                }
+                property::MTIME => {
+                    let defined = reader.defined(count)?;
+                    reader.external()?;
+                    for (entry, defined) in entries.iter_mut().zip(defined) {
+                        if defined {
+                            entry.mtime = Some(filetime_to_system_time(reader.u64()?));
+                        }
+                    }
+                }
+                property::WIN_ATTRIBUTES => {
+                    let defined = reader.defined(count)?;
+                    reader.external()?;
+                    for (entry, defined) in entries.iter_mut().zip(defined) {
+                        if defined {
+                            entry.attributes = Some(reader.u32()?);
+                        }
+                    }
+                }
+                id => trace!("skipping 7z file property {}", id),
+            }
+        }
+
+        let mut empty_idx = 0;
+        for (entry, empty) in entries.iter_mut().zip(empty_streams) {
+            if !empty {
+                continue;
+            }
+            entry.has_stream = false;
+            entry.is_dir = !empty_files.get(empty_idx).copied().unwrap_or(false);
+            entry.is_anti = anti.get(empty_idx).copied().unwrap_or(false);
+            empty_idx += 1;
+        }
+        Ok(entries)
+    }
+}
+
+#[derive(Debug)]
+struct Coder {
+    id: Vec<u8>,
+    inputs: usize,
+    outputs: usize,
+    properties: Vec<u8>,
+}
+
+/// A set of coders that together unpack one or more packed streams into a
+/// single block. Solid archives put many files in one folder.
+#[derive(Debug)]
+struct Folder {
+    coders: Vec<Coder>,
+    /// Which coder input is fed by which coder output.
+    bind_pairs: Vec<(usize, usize)>,
+    /// The coder inputs that are fed by packed streams.
+    packed: Vec<usize>,
+    unpack_sizes: Vec<u64>,
+    crc: Option<u32>,
+}
+
+impl Folder {
+    fn parse(reader: &mut Reader) -> Result<Folder> {
+        let mut coders = vec![];
+        for _ in 0..reader.size()? {
+            let flags = reader.byte()?;
+            if flags & 0x80 != 0 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    "7z alternative coder methods are not supported",
+                ));
+            }
+            let id = reader.bytes((flags & 0x0f) as usize)?.to_vec();
+            let (inputs, outputs) = match flags & 0x10 {
+                0 => (1, 1),
+                _ => (reader.size()?, reader.size()?),
+            };
+            let properties = match flags & 0x20 {
+                0 => vec![],
+                _ => {
+                    let size = reader.size()?;
+                    reader.bytes(size)?.to_vec()
+                }
+            };
+            coders.push(Coder {
+                id,
+                inputs,
+                outputs,
+                properties,
+            });
+        }
+
+        let inputs: usize = coders.iter().map(|coder| coder.inputs).sum();
+        let outputs: usize = coders.iter().map(|coder| coder.outputs).sum();
+        let mut bind_pairs = vec![];
+        for _ in 0..outputs.saturating_sub(1) {
+            bind_pairs.push((reader.size()?, reader.size()?));
+        }
+        let packed = match inputs.checked_sub(bind_pairs.len()) {
+            Some(1) => (0..inputs)
+                .filter(|input| bind_pairs.iter().all(|(bound, _)| bound != input))
+                .collect(),
+            Some(count) => (0..count).map(|_| reader.size()).collect::<Result<_>>()?,
+            None => return Err(invalid("7z folder has too many bind pairs")),
+        };
+        Ok(Folder {
+            coders,
+            bind_pairs,
+            packed,
+            unpack_sizes: vec![],
+            crc: None,
+        })
+    }
+
+    fn unpack_size(&self) -> Result<u64> {
+        let main = (0..self.unpack_sizes.len())
+            .find(|output| self.bind_pairs.iter().all(|(_, bound)| bound != output))
+            .ok_or_else(|| invalid("7z folder has no main output"))?;
+        Ok(self.unpack_sizes[main])
+    }
+
+    fn method(&self) -> Option<SevenZMethod> {
+        self.coders
+            .iter()
+            .find_map(|coder| match coder.id.as_slice() {
+                CODER_LZMA => Some(SevenZMethod::Lzma),
+                CODER_LZMA2 => Some(SevenZMethod::Lzma2),
+                _ => None,
+            })
+            .or(Some(SevenZMethod::Copy))
+    }
+
+    /// Unpacks the folder from its packed streams. Only chains of coders
+    /// with one input and one output are supported, which is what 7-Zip
+    /// writes for everything other than BCJ2.
+    fn unpack(&self, packed: &[&[u8]]) -> Result<Vec<u8>> {
+        let size = self.unpack_size()? as usize;
+        let mut filters = Filters::new();
+        let mut lzma = false;
+        let mut output = (0..self.unpack_sizes.len())
+            .find(|output| self.bind_pairs.iter().all(|(_, bound)| bound != output))
+            .unwrap_or_default();
+        let input = loop {
+            let (coder_idx, first_input) = self.coder_for_output(output)?;
+            let coder = &self.coders[coder_idx];
+            if coder.inputs != 1 || coder.outputs != 1 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    "7z coders with multiple streams are not supported",
+                ));
+            }
+            if add_filter(&mut filters, coder)? {
+                lzma = true;
+            }
+            match self
+                .bind_pairs
+                .iter()
+                .find(|(input, _)| *input == first_input)
+            {
+                Some((_, bound)) => output = *bound,
+                None => {
+                    let idx = self
+                        .packed
+                        .iter()
+                        .position(|input| *input == first_input)
+                        .ok_or_else(|| invalid("7z coder input is not bound"))?;
+                    break packed
+                        .get(idx)
+                        .ok_or_else(|| invalid("7z folder is missing a packed stream"))?;
+                }
+            }
+        };
+
+        let data = if lzma {
+            decode(
+                Stream::new_raw_decoder(&filters).map_err(lzma_err)?,
+                input,
+                size,
+            )?
+        } else if self.coders.len() == 1 {
+            input.get(..size).map(<[u8]>::to_vec).unwrap_or_default()
+        } else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "7z filters without a compressor are not supported",
+            ));
+        };
+        if data.len() != size {
+            return Err(invalid("7z folder is truncated"));
+        }
+        if self.crc.is_some_and(|crc| crc != crc32fast::hash(&data)) {
+            return Err(invalid("7z folder crc mismatch"));
+        }
+        Ok(data)
+    }
+
+    /// Finds the coder that `output` belongs to, and its first input.
+    fn coder_for_output(&self, output: usize) -> Result<(usize, usize)> {
+        let (mut outputs, mut inputs) = (0, 0);
+        for (idx, coder) in self.coders.iter().enumerate() {
+            if output < outputs + coder.outputs {
+                return Ok((idx, inputs));
+            }
+            outputs += coder.outputs;
+            inputs += coder.inputs;
+        }
+        Err(invalid("7z bind pair is out of range"))
+    }
+}
+
+/// Adds the liblzma filter for `coder`, returning whether it's a
+/// compressor rather than a filter that's applied before one.
+fn add_filter(filters: &mut Filters, coder: &Coder) -> Result<bool> {
+    let properties = coder.properties.as_slice();
+    let (result, compressor) = match coder.id.as_slice() {
+        CODER_COPY => return Ok(false),
+        CODER_LZMA => (filters.lzma1_properties(properties), true),
+        CODER_LZMA2 => (filters.lzma2_properties(properties), true),
+        [0x03] => (filters.delta_properties(properties), false),
+        [0x03, 0x03, 0x01, 0x03] => (filters.x86_properties(properties), false),
+        [0x03, 0x03, 0x02, 0x05] => (filters.powerpc_properties(properties), false),
+        [0x03, 0x03, 0x04, 0x01] => (filters.ia64_properties(properties), false),
+        [0x03, 0x03, 0x05, 0x01] => (filters.arm_properties(properties), false),
+        [0x03, 0x03, 0x07, 0x01] => (filters.arm_thumb_properties(properties), false),
+        [0x03, 0x03, 0x08, 0x05] => (filters.sparc_properties(properties), false),
+        [0x0a] => (filters.arm64_properties(properties), false),
+        [0x0b] => (filters.riscv_properties(properties), false),
+        CODER_AES => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "encrypted 7z archives are not supported",
+            ))
+        }
+        id => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!("7z coder {id:02x?} is not supported"),
+            ))
+        }
+    };
+    result.map_err(lzma_err)?;
+    Ok(compressor)
+}
+
+/// The size and crc of a stream within a folder.
+type Substream = (u64, Option<u32>);
+
+#[derive(Debug, Default)]
+struct StreamsInfo {
+    pack_position: u64,
+    pack_sizes: Vec<u64>,
+    folders: Vec<Folder>,
+    /// The size and crc of each stream in each folder.
+    substreams: Option<Vec<Vec<Substream>>>,
+}
+
+impl StreamsInfo {
+    fn parse(reader: &mut Reader) -> Result<StreamsInfo> {
+        let mut out = StreamsInfo::default();
+        let mut counts = None;
+        let mut sizes = vec![];
+        let mut crcs = None;
+        loop {
+            match reader.byte()? {
+                property::END => break,
+                property::PACK_INFO => {
+                    out.pack_position = reader.number()?;
+                    let count = reader.size()?;
+                    loop {
+                        match reader.byte()? {
+                            property::END => break,
+                            property::SIZE => {
+                                out.pack_sizes =
+                                    (0..count).map(|_| reader.number()).collect::<Result<_>>()?
+                            }
+                            property::CRC => {
+                                reader.digests(count)?;
+                            }
+                            id => {
+                                return Err(invalid(&format!("unexpected 7z pack property {id}")))
+                            }
+                        }
+                    }
+                }
+                property::UNPACK_INFO => {
+                    if reader.byte()? != property::FOLDER {
+                        return Err(invalid("7z unpack info has no folders"));
+                    }
+                    let count = reader.size()?;
+                    reader.external()?;
+                    for _ in 0..count {
+                        out.folders.push(Folder::parse(reader)?);
+                    }
+                    if reader.byte()? != property::CODERS_UNPACK_SIZE {
+                        return Err(invalid("7z unpack info has no sizes"));
+                    }
+                    for folder in &mut out.folders {
+                        let outputs = folder.coders.iter().map(|coder| coder.outputs).sum();
+                        folder.unpack_sizes = (0..outputs)
+                            .map(|_| reader.number())
+                            .collect::<Result<_>>()?;
+                    }
+                    loop {
+                        match reader.byte()? {
+                            property::END => break,
+                            property::CRC => {
+                                let digests = reader.digests(out.folders.len())?;
+                                for (folder, crc) in out.folders.iter_mut().zip(digests) {
+                                    folder.crc = crc;
+                                }
+                            }
+                            id => {
+                                return Err(invalid(&format!("unexpected 7z unpack property {id}")))
+                            }
+                        }
+                    }
+                }
+                property::SUBSTREAMS_INFO => loop {
+                    match reader.byte()? {
+                        property::END => break,
+                        property::NUM_UNPACK_STREAM => {
+                            counts = Some(
+                                (0..out.folders.len())
+                                    .map(|_| reader.size())
+                                    .collect::<Result<Vec<_>>>()?,
+                            )
+                        }
+                        property::SIZE => {
+                            for &count in counts.iter().flatten() {
+                                for _ in 1..count {
+                                    sizes.push(reader.number()?);
+                                }
+                            }
+                        }
+                        property::CRC => {
+                            let unknown = out
+                                .folders
+                                .iter()
+                                .zip(counts.clone().unwrap_or(vec![1; out.folders.len()]))
+                                .map(|(folder, count)| match (count, folder.crc) {
+                                    (1, Some(_)) => 0,
+                                    (count, _) => count,
+                                })
+                                .sum();
+                            crcs = Some(reader.digests(unknown)?);
+                        }
+                        id => {
+                            return Err(invalid(&format!("unexpected 7z substream property {id}")))
+                        }
+                    }
+                },
+                id => return Err(invalid(&format!("unexpected 7z streams property {id}"))),
+            }
+        }
+
+        // Work out the size and crc of every stream now that everything
+        // has been read.
+        let counts = counts.unwrap_or(vec![1; out.folders.len()]);
+        let mut sizes = sizes.into_iter();
+        let mut crcs = crcs.unwrap_or_default().into_iter();
+        let mut substreams = vec![];
+        for (folder, &count) in out.folders.iter().zip(&counts) {
+            let total = folder.unpack_size()?;
+            let mut streams = vec![];
+            let mut used = 0u64;
+            for idx in 0..count {
+                let size = match idx + 1 == count {
+                    true => total
+                        .checked_sub(used)
+                        .ok_or_else(|| invalid("7z substreams are bigger than their folder"))?,
+                    false => sizes
+                        .next()
+                        .ok_or_else(|| invalid("7z archive is missing substream sizes"))?,
+                };
+                used += size;
+                let crc = match (count, folder.crc) {
+                    (1, Some(crc)) => Some(crc),
+                    _ => crcs.next().flatten(),
+                };
+                streams.push((size, crc));
+            }
+            substreams.push(streams);
+        }
+        out.substreams = Some(substreams);
+        Ok(out)
+    }
+
+    /// Unpacks every stream, along with the method of the first folder.
+    fn unpack(&self, packed: &[u8]) -> Result<(Vec<Vec<u8>>, Option<SevenZMethod>)> {
+        let mut position = self.pack_position;
+        let mut pack_sizes = self.pack_sizes.iter();
+        let mut out = vec![];
+        let substreams = self.substreams.as_deref().unwrap_or_default();
+        for (folder, streams) in self.folders.iter().zip(substreams) {
+            let mut inputs = vec![];
+            for _ in 0..folder.packed.len() {
+                let size = *pack_sizes
+                    .next()
+                    .ok_or_else(|| invalid("7z archive is missing pack sizes"))?;
+                let input = usize::try_from(position)
+                    .ok()
+                    .zip(usize::try_from(position + size).ok())
+                    .and_then(|(start, end)| packed.get(start..end))
+                    .ok_or_else(|| invalid("7z packed stream is out of range"))?;
+                inputs.push(input);
+                position += size;
+            }
+            let data = folder.unpack(&inputs)?;
+
+            let mut offset = 0;
+            for &(size, crc) in streams {
+                let stream = &data[offset..offset + size as usize];
+                if crc.is_some_and(|crc| crc != crc32fast::hash(stream)) {
+                    return Err(invalid("7z stream crc mismatch"));
+                }
+                out.push(stream.to_vec());
+                offset += size as usize;
+            }
+        }
+        let method = self.folders.first().and_then(Folder::method);
+        Ok((out, method))
+    }
+}
+
+fn write_archive(entries: &[Entry], contents: &[Vec<u8>], method: SevenZMethod) -> Result<Vec<u8>> {
+    let solid = contents.concat();
+    let (packed, id, properties) = match method {
+        SevenZMethod::Copy => (solid.clone(), CODER_COPY, vec![]),
+        SevenZMethod::Lzma | SevenZMethod::Lzma2 => {
+            let options = LzmaOptions::new_preset(6).map_err(lzma_err)?;
+            // Preset 6 uses an 8MiB dictionary.
+            let dict = 8u32 << 20;
+            let mut filters = Filters::new();
+            let (id, properties) = if method == SevenZMethod::Lzma {
+                filters.lzma1(&options);
+                // lc = 3, lp = 0 and pb = 2, then the dictionary size.
+                let mut properties = vec![(2 * 5) * 9 + 3];
+                properties.extend(dict.to_le_bytes());
+                (CODER_LZMA, properties)
+            } else {
+                filters.lzma2(&options);
+                let bits = (0u8..40)
+                    .find(|bits| (2 | (*bits as u32 & 1)) << (bits / 2 + 11) >= dict)
+                    .unwrap_or(40);
+                (CODER_LZMA2, vec![bits])
+            };
+            let packed = encode(Stream::new_raw_encoder(&filters).map_err(lzma_err)?, &solid)?;
+            (packed, id, properties)
+        }
+    };
+    let mut coder = vec![id.len() as u8];
+    coder.extend(id);
+    if !properties.is_empty() {
+        coder[0] |= 0x20;
+        write_number(&mut coder, properties.len() as u64);
+        coder.extend(properties);
+    }
+
+    let mut header = vec![property::HEADER];
+    if !contents.is_empty() {
+        header.push(property::MAIN_STREAMS_INFO);
+        header.push(property::PACK_INFO);
+        write_number(&mut header, 0);
+        write_number(&mut header, 1);
+        header.push(property::SIZE);
+        write_number(&mut header, packed.len() as u64);
+        header.push(property::END);
+
+        header.extend([property::UNPACK_INFO, property::FOLDER]);
+        write_number(&mut header, 1);
+        header.push(0);
+        write_number(&mut header, 1);
+        header.extend(&coder);
+        header.push(property::CODERS_UNPACK_SIZE);
+        write_number(&mut header, solid.len() as u64);
+        header.push(property::END);
+
+        header.extend([property::SUBSTREAMS_INFO, property::NUM_UNPACK_STREAM]);
+        write_number(&mut header, contents.len() as u64);
+        header.push(property::SIZE);
+        for data in &contents[..contents.len() - 1] {
+            write_number(&mut header, data.len() as u64);
+        }
+        header.extend([property::CRC, 1]);
+        for data in contents {
+            header.extend(crc32fast::hash(data).to_le_bytes());
+        }
+        header.push(property::END);
+        header.push(property::END);
+    }
+
+    if !entries.is_empty() {
+        header.push(property::FILES_INFO);
+        write_number(&mut header, entries.len() as u64);
+        let empty: Vec<&Entry> = entries.iter().filter(|entry| !entry.has_stream).collect();
+        if !empty.is_empty() {
+            let bits = write_bits(entries.iter().map(|entry| !entry.has_stream));
+            write_property(&mut header, property::EMPTY_STREAM, &bits);
+            if empty.iter().any(|entry| !entry.is_dir) {
+                let bits = write_bits(empty.iter().map(|entry| !entry.is_dir));
+                write_property(&mut header, property::EMPTY_FILE, &bits);
+            }
+        }
+
+        let mut names = vec![0];
+        for entry in entries {
+            for unit in entry.name.encode_utf16().chain([0]) {
+                names.extend(unit.to_le_bytes());
+            }
+        }
+        write_property(&mut header, property::NAME, &names);
+
+        let mut times = vec![1, 0];
+        for entry in entries {
+            times.extend(
+                system_time_to_filetime(entry.mtime.unwrap_or(std::time::UNIX_EPOCH)).to_le_bytes(),
+            );
+        }
+        write_property(&mut header, property::MTIME, &times);
+
+        let mut attributes = vec![1, 0];
+        for entry in entries {
+            attributes.extend(entry.attributes.unwrap_or(0).to_le_bytes());
+        }
+        write_property(&mut header, property::WIN_ATTRIBUTES, &attributes);
+        header.push(property::END);
+    }
+    header.push(property::END);
+
+    let mut start_header = vec![];
+    start_header.extend((packed.len() as u64).to_le_bytes());
+    start_header.extend((header.len() as u64).to_le_bytes());
+    start_header.extend(crc32fast::hash(&header).to_le_bytes());
+
+    let mut out = SIGNATURE.to_vec();
+    out.extend([0, 4]);
+    out.extend(crc32fast::hash(&start_header).to_le_bytes());
+    out.extend(start_header);
+    out.extend(packed);
+    out.extend(header);
+    Ok(out)
+}
+
+fn count_set(bits: &[bool]) -> usize {
+    bits.iter().filter(|bit| **bit).count()
+}
+
+fn write_property(out: &mut Vec<u8>, id: u8, data: &[u8]) {
+    out.push(id);
+    write_number(out, data.len() as u64);
+    out.extend(data);
+}
+
+/// Writes a number the way 7z does: the count of leading set bits in the
+/// first byte is how many more bytes follow, and the rest of the first byte
+/// is the highest part of the number.
+fn write_number(out: &mut Vec<u8>, value: u64) {
+    for extra in 0..8 {
+        if value < 1 << (7 * (extra + 1)) {
+            let high = (value >> (8 * extra)) as u8;
+            out.push((0xff00u16 >> extra) as u8 | high);
+            out.extend(&value.to_le_bytes()[..extra]);
+            return;
+        }
+    }
+    out.push(0xff);
+    out.extend(value.to_le_bytes());
+}
+
+fn write_bits<I: Iterator<Item = bool>>(bits: I) -> Vec<u8> {
+    let mut out = vec![];
+    for (idx, bit) in bits.enumerate() {
+        if idx % 8 == 0 {
+            out.push(0);
+        }
+        if bit {
+            *out.last_mut().unwrap() |= 0x80 >> (idx % 8);
+        }
+    }
+    out
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .data
+            .get(self.position..self.position.saturating_add(len))
+            .ok_or_else(|| invalid("7z header is truncated"))?;
+        self.position += len;
+        Ok(bytes)
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.data[self.position..];
+        self.position = self.data.len();
+        rest
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32_at(self.bytes(4)?, 0))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64_at(self.bytes(8)?, 0))
+    }
+
+    fn number(&mut self) -> Result<u64> {
+        let first = self.byte()?;
+        let mut value = 0u64;
+        for extra in 0..8 {
+            let mask = 0x80 >> extra;
+            if first & mask == 0 {
+                let high = (first & (mask.wrapping_sub(1))) as u64;
+                return Ok(value | (high << (8 * extra)));
+            }
+            value |= (self.byte()? as u64) << (8 * extra);
+        }
+        Ok(value)
+    }
+
+    /// A number that's used as a count or length, so it has to fit in
+    /// memory.
+    fn size(&mut self) -> Result<usize> {
+        let size = self.number()?;
+        match usize::try_from(size) {
+            Ok(size) if size <= self.data.len().max(1 << 16) * 8 => Ok(size),
+            _ => Err(invalid("7z header has an impossible size")),
+        }
+    }
+
+    fn external(&mut self) -> Result<()> {
+        match self.byte()? {
+            0 => Ok(()),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "external 7z header data is not supported",
+            )),
+        }
+    }
+
+    fn bits(&mut self, count: usize) -> Result<Vec<bool>> {
+        let bytes = self.bytes(count.div_ceil(8))?;
+        Ok((0..count)
+            .map(|idx| bytes[idx / 8] & (0x80 >> (idx % 8)) != 0)
+            .collect())
+    }
+
+    /// Which of `count` items are defined, where a leading byte says
+    /// whether they all are.
+    fn defined(&mut self, count: usize) -> Result<Vec<bool>> {
+        match self.byte()? {
+            0 => self.bits(count),
+            _ => Ok(vec![true; count]),
+        }
+    }
+
+    fn digests(&mut self, count: usize) -> Result<Vec<Option<u32>>> {
+        self.defined(count)?
+            .into_iter()
+            .map(|defined| match defined {
+                true => self.u32().map(Some),
+                false => Ok(None),
+            })
+            .collect()
+    }
+}
+
+/// Runs a raw liblzma decoder until it has produced `size` bytes. 7z
+/// streams don't need an end marker, since their size is known.
+fn decode(mut stream: Stream, mut input: &[u8], size: usize) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(size);
+    while out.len() < size {
+        let (total_in, total_out) = (stream.total_in(), out.len());
+        let status = stream
+            .process_vec(input, &mut out, Action::Run)
+            .map_err(lzma_err)?;
+        input = &input[(stream.total_in() - total_in) as usize..];
+        if status == Status::StreamEnd {
+            break;
+        }
+        if stream.total_in() == total_in && out.len() == total_out {
+            return Err(invalid("7z stream is truncated"));
+        }
+    }
+    Ok(out)
+}
+
+fn encode(mut stream: Stream, mut input: &[u8]) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(input.len() / 2 + 64);
+    loop {
+        if out.len() == out.capacity() {
+            out.reserve(out.capacity().max(4096));
+        }
+        let total_in = stream.total_in();
+        let status = stream
+            .process_vec(input, &mut out, Action::Finish)
+            .map_err(lzma_err)?;
+        input = &input[(stream.total_in() - total_in) as usize..];
+        if status == Status::StreamEnd {
+            return Ok(out);
+        }
+    }
+}
+
+fn filetime_to_system_time(filetime: u64) -> SystemTime {
+    match filetime.checked_sub(FILETIME_UNIX_EPOCH) {
+        Some(since) => std::time::UNIX_EPOCH + std::time::Duration::from_nanos(since * 100),
+        None => std::time::UNIX_EPOCH,
+    }
+}
+
+fn system_time_to_filetime(time: SystemTime) -> u64 {
+    let since = time
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    FILETIME_UNIX_EPOCH + (since.as_nanos() / 100) as u64
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+fn lzma_err(err: liblzma::stream::Error) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, err)
+}
+
+fn invalid(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod sevenz_tests {
+    use super::*;
+
+    #[test_log::test(tokio::test)]
+    async fn test_fixture_works() -> Result<()> {
+        let disk = SevenZFloppyDisk::open("./fixtures/a.7z").await?;
+        assert_eq!(SevenZMethod::Lzma2, disk.method());
+
+        let big = disk.read_to_string("/big.txt").await?;
+        assert_eq!(400, big.lines().count());
+        assert_eq!("line 399 of a solid block", big.lines().last().unwrap());
+        assert_eq!(
+            "hello from a subdirectory\n",
+            disk.read_to_string("/dir/nested.txt").await?
+        );
+        let lzma = disk.read("/lzma.bin").await?;
+        assert!(lzma.iter().enumerate().all(|(i, b)| *b == (i % 7) as u8));
+        assert_eq!(
+            0o600,
+            disk.metadata("/lzma.bin").await?.permissions().mode()
+        );
+        assert_eq!(
+            "stored without compression\n",
+            disk.read_to_string("/stored.txt").await?
+        );
+        assert_eq!("", disk.read_to_string("/empty.txt").await?);
+        assert!(disk.metadata("/empty_dir").await?.is_dir());
+        assert_eq!(PathBuf::from("a.txt"), disk.read_link("/link").await?);
+
+        let readonly = disk.metadata("/readonly.txt").await?;
+        assert_eq!(0o444, readonly.permissions().mode());
+        assert_eq!(0x22, disk.attributes("/readonly.txt"));
+        assert_eq!(
+            std::time::UNIX_EPOCH + std::time::Duration::from_secs(1687000000),
+            readonly.modified()?
+        );
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_methods_work() -> Result<()> {
+        for method in [SevenZMethod::Copy, SevenZMethod::Lzma, SevenZMethod::Lzma2] {
+            let archive = crate::util::tests::TempFile::new("./fixtures/a.7z").await?;
+            {
+                let disk = SevenZFloppyDisk::open(archive.path_view()).await?;
+                disk.set_method(method);
+                disk.write("/new.txt", "new").await?;
+                disk.set_attributes("/new.txt", 0x02);
+                disk.close().await?;
+            }
+
+            let disk = SevenZFloppyDisk::open(archive.path_view()).await?;
+            assert_eq!(method, disk.method());
+            assert_eq!("new", disk.read_to_string("/new.txt").await?);
+            assert_eq!(0x02, disk.attributes("/new.txt"));
+            assert_eq!("asdf\n", disk.read_to_string("/a.txt").await?);
+            let lzma = disk.read("/lzma.bin").await?;
+            assert!(lzma.iter().enumerate().all(|(i, b)| *b == (i % 7) as u8));
+            assert_eq!(PathBuf::from("a.txt"), disk.read_link("/link").await?);
+            assert_eq!("", disk.read_to_string("/empty.txt").await?);
+            assert!(disk.metadata("/empty_dir").await?.is_dir());
+            assert_eq!(
+                0o444,
+                disk.metadata("/readonly.txt").await?.permissions().mode()
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_numbers_work() -> Result<()> {
+        for value in [0, 1, 0x7f, 0x80, 0x3fff, 0x4000, 0x1234_5678, u64::MAX] {
+            let mut out = vec![];
+            write_number(&mut out, value);
+            let mut reader = Reader::new(&out);
+            assert_eq!(value, reader.number()?);
+            assert_eq!(out.len(), reader.position);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,793 @@
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::FileExt;
+
+use tracing::{debug, trace};
+
+crate::image::image_format!(Squashfs, "a.squashfs", SquashfsReader, squashfs_open);
+
+const MAGIC: u32 = 0x73717368;
+const SUPERBLOCK_SIZE: usize = 96;
+const METADATA_SIZE: usize = 8192;
+const NO_TABLE: u64 = u64::MAX;
+const NO_FRAGMENT: u32 = u32::MAX;
+const NO_XATTRS: u32 = u32::MAX;
+
+/// Set in a data block or fragment size when it's stored uncompressed.
+const BLOCK_UNCOMPRESSED: u32 = 1 << 24;
+/// Set in a metadata block header when it's stored uncompressed.
+const METADATA_UNCOMPRESSED: u16 = 1 << 15;
+/// Set in an xattr type when the value is a reference to another value.
+const XATTR_OUT_OF_LINE: u16 = 0x100;
+
+const BASIC_DIR: u16 = 1;
+const BASIC_FILE: u16 = 2;
+const BASIC_SYMLINK: u16 = 3;
+const EXTENDED_DIR: u16 = 8;
+const EXTENDED_FILE: u16 = 9;
+const EXTENDED_SYMLINK: u16 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SquashfsCompression {
+    Gzip,
+    Lzma,
+    Lzo,
+    Xz,
+    Lz4,
+    Zstd,
+}
+
+impl SquashfsCompression {
+    fn from_id(id: u16) -> Result<Self> {
+        match id {
+            1 => Ok(Self::Gzip),
+            2 => Ok(Self::Lzma),
+            3 => Ok(Self::Lzo),
+            4 => Ok(Self::Xz),
+            5 => Ok(Self::Lz4),
+            6 => Ok(Self::Zstd),
+            id => Err(invalid(&format!("unknown squashfs compression {id}"))),
+        }
+    }
+
+    /// Decompresses a block that is at most `max` bytes when decompressed.
+    fn decompress(&self, data: &[u8], max: usize) -> Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(max);
+        match self {
+            Self::Gzip => {
+                flate2::read::ZlibDecoder::new(data).read_to_end(&mut out)?;
+            }
+            Self::Lzma => {
+                let stream = liblzma::stream::Stream::new_lzma_decoder(u64::MAX)?;
+                liblzma::read::XzDecoder::new_stream(data, stream).read_to_end(&mut out)?;
+            }
+            Self::Xz => {
+                liblzma::read::XzDecoder::new(data).read_to_end(&mut out)?;
+            }
+            Self::Zstd => {
+                zstd::stream::read::Decoder::new(data)?.read_to_end(&mut out)?;
+            }
+            Self::Lz4 => out = lz4::block::decompress(data, Some(max as i32))?,
+            Self::Lzo => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    "lzo compressed squashfs images are not supported",
+                ))
+            }
+        }
+        if out.len() > max {
+            return Err(invalid("squashfs block is too big"));
+        }
+        Ok(out)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct SquashfsReader {
+    file: std::fs::File,
+    compression: SquashfsCompression,
+    block_size: u32,
+    /// The position and size of each fragment block.
+    fragments: Vec<(u64, u32)>,
+    /// Extended attributes, keyed by inode number.
+    xattrs: HashMap<u64, Vec<(String, Vec<u8>)>>,
+}
+
+/// Where a file's contents are: a run of data blocks, then the tail of the
+/// file, which may be packed into a fragment block with other tails.
+#[derive(Debug, Clone)]
+pub(crate) struct SquashfsBlocks {
+    start: u64,
+    blocks: Vec<u32>,
+    /// The index of the fragment block, and where the tail starts in it.
+    fragment: Option<(u32, u32)>,
+}
+
+#[async_trait::async_trait]
+impl ImageReader for SquashfsReader {
+    type Data = SquashfsBlocks;
+
+    async fn read_file(&self, data: &Self::Data, size: u64) -> Result<Vec<u8>> {
+        let block_size = self.block_size as usize;
+        // The size comes from the inode, so it's checked against what the
+        // blocks and the fragment tail could hold before it's allocated.
+        let max = (data.blocks.len() + data.fragment.is_some() as usize) as u64 * block_size as u64;
+        if size > max {
+            return Err(invalid("squashfs file is bigger than its blocks"));
+        }
+        let mut out = Vec::with_capacity(size as usize);
+        let mut position = data.start;
+        for &block in &data.blocks {
+            let remaining = size as usize - out.len();
+            if block == 0 {
+                // Sparse blocks aren't stored at all.
+                out.resize(out.len() + remaining.min(block_size), 0);
+                continue;
+            }
+            let block = self.read_block(position, block)?;
+            position += (block.1) as u64;
+            out.extend_from_slice(&block.0[..block.0.len().min(remaining)]);
+        }
+
+        if let Some((index, offset)) = data.fragment {
+            let &(start, stored) = self
+                .fragments
+                .get(index as usize)
+                .ok_or_else(|| invalid("squashfs fragment is out of range"))?;
+            trace!("reading tail from fragment {}", index);
+            let (fragment, _) = self.read_block(start, stored)?;
+            let offset = offset as usize;
+            let tail = fragment
+                .get(offset..offset + size as usize - out.len())
+                .ok_or_else(|| invalid("squashfs fragment is truncated"))?;
+            out.extend_from_slice(tail);
+        }
+
+        if out.len() as u64 != size {
+            return Err(invalid("squashfs file is truncated"));
+        }
+        Ok(out)
+    }
+}
+
+impl SquashfsReader {
+    /// Reads a data or fragment block, returning its contents and how many
+    /// bytes it took up in the image.
+    fn read_block(&self, position: u64, stored: u32) -> Result<(Vec<u8>, u32)> {
+        let len = stored & !BLOCK_UNCOMPRESSED;
+        let mut data = vec![0; len as usize];
+        self.file.read_exact_at(&mut data, position)?;
+        if stored & BLOCK_UNCOMPRESSED == 0 {
+            data = self
+                .compression
+                .decompress(&data, self.block_size as usize)?;
+        }
+        Ok((data, len))
+    }
+}
+
+impl SquashfsFloppyDisk {
+    pub fn compression(&self) -> SquashfsCompression {
+        self.reader.compression
+    }
+
+    pub fn block_size(&self) -> u32 {
+        self.reader.block_size
+    }
+
+    /// The extended attributes of `path`, with their `user.`, `trusted.` or
+    /// `security.` prefixes. Symlinks are not followed.
+    pub fn xattrs<P: AsRef<Path>>(&self, path: P) -> Result<Vec<(String, Vec<u8>)>> {
+        let (_, entry) = self.entry(path, false)?;
+        Ok(self
+            .reader
+            .xattrs
+            .get(&entry.ino)
+            .cloned()
+            .unwrap_or_default())
+    }
+}
+
+async fn squashfs_open(path: &Path) -> Result<(SquashfsReader, ImageTree<SquashfsData>)> {
+    debug!("opening squashfs image {}", path.display());
+    let file = std::fs::File::open(path)?;
+    let mut superblock = [0; SUPERBLOCK_SIZE];
+    file.read_exact_at(&mut superblock, 0)?;
+    let superblock = Superblock::parse(&superblock)?;
+    debug!(
+        "found {:?} compressed squashfs with {} inodes",
+        superblock.compression, superblock.inode_count
+    );
+
+    let mut metadata = Metadata {
+        file: &file,
+        compression: superblock.compression,
+        cache: HashMap::new(),
+    };
+    let ids: Vec<u32> = metadata
+        .read_table(superblock.id_table, superblock.id_count as usize * 4)?
+        .chunks_exact(4)
+        .map(|id| le_u32(id, 0))
+        .collect();
+    let fragments = match superblock.fragment_table {
+        NO_TABLE => vec![],
+        position => metadata
+            .read_table(position, superblock.fragment_count as usize * 16)?
+            .chunks_exact(16)
+            .map(|entry| (le_u64(entry, 0), le_u32(entry, 8)))
+            .collect(),
+    };
+    let xattr_table = match superblock.xattr_table {
+        NO_TABLE => None,
+        position => Some(XattrTable::read(&mut metadata, position)?),
+    };
+
+    let mut walker = Walker {
+        metadata,
+        superblock: &superblock,
+        ids,
+        xattr_table,
+        xattrs: HashMap::new(),
+    };
+    let tree = walker.walk()?;
+    let xattrs = walker.xattrs;
+
+    Ok((
+        SquashfsReader {
+            file,
+            compression: superblock.compression,
+            block_size: superblock.block_size,
+            fragments,
+            xattrs,
+        },
+        tree,
+    ))
+}
+
+#[derive(Debug)]
+struct Superblock {
+    inode_count: u32,
+    block_size: u32,
+    fragment_count: u32,
+    compression: SquashfsCompression,
+    id_count: u16,
+    root_inode: u64,
+    id_table: u64,
+    xattr_table: u64,
+    inode_table: u64,
+    directory_table: u64,
+    fragment_table: u64,
+}
+
+impl Superblock {
+    fn parse(data: &[u8]) -> Result<Self> {
+        if le_u32(data, 0) != MAGIC {
+            return Err(invalid("not a squashfs image"));
+        }
+        let (major, minor) = (le_u16(data, 28), le_u16(data, 30));
+        if (major, minor) != (4, 0) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!("squashfs {major}.{minor} images are not supported"),
+            ));
+        }
+        let block_size = le_u32(data, 12);
+        if !block_size.is_power_of_two() || !(4096..=1 << 20).contains(&block_size) {
+            return Err(invalid("invalid squashfs block size"));
+        }
+        Ok(Self {
+            inode_count: le_u32(data, 4),
+            block_size,
+            fragment_count: le_u32(data, 16),
+            compression: SquashfsCompression::from_id(le_u16(data, 20))?,
+            id_count: le_u16(data, 26),
+            root_inode: le_u64(data, 32),
+            id_table: le_u64(data, 48),
+            xattr_table: le_u64(data, 56),
+            inode_table: le_u64(data, 64),
+            directory_table: le_u64(data, 72),
+            fragment_table: le_u64(data, 80),
+        })
+    }
+}
+
+/// Reads the metadata blocks that the inode, directory and other tables
+/// are made of.
+struct Metadata<'a> {
+    file: &'a std::fs::File,
+    compression: SquashfsCompression,
+    /// Each block that has been read, and where the next one starts.
+    cache: HashMap<u64, (Vec<u8>, u64)>,
+}
+
+impl Metadata<'_> {
+    fn block(&mut self, position: u64) -> Result<&(Vec<u8>, u64)> {
+        if !self.cache.contains_key(&position) {
+            let mut header = [0; 2];
+            self.file.read_exact_at(&mut header, position)?;
+            let header = u16::from_le_bytes(header);
+            let len = (header & !METADATA_UNCOMPRESSED) as usize;
+            let mut data = vec![0; len];
+            self.file.read_exact_at(&mut data, position + 2)?;
+            if header & METADATA_UNCOMPRESSED == 0 {
+                data = self.compression.decompress(&data, METADATA_SIZE)?;
+            }
+            self.cache
+                .insert(position, (data, position + 2 + len as u64));
+        }
+        Ok(&self.cache[&position])
+    }
+
+    /// Reads `len` bytes starting `offset` bytes into the block at
+    /// `position`, moving both along to just after what was read.
+    fn read(&mut self, position: &mut u64, offset: &mut usize, len: usize) -> Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(len);
+        while out.len() < len {
+            let (data, next) = self.block(*position)?;
+            if *offset >= data.len() {
+                if data.is_empty() {
+                    return Err(invalid("empty squashfs metadata block"));
+                }
+                *offset -= data.len();
+                *position = *next;
+                continue;
+            }
+            let take = (len - out.len()).min(data.len() - *offset);
+            out.extend_from_slice(&data[*offset..*offset + take]);
+            *offset += take;
+        }
+        Ok(out)
+    }
+
+    /// Reads a table through its lookup table, which lists where each of
+    /// its metadata blocks is. The blocks are always back to back.
+    fn read_table(&mut self, lookup: u64, len: usize) -> Result<Vec<u8>> {
+        if len == 0 {
+            return Ok(vec![]);
+        }
+        let mut first = [0; 8];
+        self.file.read_exact_at(&mut first, lookup)?;
+        self.read(&mut u64::from_le_bytes(first), &mut 0, len)
+    }
+}
+
+struct XattrTable {
+    /// Where the key/value pairs start.
+    start: u64,
+    /// The location, count and size of each set of xattrs.
+    ids: Vec<(u64, u32)>,
+}
+
+impl XattrTable {
+    fn read(metadata: &mut Metadata, position: u64) -> Result<Self> {
+        let mut header = [0; 16];
+        metadata.file.read_exact_at(&mut header, position)?;
+        let count = le_u32(&header, 8) as usize;
+        let ids = metadata
+            .read_table(position + 16, count * 16)?
+            .chunks_exact(16)
+            .map(|entry| (le_u64(entry, 0), le_u32(entry, 8)))
+            .collect();
+        Ok(Self {
+            start: le_u64(&header, 0),
+            ids,
+        })
+    }
+
+    fn get(&self, metadata: &mut Metadata, index: u32) -> Result<Vec<(String, Vec<u8>)>> {
+        let &(reference, count) = self
+            .ids
+            .get(index as usize)
+            .ok_or_else(|| invalid("squashfs xattr index is out of range"))?;
+        let (mut position, mut offset) = self.locate(reference);
+        let mut out = vec![];
+        for _ in 0..count {
+            let header = metadata.read(&mut position, &mut offset, 4)?;
+            let kind = le_u16(&header, 0);
+            let name = metadata.read(&mut position, &mut offset, le_u16(&header, 2) as usize)?;
+            let prefix = match kind & !XATTR_OUT_OF_LINE {
+                0 => "user.",
+                1 => "trusted.",
+                2 => "security.",
+                kind => return Err(invalid(&format!("unknown squashfs xattr type {kind}"))),
+            };
+            let len = le_u32(&metadata.read(&mut position, &mut offset, 4)?, 0) as usize;
+            let mut value = metadata.read(&mut position, &mut offset, len)?;
+            if kind & XATTR_OUT_OF_LINE != 0 {
+                let (mut position, mut offset) = self.locate(le_u64(&value, 0));
+                let len = le_u32(&metadata.read(&mut position, &mut offset, 4)?, 0) as usize;
+                value = metadata.read(&mut position, &mut offset, len)?;
+            }
+            out.push((format!("{prefix}{}", String::from_utf8_lossy(&name)), value));
+        }
+        Ok(out)
+    }
+
+    fn locate(&self, reference: u64) -> (u64, usize) {
+        (
+            self.start + (reference >> 16),
+            (reference & 0xffff) as usize,
+        )
+    }
+}
+
+/// The parts of an inode that matter for building the tree.
+struct Inode {
+    kind: InodeKind,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    mtime: u32,
+    ino: u32,
+    links: u32,
+    xattrs: u32,
+}
+
+enum InodeKind {
+    Directory {
+        start: u32,
+        offset: u16,
+        size: u32,
+    },
+    File {
+        size: u64,
+        data: SquashfsBlocks,
+    },
+    Symlink(PathBuf),
+    /// Devices, fifos and sockets, which can't be represented.
+    Other(u16),
+}
+
+struct Walker<'a> {
+    metadata: Metadata<'a>,
+    superblock: &'a Superblock,
+    ids: Vec<u32>,
+    xattr_table: Option<XattrTable>,
+    xattrs: HashMap<u64, Vec<(String, Vec<u8>)>>,
+}
+
+impl Walker<'_> {
+    fn walk(&mut self) -> Result<ImageTree<SquashfsData>> {
+        let root = self.inode(self.superblock.root_inode)?;
+        let mut tree = ImageTree::new(self.entry(&root)?);
+
+        let mut visited = HashSet::from([root.ino]);
+        let mut pending = vec![(PathBuf::from("/"), root)];
+        while let Some((dir, inode)) = pending.pop() {
+            let InodeKind::Directory {
+                start,
+                offset,
+                size,
+            } = inode.kind
+            else {
+                return Err(invalid("squashfs directory isn't a directory"));
+            };
+            for (name, reference) in self.read_dir(start, offset, size)? {
+                if name.contains('/') || name == "." || name == ".." {
+                    return Err(invalid(&format!("invalid squashfs name {name:?}")));
+                }
+                let path = dir.join(&name);
+                trace!("found {}", path.display());
+                let inode = self.inode(reference)?;
+                if let InodeKind::Other(kind) = inode.kind {
+                    trace!("skipping {} with inode type {}", path.display(), kind);
+                    continue;
+                }
+                tree.insert(&path, self.entry(&inode)?);
+                if matches!(inode.kind, InodeKind::Directory { .. }) {
+                    if !visited.insert(inode.ino) {
+                        return Err(invalid("squashfs directories loop"));
+                    }
+                    pending.push((path, inode));
+                }
+            }
+        }
+
+        Ok(tree)
+    }
+
+    fn entry(&mut self, inode: &Inode) -> Result<ImageEntry<SquashfsData>> {
+        if inode.xattrs != NO_XATTRS {
+            if let Some(table) = &self.xattr_table {
+                let xattrs = table.get(&mut self.metadata, inode.xattrs)?;
+                self.xattrs.insert(inode.ino as u64, xattrs);
+            }
+        }
+
+        let id = |index: u32| {
+            self.ids
+                .get(index as usize)
+                .copied()
+                .ok_or_else(|| invalid("squashfs id index is out of range"))
+        };
+        let base = ImageEntry {
+            mode: inode.mode,
+            uid: id(inode.uid)?,
+            gid: id(inode.gid)?,
+            mtime: std::time::UNIX_EPOCH + std::time::Duration::from_secs(inode.mtime as u64),
+            ino: inode.ino as u64,
+            ..ImageEntry::directory(0)
+        };
+        Ok(match &inode.kind {
+            InodeKind::File { size, data } => ImageEntry {
+                kind: ImageKind::File(data.clone()),
+                size: *size,
+                link_id: (inode.links > 1).then_some(inode.ino as u64),
+                ..base
+            },
+            InodeKind::Symlink(target) => ImageEntry {
+                kind: ImageKind::Symlink(target.clone()),
+                size: target.as_os_str().len() as u64,
+                ..base
+            },
+            InodeKind::Directory { .. } | InodeKind::Other(_) => base,
+        })
+    }
+
+    fn inode(&mut self, reference: u64) -> Result<Inode> {
+        let mut position = self.superblock.inode_table + (reference >> 16);
+        let mut offset = (reference & 0xffff) as usize;
+        let mut read = |len: usize| self.metadata.read(&mut position, &mut offset, len);
+
+        let header = read(16)?;
+        let kind = le_u16(&header, 0);
+        let mut links = 1;
+        let mut xattrs = NO_XATTRS;
+        let kind = match kind {
+            BASIC_DIR => {
+                let body = read(16)?;
+                links = le_u32(&body, 4);
+                InodeKind::Directory {
+                    start: le_u32(&body, 0),
+                    offset: le_u16(&body, 10),
+                    size: le_u16(&body, 8) as u32,
+                }
+            }
+            EXTENDED_DIR => {
+                let body = read(24)?;
+                links = le_u32(&body, 0);
+                xattrs = le_u32(&body, 20);
+                InodeKind::Directory {
+                    start: le_u32(&body, 8),
+                    offset: le_u16(&body, 18),
+                    size: le_u32(&body, 4),
+                }
+            }
+            BASIC_FILE | EXTENDED_FILE => {
+                let (start, size, fragment, fragment_offset) = if kind == BASIC_FILE {
+                    let body = read(16)?;
+                    let size = le_u32(&body, 12) as u64;
+                    (
+                        le_u32(&body, 0) as u64,
+                        size,
+                        le_u32(&body, 4),
+                        le_u32(&body, 8),
+                    )
+                } else {
+                    let body = read(40)?;
+                    links = le_u32(&body, 24);
+                    xattrs = le_u32(&body, 36);
+                    (
+                        le_u64(&body, 0),
+                        le_u64(&body, 8),
+                        le_u32(&body, 28),
+                        le_u32(&body, 32),
+                    )
+                };
+                let block_size = self.superblock.block_size as u64;
+                let count = match fragment {
+                    NO_FRAGMENT => size.div_ceil(block_size),
+                    _ => size / block_size,
+                };
+                let blocks = read(count as usize * 4)?
+                    .chunks_exact(4)
+                    .map(|block| le_u32(block, 0))
+                    .collect();
+                InodeKind::File {
+                    size,
+                    data: SquashfsBlocks {
+                        start,
+                        blocks,
+                        fragment: (fragment != NO_FRAGMENT).then_some((fragment, fragment_offset)),
+                    },
+                }
+            }
+            BASIC_SYMLINK | EXTENDED_SYMLINK => {
+                let body = read(8)?;
+                links = le_u32(&body, 0);
+                let target = read(le_u32(&body, 4) as usize)?;
+                if kind == EXTENDED_SYMLINK {
+                    xattrs = le_u32(&read(4)?, 0);
+                }
+                InodeKind::Symlink(PathBuf::from(OsString::from_vec(target)))
+            }
+            4..=7 | 11..=14 => InodeKind::Other(kind),
+            kind => return Err(invalid(&format!("unknown squashfs inode type {kind}"))),
+        };
+
+        Ok(Inode {
+            kind,
+            mode: le_u16(&header, 2) as u32 & 0o7777,
+            uid: le_u16(&header, 4) as u32,
+            gid: le_u16(&header, 6) as u32,
+            mtime: le_u32(&header, 8),
+            ino: le_u32(&header, 12),
+            links,
+            xattrs,
+        })
+    }
+
+    /// Reads a directory listing, returning each name and where its inode
+    /// is.
+    fn read_dir(&mut self, start: u32, offset: u16, size: u32) -> Result<Vec<(String, u64)>> {
+        // The size includes 3 bytes for the implicit `.` and `..`.
+        let Some(size) = size.checked_sub(3) else {
+            return Ok(vec![]);
+        };
+        let mut position = self.superblock.directory_table + start as u64;
+        let data = self
+            .metadata
+            .read(&mut position, &mut (offset as usize), size as usize)?;
+
+        let mut out = vec![];
+        let mut at = 0;
+        while at + 12 <= data.len() {
+            let count = le_u32(&data, at) as usize + 1;
+            let inode_block = le_u32(&data, at + 4) as u64;
+            at += 12;
+            for _ in 0..count {
+                let entry = data
+                    .get(at..at + 8)
+                    .ok_or_else(|| invalid("squashfs directory is truncated"))?;
+                let inode_offset = le_u16(entry, 0) as u64;
+                let len = le_u16(entry, 6) as usize + 1;
+                let name = data
+                    .get(at + 8..at + 8 + len)
+                    .ok_or_else(|| invalid("squashfs directory is truncated"))?;
+                out.push((
+                    String::from_utf8_lossy(name).to_string(),
+                    (inode_block << 16) | inode_offset,
+                ));
+                at += 8 + len;
+            }
+        }
+        Ok(out)
+    }
+}
+
+fn le_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn le_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+fn le_u64(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+fn invalid(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::import::FloppyDiskHardLinkExt;
+
+    #[test_log::test(tokio::test)]
+    async fn test_fixture_works() -> Result<()> {
+        let disk = SquashfsFloppyDisk::open("./fixtures/a.squashfs").await?;
+        assert_eq!(SquashfsCompression::Gzip, disk.compression());
+        assert_eq!(4096, disk.block_size());
+
+        let big = disk.read("/big.bin").await?;
+        assert_eq!(10000, big.len());
+        assert!(big[4096..8192]
+            .iter()
+            .enumerate()
+            .all(|(i, b)| *b == (i * 7 % 256) as u8));
+        assert_eq!(b"tail!abc", &big[9992..]);
+        let big = disk.metadata("/big.bin").await?;
+        assert_eq!(0o600, big.permissions().mode());
+        assert_eq!(1000, big.uid()?);
+        assert_eq!(
+            std::time::UNIX_EPOCH + std::time::Duration::from_secs(1687000000),
+            big.modified()?
+        );
+
+        let sparse = disk.read("/sparse.bin").await?;
+        assert_eq!(vec![0; 4096], sparse[..4096]);
+        assert_eq!(b"end\n", &sparse[4096..]);
+        let tail = disk.read("/tail.bin").await?;
+        assert!(tail.iter().enumerate().all(|(i, b)| *b == (i % 13) as u8));
+        assert_eq!("", disk.read_to_string("/empty.txt").await?);
+        assert!(disk.metadata("/empty_dir").await?.is_dir());
+        assert_eq!(
+            "hello from a subdirectory\n",
+            disk.read_to_string("/dir/nested.txt").await?
+        );
+        assert_eq!(
+            PathBuf::from("../a.txt"),
+            disk.read_link("/dir/link").await?
+        );
+        assert_eq!("asdf\n", disk.read_to_string("/dir/link").await?);
+        assert!(!disk.try_exists("/dir/fifo").await?);
+
+        assert_eq!(
+            disk.hard_link_id("/a.txt").await?,
+            disk.hard_link_id("/hard.txt").await?
+        );
+        assert!(disk.hard_link_id("/a.txt").await?.is_some());
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_corrupt_sizes_are_errors() -> Result<()> {
+        let disk = SquashfsFloppyDisk::open("./fixtures/a.squashfs").await?;
+        let blocks = SquashfsBlocks {
+            start: 0,
+            blocks: vec![0],
+            fragment: None,
+        };
+        let err = disk.reader.read_file(&blocks, u64::MAX).await.unwrap_err();
+        assert_eq!(std::io::ErrorKind::InvalidData, err.kind());
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_xattrs_work() -> Result<()> {
+        let disk = SquashfsFloppyDisk::open("./fixtures/a.squashfs").await?;
+        assert_eq!(
+            vec![("user.comment".to_string(), b"hello".to_vec())],
+            disk.xattrs("/a.txt")?
+        );
+        assert_eq!(
+            vec![
+                ("user.comment".to_string(), b"hello".to_vec()),
+                ("trusted.md5".to_string(), b"0123456789abcdef".to_vec()),
+            ],
+            disk.xattrs("/big.bin")?
+        );
+        assert_eq!(
+            vec![(
+                "security.selinux".to_string(),
+                b"system_u:object_r:etc_t:s0\0".to_vec()
+            )],
+            disk.xattrs("/dir")?
+        );
+        assert!(disk.xattrs("/empty.txt")?.is_empty());
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_compression_works() -> Result<()> {
+        for (fixture, compression) in [
+            ("./fixtures/a.xz.squashfs", SquashfsCompression::Xz),
+            ("./fixtures/a.zstd.squashfs", SquashfsCompression::Zstd),
+            ("./fixtures/a.lz4.squashfs", SquashfsCompression::Lz4),
+        ] {
+            let disk = SquashfsFloppyDisk::open(fixture).await?;
+            assert_eq!(compression, disk.compression());
+            assert_eq!("asdf\n", disk.read_to_string("/a.txt").await?);
+            let reference = SquashfsFloppyDisk::open("./fixtures/a.squashfs").await?;
+            for path in ["/big.bin", "/sparse.bin", "/tail.bin", "/dir/nested.txt"] {
+                assert_eq!(reference.read(path).await?, disk.read(path).await?);
+            }
+        }
+
+        Ok(())
+    }
+}
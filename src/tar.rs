@@ -1,43 +1,180 @@
-use std::os::unix::prelude::OsStringExt;
+use std::io::SeekFrom;
+use std::ops::Range;
+use std::os::unix::prelude::{OsStrExt, OsStringExt};
+
+use debug_ignore::DebugIgnore;
 
 use futures::TryStreamExt;
 use smoosh::CompressionType;
 use tokio::fs::File;
-use tokio::io::AsyncReadExt;
-use tokio_tar_up2date::EntryType;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio_tar_up2date::{EntryType, Header};
 use tracing::{debug, warn};
 
 crate::util::archive_format!(Tar, "a.tar", tar_open, tar_close);
 
+#[derive(Debug, Default)]
+pub(crate) struct TarState {
+    /// Whether paths were written as `./path` instead of `path`, like
+    /// `tar -C dir .` and dpkg do.
+    pub dot_prefix: bool,
+    /// The uncompressed archive that was opened, which files are read from
+    /// when they're first needed.
+    source: DebugIgnore<Vec<u8>>,
+    /// Where each entry that hasn't changed is in the source, so that it can
+    /// be copied over as it was instead of being written again.
+    originals: std::sync::Mutex<HashMap<PathBuf, TarOriginal>>,
+    /// Where the last entry ends and how many entries there are, if the
+    /// archive is uncompressed and everything in it was read, so that new
+    /// entries can be written over the end instead of writing it again.
+    appendable: Option<(usize, usize)>,
+}
+
+#[derive(Debug, Clone)]
+struct TarOriginal {
+    header: Header,
+    /// The entry's headers, including any long name or pax ones before it,
+    /// and its padded data.
+    record: Range<usize>,
+    /// The file's contents, which for hard links are the target's.
+    data: Option<Range<usize>>,
+}
+
+#[async_trait::async_trait]
+impl crate::util::LazyEntries for TarState {
+    fn unloaded(&self) -> HashMap<PathBuf, u64> {
+        self.originals
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(path, original)| {
+                let data = original.data.as_ref()?;
+                Some((path.clone(), data.len() as u64))
+            })
+            .collect()
+    }
+
+    async fn load(&self, path: &Path) -> Result<Vec<u8>> {
+        let data = self
+            .originals
+            .lock()
+            .unwrap()
+            .get(path)
+            .and_then(|original| original.data.clone());
+        match data {
+            Some(data) => Ok(self.source[data].to_vec()),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("{} isn't in the source tar", path.display()),
+            )),
+        }
+    }
+
+    fn invalidate(&self, path: &Path) {
+        self.originals.lock().unwrap().remove(path);
+    }
+}
+
 async fn tar_open<P: Into<PathBuf>>(path: P) -> Result<TarInternalMetadata> {
     let path = path.into();
     debug!("considering {}...", path.display());
     if !crate::util::exists_async(path.clone()).await {
         debug!("nah, just empty tar: {}", path.display());
-        let _archive = tokio_tar_up2date::Builder::new(File::create(path).await?);
+        // Finish the empty archive right away, otherwise the builder finishes
+        // it in the background when dropped and can clobber what we write on
+        // close.
+        let mut file = tokio_tar_up2date::Builder::new(File::create(path).await?)
+            .into_inner()
+            .await?;
+        file.flush().await?;
         return Ok(TarInternalMetadata {
             delegate: MemFloppyDisk::new(),
             compression: CompressionType::None,
             ordered_paths: IndexSet::new(),
+            times: HashMap::new(),
+            hard_links: IndexMap::new(),
+            state: Default::default(),
         });
     }
 
     debug!("opening tar file {}", path.display());
     let mut file = crate::util::async_file(path).await?;
     let mut buffer = vec![];
-    let c = smoosh::recompress(&mut file, &mut buffer, smoosh::CompressionType::None).await?;
+    file.read_to_end(&mut buffer).await?;
+    tar_read(&buffer).await
+}
+
+/// The size of an entry's data, which a pax `size` record overrides.
+async fn entry_size<R: tokio::io::AsyncRead + Unpin>(
+    entry: &mut tokio_tar_up2date::Entry<R>,
+) -> Result<u64> {
+    if let Some(extensions) = entry.pax_extensions().await? {
+        for extension in extensions {
+            let extension = extension?;
+            if extension.key().ok() == Some("size") {
+                return extension
+                    .value()
+                    .ok()
+                    .and_then(|size| size.parse().ok())
+                    .ok_or_else(|| {
+                        std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            "invalid pax size record",
+                        )
+                    });
+            }
+        }
+    }
+    entry.header().entry_size()
+}
+
+/// Loads a (possibly compressed) tar from memory, ie. one that lives inside
+/// of another archive.
+pub(crate) async fn tar_read(data: &[u8]) -> Result<TarInternalMetadata> {
+    let mut buffer = vec![];
+    let c = smoosh::recompress(&mut &data[..], &mut buffer, smoosh::CompressionType::None).await?;
     let mut archive = tokio_tar_up2date::Archive::new(buffer.as_slice());
     let out = MemFloppyDisk::new();
     let mut ordered_paths = IndexSet::new();
+    let mut times = HashMap::new();
+    let mut hard_links = IndexMap::new();
+    let mut state = TarState::default();
+    let mut originals = HashMap::new();
+    let mut record_start = 0;
+    let mut count = 0;
     out.create_dir_all("/").await?;
 
     let mut entries = archive.entries()?;
     while let Some(mut entry) = entries.try_next().await? {
         debug!("reading header...");
+        count += 1;
+        let size = entry_size(&mut entry).await?;
         let header = entry.header();
-        let path = PathBuf::from(OsString::from_vec(header.path_bytes().as_ref().to_vec()));
+        let data_start = entry.raw_file_position() as usize;
+        let data = data_start..data_start + size as usize;
+        if data.end > buffer.len() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("tar entry {} is truncated", header.path()?.display()),
+            ));
+        }
+        let record = record_start..data_start + data.len().div_ceil(512) * 512;
+        record_start = record.end;
+        let mut original = TarOriginal {
+            header: header.clone(),
+            record,
+            data: None,
+        };
+        let raw_path = header.path_bytes();
+        if ordered_paths.is_empty() && raw_path.starts_with(b"./") {
+            state.dot_prefix = true;
+        }
+        let path =
+            crate::util::normalize_path(PathBuf::from(OsString::from_vec(raw_path.to_vec())));
         debug!("processing archive path {}", path.display());
         ordered_paths.insert(path.clone());
+        let mtime = std::time::UNIX_EPOCH + std::time::Duration::from_secs(header.mtime()?);
+        times.insert(path.clone(), mtime);
 
         if header.entry_type().is_dir() {
             debug!("creating: {}", path.display());
@@ -47,13 +184,15 @@ async fn tar_open<P: Into<PathBuf>>(path: P) -> Result<TarInternalMetadata> {
                 .await?;
             out.set_permissions(&path, MemPermissions::from_mode(header.mode()?))
                 .await?;
+            originals.insert(path.clone(), original);
         } else if header.entry_type().is_file() {
             if let Some(parent) = path.parent() {
                 debug!("creating parent(s): {}", parent.display());
                 out.create_dir_all(parent).await?;
             }
+            // The contents are read from the source when they're needed.
             debug!("open: {}", path.display());
-            let mut handle = MemOpenOptions::new()
+            MemOpenOptions::new()
                 .create(true)
                 .write(true)
                 .open(&out, &path)
@@ -63,11 +202,8 @@ async fn tar_open<P: Into<PathBuf>>(path: P) -> Result<TarInternalMetadata> {
                 .await?;
             out.set_permissions(&path, MemPermissions::from_mode(header.mode()?))
                 .await?;
-
-            debug!("read archive entry");
-            let mut data = vec![];
-            entry.read_to_end(&mut data).await?;
-            tokio::io::copy(&mut data.as_slice(), &mut handle).await?;
+            original.data = Some(data);
+            originals.insert(path.clone(), original);
         } else if header.entry_type().is_symlink() {
             let to = PathBuf::from(OsString::from_vec(
                 header.link_name_bytes().as_ref().unwrap().to_vec(),
@@ -87,6 +223,7 @@ async fn tar_open<P: Into<PathBuf>>(path: P) -> Result<TarInternalMetadata> {
                 debug!("creating parent(s): {}", parent.display());
                 out.create_dir_all(parent).await;
             }
+            originals.insert(path.clone(), original);
             debug!("creating the symlink!");
             debug!(
                 "to ({}) exists: {}",
@@ -100,128 +237,334 @@ async fn tar_open<P: Into<PathBuf>>(path: P) -> Result<TarInternalMetadata> {
             );
             out.symlink(to, path).await?;
         } else if header.entry_type().is_hard_link() {
-            // If the file is a hardlink, just duplicate the file
-            // TODO: Figure out how to make hardlinks not be hellfuck misery
+            // If the file is a hardlink, just duplicate the file and
+            // remember where it came from.
             let to = PathBuf::from(OsString::from_vec(
                 header.link_name_bytes().as_ref().unwrap().to_vec(),
             ));
             debug!("read hardlink: {} -> {}", path.display(), to.display());
-            let path = if !path.starts_with("/") {
-                PathBuf::from("/").join(path)
-            } else {
-                path
-            };
-            out.copy(to, path).await?;
+            let path = crate::util::normalize_path(path);
+            let to = crate::util::normalize_path(to);
+            out.copy(&to, &path).await?;
+            let metadata = out.metadata(&to).await?;
+            out.set_permissions(&path, metadata.permissions()).await?;
+            out.chown(&path, metadata.uid()?, metadata.gid()?).await?;
+            // The copy is only a placeholder if the target hasn't been
+            // read yet, so the link reads the target's contents instead.
+            if let Some(data) = originals.get(&to).map(|target| target.data.clone()) {
+                original.data = data;
+                originals.insert(path.clone(), original);
+            }
+            let to = hard_links.get(&to).cloned().unwrap_or(to);
+            hard_links.insert(path, to);
         }
     }
 
     debug!("done reading entries!");
+    drop(entries);
+    drop(archive);
+    state.source = DebugIgnore(buffer);
+    if matches!(c, CompressionType::None) && originals.len() == count {
+        state.appendable = Some((record_start, count));
+    }
+    state.originals = std::sync::Mutex::new(originals);
 
     Ok(TarInternalMetadata {
         delegate: out,
         compression: c,
         ordered_paths,
+        times,
+        hard_links,
+        state,
     })
 }
 
-async fn tar_close(
-    disk: &MemFloppyDisk,
-    scope: &Path,
-    compression: CompressionType,
-    ordered_paths: &IndexSet<PathBuf>,
-) -> Result<()> {
+async fn tar_close(tar: &TarFloppyDisk) -> Result<()> {
+    let scope = &tar.path;
     debug!("closing tar at {}", scope.display());
-    let buffer = vec![];
+    if let Some((end, data)) = tar_append(tar).await? {
+        debug!("appending {} bytes at {}", data.len(), end);
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .open(scope)
+            .await?;
+        file.seek(SeekFrom::Start(end as u64)).await?;
+        file.write_all(&data).await?;
+        file.set_len((end + data.len()) as u64).await?;
+        file.flush().await?;
+        debug!("done appending to archive!");
+        return Ok(());
+    }
+
+    let data = tar_write(tar).await?;
     let mut file = tokio::fs::OpenOptions::new()
         .truncate(true)
         .write(true)
         .open(scope)
         .await?;
+    file.write_all(&data).await?;
+    file.flush().await?;
+    debug!("done writing archive!");
+
+    Ok(())
+}
+
+/// Serialises the archive, compressed with its compression, without writing
+/// it anywhere.
+pub(crate) async fn tar_write(tar: &TarFloppyDisk) -> Result<Vec<u8>> {
+    let ordered_paths = &*tar.ordered_paths.lock().await;
+    let hard_links = &*tar.hard_links.lock().await;
+    let buffer = vec![];
     let mut archive = tokio_tar_up2date::Builder::new(buffer);
 
     for path in ordered_paths {
         debug!("processing output archive path {}", path.display());
+        let Some((header, has_data)) = entry_header(tar, path, ordered_paths, hard_links).await?
+        else {
+            continue;
+        };
 
-        if path.as_os_str() == "/" {
-            debug!("not writing /!");
+        // Entries that would be written the same way they were read are
+        // copied over as they were, which also keeps anything in their
+        // headers that we don't know about.
+        let original = tar.state.originals.lock().unwrap().get(path).cloned();
+        if let Some(original) = original.filter(|original| is_unchanged(&original.header, &header))
+        {
+            trace!("copying original entry");
+            archive
+                .get_mut()
+                .extend_from_slice(&tar.state.source[original.record]);
             continue;
         }
+        append_entry(tar, &mut archive, path, header, has_data).await?;
+    }
 
-        let mut header = tokio_tar_up2date::Header::new_ustar();
-        trace!("ustar header!");
-        {
-            let path = if path.starts_with("/") {
-                path.strip_prefix("/").unwrap()
-            } else {
-                path
-            };
-            header.set_path(path)?;
+    let buffer = archive.into_inner().await?;
+    let mut out = vec![];
+    crate::util::write_compressed(&buffer, &mut out, tar.compression).await?;
+
+    Ok(out)
+}
+
+/// If everything that was in the archive is still at the start of it,
+/// unchanged, returns where it ends and the new entries to write there, so
+/// that adding to a big archive doesn't mean writing all of it again.
+async fn tar_append(tar: &TarFloppyDisk) -> Result<Option<(usize, Vec<u8>)>> {
+    let Some((end, count)) = tar.state.appendable else {
+        return Ok(None);
+    };
+    if !matches!(tar.compression, CompressionType::None) {
+        return Ok(None);
+    }
+    let ordered_paths = &*tar.ordered_paths.lock().await;
+    let hard_links = &*tar.hard_links.lock().await;
+    let mut archive = tokio_tar_up2date::Builder::new(vec![]);
+
+    let mut kept = 0;
+    let mut last = None;
+    for path in ordered_paths {
+        let Some((header, has_data)) = entry_header(tar, path, ordered_paths, hard_links).await?
+        else {
+            continue;
+        };
+        if kept < count {
+            // The originals have to be in the same order as they were, so
+            // each one has to start after the last.
+            let original = tar.state.originals.lock().unwrap().get(path).cloned();
+            match original {
+                Some(original)
+                    if is_unchanged(&original.header, &header)
+                        && last < Some(original.record.start) =>
+                {
+                    last = Some(original.record.start);
+                    kept += 1;
+                    continue;
+                }
+                _ => return Ok(None),
+            }
         }
-        let path = if !path.starts_with("/") {
-            PathBuf::from("/").join(path)
+        append_entry(tar, &mut archive, path, header, has_data).await?;
+    }
+    if kept < count {
+        return Ok(None);
+    }
+
+    Ok(Some((end, archive.into_inner().await?)))
+}
+
+/// Builds the header that `path` is written with, and whether its contents
+/// follow it, or `None` if it isn't written at all.
+async fn entry_header(
+    tar: &TarFloppyDisk,
+    path: &Path,
+    ordered_paths: &IndexSet<PathBuf>,
+    hard_links: &IndexMap<PathBuf, PathBuf>,
+) -> Result<Option<(Header, bool)>> {
+    let disk = &tar.delegate;
+    if path.as_os_str() == "/" && !tar.state.dot_prefix {
+        debug!("not writing /!");
+        return Ok(None);
+    }
+
+    let mut header = tokio_tar_up2date::Header::new_ustar();
+    trace!("ustar header!");
+    {
+        let path = if path.starts_with("/") {
+            path.strip_prefix("/").unwrap()
         } else {
-            path.to_path_buf()
+            path
         };
-        let path = path.as_path();
-        let kind = determine_file_type(disk, path).await?;
-        trace!("set path!");
+        if path.as_os_str().is_empty() {
+            // Only written when paths are `./`-prefixed.
+            header.set_path("./")?;
+        } else {
+            header.set_path(path)?;
+        }
+    }
+    let path = if !path.starts_with("/") {
+        PathBuf::from("/").join(path)
+    } else {
+        path.to_path_buf()
+    };
+    let path = path.as_path();
+    let kind = determine_file_type(disk, path).await?;
+    if tar.state.dot_prefix {
+        set_dot_prefixed_path(&mut header, path, kind == EntryType::Directory);
+    }
+    trace!("set path!");
+    header.set_mtime(
+        tar.modified(path)
+            .await?
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+    );
 
-        if kind == EntryType::Regular {
-            debug!("creating file: {}", path.display(),);
-            let metadata = disk.metadata(path).await?;
+    // Only link to things that are already in the archive.
+    let link_target = hard_links.get(path).filter(|target| {
+        matches!(
+            (ordered_paths.get_index_of(*target), ordered_paths.get_index_of(path)),
+            (Some(target), Some(path)) if target < path
+        )
+    });
 
-            trace!("basic metadata");
-            header.set_entry_type(EntryType::Regular);
-            header.set_size(metadata.len());
-            header.set_mode(metadata.permissions().mode());
-            header.set_gid(metadata.gid()?.into());
-            header.set_uid(metadata.uid()?.into());
+    let mut has_data = false;
+    if let (EntryType::Regular, Some(target)) = (kind, link_target) {
+        debug!(
+            "creating hardlink: {} -> {}",
+            path.display(),
+            target.display()
+        );
+        let metadata = disk.metadata(path).await?;
 
-            trace!("opening handle!");
-            let mut handle = MemOpenOptions::new().read(true).open(disk, path).await?;
+        header.set_entry_type(EntryType::Link);
+        header.set_link_name(target.strip_prefix("/").unwrap_or(target))?;
+        header.set_size(0);
+        header.set_mode(metadata.permissions().mode());
+        header.set_gid(metadata.gid()?.into());
+        header.set_uid(metadata.uid()?.into());
+    } else if kind == EntryType::Regular {
+        debug!("creating file: {}", path.display(),);
+        // The memfs only has a placeholder for files that haven't been
+        // read yet, so this has to go through the disk.
+        let metadata = tar.metadata(path).await?;
 
-            trace!("checksum!");
-            header.set_cksum();
+        trace!("basic metadata");
+        header.set_entry_type(EntryType::Regular);
+        header.set_size(metadata.len());
+        header.set_mode(metadata.permissions().mode());
+        header.set_gid(metadata.gid()?.into());
+        header.set_uid(metadata.uid()?.into());
+        has_data = true;
+    } else if kind == EntryType::Directory {
+        debug!("creating dir: {}", path.display());
+        let metadata = disk.metadata(path).await?;
 
-            trace!("append!");
-            archive.append(&header, &mut handle).await?;
-        } else if kind == EntryType::Directory {
-            debug!("creating dir: {}", path.display());
-            let metadata = disk.metadata(path).await?;
+        trace!("basic metadata");
+        header.set_entry_type(EntryType::Directory);
+        header.set_size(0);
+        header.set_mode(metadata.permissions().mode());
+        header.set_gid(metadata.gid()?.into());
+        header.set_uid(metadata.uid()?.into());
+    } else if kind == EntryType::Symlink {
+        let link = disk.read_link(path).await?;
+        debug!("creating symlink: {} -> {}", path.display(), link.display());
 
-            trace!("basic metadata");
-            header.set_entry_type(EntryType::Directory);
-            header.set_size(0);
-            header.set_mode(metadata.permissions().mode());
-            header.set_gid(metadata.gid()?.into());
-            header.set_uid(metadata.uid()?.into());
+        trace!("basic metadata");
+        header.set_entry_type(EntryType::Symlink);
+        header.set_link_name(link.to_str().unwrap())?;
+        header.set_size(0);
+    }
 
-            trace!("checksum");
-            header.set_cksum();
-            let empty: &[u8] = &[];
-            trace!("append");
-            archive.append(&header, empty).await?;
-        } else if kind == EntryType::Symlink {
-            let link = disk.read_link(path).await?;
-            debug!("creating symlink: {} -> {}", path.display(), link.display());
-
-            trace!("basic metadata");
-            header.set_entry_type(EntryType::Symlink);
-            header.set_link_name(link.to_str().unwrap())?;
-            header.set_size(0);
-            header.set_cksum();
+    Ok(Some((header, has_data)))
+}
 
-            let empty: &[u8] = &[];
-            trace!("append");
-            archive.append(&header, empty).await?;
+async fn append_entry(
+    tar: &TarFloppyDisk,
+    archive: &mut tokio_tar_up2date::Builder<Vec<u8>>,
+    path: &Path,
+    mut header: Header,
+    has_data: bool,
+) -> Result<()> {
+    trace!("checksum!");
+    header.set_cksum();
+    trace!("append!");
+    if has_data {
+        tar.load(path).await?;
+        let mut handle = MemOpenOptions::new()
+            .read(true)
+            .open(&tar.delegate, path)
+            .await?;
+        archive.append(&header, &mut handle).await
+    } else {
+        let empty: &[u8] = &[];
+        archive.append(&header, empty).await
+    }
+}
+/// Whether an entry would be written with the same header that it was read
+/// with. Symlinks don't keep their mode or owner, so only their target and
+/// mtime matter, and hard links can point at `./path` or `path`.
+fn is_unchanged(original: &Header, header: &Header) -> bool {
+    fn same<T: PartialEq>(a: Result<T>, b: Result<T>) -> bool {
+        matches!((a, b), (Ok(a), Ok(b)) if a == b)
+    }
+    fn link_name(header: &Header) -> Option<PathBuf> {
+        let name = PathBuf::from(OsString::from_vec(header.link_name_bytes()?.to_vec()));
+        match header.entry_type() {
+            EntryType::Link => Some(crate::util::normalize_path(name)),
+            _ => Some(name),
         }
     }
 
-    let buffer = archive.into_inner().await?;
-    smoosh::recompress(&mut buffer.as_slice(), &mut file, compression).await?;
-    debug!("done writing archive!");
+    original.entry_type() == header.entry_type()
+        && link_name(original) == link_name(header)
+        && same(original.mtime(), header.mtime())
+        && (header.entry_type() == EntryType::Symlink
+            || same(original.entry_size(), header.entry_size())
+                && same(original.mode(), header.mode())
+                && same(original.uid(), header.uid())
+                && same(original.gid(), header.gid()))
+}
 
-    Ok(())
+/// `Header::set_path` strips `./`, so short enough names are written by hand
+/// instead.
+fn set_dot_prefixed_path(header: &mut tokio_tar_up2date::Header, path: &Path, is_dir: bool) {
+    let mut name = b"./".to_vec();
+    name.extend_from_slice(
+        path.strip_prefix("/")
+            .unwrap_or(path)
+            .as_os_str()
+            .as_bytes(),
+    );
+    if is_dir && !name.ends_with(b"/") {
+        name.push(b'/');
+    }
+
+    let slot = &mut header.as_old_mut().name;
+    if name.len() <= slot.len() {
+        slot.fill(0);
+        slot[..name.len()].copy_from_slice(&name);
+    }
 }
 
 async fn determine_file_type(disk: &MemFloppyDisk, path: &Path) -> Result<EntryType> {
@@ -260,3 +603,125 @@ async fn determine_file_type(disk: &MemFloppyDisk, path: &Path) -> Result<EntryT
         }
     }
 }
+
+#[cfg(test)]
+mod tar_tests {
+    use super::*;
+    use crate::util::tests::TempFile;
+
+    #[test_log::test(tokio::test)]
+    async fn test_unchanged_entries_are_copied_as_they_were() -> Result<()> {
+        let archive = TempFile::new("./fixtures/a.tar").await?;
+        let fixture = tokio::fs::read("./fixtures/a.tar").await?;
+        let records = fixture.iter().rposition(|byte| *byte != 0).unwrap() / 512 * 512 + 512;
+        {
+            let disk = TarFloppyDisk::open(archive.path_view()).await?;
+            disk.write("/b.txt", "wow!!!").await?;
+            disk.close().await?;
+        }
+
+        let data = tokio::fs::read(archive.path_view()).await?;
+        assert_eq!(fixture[..records], data[..records]);
+        let disk = TarFloppyDisk::open(archive.path_view()).await?;
+        assert_eq!("asdf\n", disk.read_to_string("/a.txt").await?);
+        assert_eq!("wow!!!", disk.read_to_string("/b.txt").await?);
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_pax_sizes_are_used() -> Result<()> {
+        let record = b"12 size=600\n";
+        let mut data = vec![];
+        for (path, entry_type, size) in [
+            ("PaxHeaders/a.txt", EntryType::XHeader, record.len()),
+            ("a.txt", EntryType::Regular, 0),
+        ] {
+            let mut header = Header::new_ustar();
+            header.set_path(path)?;
+            header.set_entry_type(entry_type);
+            header.set_size(size as u64);
+            header.set_mode(0o644);
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_mtime(0);
+            header.set_cksum();
+            data.extend_from_slice(header.as_bytes());
+            if size > 0 {
+                data.extend_from_slice(record);
+                data.resize(data.len().div_ceil(512) * 512, 0);
+            }
+        }
+        // Zeroes, so that the header's size of 0 still ends the archive
+        // where it would.
+        data.resize(data.len() + 1024 + 1024, 0);
+
+        let disk = TarFloppyDisk::from_metadata(PathBuf::from("a.tar"), tar_read(&data).await?);
+        assert_eq!(vec![0; 600], disk.read("/a.txt").await?);
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_additions_are_appended() -> Result<()> {
+        let archive = TempFile::new("./fixtures/a.tar").await?;
+        {
+            let disk = TarFloppyDisk::open(archive.path_view()).await?;
+            disk.write("/b.txt", "wow!!!").await?;
+            assert!(tar_append(&disk).await?.is_some());
+            disk.close().await?;
+        }
+        {
+            let disk = TarFloppyDisk::open(archive.path_view()).await?;
+            assert_eq!("asdf\n", disk.read_to_string("/a.txt").await?);
+            assert_eq!("wow!!!", disk.read_to_string("/b.txt").await?);
+            disk.write("/c.txt", "more!!!").await?;
+            assert!(tar_append(&disk).await?.is_some());
+            disk.write("/a.txt", "changed!!!").await?;
+            assert!(tar_append(&disk).await?.is_none());
+        }
+        {
+            let disk = TarFloppyDisk::open(archive.path_view()).await?;
+            disk.set_permissions("/a.txt", TarPermissions::from_mode(0o600))
+                .await?;
+            assert!(tar_append(&disk).await?.is_none());
+        }
+        {
+            let disk = TarFloppyDisk::open(archive.path_view()).await?;
+            disk.remove_file("/a.txt").await?;
+            assert!(tar_append(&disk).await?.is_none());
+        }
+        {
+            let mut disk = TarFloppyDisk::open(archive.path_view()).await?;
+            disk.set_compression(CompressionType::Gzip);
+            assert!(tar_append(&disk).await?.is_none());
+        }
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_hard_links_keep_their_contents() -> Result<()> {
+        let dir = crate::util::TempDir::new().await?;
+        let path = dir.join("links.tar");
+        {
+            let disk = TarFloppyDisk::open(&path).await?;
+            disk.write("/a.txt", "one").await?;
+            disk.hard_link("/a.txt", "/b.txt").await?;
+            disk.close().await?;
+        }
+        {
+            let disk = TarFloppyDisk::open(&path).await?;
+            assert_eq!(3, disk.metadata("/b.txt").await?.len());
+            disk.write("/a.txt", "two").await?;
+            disk.close().await?;
+        }
+
+        let disk = TarFloppyDisk::open(&path).await?;
+        assert_eq!("two", disk.read_to_string("/a.txt").await?);
+        assert_eq!("one", disk.read_to_string("/b.txt").await?);
+        assert_eq!(None, disk.hard_link_target("/b.txt").await);
+
+        Ok(())
+    }
+}
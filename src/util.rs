@@ -1,14 +1,16 @@
 macro_rules! archive_format {
     ( $format:ident, $fixture:expr, $open:expr, $close:expr ) => {
         paste::paste! {
+            use std::collections::HashMap;
             use std::ffi::OsString;
             use std::io::Result;
             use std::path::{Path, PathBuf};
+            use std::sync::Arc;
             use std::time::SystemTime;
 
             use floppy_disk::mem::*;
             use floppy_disk::prelude::*;
-            use indexmap::IndexSet;
+            use indexmap::{IndexMap, IndexSet};
             use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite};
             use tokio::pin;
             use tokio::sync::Mutex;
@@ -18,6 +20,9 @@ macro_rules! archive_format {
                 pub delegate: MemFloppyDisk,
                 pub compression: CompressionType,
                 pub ordered_paths: IndexSet<PathBuf>,
+                pub times: HashMap<PathBuf, SystemTime>,
+                pub hard_links: IndexMap<PathBuf, PathBuf>,
+                pub state: [< $format State >],
             }
 
             #[derive(Debug)]
@@ -26,45 +31,276 @@ macro_rules! archive_format {
                 compression: smoosh::CompressionType,
                 path: PathBuf,
                 ordered_paths: Mutex<IndexSet<PathBuf>>,
+                // The memfs can't store arbitrary mtimes or real hard links,
+                // so we keep track of them ourselves.
+                times: Arc<Mutex<HashMap<PathBuf, SystemTime>>>,
+                hard_links: Mutex<IndexMap<PathBuf, PathBuf>>,
+                // The sizes of files whose contents are still in the source
+                // archive, since the memfs only has empty placeholders.
+                unloaded: Arc<Mutex<HashMap<PathBuf, u64>>>,
+                // Anything else that the format needs to remember between
+                // opening and closing.
+                #[allow(dead_code)]
+                state: [< $format State >],
             }
 
             impl [< $format FloppyDisk >] {
-                pub async fn open<'a, P: AsRef<Path>>(path: P) -> Result<[< $format FloppyDisk >]> {
+                pub async fn open<P: AsRef<Path>>(path: P) -> Result<[< $format FloppyDisk >]> {
                     let path = path.as_ref();
                     let metadata: [< $format InternalMetadata >] = $open(path).await?;
-                    Ok(Self {
+                    Ok(Self::from_metadata(path.to_path_buf(), metadata))
+                }
+
+                pub(crate) fn from_metadata(
+                    path: PathBuf,
+                    metadata: [< $format InternalMetadata >],
+                ) -> [< $format FloppyDisk >] {
+                    let unloaded = crate::util::LazyEntries::unloaded(&metadata.state);
+                    Self {
                         delegate: metadata.delegate,
                         compression: metadata.compression,
-                        path: path.to_path_buf(),
+                        path,
                         ordered_paths: Mutex::new(metadata.ordered_paths),
-                    })
+                        times: Arc::new(Mutex::new(metadata.times)),
+                        hard_links: Mutex::new(metadata.hard_links),
+                        unloaded: Arc::new(Mutex::new(unloaded)),
+                        state: metadata.state,
+                    }
+                }
+
+                /// Creates an archive at `dest` from the contents of the host
+                /// directory `src`. If `dest` already exists, `src` is merged
+                /// into it. Nothing is written until the disk is closed.
+                pub async fn from_dir<P: AsRef<Path>, Q: AsRef<Path>>(
+                    src: P,
+                    dest: Q,
+                ) -> Result<[< $format FloppyDisk >]> {
+                    let disk = Self::open(dest).await?;
+                    let host = TokioFloppyDisk::new(None);
+                    disk.import_from(&host, src.as_ref(), Path::new("/")).await?;
+                    Ok(disk)
+                }
+
+                /// Copies `src_path` from `src_disk` into this archive at
+                /// `dest_path`, along with permissions, ownership, mtimes,
+                /// symlinks, hard links, and empty directories.
+                pub async fn import_from<'b, D, P, Q>(
+                    &self,
+                    src_disk: &'b D,
+                    src_path: P,
+                    dest_path: Q,
+                ) -> Result<()>
+                where
+                    D: FloppyDisk<'b> + crate::import::FloppyDiskHardLinkExt + Sync,
+                    D::Metadata: FloppyUnixMetadata,
+                    D::Permissions: FloppyUnixPermissions,
+                    P: AsRef<Path>,
+                    Q: AsRef<Path>,
+                {
+                    self.import_from_with(
+                        src_disk,
+                        src_path,
+                        dest_path,
+                        &crate::import::ImportOptions::new(),
+                    )
+                    .await
+                }
+
+                /// Like [`Self::import_from`], but only imports the paths
+                /// allowed by `options`.
+                pub async fn import_from_with<'b, D, P, Q>(
+                    &self,
+                    src_disk: &'b D,
+                    src_path: P,
+                    dest_path: Q,
+                    options: &crate::import::ImportOptions,
+                ) -> Result<()>
+                where
+                    D: FloppyDisk<'b> + crate::import::FloppyDiskHardLinkExt + Sync,
+                    D::Metadata: FloppyUnixMetadata,
+                    D::Permissions: FloppyUnixPermissions,
+                    P: AsRef<Path>,
+                    Q: AsRef<Path>,
+                {
+                    let dest_root = crate::util::normalize_path(dest_path);
+                    let entries =
+                        crate::import::collect(src_disk, src_path.as_ref(), options).await?;
+                    self.import_entries(&dest_root, entries).await
+                }
+
+                pub(crate) async fn import_entries(
+                    &self,
+                    dest_root: &Path,
+                    entries: Vec<crate::import::ImportedEntry>,
+                ) -> Result<()> {
+                    use crate::import::ImportedKind;
+
+                    debug!("importing {} entries into {}", entries.len(), dest_root.display());
+                    for entry in entries {
+                        let dest = if entry.path.as_os_str().is_empty() {
+                            dest_root.to_path_buf()
+                        } else {
+                            dest_root.join(&entry.path)
+                        };
+                        trace!("importing {}", dest.display());
+                        if let Some(parent) = dest.parent() {
+                            self.create_dir_all(parent).await?;
+                        }
+
+                        match entry.kind {
+                            ImportedKind::Directory => {
+                                if dest.as_os_str() == "/" {
+                                    continue;
+                                }
+                                self.create_dir_all(&dest).await?;
+                            }
+                            ImportedKind::File(data) => {
+                                self.write(&dest, data).await?;
+                            }
+                            ImportedKind::Symlink(target) => {
+                                self.symlink(target, dest).await?;
+                                continue;
+                            }
+                            ImportedKind::HardLink(target) => {
+                                self.hard_link(dest_root.join(target), dest).await?;
+                                continue;
+                            }
+                        }
+
+                        self.delegate
+                            .set_permissions(&dest, MemPermissions::from_mode(entry.mode & 0o7777))
+                            .await?;
+                        self.delegate.chown(&dest, entry.uid, entry.gid).await?;
+                        if let Some(modified) = entry.modified {
+                            self.set_modified(&dest, modified).await?;
+                        }
+                    }
+
+                    Ok(())
+                }
+
+                /// The compression that will be applied to the archive when
+                /// it's closed.
+                pub fn compression(&self) -> CompressionType {
+                    self.compression
+                }
+
+                pub fn set_compression(&mut self, compression: CompressionType) {
+                    self.compression = compression;
                 }
 
                 pub async fn close(self) -> Result<()> {
-                    let ordered_paths = &*self.ordered_paths.lock().await;
-                    $close(&self.delegate, &self.path, self.compression, ordered_paths).await
+                    $close(&self).await
+                }
+
+                /// Sets the modification time that will be written for `path`
+                /// when the archive is closed.
+                pub async fn set_modified<P: AsRef<Path> + Send>(
+                    &self,
+                    path: P,
+                    modified: SystemTime,
+                ) -> Result<()> {
+                    let path = crate::util::normalize_path(path);
+                    self.delegate.symlink_metadata(&path).await?;
+                    self.times.lock().await.insert(path, modified);
+                    Ok(())
+                }
+
+                pub(crate) async fn modified<P: AsRef<Path> + Send>(&self, path: P) -> Result<SystemTime> {
+                    let path = crate::util::normalize_path(path);
+                    if let Some(modified) = self.times.lock().await.get(&path) {
+                        return Ok(*modified);
+                    }
+                    self.delegate.symlink_metadata(&path).await?.modified()
+                }
+
+                /// Returns the path that `path` was hard-linked from, if any.
+                pub async fn hard_link_target<P: AsRef<Path> + Send>(&self, path: P) -> Option<PathBuf> {
+                    let path = crate::util::normalize_path(path);
+                    self.hard_links.lock().await.get(&path).cloned()
                 }
 
                 pub(crate) async fn add_path<P: AsRef<Path> + Send>(&self, path: P) {
-                    let path = path.as_ref().to_path_buf();
-                    let path = if !path.starts_with("/") {
-                        PathBuf::from("/").join(path)
-                    } else {
-                        path
-                    };
+                    let path = crate::util::normalize_path(path);
                     trace!("adding ordered path: {}", path.display());
                     self.ordered_paths.lock().await.insert(path);
                 }
 
                 pub(crate) async fn remove_path<P: AsRef<Path> + Send>(&self, path: P) {
-                    let path = path.as_ref().to_path_buf();
-                    let path = if !path.starts_with("/") {
-                        PathBuf::from("/").join(path)
-                    } else {
-                        path
-                    };
+                    let path = crate::util::normalize_path(path);
                     trace!("removing ordered path: {}", path.display());
                     self.ordered_paths.lock().await.remove(&path);
+                    self.times.lock().await.remove(&path);
+                    self.forget(&path).await;
+                    self.unlink(&path).await;
+                }
+
+                /// Called whenever the contents of `path` change. The stored
+                /// mtime is dropped in favour of the memfs one, and since hard
+                /// links are really copies, the file stops being linked to
+                /// anything.
+                pub(crate) async fn touch_path<P: AsRef<Path> + Send>(&self, path: P) {
+                    let path = crate::util::normalize_path(path);
+                    trace!("touching path: {}", path.display());
+                    self.times.lock().await.remove(&path);
+                    self.forget(&path).await;
+                    self.unlink(&path).await;
+                }
+
+                /// Reads the contents of `path` in from the source archive,
+                /// if they haven't been already. Anything that looks at the
+                /// contents in the memfs has to call this first.
+                pub(crate) async fn load<P: AsRef<Path> + Send>(&self, path: P) -> Result<()> {
+                    let path = crate::util::normalize_path(path);
+                    if !self.unloaded.lock().await.contains_key(&path) {
+                        return Ok(());
+                    }
+                    trace!("loading path: {}", path.display());
+                    let data = crate::util::LazyEntries::load(&self.state, &path).await?;
+                    let mut handle = MemOpenOptions::new()
+                        .write(true)
+                        .truncate(true)
+                        .open(&self.delegate, &path)
+                        .await?;
+                    tokio::io::copy(&mut data.as_slice(), &mut handle).await?;
+                    self.unloaded.lock().await.remove(&path);
+                    Ok(())
+                }
+
+                /// Called when the contents of `path` change or it goes away,
+                /// so that nothing is copied for it from the source archive.
+                async fn forget(&self, path: &Path) {
+                    self.unloaded.lock().await.remove(path);
+                    crate::util::LazyEntries::invalidate(&self.state, path);
+                }
+
+                /// Every ordered path at or below `path`, in order.
+                async fn paths_under<P: AsRef<Path> + Send>(&self, path: P) -> Vec<PathBuf> {
+                    let path = crate::util::normalize_path(path);
+                    self.ordered_paths
+                        .lock()
+                        .await
+                        .iter()
+                        .filter(|p| p.starts_with(&path))
+                        .cloned()
+                        .collect()
+                }
+
+                async fn unlink(&self, path: &Path) {
+                    let mut hard_links = self.hard_links.lock().await;
+                    hard_links.remove(path);
+                    // Promote the first remaining link to be the new target.
+                    let links: Vec<PathBuf> = hard_links
+                        .iter()
+                        .filter(|(_, target)| target.as_path() == path)
+                        .map(|(link, _)| link.clone())
+                        .collect();
+                    if let Some((first, rest)) = links.split_first() {
+                        hard_links.remove(first);
+                        for link in rest {
+                            hard_links.insert(link.clone(), first.clone());
+                        }
+                    }
                 }
             }
 
@@ -84,9 +320,11 @@ macro_rules! archive_format {
                 }
 
                 async fn copy<P: AsRef<Path> + Send>(&self, from: P, to: P) -> Result<u64> {
+                    self.load(from.as_ref()).await?;
                     {
                         let to = to.as_ref();
                         self.add_path(to).await;
+                        self.touch_path(to).await;
                     }
                     self.delegate.copy(from, to).await
                 }
@@ -119,20 +357,41 @@ macro_rules! archive_format {
                 }
 
                 async fn hard_link<P: AsRef<Path> + Send>(&self, src: P, dst: P) -> Result<()> {
-                    {let path = dst.as_ref();self.add_path(path.to_path_buf()).await;}
-                    self.delegate.hard_link(src, dst).await
+                    let src = crate::util::normalize_path(src);
+                    let dst = crate::util::normalize_path(dst);
+                    // The memfs has no hard links, so we make a copy and
+                    // remember that it's a link for when we close.
+                    let metadata = self.delegate.metadata(&src).await?;
+                    self.copy(&src, &dst).await?;
+                    self.delegate.set_permissions(&dst, metadata.permissions()).await?;
+                    self.delegate.chown(&dst, metadata.uid()?, metadata.gid()?).await?;
+                    let modified = self.times.lock().await.get(&src).copied();
+                    if let Some(modified) = modified {
+                        self.times.lock().await.insert(dst.clone(), modified);
+                    }
+
+                    let mut hard_links = self.hard_links.lock().await;
+                    let src = hard_links.get(&src).cloned().unwrap_or(src);
+                    hard_links.insert(dst, src);
+                    Ok(())
                 }
 
                 async fn metadata<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::Metadata> {
-                    self.delegate.metadata(path).await.map([< $format Metadata >])
+                    let path = crate::util::normalize_path(path);
+                    let metadata = self.delegate.metadata(&path).await?;
+                    let modified = self.times.lock().await.get(&path).copied();
+                    let len = self.unloaded.lock().await.get(&path).copied();
+                    Ok([< $format Metadata >](metadata, modified, len))
                 }
 
                 async fn read<P: AsRef<Path> + Send>(&self, path: P) -> Result<Vec<u8>> {
+                    self.load(path.as_ref()).await?;
                     self.delegate.read(path).await
                 }
 
                 async fn read_dir<P: AsRef<Path> + Send>(&self, path: P) -> Result<Self::ReadDir> {
-                    self.delegate.read_dir(path).await.map([< $format ReadDir >])
+                    let read_dir = self.delegate.read_dir(path).await?;
+                    Ok([< $format ReadDir >](read_dir, self.times.clone(), self.unloaded.clone()))
                 }
 
                 async fn read_link<P: AsRef<Path> + Send>(&self, path: P) -> Result<PathBuf> {
@@ -140,6 +399,7 @@ macro_rules! archive_format {
                 }
 
                 async fn read_to_string<P: AsRef<Path> + Send>(&self, path: P) -> Result<String> {
+                    self.load(path.as_ref()).await?;
                     self.delegate.read_to_string(path).await
                 }
 
@@ -149,12 +409,8 @@ macro_rules! archive_format {
                 }
 
                 async fn remove_dir_all<P: AsRef<Path> + Send>(&self, path: P) -> Result<()> {
-                    {
-                        let mut path = path.as_ref();
-                        while let Some(parent) = path.parent() {
-                            self.remove_path(parent).await;
-                            path = parent;
-                        }
+                    for child in self.paths_under(path.as_ref()).await {
+                        self.remove_path(child).await;
                     }
                     self.delegate.remove_dir_all(path).await
                 }
@@ -169,12 +425,23 @@ macro_rules! archive_format {
 
                 async fn rename<P: AsRef<Path> + Send>(&self, from: P, to: P) -> Result<()> {
                     {
-                        let from = from.as_ref();
-                        self.remove_path(from).await;
-                    }
-                    {
-                        let to = to.as_ref();
-                        self.add_path(to).await;
+                        let from = crate::util::normalize_path(from.as_ref());
+                        let to = crate::util::normalize_path(to.as_ref());
+                        // Renaming a directory moves everything inside of it.
+                        for old in self.paths_under(&from).await {
+                            self.load(&old).await?;
+                            let new = match old.strip_prefix(&from) {
+                                Ok(rest) if !rest.as_os_str().is_empty() => to.join(rest),
+                                _ => to.clone(),
+                            };
+                            let modified = self.times.lock().await.get(&old).copied();
+                            self.remove_path(&old).await;
+                            self.add_path(&new).await;
+                            self.touch_path(&new).await;
+                            if let Some(modified) = modified {
+                                self.times.lock().await.insert(new, modified);
+                            }
+                        }
                     }
                     self.delegate.rename(from, to).await
                 }
@@ -199,7 +466,11 @@ macro_rules! archive_format {
                     &self,
                     path: P,
                 ) -> Result<Self::Metadata> {
-                    self.delegate.symlink_metadata(path).await.map([< $format Metadata >])
+                    let path = crate::util::normalize_path(path);
+                    let metadata = self.delegate.symlink_metadata(&path).await?;
+                    let modified = self.times.lock().await.get(&path).copied();
+                    let len = self.unloaded.lock().await.get(&path).copied();
+                    Ok([< $format Metadata >](metadata, modified, len))
                 }
 
                 async fn try_exists<P: AsRef<Path> + Send>(&self, path: P) -> Result<bool> {
@@ -214,6 +485,7 @@ macro_rules! archive_format {
                     {
                         let path = path.as_ref();
                         self.add_path(path).await;
+                        self.touch_path(path).await;
                     }
                     self.delegate.write(path, contents).await
                 }
@@ -227,6 +499,20 @@ macro_rules! archive_format {
                 }
             }
 
+            #[async_trait::async_trait]
+            impl crate::import::FloppyDiskHardLinkExt for [< $format FloppyDisk >] {
+                async fn hard_link_id<P: AsRef<Path> + Send>(&self, path: P) -> Result<Option<u64>> {
+                    let path = crate::util::normalize_path(path);
+                    let hard_links = self.hard_links.lock().await;
+                    let target = match hard_links.get(&path) {
+                        Some(target) => target.clone(),
+                        None if hard_links.values().any(|target| target == &path) => path,
+                        None => return Ok(None),
+                    };
+                    Ok(Some(crate::util::hash_path(&target)))
+                }
+            }
+
             #[async_trait::async_trait]
             impl FloppyDiskUnixExt for [< $format FloppyDisk >] {
                 async fn chown<P: Into<PathBuf> + Send>(
@@ -269,8 +555,11 @@ macro_rules! archive_format {
             }
 
             #[derive(Debug)]
-            #[repr(transparent)]
-            pub struct [< $format DirEntry >](#[doc(hidden)] MemDirEntry);
+            pub struct [< $format DirEntry >](
+                #[doc(hidden)] MemDirEntry,
+                #[doc(hidden)] Arc<Mutex<HashMap<PathBuf, SystemTime>>>,
+                #[doc(hidden)] Arc<Mutex<HashMap<PathBuf, u64>>>,
+            );
 
             #[async_trait::async_trait]
             impl<'a> FloppyDirEntry<'a, [< $format FloppyDisk >]> for [< $format DirEntry >] {
@@ -283,7 +572,11 @@ macro_rules! archive_format {
                 }
 
                 async fn metadata(&self) -> Result<<[< $format FloppyDisk >] as FloppyDisk<'a>>::Metadata> {
-                    self.0.metadata().await.map([< $format Metadata >])
+                    let metadata = self.0.metadata().await?;
+                    let path = crate::util::normalize_path(self.0.path());
+                    let modified = self.1.lock().await.get(&path).copied();
+                    let len = self.2.lock().await.get(&path).copied();
+                    Ok([< $format Metadata >](metadata, modified, len))
                 }
 
                 async fn file_type(&self) -> Result<<[< $format FloppyDisk >] as FloppyDisk<'a>>::FileType> {
@@ -315,7 +608,7 @@ macro_rules! archive_format {
                 }
 
                 async fn metadata(&self) -> Result<<[< $format FloppyDisk >] as FloppyDisk>::Metadata> {
-                    self.0.metadata().await.map([< $format Metadata >])
+                    self.0.metadata().await.map(|metadata| [< $format Metadata >](metadata, None, None))
                 }
 
                 async fn try_clone(&'a self) -> Result<Box<<[< $format FloppyDisk >] as FloppyDisk>::File>> {
@@ -422,8 +715,11 @@ macro_rules! archive_format {
             }
 
             #[derive(Debug)]
-            #[repr(transparent)]
-            pub struct [< $format Metadata >](#[doc(hidden)] MemMetadata);
+            pub struct [< $format Metadata >](
+                #[doc(hidden)] MemMetadata,
+                #[doc(hidden)] Option<SystemTime>,
+                #[doc(hidden)] Option<u64>,
+            );
 
             impl<'a> FloppyMetadata<'a, [< $format FloppyDisk >]> for [< $format Metadata >] {
                 fn file_type(&self) -> <[< $format FloppyDisk >] as FloppyDisk<'a>>::FileType {
@@ -443,7 +739,7 @@ macro_rules! archive_format {
                 }
 
                 fn len(&self) -> u64 {
-                    self.0.len()
+                    self.2.unwrap_or_else(|| self.0.len())
                 }
 
                 fn permissions(&self) -> <[< $format FloppyDisk >] as FloppyDisk<'a>>::Permissions {
@@ -451,7 +747,10 @@ macro_rules! archive_format {
                 }
 
                 fn modified(&self) -> Result<SystemTime> {
-                    self.0.modified()
+                    match self.1 {
+                        Some(modified) => Ok(modified),
+                        None => self.0.modified(),
+                    }
                 }
 
                 fn accessed(&self) -> Result<SystemTime> {
@@ -474,36 +773,40 @@ macro_rules! archive_format {
             }
 
             #[derive(Debug)]
-            pub struct [< $format OpenOptions>](#[doc(hidden)] MemOpenOptions, #[doc(hidden)] bool);
+            pub struct [< $format OpenOptions>](
+                #[doc(hidden)] MemOpenOptions,
+                #[doc(hidden)] bool,
+                #[doc(hidden)] bool,
+            );
 
             #[async_trait::async_trait]
             impl<'a> FloppyOpenOptions<'a, [< $format FloppyDisk >]> for [< $format OpenOptions >] {
                 fn new() -> Self {
-                    Self(MemOpenOptions::new(), false)
+                    Self(MemOpenOptions::new(), false, false)
                 }
 
                 fn read(self, read: bool) -> Self {
-                    Self(self.0.read(read), self.1)
+                    Self(self.0.read(read), self.1, self.2)
                 }
 
                 fn write(self, write: bool) -> Self {
-                    Self(self.0.write(write), self.1)
+                    Self(self.0.write(write), self.1, write)
                 }
 
                 fn append(self, append: bool) -> Self {
-                    Self(self.0.append(append), self.1)
+                    Self(self.0.append(append), self.1, self.2 || append)
                 }
 
                 fn truncate(self, truncate: bool) -> Self {
-                    Self(self.0.truncate(truncate), self.1)
+                    Self(self.0.truncate(truncate), self.1, self.2)
                 }
 
                 fn create(self, create: bool) -> Self {
-                    Self(self.0.create(create), create)
+                    Self(self.0.create(create), create, self.2)
                 }
 
                 fn create_new(self, create_new: bool) -> Self {
-                    Self(self.0.create_new(create_new), create_new)
+                    Self(self.0.create_new(create_new), create_new, self.2)
                 }
 
                 async fn open<P: AsRef<Path> + Send>(
@@ -511,10 +814,15 @@ macro_rules! archive_format {
                     disk: &'a [< $format FloppyDisk >],
                     path: P,
                 ) -> Result<<[< $format  FloppyDisk >] as FloppyDisk<'a>>::File> {
+                    disk.load(path.as_ref()).await?;
                     if self.1 {
                         let path = path.as_ref();
                         disk.add_path(path).await;
                     }
+                    if self.2 {
+                        let path = path.as_ref();
+                        disk.touch_path(path).await;
+                    }
                     self.0.open(&disk.delegate, path).await.map([< $format File >])
                 }
             }
@@ -548,15 +856,23 @@ macro_rules! archive_format {
             }
 
             #[derive(Debug)]
-            #[repr(transparent)]
-            pub struct [< $format ReadDir >](#[doc(hidden)] MemReadDir);
+            pub struct [< $format ReadDir >](
+                #[doc(hidden)] MemReadDir,
+                #[doc(hidden)] Arc<Mutex<HashMap<PathBuf, SystemTime>>>,
+                #[doc(hidden)] Arc<Mutex<HashMap<PathBuf, u64>>>,
+            );
 
             #[async_trait::async_trait]
             impl<'a> FloppyReadDir<'a, [< $format FloppyDisk >]> for [< $format ReadDir >] {
                 async fn next_entry(
                     &mut self,
                 ) -> Result<Option<<[< $format FloppyDisk >] as FloppyDisk<'a>>::DirEntry>> {
-                    self.0.next_entry().await.map(|e| e.map([< $format DirEntry >]))
+                    let times = self.1.clone();
+                    let unloaded = self.2.clone();
+                    self.0
+                        .next_entry()
+                        .await
+                        .map(|e| e.map(|e| [< $format DirEntry >](e, times, unloaded)))
                 }
             }
 
@@ -616,6 +932,54 @@ macro_rules! archive_format {
                     Ok(())
                 }
 
+                #[test_log::test(tokio::test)]
+                async fn test_from_dir_works() -> Result<()> {
+                    let src = crate::util::TempDir::new().await?;
+                    tokio::fs::create_dir_all(src.join("a/b")).await?;
+                    tokio::fs::write(src.join("a/b/c.txt"), "nested!!!").await?;
+                    tokio::fs::write(src.join("d.txt"), "top!!!").await?;
+
+                    let out = crate::util::TempDir::new().await?;
+                    let archive = out.join(concat!("out-", $fixture));
+                    {
+                        let disk = [< $format FloppyDisk >]::from_dir(&src, &archive).await?;
+                        disk.close().await?;
+                    }
+                    {
+                        let disk = [< $format FloppyDisk >]::open(&archive).await?;
+                        assert_eq!("nested!!!", disk.read_to_string("/a/b/c.txt").await?);
+                        assert_eq!("top!!!", disk.read_to_string("/d.txt").await?);
+                        disk.close().await?;
+                    }
+
+                    Ok(())
+                }
+
+                #[test_log::test(tokio::test)]
+                async fn test_rename_and_remove_directories_works() -> Result<()> {
+                    let archive = crate::util::tests::TempFile::new(concat!("./fixtures/", $fixture)).await?;
+                    {
+                        let disk = [< $format FloppyDisk >]::open(archive.path_view()).await?;
+                        disk.create_dir_all("/old/nested").await?;
+                        disk.write("/old/nested/a.txt", "moved!!!").await?;
+                        disk.create_dir_all("/gone/nested").await?;
+                        disk.write("/gone/nested/b.txt", "removed!!!").await?;
+                        disk.rename("/old", "/new").await?;
+                        disk.remove_dir_all("/gone").await?;
+                        disk.close().await?;
+                    }
+                    {
+                        let disk = [< $format FloppyDisk >]::open(archive.path_view()).await?;
+                        assert_eq!("moved!!!", disk.read_to_string("/new/nested/a.txt").await?);
+                        assert!(!disk.try_exists("/old/nested/a.txt").await?);
+                        assert!(!disk.try_exists("/gone/nested/b.txt").await?);
+                        assert_eq!("asdf\n", disk.read_to_string("/a.txt").await?);
+                        disk.close().await?;
+                    }
+
+                    Ok(())
+                }
+
                 #[test_log::test(tokio::test)]
                 async fn test_many_files_and_directories_works() -> Result<()> {
                     let archive = crate::util::tests::TempFile::new(concat!("./fixtures/", $fixture)).await?;
@@ -656,11 +1020,157 @@ use std::path::{Path, PathBuf};
 pub(crate) use archive_format;
 use tracing::debug;
 
+/// Lets a format leave the contents of files in the source archive until
+/// something reads them, instead of loading everything when it's opened.
+/// Formats that read everything up front can use the defaults.
+#[async_trait::async_trait]
+pub(crate) trait LazyEntries {
+    /// The sizes of the files that were opened with empty placeholders in
+    /// the memfs.
+    fn unloaded(&self) -> std::collections::HashMap<PathBuf, u64> {
+        std::collections::HashMap::new()
+    }
+
+    /// Reads the contents of an unloaded file from the source archive.
+    async fn load(&self, path: &Path) -> std::io::Result<Vec<u8>> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("{} was never unloaded", path.display()),
+        ))
+    }
+
+    /// Called when the contents of `path` change or it's removed, so that
+    /// the copy in the source archive is stale.
+    fn invalidate(&self, _path: &Path) {}
+}
+
+pub(crate) fn normalize_path<P: AsRef<Path>>(path: P) -> PathBuf {
+    let path = path.as_ref();
+    let path = if !path.starts_with("/") {
+        PathBuf::from("/").join(path)
+    } else {
+        path.to_path_buf()
+    };
+    // Archives made with `tar -C dir .` have paths like `./a/b`.
+    path.components()
+        .filter(|component| !matches!(component, std::path::Component::CurDir))
+        .collect()
+}
+
+pub(crate) fn hash_path<P: AsRef<Path>>(path: P) -> u64 {
+    use std::hash::{Hash, Hasher};
+
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    path.as_ref().hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Compresses `data` into `out`. `smoosh::recompress` only flushes its
+/// encoders instead of shutting them down, which leaves compressed output
+/// without its trailer, so archives are written through this instead.
+pub(crate) async fn write_compressed<W: tokio::io::AsyncWrite + Unpin + Send>(
+    data: &[u8],
+    out: &mut W,
+    compression: smoosh::CompressionType,
+) -> std::io::Result<()> {
+    use tokio::io::AsyncWriteExt;
+
+    let mut encoder = compressor(out, compression);
+    encoder.write_all(data).await?;
+    encoder.shutdown().await
+}
+
+/// Wraps `out` in an encoder for `compression`, for output that's too big
+/// to compress in one go. It has to be shut down to be finished.
+pub(crate) fn compressor<'a, W: tokio::io::AsyncWrite + Unpin + Send + 'a>(
+    out: W,
+    compression: smoosh::CompressionType,
+) -> Box<dyn tokio::io::AsyncWrite + Unpin + Send + 'a> {
+    use async_compression::tokio::write::*;
+    use smoosh::CompressionType;
+
+    match compression {
+        CompressionType::Bzip => Box::new(BzEncoder::new(out)),
+        CompressionType::Deflate => Box::new(DeflateEncoder::new(out)),
+        CompressionType::Gzip => Box::new(GzipEncoder::new(out)),
+        CompressionType::Xz => Box::new(XzEncoder::new(out)),
+        CompressionType::Zlib => Box::new(ZlibEncoder::new(out)),
+        CompressionType::Zstd => Box::new(ZstdEncoder::new(out)),
+        CompressionType::None => Box::new(out),
+    }
+}
+
+/// Converts a calendar date and time in UTC to a [`SystemTime`].
+pub(crate) fn civil_to_system_time(
+    year: i64,
+    month: u32,
+    day: u32,
+    hour: u32,
+    minute: u32,
+    second: u32,
+) -> std::time::SystemTime {
+    // Howard Hinnant's days_from_civil.
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let month = month as i64;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    let seconds = days * 86400 + hour as i64 * 3600 + minute as i64 * 60 + second as i64;
+    if seconds >= 0 {
+        std::time::UNIX_EPOCH + std::time::Duration::from_secs(seconds as u64)
+    } else {
+        std::time::UNIX_EPOCH - std::time::Duration::from_secs(seconds.unsigned_abs())
+    }
+}
+
+/// Converts a [`std::time::SystemTime`] to a calendar date and time in UTC,
+/// as `(year, month, day, hour, minute, second)`.
+pub(crate) fn system_time_to_civil(time: std::time::SystemTime) -> (i64, u32, u32, u32, u32, u32) {
+    let seconds = match time.duration_since(std::time::UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64),
+    };
+    let (days, rest) = (seconds.div_euclid(86400), seconds.rem_euclid(86400));
+    // Howard Hinnant's civil_from_days.
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (
+        year,
+        month,
+        day,
+        (rest / 3600) as u32,
+        (rest % 3600 / 60) as u32,
+        (rest % 60) as u32,
+    )
+}
+
 pub(crate) async fn exists_async<P: AsRef<Path>>(path: P) -> bool {
     let path = path.as_ref();
     tokio::fs::canonicalize(path).await.is_ok()
 }
 
+/// Fails with `NotFound` if there's nothing at `path`, for archives that are
+/// only read from. Opening one that doesn't exist would create it.
+pub(crate) async fn require_exists<P: AsRef<Path>>(path: P) -> std::io::Result<()> {
+    let path = path.as_ref();
+    match tokio::fs::metadata(path).await {
+        Ok(_) => Ok(()),
+        Err(e) => Err(std::io::Error::new(
+            e.kind(),
+            format!("could not open {}: {e}", path.display()),
+        )),
+    }
+}
+
 pub(crate) async fn async_file<P: AsRef<Path>>(path: P) -> std::io::Result<tokio::fs::File> {
     let path = path.as_ref();
     let path = tokio::fs::canonicalize(path).await?;
@@ -669,6 +1179,7 @@ pub(crate) async fn async_file<P: AsRef<Path>>(path: P) -> std::io::Result<tokio
         .read(true)
         .write(true)
         .create(true)
+        .truncate(false)
         .open(&path)
         .await?;
     file.sync_all().await?;
@@ -0,0 +1,558 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::os::unix::fs::FileExt;
+
+use tracing::{debug, trace};
+
+use crate::cpio::CpioFloppyDisk;
+
+crate::image::image_format!(Xar, "a.xar", XarReader, xar_open);
+
+const MAGIC: &[u8; 4] = b"xar!";
+const HEADER_SIZE: usize = 28;
+
+/// The algorithms xar can checksum the TOC and file contents with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XarChecksum {
+    None,
+    Md5,
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl XarChecksum {
+    fn from_style(style: &str) -> Result<Self> {
+        match style.to_ascii_lowercase().as_str() {
+            "none" => Ok(Self::None),
+            "md5" => Ok(Self::Md5),
+            "sha1" => Ok(Self::Sha1),
+            "sha256" => Ok(Self::Sha256),
+            "sha512" => Ok(Self::Sha512),
+            style => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!("unsupported xar checksum {style}"),
+            )),
+        }
+    }
+
+    fn digest(self, data: &[u8]) -> Vec<u8> {
+        use sha2::Digest;
+
+        match self {
+            Self::None => vec![],
+            Self::Md5 => md5::Md5::digest(data).to_vec(),
+            Self::Sha1 => sha1::Sha1::digest(data).to_vec(),
+            Self::Sha256 => sha2::Sha256::digest(data).to_vec(),
+            Self::Sha512 => sha2::Sha512::digest(data).to_vec(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum XarEncoding {
+    None,
+    Gzip,
+    Bzip2,
+    Lzma,
+    Xz,
+}
+
+impl XarEncoding {
+    fn from_style(style: &str) -> Result<Self> {
+        match style {
+            "application/octet-stream" => Ok(Self::None),
+            "application/x-gzip" => Ok(Self::Gzip),
+            "application/x-bzip2" => Ok(Self::Bzip2),
+            "application/x-lzma" => Ok(Self::Lzma),
+            "application/x-xz" => Ok(Self::Xz),
+            style => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!("unsupported xar encoding {style}"),
+            )),
+        }
+    }
+
+    fn decode(self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut out = vec![];
+        match self {
+            Self::None => out.extend_from_slice(data),
+            // Despite the name, this is a zlib stream.
+            Self::Gzip => {
+                flate2::read::ZlibDecoder::new(data).read_to_end(&mut out)?;
+            }
+            Self::Bzip2 => {
+                bzip2::read::MultiBzDecoder::new(data).read_to_end(&mut out)?;
+            }
+            Self::Lzma => {
+                let stream = liblzma::stream::Stream::new_lzma_decoder(u64::MAX)?;
+                liblzma::read::XzDecoder::new_stream(data, stream).read_to_end(&mut out)?;
+            }
+            Self::Xz => {
+                liblzma::read::XzDecoder::new_multi_decoder(data).read_to_end(&mut out)?;
+            }
+        }
+        Ok(out)
+    }
+}
+
+/// Where a file's contents are in the heap, and how to check them.
+#[derive(Debug, Clone)]
+pub(crate) struct XarBlob {
+    offset: u64,
+    length: u64,
+    encoding: XarEncoding,
+    archived_checksum: Option<(XarChecksum, Vec<u8>)>,
+    extracted_checksum: Option<(XarChecksum, Vec<u8>)>,
+}
+
+#[derive(Debug)]
+pub(crate) struct XarReader {
+    file: std::fs::File,
+    heap: u64,
+    toc: String,
+}
+
+#[async_trait::async_trait]
+impl ImageReader for XarReader {
+    type Data = XarBlob;
+
+    async fn read_file(&self, blob: &Self::Data, size: u64) -> Result<Vec<u8>> {
+        let offset = self
+            .heap
+            .checked_add(blob.offset)
+            .ok_or_else(|| invalid("xar file is past the end of the archive"))?;
+        let archived = read_at(&self.file, offset, blob.length)?;
+        verify(&blob.archived_checksum, &archived, "archived")?;
+        let data = blob.encoding.decode(&archived)?;
+        verify(&blob.extracted_checksum, &data, "extracted")?;
+        if data.len() as u64 != size {
+            return Err(invalid(&format!(
+                "xar file is {} bytes, expected {size}",
+                data.len()
+            )));
+        }
+        Ok(data)
+    }
+}
+
+impl XarFloppyDisk {
+    /// The decompressed XML table of contents.
+    pub fn toc(&self) -> &str {
+        &self.reader.toc
+    }
+
+    /// Every file called `Payload`, ie. one for a component package, or one
+    /// per component in a product archive.
+    pub fn payloads(&self) -> Vec<PathBuf> {
+        let mut pending = vec![PathBuf::from("/")];
+        let mut out = vec![];
+        while let Some(dir) = pending.pop() {
+            for (path, entry) in self.tree.children(&dir) {
+                match entry.kind {
+                    ImageKind::Directory => pending.push(path.clone()),
+                    ImageKind::File(_) if path.file_name() == Some("Payload".as_ref()) => {
+                        out.push(path.clone())
+                    }
+                    _ => {}
+                }
+            }
+        }
+        out.sort();
+        out
+    }
+
+    /// Opens the cpio archive in the installer payload at `path`, like
+    /// `/Payload` or `/foo.pkg/Payload`. The payload is loaded into memory,
+    /// and changes to it aren't written back into the xar.
+    pub async fn payload<P: AsRef<Path> + Send>(&self, path: P) -> Result<CpioFloppyDisk> {
+        let path = path.as_ref();
+        debug!("loading xar payload {}", path.display());
+        let data = self.read(path).await?;
+        if data.starts_with(b"pbzx") {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "pbzx payloads are not supported",
+            ));
+        }
+        let metadata = crate::cpio::cpio_read(&data).await?;
+        Ok(CpioFloppyDisk::from_metadata(path.to_path_buf(), metadata))
+    }
+}
+
+async fn xar_open(path: &Path) -> Result<(XarReader, ImageTree<XarBlob>)> {
+    debug!("opening xar {}", path.display());
+    let file = std::fs::File::open(path)?;
+    let mut header = [0; HEADER_SIZE];
+    file.read_exact_at(&mut header, 0)?;
+    if &header[..4] != MAGIC {
+        return Err(invalid("not a xar archive"));
+    }
+    let header_size = be_u16(&header, 4) as u64;
+    let toc_compressed = be_u64(&header, 8);
+    let toc_size = be_u64(&header, 16);
+    if header_size < HEADER_SIZE as u64 {
+        return Err(invalid("xar header is too small"));
+    }
+    let checksum = match be_u32(&header, 24) {
+        0 => XarChecksum::None,
+        1 => XarChecksum::Sha1,
+        2 => XarChecksum::Md5,
+        // Anything else is named after the fixed part of the header.
+        3 => {
+            let mut name = vec![0; header_size as usize - HEADER_SIZE];
+            file.read_exact_at(&mut name, HEADER_SIZE as u64)?;
+            let end = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+            XarChecksum::from_style(&String::from_utf8_lossy(&name[..end]))?
+        }
+        algorithm => return Err(invalid(&format!("unknown xar checksum {algorithm}"))),
+    };
+
+    let compressed = read_at(&file, header_size, toc_compressed)?;
+    let toc = XarEncoding::Gzip.decode(&compressed)?;
+    if toc.len() as u64 != toc_size {
+        return Err(invalid("xar toc is the wrong size"));
+    }
+    let toc = String::from_utf8(toc).map_err(|_| invalid("xar toc is not utf-8"))?;
+    let heap = header_size + toc_compressed;
+
+    let tree = {
+        let document = roxmltree::Document::parse(&toc)
+            .map_err(|e| invalid(&format!("invalid xar toc: {e}")))?;
+        let toc_node = child(document.root_element(), "toc")
+            .ok_or_else(|| invalid("xar is missing its toc"))?;
+
+        // The TOC's checksum is stored at the start of the heap.
+        if checksum != XarChecksum::None {
+            let node = child(toc_node, "checksum")
+                .ok_or_else(|| invalid("xar toc is missing its checksum"))?;
+            let offset = heap
+                .checked_add(number(node, "offset")?)
+                .ok_or_else(|| invalid("xar toc checksum is past the end of the archive"))?;
+            let expected = read_at(&file, offset, number(node, "size")?)?;
+            if checksum.digest(&compressed) != expected {
+                return Err(invalid("xar toc checksum mismatch"));
+            }
+            trace!("verified {:?} toc checksum", checksum);
+        }
+
+        let mut records = vec![];
+        collect(toc_node, Path::new("/"), &mut records)?;
+        build_tree(records)?
+    };
+
+    Ok((XarReader { file, heap, toc }, tree))
+}
+
+/// Reads `len` bytes at `offset`. Both come from the archive, so they're
+/// checked against its length before anything that big is allocated.
+fn read_at(file: &std::fs::File, offset: u64, len: u64) -> Result<Vec<u8>> {
+    let file_len = file.metadata()?.len();
+    if offset.checked_add(len).is_none_or(|end| end > file_len) {
+        return Err(invalid("xar data is past the end of the archive"));
+    }
+    let mut out = vec![0; len as usize];
+    file.read_exact_at(&mut out, offset)?;
+    Ok(out)
+}
+
+/// A `<file>` in the TOC.
+struct Record {
+    path: PathBuf,
+    id: u64,
+    kind: String,
+    /// For hard links, either `original` or the id of the original.
+    link: Option<String>,
+    target: Option<String>,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    mtime: SystemTime,
+    data: Option<(u64, XarBlob)>,
+}
+
+fn collect(node: roxmltree::Node, dir: &Path, out: &mut Vec<Record>) -> Result<()> {
+    for file in node.children().filter(|n| n.has_tag_name("file")) {
+        let name = text(file, "name").ok_or_else(|| invalid("xar file has no name"))?;
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            return Err(invalid(&format!("invalid xar name {name:?}")));
+        }
+        let path = dir.join(name);
+        trace!("found {}", path.display());
+        let kind_node = child(file, "type").ok_or_else(|| invalid("xar file has no type"))?;
+        let mtime = match text(file, "mtime") {
+            Some(mtime) => chrono::DateTime::parse_from_rfc3339(mtime)
+                .map(SystemTime::from)
+                .map_err(|_| invalid(&format!("invalid xar mtime {mtime}")))?,
+            None => std::time::UNIX_EPOCH,
+        };
+        let data = match child(file, "data") {
+            Some(data) => Some((number(data, "size")?, parse_blob(data)?)),
+            None => None,
+        };
+        out.push(Record {
+            path: path.clone(),
+            id: file
+                .attribute("id")
+                .and_then(|id| id.parse().ok())
+                .ok_or_else(|| invalid("xar file has no id"))?,
+            kind: kind_node.text().unwrap_or("").to_string(),
+            link: kind_node.attribute("link").map(String::from),
+            target: text(file, "link").map(String::from),
+            mode: match text(file, "mode") {
+                Some(mode) => u32::from_str_radix(mode, 8)
+                    .map_err(|_| invalid(&format!("invalid xar mode {mode}")))?,
+                None => 0o644,
+            },
+            uid: text(file, "uid").and_then(|v| v.parse().ok()).unwrap_or(0),
+            gid: text(file, "gid").and_then(|v| v.parse().ok()).unwrap_or(0),
+            mtime,
+            data,
+        });
+        collect(file, &path, out)?;
+    }
+    Ok(())
+}
+
+fn parse_blob(data: roxmltree::Node) -> Result<XarBlob> {
+    let checksum = |name: &str| -> Result<Option<(XarChecksum, Vec<u8>)>> {
+        let Some(node) = child(data, name) else {
+            return Ok(None);
+        };
+        let algorithm = XarChecksum::from_style(node.attribute("style").unwrap_or("none"))?;
+        let hex = node.text().unwrap_or("").trim();
+        let digest = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(hex.get(i..i + 2).unwrap_or(""), 16))
+            .collect::<std::result::Result<Vec<u8>, _>>()
+            .map_err(|_| invalid(&format!("invalid xar checksum {hex}")))?;
+        Ok(Some((algorithm, digest)))
+    };
+    Ok(XarBlob {
+        offset: number(data, "offset")?,
+        length: number(data, "length")?,
+        encoding: match child(data, "encoding").and_then(|n| n.attribute("style")) {
+            Some(style) => XarEncoding::from_style(style)?,
+            None => XarEncoding::None,
+        },
+        archived_checksum: checksum("archived-checksum")?,
+        extracted_checksum: checksum("extracted-checksum")?,
+    })
+}
+
+fn build_tree(records: Vec<Record>) -> Result<ImageTree<XarBlob>> {
+    let mut tree = ImageTree::new(ImageEntry::directory(0o755));
+    // Hard links only have data on the original, so find those first.
+    let originals: HashMap<u64, (u64, XarBlob)> = records
+        .iter()
+        .filter(|record| record.link.as_deref() == Some("original"))
+        .filter_map(|record| record.data.clone().map(|data| (record.id, data)))
+        .collect();
+
+    for record in records {
+        let base = ImageEntry {
+            mode: record.mode & 0o7777,
+            uid: record.uid,
+            gid: record.gid,
+            mtime: record.mtime,
+            ino: record.id,
+            ..ImageEntry::directory(0)
+        };
+        let entry = match record.kind.as_str() {
+            "directory" => base,
+            "file" => {
+                let (size, blob) = record.data.unwrap_or_else(empty);
+                ImageEntry {
+                    kind: ImageKind::File(blob),
+                    size,
+                    ..base
+                }
+            }
+            "hardlink" => {
+                let link = record.link.unwrap_or_default();
+                let original = match link.as_str() {
+                    "original" => record.id,
+                    id => id
+                        .parse()
+                        .map_err(|_| invalid(&format!("invalid xar hard link {id}")))?,
+                };
+                let (size, blob) = match originals.get(&original) {
+                    Some(data) => data.clone(),
+                    None => record.data.unwrap_or_else(empty),
+                };
+                ImageEntry {
+                    kind: ImageKind::File(blob),
+                    size,
+                    link_id: Some(original),
+                    ..base
+                }
+            }
+            "symlink" => {
+                let target = record
+                    .target
+                    .ok_or_else(|| invalid("xar symlink has no target"))?;
+                ImageEntry {
+                    size: target.len() as u64,
+                    kind: ImageKind::Symlink(PathBuf::from(target)),
+                    ..base
+                }
+            }
+            kind => {
+                trace!("skipping {} of type {}", record.path.display(), kind);
+                continue;
+            }
+        };
+        tree.insert(&record.path, entry);
+    }
+    Ok(tree)
+}
+
+fn empty() -> (u64, XarBlob) {
+    (
+        0,
+        XarBlob {
+            offset: 0,
+            length: 0,
+            encoding: XarEncoding::None,
+            archived_checksum: None,
+            extracted_checksum: None,
+        },
+    )
+}
+
+fn verify(checksum: &Option<(XarChecksum, Vec<u8>)>, data: &[u8], which: &str) -> Result<()> {
+    match checksum {
+        Some((algorithm, expected)) if *algorithm != XarChecksum::None => {
+            if algorithm.digest(data) != *expected {
+                return Err(invalid(&format!("xar {which} checksum mismatch")));
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+fn child<'a, 'input>(
+    node: roxmltree::Node<'a, 'input>,
+    name: &str,
+) -> Option<roxmltree::Node<'a, 'input>> {
+    node.children().find(|n| n.has_tag_name(name))
+}
+
+fn text<'a>(node: roxmltree::Node<'a, '_>, name: &str) -> Option<&'a str> {
+    child(node, name).and_then(|n| n.text()).map(str::trim)
+}
+
+fn number(node: roxmltree::Node, name: &str) -> Result<u64> {
+    text(node, name)
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| invalid(&format!("xar is missing <{name}>")))
+}
+
+fn be_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([data[offset], data[offset + 1]])
+}
+
+fn be_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+fn be_u64(data: &[u8], offset: usize) -> u64 {
+    ((be_u32(data, offset) as u64) << 32) | be_u32(data, offset + 4) as u64
+}
+
+fn invalid(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::import::FloppyDiskHardLinkExt;
+
+    #[test_log::test(tokio::test)]
+    async fn test_corrupt_sizes_are_errors() -> Result<()> {
+        let dir = crate::util::TempDir::new().await?;
+        let path = dir.join("corrupt.xar");
+        let mut data = tokio::fs::read("./fixtures/a.xar").await?;
+        data[8..16].copy_from_slice(&(1u64 << 63).to_be_bytes());
+        tokio::fs::write(&path, &data).await?;
+
+        let err = XarFloppyDisk::open(&path).await.unwrap_err();
+        assert_eq!(std::io::ErrorKind::InvalidData, err.kind());
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_xar_works() -> Result<()> {
+        let disk = XarFloppyDisk::open("./fixtures/a.xar").await?;
+        assert!(disk.toc().starts_with("<?xml"));
+
+        assert_eq!("asdf\n", disk.read_to_string("/a.txt").await?);
+        assert_eq!("asdf\n", disk.read_to_string("/hard.txt").await?);
+        assert_eq!(
+            disk.hard_link_id("/a.txt").await?,
+            disk.hard_link_id("/hard.txt").await?
+        );
+        assert!(disk.hard_link_id("/a.txt").await?.is_some());
+        assert_eq!("bzip2!\n", disk.read_to_string("/bzip2.txt").await?);
+        assert_eq!("xz!\n", disk.read_to_string("/xz.txt").await?);
+        assert_eq!(
+            "hello from a subdirectory\n",
+            disk.read_to_string("/dir/nested.txt").await?
+        );
+        assert_eq!(PathBuf::from("a.txt"), disk.read_link("/link").await?);
+        assert_eq!("asdf\n", disk.read_to_string("/link").await?);
+
+        let metadata = disk.metadata("/script.sh").await?;
+        assert_eq!(0o755, metadata.permissions().mode());
+        assert_eq!(501, metadata.uid()?);
+        assert_eq!(20, metadata.gid()?);
+        assert_eq!(
+            std::time::UNIX_EPOCH + std::time::Duration::from_secs(1687000000),
+            metadata.modified()?
+        );
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_payload_works() -> Result<()> {
+        let disk = XarFloppyDisk::open("./fixtures/a.xar").await?;
+        assert_eq!(vec![PathBuf::from("/Payload")], disk.payloads());
+
+        let payload = disk.payload("/Payload").await?;
+        assert_eq!(
+            "#!/bin/sh\necho hi\n",
+            payload.read_to_string("/usr/bin/hello").await?
+        );
+        assert_eq!(
+            0o755,
+            payload
+                .metadata("/usr/bin/hello")
+                .await?
+                .permissions()
+                .mode()
+        );
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_checksum_mismatch_fails() -> Result<()> {
+        let disk = XarFloppyDisk::open("./fixtures/bad.xar").await?;
+        let err = disk.read("/a.txt").await.unwrap_err();
+        assert_eq!(std::io::ErrorKind::InvalidData, err.kind());
+        assert!(err.to_string().contains("checksum"));
+        // Other files are still fine.
+        assert_eq!("xz!\n", disk.read_to_string("/xz.txt").await?);
+
+        Ok(())
+    }
+}
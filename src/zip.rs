@@ -1,126 +1,1641 @@
-use std::io::Cursor;
-use std::os::unix::prelude::OsStringExt;
+use std::io::{Cursor, SeekFrom};
+use std::ops::Range;
+use std::os::unix::prelude::{OsStrExt, OsStringExt};
 
-use async_zip::tokio::read::seek::ZipFileReader;
-use async_zip::tokio::write::ZipFileWriter;
-use async_zip::{ZipDateTime, ZipEntryBuilder, ZipString};
-use chrono::DateTime;
+use chrono::{DateTime, Datelike, TimeZone, Timelike, Utc};
 use smoosh::CompressionType;
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 use tracing::debug;
 
 crate::util::archive_format!(Zip, "a.zip", zip_open, zip_close);
 
+#[derive(Debug, Default)]
+pub(crate) struct ZipState {
+    comment: std::sync::Mutex<Vec<u8>>,
+    /// What the archive knows about each entry that a floppy disk doesn't,
+    /// so that it survives a round-trip.
+    entries: std::sync::Mutex<HashMap<PathBuf, ZipEntryMetadata>>,
+    /// The archive that was opened, which files are read from when they're
+    /// first needed.
+    source: Mutex<Option<Box<dyn ZipSource>>>,
+    /// The files whose contents haven't changed since they were read from
+    /// the source, so they can be copied over without recompressing them.
+    originals: std::sync::Mutex<HashMap<PathBuf, ZipEntry>>,
+}
+
+trait ZipSource: AsyncRead + AsyncSeek + Unpin + Send + Sync + std::fmt::Debug {}
+
+impl<T: AsyncRead + AsyncSeek + Unpin + Send + Sync + std::fmt::Debug> ZipSource for T {}
+
+#[async_trait::async_trait]
+impl crate::util::LazyEntries for ZipState {
+    fn unloaded(&self) -> HashMap<PathBuf, u64> {
+        self.originals
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(path, entry)| (path.clone(), entry.uncompressed_size))
+            .collect()
+    }
+
+    async fn load(&self, path: &Path) -> Result<Vec<u8>> {
+        let entry = self.original(path)?;
+        let mut source = self.source.lock().await;
+        match source.as_mut() {
+            Some(source) => read_entry_data(source, &entry).await,
+            None => Err(std::io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn invalidate(&self, path: &Path) {
+        self.originals.lock().unwrap().remove(path);
+    }
+}
+
+impl ZipState {
+    /// Reads an entry's headers as they are in the source.
+    async fn read_original(&self, entry: &ZipEntry) -> Result<ZipRecord> {
+        let mut source = self.source.lock().await;
+        match source.as_mut() {
+            Some(source) => read_raw_entry(source, entry).await,
+            None => Err(std::io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    /// Copies `range` of the source into `out`, without decompressing it.
+    async fn copy_original(&self, range: Range<u64>, out: &mut ZipWriter) -> Result<()> {
+        let mut source = self.source.lock().await;
+        match source.as_mut() {
+            Some(source) => out.copy_from(source, range).await,
+            None => Err(std::io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn original(&self, path: &Path) -> Result<ZipEntry> {
+        self.originals
+            .lock()
+            .unwrap()
+            .get(path)
+            .cloned()
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("{} isn't in the source zip", path.display()),
+                )
+            })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct ZipEntryMetadata {
+    /// The name as it was stored in the archive, and whether it was flagged
+    /// as UTF-8, so that it's written back exactly even if it had to be
+    /// decoded.
+    raw_name: Option<(Vec<u8>, bool)>,
+    comment: Vec<u8>,
+    extra: Vec<u8>,
+    external_attributes: u32,
+}
+
+impl ZipFloppyDisk {
+    /// The archive comment, which is bytes since nothing says what encoding
+    /// it's in.
+    pub fn comment(&self) -> Vec<u8> {
+        self.state.comment.lock().unwrap().clone()
+    }
+
+    pub fn set_comment<C: Into<Vec<u8>>>(&self, comment: C) -> Result<()> {
+        *self.state.comment.lock().unwrap() = checked_len(comment.into(), "comment")?;
+        Ok(())
+    }
+
+    /// The name of the entry at `path` as it's stored in the archive, which
+    /// might be CP437 or not text at all. Only entries read from the archive
+    /// have one.
+    pub fn raw_name<P: AsRef<Path>>(&self, path: P) -> Option<Vec<u8>> {
+        self.entry_metadata(path)
+            .and_then(|metadata| metadata.raw_name)
+            .map(|(raw, _)| raw)
+    }
+
+    pub fn entry_comment<P: AsRef<Path>>(&self, path: P) -> Option<Vec<u8>> {
+        self.entry_metadata(path).map(|metadata| metadata.comment)
+    }
+
+    pub fn set_entry_comment<P: AsRef<Path>, C: Into<Vec<u8>>>(
+        &self,
+        path: P,
+        comment: C,
+    ) -> Result<()> {
+        let comment = checked_len(comment.into(), "comment")?;
+        self.update_entry_metadata(path, |metadata| metadata.comment = comment);
+        Ok(())
+    }
+
+    /// The extra fields of the entry at `path`, as ids and their data. The
+    /// Zip64 field isn't included, since it's rebuilt on close.
+    pub fn extra_fields<P: AsRef<Path>>(&self, path: P) -> Option<Vec<(u16, Vec<u8>)>> {
+        self.entry_metadata(path).map(|metadata| {
+            extra_fields(&metadata.extra)
+                .map(|(id, data)| (id, data.to_vec()))
+                .collect()
+        })
+    }
+
+    /// Replaces the extra fields of the entry at `path`. A Zip64 field is
+    /// ignored, since it's rebuilt on close.
+    pub fn set_extra_fields<P: AsRef<Path>>(
+        &self,
+        path: P,
+        fields: &[(u16, Vec<u8>)],
+    ) -> Result<()> {
+        let mut extra = vec![];
+        for (id, data) in fields {
+            if *id != ZIP64_EXTRA_FIELD {
+                let data = checked_len(data.clone(), "extra field")?;
+                push_extra_field(&mut extra, *id, &data);
+            }
+        }
+        // Leave room for the Zip64 field, which can be up to 28 bytes.
+        if extra.len() > u16::MAX as usize - 28 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "zip extra fields are too long",
+            ));
+        }
+        self.update_entry_metadata(path, |metadata| metadata.extra = extra);
+        Ok(())
+    }
+
+    /// The external attributes of the entry at `path`. The upper 16 bits
+    /// are the unix mode, which comes from the entry's permissions on close,
+    /// and the lower 16 are MS-DOS attributes like read-only and hidden.
+    pub fn external_attributes<P: AsRef<Path>>(&self, path: P) -> Option<u32> {
+        self.entry_metadata(path)
+            .map(|metadata| metadata.external_attributes)
+    }
+
+    /// Sets the external attributes of the entry at `path`. Only the MS-DOS
+    /// attributes in the lower 16 bits are kept, since the unix mode comes
+    /// from the entry's permissions.
+    pub fn set_external_attributes<P: AsRef<Path>>(&self, path: P, attributes: u32) {
+        self.update_entry_metadata(path, |metadata| metadata.external_attributes = attributes);
+    }
+
+    fn entry_metadata<P: AsRef<Path>>(&self, path: P) -> Option<ZipEntryMetadata> {
+        let path = crate::util::normalize_path(path);
+        self.state.entries.lock().unwrap().get(&path).cloned()
+    }
+
+    fn update_entry_metadata<P: AsRef<Path>, F: FnOnce(&mut ZipEntryMetadata)>(
+        &self,
+        path: P,
+        update: F,
+    ) {
+        let path = crate::util::normalize_path(path);
+        update(self.state.entries.lock().unwrap().entry(path).or_default());
+    }
+}
+
+const S_IFMT: u32 = 0o170000;
+const S_IFLNK: u32 = 0o120000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+const LOCAL_HEADER_SIGNATURE: u32 = 0x04034b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x02014b50;
+const END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06054b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06064b50;
+const ZIP64_LOCATOR_SIGNATURE: u32 = 0x07064b50;
+const DATA_DESCRIPTOR_SIGNATURE: u32 = 0x08074b50;
+const LOCAL_HEADER_SIZE: usize = 30;
+const CENTRAL_HEADER_SIZE: usize = 46;
+const END_OF_CENTRAL_DIRECTORY_SIZE: usize = 22;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_SIZE: usize = 56;
+const ZIP64_LOCATOR_SIZE: usize = 20;
+const ZIP64_EXTRA_FIELD: u16 = 0x0001;
+/// Sizes and offsets at or above this are stored in the Zip64 extra field
+/// instead, with this as a marker.
+const ZIP64_LIMIT: u64 = 0xffffffff;
+/// Same as [`ZIP64_LIMIT`], for the number of entries.
+const ZIP64_ENTRY_LIMIT: u64 = 0xffff;
+
+const FLAG_ENCRYPTED: u16 = 0x0001;
+/// The crc and sizes follow the data instead of being in the local header.
+const FLAG_DATA_DESCRIPTOR: u16 = 0x0008;
+const FLAG_UTF8: u16 = 0x0800;
+const UNICODE_PATH_EXTRA_FIELD: u16 = 0x7075;
+const EXTENDED_TIMESTAMP_EXTRA_FIELD: u16 = 0x5455;
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATED: u16 = 8;
+const HOST_MSDOS: u16 = 0;
+const HOST_UNIX: u16 = 3;
+const HOST_HPFS: u16 = 6;
+const HOST_NTFS: u16 = 11;
+const MSDOS_DIRECTORY: u32 = 0x10;
+const VERSION_DEFAULT: u16 = 20;
+const VERSION_ZIP64: u16 = 45;
+
+/// The upper half of code page 437, which is what names are in when they
+/// aren't flagged as UTF-8.
+const CP437: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å', 'É', 'æ', 'Æ',
+    'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ', 'á', 'í', 'ó', 'ú', 'ñ', 'Ñ',
+    'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»', '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕',
+    '╣', '║', '╗', '╝', '╜', '╛', '┐', '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦',
+    '╠', '═', '╬', '╧', '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐',
+    '▀', 'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩', '≡', '±',
+    '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}',
+];
+
 async fn zip_open<P: Into<PathBuf>>(path: P) -> Result<ZipInternalMetadata> {
     let path = path.into();
     if !crate::util::exists_async(path.clone()).await {
-        let _archive = ZipFileWriter::with_tokio(tokio::fs::File::create(path).await?);
+        let mut out = vec![];
+        write_end_of_central_directory(&mut out, 0, 0, 0, &[]);
+        tokio::fs::write(&path, out).await?;
         return Ok(ZipInternalMetadata {
             delegate: MemFloppyDisk::new(),
             compression: CompressionType::None,
             ordered_paths: IndexSet::new(),
+            times: HashMap::new(),
+            hard_links: IndexMap::new(),
+            state: Default::default(),
         });
     }
 
     debug!("opening zip file {}", path.display());
     let mut file = crate::util::async_file(path).await?;
-    let mut buffer = vec![];
-    let c = smoosh::recompress(&mut file, &mut buffer, smoosh::CompressionType::None).await?;
-    let mut archive = ZipFileReader::with_tokio(Cursor::new(buffer))
-        .await
-        .map_err(fix_err)?;
+    let mut magic = [0u8; 4];
+    let is_zip = match file.read_exact(&mut magic).await {
+        Ok(_) => magic.starts_with(b"PK"),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => false,
+        Err(e) => return Err(e),
+    };
+    file.seek(SeekFrom::Start(0)).await?;
+
+    // Plain zips are read in place, so that nothing but the central
+    // directory is read until it's needed. Anything else has to be
+    // decompressed first.
+    if is_zip {
+        read_archive(Box::new(file)).await
+    } else {
+        let mut buffer = vec![];
+        let c = smoosh::recompress(&mut file, &mut buffer, smoosh::CompressionType::None).await?;
+        let mut metadata = read_archive(Box::new(Cursor::new(buffer))).await?;
+        metadata.compression = c;
+        Ok(metadata)
+    }
+}
+
+/// Builds the tree from the central directory. Files are left as empty
+/// placeholders and read from `source` when they're first needed.
+async fn read_archive(mut source: Box<dyn ZipSource>) -> Result<ZipInternalMetadata> {
     let out = MemFloppyDisk::new();
     let mut ordered_paths = IndexSet::new();
+    let mut times = HashMap::new();
+    let mut metadata = HashMap::new();
+    let mut originals = HashMap::new();
 
-    let archive_file = archive.file();
-    let entries = archive_file.entries();
-    for idx in 0..entries.len() {
-        let mut zip_entry = archive.reader_with_entry(idx).await.map_err(fix_err)?;
-        let entry = zip_entry.entry();
-        let path = PathBuf::from(OsString::from_vec(entry.filename().as_bytes().to_vec()));
+    let directory = read_central_directory(&mut source).await?;
+    debug!("found {} zip entries", directory.entries.len());
+    for entry in &directory.entries {
+        let path = PathBuf::from(OsString::from_vec(entry.decoded_name()));
+        let path = crate::util::normalize_path(path);
         debug!("processing archive path {}", path.display());
         ordered_paths.insert(path.clone());
+        metadata.insert(
+            path.clone(),
+            ZipEntryMetadata {
+                raw_name: Some((entry.name.clone(), entry.flags & FLAG_UTF8 != 0)),
+                comment: entry.comment.clone(),
+                extra: entry.extra.clone(),
+                external_attributes: entry.external_attributes,
+            },
+        );
+        times.insert(path.clone(), entry.modified());
+        let mode = entry.unix_mode().unwrap_or(0);
+
+        if entry.is_dir() {
+            debug!("creating dir: {}", path.display());
+            out.create_dir_all(&path).await?;
+            if mode & 0o7777 != 0 {
+                out.set_permissions(&path, MemPermissions::from_mode(mode & 0o7777))
+                    .await?;
+            }
+            continue;
+        }
 
         if let Some(parent) = path.parent() {
             out.create_dir_all(parent).await?;
         }
-        let mut handle = MemOpenOptions::new()
+
+        if mode & S_IFMT == S_IFLNK {
+            // Symlinks are stored with the link target as their contents,
+            // which the memfs needs right away.
+            let data = read_entry_data(&mut source, entry).await?;
+            let to = PathBuf::from(OsString::from_vec(data));
+            debug!("read symlink: {} -> {}", path.display(), to.display());
+            out.symlink(to, path).await?;
+            continue;
+        }
+
+        MemOpenOptions::new()
             .create(true)
             .write(true)
             .open(&out, &path)
             .await?;
-
-        let mut data = vec![];
-        zip_entry
-            .read_to_end_checked(&mut data)
-            .await
-            .map_err(fix_err)?;
-        tokio::io::copy(&mut data.as_slice(), &mut handle).await?;
-        debug!("copied path!");
+        originals.insert(path.clone(), entry.clone());
+        if mode & 0o7777 != 0 {
+            out.set_permissions(&path, MemPermissions::from_mode(mode & 0o7777))
+                .await?;
+        }
+        debug!("added placeholder!");
     }
 
     Ok(ZipInternalMetadata {
         delegate: out,
-        compression: c,
+        compression: CompressionType::None,
         ordered_paths,
+        times,
+        hard_links: IndexMap::new(),
+        state: ZipState {
+            comment: std::sync::Mutex::new(directory.comment),
+            entries: std::sync::Mutex::new(metadata),
+            source: Mutex::new(Some(source)),
+            originals: std::sync::Mutex::new(originals),
+        },
     })
 }
 
-async fn zip_close(
-    disk: &MemFloppyDisk,
-    scope: &Path,
-    compression: CompressionType,
-    ordered_paths: &IndexSet<PathBuf>,
-) -> Result<()> {
+async fn zip_close(zip: &ZipFloppyDisk) -> Result<()> {
+    let scope = &zip.path;
+    let compression = zip.compression;
+    let ordered_paths = &*zip.ordered_paths.lock().await;
     debug!("closing zip at {}", scope.display());
-    let mut file = tokio::fs::OpenOptions::new()
-        .write(true)
-        .truncate(true)
-        .open(scope)
-        .await?;
 
-    let buffer = vec![];
-    let mut writer = ZipFileWriter::with_tokio(buffer);
+    // The archive is written next to the old one and moved over it at the
+    // end, since unchanged entries are copied from the old one as it's
+    // written, and it doesn't have to fit in memory.
+    let target = tokio::fs::canonicalize(scope).await?;
+    let temp = target.with_file_name(format!(
+        ".{}.{}",
+        target.file_name().unwrap_or_default().to_string_lossy(),
+        rand::random::<u64>()
+    ));
+    let result = async {
+        let mut out = ZipWriter::create(&temp, compression).await?;
+        write_archive(zip, ordered_paths, &mut out).await?;
+        out.finish().await?;
+        let permissions = tokio::fs::metadata(&target).await?.permissions();
+        tokio::fs::set_permissions(&temp, permissions).await?;
+        tokio::fs::rename(&temp, &target).await
+    }
+    .await;
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(&temp).await;
+    }
+    result
+}
 
+/// Writes every entry, then the central directory.
+async fn write_archive(
+    zip: &ZipFloppyDisk,
+    ordered_paths: &IndexSet<PathBuf>,
+    out: &mut ZipWriter,
+) -> Result<()> {
+    let disk = &zip.delegate;
+    let mut entries = vec![];
     for path in ordered_paths {
-        let metadata = disk.metadata(path).await?;
-        if metadata.is_file() {
-            debug!("writing path {} to zip!", path.display());
-            let mut handle = MemOpenOptions::new().read(true).open(disk, path).await?;
-
-            let mut data = vec![];
-            handle.read_to_end(&mut data).await?;
-
-            // TODO: lol not utf8
-            let entry = ZipEntryBuilder::new(
-                ZipString::new(
-                    path.to_string_lossy().to_string().as_bytes().to_vec(),
-                    async_zip::StringEncoding::Utf8,
+        if path.as_os_str() == "/" {
+            continue;
+        }
+        debug!("writing path {} to zip!", path.display());
+        let name = path
+            .strip_prefix("/")
+            .unwrap_or(path)
+            .as_os_str()
+            .as_bytes()
+            .to_vec();
+
+        let (name, mode, data, original) = if let Ok(link) = disk.read_link(path).await {
+            debug!("writing symlink: {} -> {}", path.display(), link.display());
+            (
+                name,
+                S_IFLNK | 0o777,
+                link.as_os_str().as_bytes().to_vec(),
+                None,
+            )
+        } else {
+            let metadata = disk.metadata(path).await?;
+            let mode = metadata.permissions().mode() & 0o7777;
+            if metadata.is_dir() {
+                let mut name = name;
+                name.push(b'/');
+                (name, S_IFDIR | mode, vec![], None)
+            } else if let Ok(original) = zip.state.original(path) {
+                // Files that haven't changed keep their compressed data, so
+                // they don't have to be read or recompressed.
+                let record = zip.state.read_original(&original).await?;
+                (name, S_IFREG | mode, vec![], Some((original, record)))
+            } else if metadata.is_file() {
+                let mut handle = MemOpenOptions::new().read(true).open(disk, path).await?;
+                let mut data = vec![];
+                handle.read_to_end(&mut data).await?;
+                (name, S_IFREG | mode, data, None)
+            } else {
+                continue;
+            }
+        };
+
+        let metadata = zip.state.entries.lock().unwrap().get(path).cloned();
+        let metadata = metadata.unwrap_or_default();
+        let mut extra = metadata.extra;
+        // Names that were read from the archive are written back the same
+        // way, unless the entry changed between a file and a directory.
+        let (name, flags) = match metadata.raw_name {
+            Some((raw, utf8)) if raw.ends_with(b"/") == name.ends_with(b"/") => {
+                // Entries are always written as unix ones, so decoded names
+                // need a Unicode Path extra field to be read the same way.
+                if !utf8 && raw != name && extra_field(&extra, UNICODE_PATH_EXTRA_FIELD).is_none() {
+                    let mut field = vec![1];
+                    field.extend_from_slice(&crc32fast::hash(&raw).to_le_bytes());
+                    field.extend_from_slice(&name);
+                    push_extra_field(&mut extra, UNICODE_PATH_EXTRA_FIELD, &field);
+                }
+                (raw, if utf8 { FLAG_UTF8 } else { 0 })
+            }
+            _ => {
+                // Names that aren't UTF-8 are written as they are, like
+                // Info-ZIP does on unix.
+                let flags = match std::str::from_utf8(&name) {
+                    Ok(text) if !text.is_ascii() => FLAG_UTF8,
+                    _ => 0,
+                };
+                (name, flags)
+            }
+        };
+
+        let modified = zip.modified(path).await?;
+        set_extended_timestamp(&mut extra, modified);
+        let (dos_time, dos_date) = dos_date_time(modified);
+        let mut external_attributes = mode << 16 | (metadata.external_attributes & 0xffff);
+        if mode & S_IFMT == S_IFDIR {
+            external_attributes |= MSDOS_DIRECTORY;
+        }
+        let (flags, method, crc32, compressed_size, uncompressed_size) = match &original {
+            Some((original, _)) => (
+                flags | (original.flags & !FLAG_UTF8),
+                original.method,
+                original.crc32,
+                original.compressed_size,
+                original.uncompressed_size,
+            ),
+            None => (
+                flags,
+                METHOD_STORED,
+                crc32fast::hash(&data),
+                data.len() as u64,
+                data.len() as u64,
+            ),
+        };
+        let entry = ZipEntry {
+            version_made_by: HOST_UNIX << 8 | VERSION_DEFAULT,
+            flags,
+            method,
+            dos_time,
+            dos_date,
+            crc32,
+            compressed_size,
+            uncompressed_size,
+            external_attributes,
+            local_header_offset: out.offset,
+            name,
+            extra,
+            comment: metadata.comment,
+        };
+
+        // If nothing about the entry changed either, its headers are copied
+        // as they were too.
+        if let Some((original, record)) = original {
+            if entry.name == original.name
+                && entry.flags == original.flags
+                && entry.extra == original.extra
+                && entry.comment == original.comment
+                && entry.external_attributes == original.external_attributes
+                && modified == original.modified()
+            {
+                debug!("copying original entry");
+                out.write(&record.local_header).await?;
+                zip.state.copy_original(record.data, out).await?;
+                out.write(&record.data_descriptor).await?;
+                entries.push(ZipEntry {
+                    local_header_offset: entry.local_header_offset,
+                    ..original
+                });
+                continue;
+            }
+
+            let mut header = vec![];
+            write_local_header(&mut header, &entry);
+            out.write(&header).await?;
+            zip.state.copy_original(record.data, out).await?;
+        } else {
+            let mut header = vec![];
+            write_local_header(&mut header, &entry);
+            out.write(&header).await?;
+            out.write(&data).await?;
+        }
+        if entry.flags & FLAG_DATA_DESCRIPTOR != 0 {
+            let mut descriptor = vec![];
+            write_data_descriptor(&mut descriptor, &entry);
+            out.write(&descriptor).await?;
+        }
+        entries.push(entry);
+
+        debug!("wrote path!");
+    }
+
+    let central_directory_offset = out.offset;
+    let mut directory = vec![];
+    for entry in &entries {
+        write_central_header(&mut directory, entry);
+    }
+    let central_directory_size = directory.len() as u64;
+    write_end_of_central_directory(
+        &mut directory,
+        entries.len() as u64,
+        central_directory_size,
+        central_directory_offset,
+        &zip.comment(),
+    );
+    out.write(&directory).await
+}
+
+/// Where a new archive is written, keeping track of how much has been
+/// written so far for the offsets in the central directory.
+struct ZipWriter {
+    out: ZipOutput,
+    offset: u64,
+}
+
+enum ZipOutput {
+    File(tokio::fs::File),
+    Compressed(Box<dyn AsyncWrite + Unpin + Send>),
+}
+
+impl ZipWriter {
+    async fn create(path: &Path, compression: CompressionType) -> Result<Self> {
+        let file = tokio::fs::File::create(path).await?;
+        let out = match compression {
+            CompressionType::None => ZipOutput::File(file),
+            compression => ZipOutput::Compressed(crate::util::compressor(file, compression)),
+        };
+        Ok(Self { out, offset: 0 })
+    }
+
+    async fn write(&mut self, data: &[u8]) -> Result<()> {
+        match &mut self.out {
+            ZipOutput::File(file) => file.write_all(data).await?,
+            ZipOutput::Compressed(encoder) => encoder.write_all(data).await?,
+        }
+        self.offset += data.len() as u64;
+        Ok(())
+    }
+
+    /// Copies `range` of `reader` in chunks. Chunks of zeroes are skipped
+    /// over in uncompressed output, so that holes in sparse archives stay
+    /// holes.
+    async fn copy_from<R: AsyncRead + AsyncSeek + Unpin>(
+        &mut self,
+        reader: &mut R,
+        range: Range<u64>,
+    ) -> Result<()> {
+        const CHUNK_SIZE: usize = 1024 * 1024;
+        let zeroes = vec![0; CHUNK_SIZE];
+        let mut buffer = vec![0; CHUNK_SIZE];
+        reader.seek(SeekFrom::Start(range.start)).await?;
+        let mut left = range.end - range.start;
+        while left > 0 {
+            let len = left.min(CHUNK_SIZE as u64) as usize;
+            reader.read_exact(&mut buffer[..len]).await?;
+            match &mut self.out {
+                ZipOutput::File(file) if buffer[..len] == zeroes[..len] => {
+                    file.seek(SeekFrom::Current(len as i64)).await?;
+                    self.offset += len as u64;
+                }
+                _ => self.write(&buffer[..len]).await?,
+            }
+            left -= len as u64;
+        }
+        Ok(())
+    }
+
+    async fn finish(self) -> Result<()> {
+        match self.out {
+            ZipOutput::File(mut file) => file.flush().await,
+            ZipOutput::Compressed(mut encoder) => encoder.shutdown().await,
+        }
+    }
+}
+
+/// An entry in the central directory.
+#[derive(Debug, Clone)]
+struct ZipEntry {
+    version_made_by: u16,
+    flags: u16,
+    method: u16,
+    dos_time: u16,
+    dos_date: u16,
+    crc32: u32,
+    compressed_size: u64,
+    uncompressed_size: u64,
+    external_attributes: u32,
+    local_header_offset: u64,
+    name: Vec<u8>,
+    /// Every extra field besides the Zip64 one, which is rebuilt on write.
+    extra: Vec<u8>,
+    comment: Vec<u8>,
+}
+
+/// The parts of the central directory that matter.
+struct ZipDirectory {
+    entries: Vec<ZipEntry>,
+    comment: Vec<u8>,
+}
+
+impl ZipEntry {
+    /// The entry's name as a unix path. Names are UTF-8 if they're flagged
+    /// as such or have a matching Unicode Path extra field, and CP437 if
+    /// they were written on DOS or Windows. Anything else is taken as raw
+    /// bytes, since that's what unix tools write.
+    fn decoded_name(&self) -> Vec<u8> {
+        if self.flags & FLAG_UTF8 != 0 {
+            return self.name.clone();
+        }
+        // A version, the crc of the raw name, then the name as UTF-8. The
+        // crc makes sure the raw name wasn't changed by a tool that didn't
+        // know to update this.
+        if let Some(field) = extra_field(&self.extra, UNICODE_PATH_EXTRA_FIELD) {
+            let unicode = field
+                .get(5..)
+                .filter(|name| std::str::from_utf8(name).is_ok());
+            if let (Some(1), Some(crc32), Some(unicode)) = (field.first(), le32(field, 1), unicode)
+            {
+                if crc32 == crc32fast::hash(&self.name) {
+                    return unicode.to_vec();
+                }
+            }
+        }
+        match self.version_made_by >> 8 {
+            HOST_MSDOS | HOST_HPFS | HOST_NTFS => decode_cp437(&self.name).into_bytes(),
+            _ => self.name.clone(),
+        }
+    }
+
+    fn is_dir(&self) -> bool {
+        self.name.ends_with(b"/")
+    }
+
+    /// The unix mode from the external attributes, if the entry was written
+    /// on unix.
+    fn unix_mode(&self) -> Option<u32> {
+        match self.version_made_by >> 8 {
+            HOST_UNIX => Some(self.external_attributes >> 16),
+            _ => None,
+        }
+    }
+
+    /// When the entry was modified, from the extended timestamp extra field
+    /// if there is one, since it's in UTC and has one second precision.
+    fn modified(&self) -> SystemTime {
+        let timestamp = extra_field(&self.extra, EXTENDED_TIMESTAMP_EXTRA_FIELD)
+            .filter(|field| field.first().is_some_and(|flags| flags & 1 != 0))
+            .and_then(|field| le32(field, 1));
+        if let Some(timestamp) = timestamp {
+            return match timestamp as i32 {
+                secs @ 0.. => std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs as u64),
+                secs => {
+                    std::time::UNIX_EPOCH - std::time::Duration::from_secs(-(secs as i64) as u64)
+                }
+            };
+        }
+
+        let date = self.dos_date as u32;
+        let time = self.dos_time as u32;
+        let modified = Utc
+            .with_ymd_and_hms(
+                (date >> 9) as i32 + 1980,
+                (date >> 5) & 0xf,
+                date & 0x1f,
+                time >> 11,
+                (time >> 5) & 0x3f,
+                (time & 0x1f) * 2,
+            )
+            .single()
+            .unwrap_or_default();
+        SystemTime::from(modified)
+    }
+
+    fn needs_zip64(&self) -> bool {
+        self.compressed_size >= ZIP64_LIMIT
+            || self.uncompressed_size >= ZIP64_LIMIT
+            || self.local_header_offset >= ZIP64_LIMIT
+    }
+
+    fn version_needed(&self) -> u16 {
+        match self.needs_zip64() {
+            true => VERSION_ZIP64,
+            false => VERSION_DEFAULT,
+        }
+    }
+}
+
+/// Updates the modification time in the extended timestamp extra field, if
+/// there is one, so that it doesn't go stale.
+fn set_extended_timestamp(extra: &mut [u8], time: SystemTime) {
+    let mut offset = 0;
+    while let (Some(id), Some(len)) = (le16(extra, offset), le16(extra, offset + 2)) {
+        let data = offset + 4;
+        if id == EXTENDED_TIMESTAMP_EXTRA_FIELD && len >= 5 && extra[data] & 1 != 0 {
+            let secs = DateTime::<Utc>::from(time).timestamp() as i32;
+            extra[data + 1..data + 5].copy_from_slice(&secs.to_le_bytes());
+        }
+        offset = data + len as usize;
+    }
+}
+
+/// Converts a time to the MS-DOS format that zip headers use, which only
+/// covers 1980 to 2107 and has two second precision.
+fn dos_date_time(time: SystemTime) -> (u16, u16) {
+    let time = DateTime::<Utc>::from(time);
+    if time.year() < 1980 {
+        return (0, 1 << 5 | 1);
+    }
+    let year = (time.year() - 1980).min(127) as u16;
+    (
+        (time.hour() as u16) << 11 | (time.minute() as u16) << 5 | (time.second() as u16 / 2),
+        year << 9 | (time.month() as u16) << 5 | time.day() as u16,
+    )
+}
+
+/// Finds and parses the central directory, using the Zip64 end of central
+/// directory record when there is one.
+async fn read_central_directory<R: AsyncRead + AsyncSeek + Unpin>(
+    reader: &mut R,
+) -> Result<ZipDirectory> {
+    let len = reader.seek(SeekFrom::End(0)).await?;
+    // The end of central directory record is followed by a comment of up to
+    // 64KiB, so it has to be searched for.
+    let tail_len = len.min((END_OF_CENTRAL_DIRECTORY_SIZE + u16::MAX as usize) as u64);
+    let tail_start = len - tail_len;
+    reader.seek(SeekFrom::Start(tail_start)).await?;
+    let mut tail = vec![0; tail_len as usize];
+    reader.read_exact(&mut tail).await?;
+    let end = (0..tail.len().saturating_sub(END_OF_CENTRAL_DIRECTORY_SIZE - 1))
+        .rev()
+        .find(|idx| {
+            le32(&tail, *idx) == Some(END_OF_CENTRAL_DIRECTORY_SIGNATURE)
+                && le16(&tail, idx + 20).is_some_and(|comment_len| {
+                    idx + END_OF_CENTRAL_DIRECTORY_SIZE + comment_len as usize <= tail.len()
+                })
+        })
+        .ok_or_else(|| invalid("missing zip end of central directory record"))?;
+
+    let comment_len = le16(&tail, end + 20).unwrap() as usize;
+    let comment_start = end + END_OF_CENTRAL_DIRECTORY_SIZE;
+    let comment = tail[comment_start..comment_start + comment_len].to_vec();
+    let mut entry_count = le16(&tail, end + 10).unwrap() as u64;
+    let mut directory_size = le32(&tail, end + 12).unwrap() as u64;
+    let mut directory_offset = le32(&tail, end + 16).unwrap() as u64;
+
+    let locator = end
+        .checked_sub(ZIP64_LOCATOR_SIZE)
+        .filter(|locator| le32(&tail, *locator) == Some(ZIP64_LOCATOR_SIGNATURE));
+    if let Some(locator) = locator {
+        let record_offset = le64(&tail, locator + 8).unwrap();
+        debug!("reading zip64 end of central directory at {record_offset}");
+        reader.seek(SeekFrom::Start(record_offset)).await?;
+        let mut record = [0; ZIP64_END_OF_CENTRAL_DIRECTORY_SIZE];
+        reader.read_exact(&mut record).await?;
+        if le32(&record, 0) != Some(ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE) {
+            return Err(invalid("bad zip64 end of central directory record"));
+        }
+        entry_count = le64(&record, 32).unwrap();
+        directory_size = le64(&record, 40).unwrap();
+        directory_offset = le64(&record, 48).unwrap();
+    }
+
+    // The sizes come from the archive, so they're checked against it before
+    // anything that big is allocated.
+    if directory_offset
+        .checked_add(directory_size)
+        .is_none_or(|directory_end| directory_end > len)
+    {
+        return Err(invalid(
+            "zip central directory is past the end of the archive",
+        ));
+    }
+    reader.seek(SeekFrom::Start(directory_offset)).await?;
+    let directory_size =
+        usize::try_from(directory_size).map_err(|_| invalid("zip central directory is too big"))?;
+    let mut directory = vec![0; directory_size];
+    reader.read_exact(&mut directory).await?;
+
+    let mut entries = vec![];
+    let mut offset = 0;
+    for _ in 0..entry_count {
+        let (entry, next) = parse_central_header(&directory, offset)?;
+        entries.push(entry);
+        offset = next;
+    }
+    Ok(ZipDirectory { entries, comment })
+}
+
+/// Parses the central directory header at `offset`, returning it with the
+/// offset of the next one.
+fn parse_central_header(directory: &[u8], offset: usize) -> Result<(ZipEntry, usize)> {
+    let truncated = || invalid("truncated zip central directory");
+    let header = directory
+        .get(offset..offset + CENTRAL_HEADER_SIZE)
+        .ok_or_else(truncated)?;
+    if le32(header, 0) != Some(CENTRAL_HEADER_SIGNATURE) {
+        return Err(invalid("bad zip central directory header"));
+    }
+    let name_len = le16(header, 28).unwrap() as usize;
+    let extra_len = le16(header, 30).unwrap() as usize;
+    let comment_len = le16(header, 32).unwrap() as usize;
+    let name_start = offset + CENTRAL_HEADER_SIZE;
+    let name = directory
+        .get(name_start..name_start + name_len)
+        .ok_or_else(truncated)?;
+    let extra = directory
+        .get(name_start + name_len..name_start + name_len + extra_len)
+        .ok_or_else(truncated)?;
+    let comment_start = name_start + name_len + extra_len;
+    let comment = directory
+        .get(comment_start..comment_start + comment_len)
+        .ok_or_else(truncated)?;
+
+    let mut entry = ZipEntry {
+        version_made_by: le16(header, 4).unwrap(),
+        flags: le16(header, 8).unwrap(),
+        method: le16(header, 10).unwrap(),
+        dos_time: le16(header, 12).unwrap(),
+        dos_date: le16(header, 14).unwrap(),
+        crc32: le32(header, 16).unwrap(),
+        compressed_size: le32(header, 20).unwrap() as u64,
+        uncompressed_size: le32(header, 24).unwrap() as u64,
+        external_attributes: le32(header, 38).unwrap(),
+        local_header_offset: le32(header, 42).unwrap() as u64,
+        name: name.to_vec(),
+        extra: vec![],
+        comment: comment.to_vec(),
+    };
+
+    // Only the fields that overflowed are in the Zip64 extra field, in this
+    // order.
+    if let Some(zip64) = extra_field(extra, ZIP64_EXTRA_FIELD) {
+        let mut values = zip64.chunks_exact(8).map(|value| le64(value, 0).unwrap());
+        for field in [
+            &mut entry.uncompressed_size,
+            &mut entry.compressed_size,
+            &mut entry.local_header_offset,
+        ] {
+            if *field == ZIP64_LIMIT {
+                *field = values.next().ok_or_else(truncated)?;
+            }
+        }
+    }
+
+    entry.extra = extra_fields(extra)
+        .filter(|(id, _)| *id != ZIP64_EXTRA_FIELD)
+        .fold(vec![], |mut out, (id, data)| {
+            push_extra_field(&mut out, id, data);
+            out
+        });
+
+    Ok((entry, name_start + name_len + extra_len + comment_len))
+}
+
+/// Splits extra fields into their ids and data, stopping at anything
+/// malformed.
+fn extra_fields(extra: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    let mut offset = 0;
+    std::iter::from_fn(move || {
+        let id = le16(extra, offset)?;
+        let len = le16(extra, offset + 2)? as usize;
+        let data = extra.get(offset + 4..offset + 4 + len)?;
+        offset += 4 + len;
+        Some((id, data))
+    })
+}
+
+/// Finds the extra field with the given id.
+fn extra_field(extra: &[u8], id: u16) -> Option<&[u8]> {
+    extra_fields(extra)
+        .find(|(field_id, _)| *field_id == id)
+        .map(|(_, data)| data)
+}
+
+fn push_extra_field(out: &mut Vec<u8>, id: u16, data: &[u8]) {
+    out.extend_from_slice(&id.to_le_bytes());
+    out.extend_from_slice(&(data.len() as u16).to_le_bytes());
+    out.extend_from_slice(data);
+}
+
+fn decode_cp437(name: &[u8]) -> String {
+    name.iter()
+        .map(|byte| match byte {
+            0x80.. => CP437[*byte as usize - 0x80],
+            _ => *byte as char,
+        })
+        .collect()
+}
+
+/// Reads and decompresses an entry's data, checking it against the size and
+/// crc in the central directory.
+async fn read_entry_data<R: AsyncRead + AsyncSeek + Unpin>(
+    reader: &mut R,
+    entry: &ZipEntry,
+) -> Result<Vec<u8>> {
+    let name = String::from_utf8_lossy(&entry.name);
+    if entry.flags & FLAG_ENCRYPTED != 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            format!("{name} is encrypted"),
+        ));
+    }
+
+    let record = read_raw_entry(reader, entry).await?;
+    let compressed_size = usize::try_from(entry.compressed_size)
+        .map_err(|_| invalid(&format!("{name} is too big")))?;
+    let mut compressed = vec![0; compressed_size];
+    reader.seek(SeekFrom::Start(record.data.start)).await?;
+    reader.read_exact(&mut compressed).await?;
+    let data = match entry.method {
+        METHOD_STORED => compressed,
+        METHOD_DEFLATED => {
+            // Deflate can't expand data more than 1032 times, so a bigger
+            // size than that is a lie, and decompressing stops just past it
+            // either way.
+            let capacity = entry
+                .uncompressed_size
+                .min((compressed.len() as u64).saturating_mul(1032));
+            let mut data = Vec::with_capacity(capacity as usize);
+            std::io::Read::read_to_end(
+                &mut std::io::Read::take(
+                    flate2::read::DeflateDecoder::new(compressed.as_slice()),
+                    entry.uncompressed_size.saturating_add(1),
                 ),
-                async_zip::Compression::Stored,
-            );
+                &mut data,
+            )?;
+            data
+        }
+        method => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!("{name} uses unsupported compression method {method}"),
+            ))
+        }
+    };
 
-            let entry = entry
-                .last_modification_date(ZipDateTime::from_chrono(&DateTime::from(
-                    metadata.modified().unwrap(),
-                )))
-                .unix_permissions(metadata.permissions().mode() as u16);
+    if data.len() as u64 != entry.uncompressed_size || crc32fast::hash(&data) != entry.crc32 {
+        return Err(invalid(&format!("{name} failed its zip crc check")));
+    }
+    Ok(data)
+}
+
+/// An entry as it's stored in the archive.
+struct ZipRecord {
+    /// The local header, with its name and extra fields.
+    local_header: Vec<u8>,
+    /// Where the data is in the archive, since it can be too big to read
+    /// all at once.
+    data: Range<u64>,
+    /// The crc and sizes after the data, for entries that have them there.
+    data_descriptor: Vec<u8>,
+}
+
+/// Reads an entry's headers as they're stored, and finds its data.
+async fn read_raw_entry<R: AsyncRead + AsyncSeek + Unpin>(
+    reader: &mut R,
+    entry: &ZipEntry,
+) -> Result<ZipRecord> {
+    let name = String::from_utf8_lossy(&entry.name);
+    let len = reader.seek(SeekFrom::End(0)).await?;
+    reader
+        .seek(SeekFrom::Start(entry.local_header_offset))
+        .await?;
+    let mut local_header = vec![0; LOCAL_HEADER_SIZE];
+    reader.read_exact(&mut local_header).await?;
+    if le32(&local_header, 0) != Some(LOCAL_HEADER_SIGNATURE) {
+        return Err(invalid(&format!("bad zip local header for {name}")));
+    }
+    let name_len = le16(&local_header, 26).unwrap() as usize;
+    let extra_len = le16(&local_header, 28).unwrap() as usize;
+    local_header.resize(LOCAL_HEADER_SIZE + name_len + extra_len, 0);
+    reader
+        .read_exact(&mut local_header[LOCAL_HEADER_SIZE..])
+        .await?;
+
+    let data_start = entry.local_header_offset + local_header.len() as u64;
+    if data_start
+        .checked_add(entry.compressed_size)
+        .is_none_or(|data_end| data_end > len)
+    {
+        return Err(invalid(&format!("{name} is past the end of the archive")));
+    }
+    let data = data_start..data_start + entry.compressed_size;
 
-            writer
-                .write_entry_whole(entry, &data)
-                .await
-                .map_err(fix_err)?;
+    // The signature is optional, and the sizes are 64 bits if the local
+    // header has a Zip64 field.
+    let mut data_descriptor = vec![];
+    if entry.flags & FLAG_DATA_DESCRIPTOR != 0 {
+        let extra = &local_header[LOCAL_HEADER_SIZE + name_len..];
+        let sizes = match extra_field(extra, ZIP64_EXTRA_FIELD) {
+            Some(_) => 16,
+            None => 8,
+        };
+        reader.seek(SeekFrom::Start(data.end)).await?;
+        data_descriptor.resize(4, 0);
+        reader.read_exact(&mut data_descriptor).await?;
+        let rest = match le32(&data_descriptor, 0) {
+            Some(DATA_DESCRIPTOR_SIGNATURE) => 4 + sizes,
+            _ => sizes,
+        };
+        data_descriptor.resize(4 + rest, 0);
+        reader.read_exact(&mut data_descriptor[4..]).await?;
+    }
+
+    Ok(ZipRecord {
+        local_header,
+        data,
+        data_descriptor,
+    })
+}
+
+fn write_local_header(out: &mut Vec<u8>, entry: &ZipEntry) {
+    // The local header has to have both sizes in the Zip64 extra field if
+    // either overflows.
+    let zip64 = entry.compressed_size >= ZIP64_LIMIT || entry.uncompressed_size >= ZIP64_LIMIT;
+    let mut extra = vec![];
+    if zip64 {
+        let mut sizes = entry.uncompressed_size.to_le_bytes().to_vec();
+        sizes.extend_from_slice(&entry.compressed_size.to_le_bytes());
+        push_extra_field(&mut extra, ZIP64_EXTRA_FIELD, &sizes);
+    }
+    extra.extend_from_slice(&entry.extra);
+    let size = |size: u64| match zip64 {
+        true => ZIP64_LIMIT as u32,
+        false => size as u32,
+    };
+
+    out.extend_from_slice(&LOCAL_HEADER_SIGNATURE.to_le_bytes());
+    out.extend_from_slice(&entry.version_needed().to_le_bytes());
+    out.extend_from_slice(&entry.flags.to_le_bytes());
+    out.extend_from_slice(&entry.method.to_le_bytes());
+    out.extend_from_slice(&entry.dos_time.to_le_bytes());
+    out.extend_from_slice(&entry.dos_date.to_le_bytes());
+    out.extend_from_slice(&entry.crc32.to_le_bytes());
+    out.extend_from_slice(&size(entry.compressed_size).to_le_bytes());
+    out.extend_from_slice(&size(entry.uncompressed_size).to_le_bytes());
+    out.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+    out.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+    out.extend_from_slice(&entry.name);
+    out.extend_from_slice(&extra);
+}
 
-            debug!("wrote path!");
+/// Writes the data descriptor that follows the data of entries that have
+/// one, with Zip64 sizes if the local header has them.
+fn write_data_descriptor(out: &mut Vec<u8>, entry: &ZipEntry) {
+    out.extend_from_slice(&DATA_DESCRIPTOR_SIGNATURE.to_le_bytes());
+    out.extend_from_slice(&entry.crc32.to_le_bytes());
+    if entry.compressed_size >= ZIP64_LIMIT || entry.uncompressed_size >= ZIP64_LIMIT {
+        out.extend_from_slice(&entry.compressed_size.to_le_bytes());
+        out.extend_from_slice(&entry.uncompressed_size.to_le_bytes());
+    } else {
+        out.extend_from_slice(&(entry.compressed_size as u32).to_le_bytes());
+        out.extend_from_slice(&(entry.uncompressed_size as u32).to_le_bytes());
+    }
+}
+
+fn write_central_header(out: &mut Vec<u8>, entry: &ZipEntry) {
+    let mut zip64 = vec![];
+    let mut field = |value: u64| {
+        if value >= ZIP64_LIMIT {
+            zip64.extend_from_slice(&value.to_le_bytes());
+            ZIP64_LIMIT as u32
+        } else {
+            value as u32
         }
+    };
+    let uncompressed_size = field(entry.uncompressed_size);
+    let compressed_size = field(entry.compressed_size);
+    let local_header_offset = field(entry.local_header_offset);
+    let mut extra = vec![];
+    if !zip64.is_empty() {
+        push_extra_field(&mut extra, ZIP64_EXTRA_FIELD, &zip64);
+    }
+    extra.extend_from_slice(&entry.extra);
+    let version_made_by = match entry.needs_zip64() {
+        true => (entry.version_made_by & 0xff00) | VERSION_ZIP64.max(entry.version_made_by & 0xff),
+        false => entry.version_made_by,
+    };
+
+    out.extend_from_slice(&CENTRAL_HEADER_SIGNATURE.to_le_bytes());
+    out.extend_from_slice(&version_made_by.to_le_bytes());
+    out.extend_from_slice(&entry.version_needed().to_le_bytes());
+    out.extend_from_slice(&entry.flags.to_le_bytes());
+    out.extend_from_slice(&entry.method.to_le_bytes());
+    out.extend_from_slice(&entry.dos_time.to_le_bytes());
+    out.extend_from_slice(&entry.dos_date.to_le_bytes());
+    out.extend_from_slice(&entry.crc32.to_le_bytes());
+    out.extend_from_slice(&compressed_size.to_le_bytes());
+    out.extend_from_slice(&uncompressed_size.to_le_bytes());
+    out.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+    out.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+    out.extend_from_slice(&(entry.comment.len() as u16).to_le_bytes());
+    // Disk number and internal attributes.
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(&entry.external_attributes.to_le_bytes());
+    out.extend_from_slice(&local_header_offset.to_le_bytes());
+    out.extend_from_slice(&entry.name);
+    out.extend_from_slice(&extra);
+    out.extend_from_slice(&entry.comment);
+}
+
+/// Writes the end of central directory record, preceded by the Zip64 record
+/// and locator if anything overflows it.
+fn write_end_of_central_directory(
+    out: &mut Vec<u8>,
+    entry_count: u64,
+    directory_size: u64,
+    directory_offset: u64,
+    comment: &[u8],
+) {
+    let zip64 = entry_count >= ZIP64_ENTRY_LIMIT
+        || directory_size >= ZIP64_LIMIT
+        || directory_offset >= ZIP64_LIMIT;
+    if zip64 {
+        debug!("writing zip64 end of central directory");
+        // The record comes right after the central directory.
+        let record_offset = directory_offset + directory_size;
+        out.extend_from_slice(&ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE.to_le_bytes());
+        // The size of the rest of the record.
+        out.extend_from_slice(&(ZIP64_END_OF_CENTRAL_DIRECTORY_SIZE as u64 - 12).to_le_bytes());
+        out.extend_from_slice(&(HOST_UNIX << 8 | VERSION_ZIP64).to_le_bytes());
+        out.extend_from_slice(&VERSION_ZIP64.to_le_bytes());
+        // This disk, and the disk the central directory starts on.
+        out.extend_from_slice(&[0; 8]);
+        out.extend_from_slice(&entry_count.to_le_bytes());
+        out.extend_from_slice(&entry_count.to_le_bytes());
+        out.extend_from_slice(&directory_size.to_le_bytes());
+        out.extend_from_slice(&directory_offset.to_le_bytes());
+
+        out.extend_from_slice(&ZIP64_LOCATOR_SIGNATURE.to_le_bytes());
+        out.extend_from_slice(&0u32.to_le_bytes());
+        out.extend_from_slice(&record_offset.to_le_bytes());
+        out.extend_from_slice(&1u32.to_le_bytes());
     }
 
-    let writer = writer.close().await.map_err(fix_err)?;
-    smoosh::recompress(&mut writer.get_ref().as_slice(), &mut file, compression).await?;
+    let entry_count = entry_count.min(ZIP64_ENTRY_LIMIT) as u16;
+    out.extend_from_slice(&END_OF_CENTRAL_DIRECTORY_SIGNATURE.to_le_bytes());
+    // This disk, and the disk the central directory starts on.
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(&entry_count.to_le_bytes());
+    out.extend_from_slice(&entry_count.to_le_bytes());
+    out.extend_from_slice(&(directory_size.min(ZIP64_LIMIT) as u32).to_le_bytes());
+    out.extend_from_slice(&(directory_offset.min(ZIP64_LIMIT) as u32).to_le_bytes());
+    out.extend_from_slice(&(comment.len() as u16).to_le_bytes());
+    out.extend_from_slice(comment);
+}
+
+fn le16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        data.get(offset..offset + 2)?.try_into().unwrap(),
+    ))
+}
+
+fn le32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        data.get(offset..offset + 4)?.try_into().unwrap(),
+    ))
+}
+
+fn le64(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        data.get(offset..offset + 8)?.try_into().unwrap(),
+    ))
+}
+
+/// Makes sure something fits in one of the 16 bit lengths in the headers.
+fn checked_len(data: Vec<u8>, what: &str) -> Result<Vec<u8>> {
+    if data.len() > u16::MAX as usize {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("zip {what} is too long"),
+        ));
+    }
+    Ok(data)
+}
 
-    Ok(())
+fn invalid(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
 }
 
-fn fix_err<E: std::error::Error + Send + Sync + 'static>(err: E) -> std::io::Error {
-    std::io::Error::new(std::io::ErrorKind::Other, err)
+#[cfg(test)]
+mod zip_tests {
+    use std::io::{Seek, Write};
+
+    use super::*;
+    use crate::util::tests::TempFile;
+
+    #[test_log::test(tokio::test)]
+    async fn test_corrupt_sizes_are_errors() -> Result<()> {
+        let dir = crate::util::TempDir::new().await?;
+        let path = dir.join("corrupt.zip");
+
+        // A Zip64 central directory much bigger than the archive.
+        let mut data = tokio::fs::read("./fixtures/a.zip64.zip").await?;
+        let record = data.windows(4).position(|w| w == b"PK\x06\x06").unwrap();
+        data[record + 40..record + 48].copy_from_slice(&(1u64 << 60).to_le_bytes());
+        tokio::fs::write(&path, &data).await?;
+        let err = ZipFloppyDisk::open(&path).await.unwrap_err();
+        assert_eq!(std::io::ErrorKind::InvalidData, err.kind());
+
+        // An entry much bigger than the archive, which is only noticed once
+        // it's read.
+        let mut data = tokio::fs::read("./fixtures/a.zip").await?;
+        let header = data.windows(4).position(|w| w == b"PK\x01\x02").unwrap();
+        data[header + 20..header + 24].copy_from_slice(&0x7fff_ffffu32.to_le_bytes());
+        tokio::fs::write(&path, &data).await?;
+        let disk = ZipFloppyDisk::open(&path).await?;
+        let err = disk.read("/a.txt").await.unwrap_err();
+        assert_eq!(std::io::ErrorKind::InvalidData, err.kind());
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_names_are_decoded() -> Result<()> {
+        let archive = TempFile::new("./fixtures/names.zip").await?;
+        let raw_path = PathBuf::from(OsString::from_vec(b"/raw_\xff.txt".to_vec()));
+        {
+            let disk = ZipFloppyDisk::open(archive.path_view()).await?;
+            for path in [
+                Path::new("/café.txt"),
+                Path::new("/unicode_ü.txt"),
+                &raw_path,
+                Path::new("/日本.txt"),
+            ] {
+                assert_eq!("asdf\n", disk.read_to_string(path).await?);
+            }
+            assert_eq!(Some(b"caf\x82.txt".to_vec()), disk.raw_name("/café.txt"));
+            assert_eq!(
+                Some(b"unicode_?.txt".to_vec()),
+                disk.raw_name("/unicode_ü.txt")
+            );
+            disk.write("/café.txt", "changed").await?;
+            disk.write("/naïve.txt", "new").await?;
+            disk.write(OsString::from_vec(b"/new_\xfe.txt".to_vec()), "new")
+                .await?;
+            disk.close().await?;
+        }
+
+        // Names from the archive are written back exactly.
+        let data = tokio::fs::read(archive.path_view()).await?;
+        let fixture = tokio::fs::read("./fixtures/names.zip").await?;
+        let entries = read_central_directory(&mut Cursor::new(&data))
+            .await?
+            .entries;
+        let fixture_entries = read_central_directory(&mut Cursor::new(&fixture))
+            .await?
+            .entries;
+        for (entry, fixture_entry) in entries.iter().zip(&fixture_entries) {
+            assert_eq!(fixture_entry.name, entry.name);
+            assert_eq!(fixture_entry.flags & FLAG_UTF8, entry.flags & FLAG_UTF8);
+            assert_eq!(fixture_entry.decoded_name(), entry.decoded_name());
+        }
+        assert_eq!(fixture_entries[1].extra, entries[1].extra);
+        // New names are UTF-8 when they can be, and raw when they can't.
+        assert_eq!("naïve.txt".as_bytes(), entries[4].name);
+        assert_eq!(FLAG_UTF8, entries[4].flags & FLAG_UTF8);
+        assert_eq!(b"new_\xfe.txt", entries[5].name.as_slice());
+        assert_eq!(0, entries[5].flags & FLAG_UTF8);
+
+        let disk = ZipFloppyDisk::open(archive.path_view()).await?;
+        assert_eq!("changed", disk.read_to_string("/café.txt").await?);
+        assert_eq!("asdf\n", disk.read_to_string("/unicode_ü.txt").await?);
+        assert_eq!("new", disk.read_to_string("/naïve.txt").await?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cp437_works() {
+        assert_eq!(
+            "Çüé░█■\u{a0}",
+            decode_cp437(b"\x80\x81\x82\xb0\xdb\xfe\xff")
+        );
+        assert_eq!("plain.txt", decode_cp437(b"plain.txt"));
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_comments_and_extra_fields_work() -> Result<()> {
+        let archive = TempFile::new("./fixtures/comments.zip").await?;
+        {
+            let disk = ZipFloppyDisk::open(archive.path_view()).await?;
+            assert_eq!(b"archive comment".to_vec(), disk.comment());
+            assert_eq!(
+                Some(b"entry comment".to_vec()),
+                disk.entry_comment("/a.txt")
+            );
+            assert_eq!(
+                Some(vec![(0xcafe, b"data".to_vec())]),
+                disk.extra_fields("/a.txt")
+            );
+            assert_eq!(
+                Some((0o100644 << 16) | 0x01),
+                disk.external_attributes("/a.txt")
+            );
+
+            disk.write("/a.txt", "changed").await?;
+            disk.write("/b.txt", "new").await?;
+            disk.set_comment("new archive comment")?;
+            disk.set_entry_comment("/b.txt", "new entry comment")?;
+            disk.set_extra_fields("/b.txt", &[(0xbeef, b"more".to_vec())])?;
+            disk.set_external_attributes("/b.txt", 0x02);
+            assert!(disk.set_comment(vec![0; 70000]).is_err());
+            disk.close().await?;
+        }
+
+        let disk = ZipFloppyDisk::open(archive.path_view()).await?;
+        assert_eq!("changed", disk.read_to_string("/a.txt").await?);
+        assert_eq!(b"new archive comment".to_vec(), disk.comment());
+        assert_eq!(
+            Some(b"entry comment".to_vec()),
+            disk.entry_comment("/a.txt")
+        );
+        assert_eq!(
+            Some(vec![(0xcafe, b"data".to_vec())]),
+            disk.extra_fields("/a.txt")
+        );
+        assert_eq!(
+            Some((0o100644 << 16) | 0x01),
+            disk.external_attributes("/a.txt")
+        );
+        assert_eq!(
+            Some(b"new entry comment".to_vec()),
+            disk.entry_comment("/b.txt")
+        );
+        assert_eq!(
+            Some(vec![(0xbeef, b"more".to_vec())]),
+            disk.extra_fields("/b.txt")
+        );
+        // The mode comes from the permissions, and the rest is kept.
+        let mode = disk.metadata("/b.txt").await?.permissions().mode();
+        assert_eq!(
+            Some((S_IFREG | mode) << 16 | 0x02),
+            disk.external_attributes("/b.txt")
+        );
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_zip64_fixture_works() -> Result<()> {
+        let archive = TempFile::new("./fixtures/a.zip64.zip").await?;
+        let expected = "asdf\n".repeat(1000);
+        {
+            let disk = ZipFloppyDisk::open(archive.path_view()).await?;
+            assert_eq!(expected, disk.read_to_string("/a.txt").await?);
+            disk.write("/b.txt", "wow!!!").await?;
+            disk.close().await?;
+        }
+
+        let disk = ZipFloppyDisk::open(archive.path_view()).await?;
+        assert_eq!(expected, disk.read_to_string("/a.txt").await?);
+        assert_eq!("wow!!!", disk.read_to_string("/b.txt").await?);
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_entries_are_read_lazily() -> Result<()> {
+        let archive = TempFile::new("./fixtures/a.zip64.zip").await?;
+        let expected = "asdf\n".repeat(1000);
+        {
+            let disk = ZipFloppyDisk::open(archive.path_view()).await?;
+            assert!(disk.unloaded.lock().await.contains_key(Path::new("/a.txt")));
+            let metadata = disk.metadata("/a.txt").await?;
+            assert_eq!(5000, metadata.len());
+            assert_eq!(0o644, metadata.permissions().mode() & 0o7777);
+            disk.write("/b.txt", "wow!!!").await?;
+            disk.close().await?;
+        }
+
+        // The untouched entry is copied as it was, still deflated.
+        let data = tokio::fs::read(archive.path_view()).await?;
+        let entries = read_central_directory(&mut Cursor::new(&data))
+            .await?
+            .entries;
+        assert_eq!(METHOD_DEFLATED, entries[0].method);
+        assert_eq!(METHOD_STORED, entries[1].method);
+        {
+            let disk = ZipFloppyDisk::open(archive.path_view()).await?;
+            assert_eq!(expected, disk.read_to_string("/a.txt").await?);
+            assert!(!disk.unloaded.lock().await.contains_key(Path::new("/a.txt")));
+            let metadata = disk.metadata("/a.txt").await?;
+            assert_eq!(5000, metadata.len());
+            assert_eq!(0o644, metadata.permissions().mode() & 0o7777);
+            disk.write("/a.txt", "changed!!!").await?;
+            disk.close().await?;
+        }
+
+        let data = tokio::fs::read(archive.path_view()).await?;
+        let entries = read_central_directory(&mut Cursor::new(&data))
+            .await?
+            .entries;
+        assert_eq!(METHOD_STORED, entries[0].method);
+        let disk = ZipFloppyDisk::open(archive.path_view()).await?;
+        assert_eq!("changed!!!", disk.read_to_string("/a.txt").await?);
+        assert_eq!("wow!!!", disk.read_to_string("/b.txt").await?);
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_unchanged_entries_are_copied_as_they_were() -> Result<()> {
+        let archive = TempFile::new("./fixtures/comments.zip").await?;
+        let fixture = tokio::fs::read("./fixtures/comments.zip").await?;
+        let records = fixture
+            .windows(4)
+            .position(|window| window == CENTRAL_HEADER_SIGNATURE.to_le_bytes())
+            .unwrap();
+        {
+            let disk = ZipFloppyDisk::open(archive.path_view()).await?;
+            disk.write("/b.txt", "wow!!!").await?;
+            disk.close().await?;
+        }
+
+        let data = tokio::fs::read(archive.path_view()).await?;
+        assert_eq!(fixture[..records], data[..records]);
+        let modified = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+        {
+            let disk = ZipFloppyDisk::open(archive.path_view()).await?;
+            disk.set_modified("/a.txt", modified).await?;
+            disk.close().await?;
+        }
+
+        // The headers change along with the entry, but the data doesn't.
+        let data = tokio::fs::read(archive.path_view()).await?;
+        assert_ne!(fixture[..records], data[..records]);
+        let disk = ZipFloppyDisk::open(archive.path_view()).await?;
+        assert_eq!(modified, disk.metadata("/a.txt").await?.modified()?);
+        assert_eq!("asdf\n", disk.read_to_string("/a.txt").await?);
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_many_entries_use_zip64() -> Result<()> {
+        let dir = crate::util::TempDir::new().await?;
+        let path = dir.join("many.zip");
+        let count = ZIP64_ENTRY_LIMIT + 1;
+        {
+            let disk = ZipFloppyDisk::open(&path).await?;
+            for idx in 0..count {
+                disk.write(format!("/{idx}"), "").await?;
+            }
+            disk.write("/last.txt", "asdf\n").await?;
+            disk.close().await?;
+        }
+
+        let data = tokio::fs::read(&path).await?;
+        assert!(data
+            .windows(4)
+            .any(|window| window == ZIP64_LOCATOR_SIGNATURE.to_le_bytes()));
+        let entries = read_central_directory(&mut Cursor::new(&data))
+            .await?
+            .entries;
+        assert_eq!(count as usize + 1, entries.len());
+
+        let disk = ZipFloppyDisk::open(&path).await?;
+        assert_eq!("", disk.read_to_string("/1234").await?);
+        assert_eq!("asdf\n", disk.read_to_string("/last.txt").await?);
+
+        Ok(())
+    }
+
+    const HUGE_SIZE: u64 = ZIP64_LIMIT + 1;
+
+    /// Builds a sparse archive with a stored entry over 4GiB, and an entry
+    /// after it that can only be found with a Zip64 offset.
+    fn write_sparse_zip(path: &Path) -> Result<()> {
+        let entry = |name: &str, data: &[u8], size: u64, offset: u64| ZipEntry {
+            version_made_by: HOST_UNIX << 8 | VERSION_DEFAULT,
+            flags: 0,
+            method: METHOD_STORED,
+            dos_time: 0,
+            dos_date: 1 << 5 | 1,
+            crc32: crc32fast::hash(data),
+            compressed_size: size,
+            uncompressed_size: size,
+            external_attributes: (S_IFREG | 0o644) << 16,
+            local_header_offset: offset,
+            name: name.as_bytes().to_vec(),
+            extra: vec![],
+            comment: vec![],
+        };
+
+        let mut file = std::fs::File::create(path)?;
+        let mut entries = vec![];
+        for (name, data, size) in [
+            ("a.txt", b"asdf\n".as_slice(), 5),
+            ("huge.bin", b"".as_slice(), HUGE_SIZE),
+            ("b.txt", b"wow!!!".as_slice(), 6),
+        ] {
+            let offset = file.stream_position()?;
+            let entry = entry(name, data, size, offset);
+            let mut header = vec![];
+            write_local_header(&mut header, &entry);
+            file.write_all(&header)?;
+            if size == HUGE_SIZE {
+                // Leave a hole instead of writing 4GiB of zeroes.
+                file.seek(SeekFrom::Current(HUGE_SIZE as i64))?;
+            } else {
+                file.write_all(data)?;
+            }
+            entries.push(entry);
+        }
+        let directory_offset = file.stream_position()?;
+        let mut directory = vec![];
+        for entry in &entries {
+            write_central_header(&mut directory, entry);
+        }
+        let directory_size = directory.len() as u64;
+        write_end_of_central_directory(
+            &mut directory,
+            entries.len() as u64,
+            directory_size,
+            directory_offset,
+            &[],
+        );
+        file.write_all(&directory)?;
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_zip64_offsets_work() -> Result<()> {
+        let dir = crate::util::TempDir::new().await?;
+        let path = dir.join("sparse.zip");
+        write_sparse_zip(&path)?;
+
+        let mut file = tokio::fs::File::open(&path).await?;
+        let read = read_central_directory(&mut file).await?.entries;
+        assert_eq!(3, read.len());
+        assert_eq!(HUGE_SIZE, read[1].uncompressed_size);
+        assert_eq!(HUGE_SIZE, read[1].compressed_size);
+        assert!(read[2].local_header_offset > ZIP64_LIMIT);
+        assert_eq!(
+            b"asdf\n".to_vec(),
+            read_entry_data(&mut file, &read[0]).await?
+        );
+        assert_eq!(
+            b"wow!!!".to_vec(),
+            read_entry_data(&mut file, &read[2]).await?
+        );
+
+        Ok(())
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_zip64_archives_round_trip() -> Result<()> {
+        let dir = crate::util::TempDir::new().await?;
+        let path = dir.join("sparse.zip");
+        write_sparse_zip(&path)?;
+        {
+            let disk = ZipFloppyDisk::open(&path).await?;
+            assert_eq!(HUGE_SIZE, disk.metadata("/huge.bin").await?.len());
+            disk.write("/c.txt", "more!!!").await?;
+            disk.close().await?;
+        }
+
+        let disk = ZipFloppyDisk::open(&path).await?;
+        assert_eq!(HUGE_SIZE, disk.metadata("/huge.bin").await?.len());
+        assert_eq!("asdf\n", disk.read_to_string("/a.txt").await?);
+        assert_eq!("wow!!!", disk.read_to_string("/b.txt").await?);
+        assert_eq!("more!!!", disk.read_to_string("/c.txt").await?);
+        let mut file = tokio::fs::File::open(&path).await?;
+        let read = read_central_directory(&mut file).await?.entries;
+        assert!(read[2].local_header_offset > ZIP64_LIMIT);
+        assert!(read[3].local_header_offset > ZIP64_LIMIT);
+        // The huge entry was copied as a hole rather than written out.
+        let metadata = std::fs::metadata(&path)?;
+        assert!(metadata.len() > ZIP64_LIMIT);
+        assert!(std::os::unix::fs::MetadataExt::blocks(&metadata) * 512 < ZIP64_LIMIT);
+
+        Ok(())
+    }
 }